@@ -15,36 +15,78 @@ use tower_http::trace::{
 use crate::{
     router, 
     services::{
-        dmc::PaletteDmc, processing::WorkDispatcher, ImageStorageService
+        dmc::PaletteDmc, 
+        processing::{
+            DispatcherOptions, 
+            WorkDispatcher
+        }, 
+        storage::{
+            open_backend, 
+            StorageBackendError
+        }, 
+        ImageStorageService
     }, 
-    settings::Settings
+    settings::{
+        Settings, 
+        Size
+    }
 };
 
 #[derive(Debug)]
 pub struct AppData {
+    pub image_min_width: u32,
+    pub image_min_height: u32,
     pub image_max_width: u32,
     pub image_max_height: u32,
+    pub image_max_pixels: u64,
+    pub canvas_max_size_cm: Size<f32>,
     pub palette_dmc_full: Arc<PaletteDmc>,
-    pub image_storage_service: tokio::sync::Mutex<ImageStorageService>,
+    /// Locked with [`AppData::with_image_storage`] whenever images may be read from or written to disk.
+    pub image_storage_service: Arc<tokio::sync::Mutex<ImageStorageService>>,
     pub processing_runner_service: tokio::sync::Mutex<WorkDispatcher>,
+    /// Set once the server shuts down, long-lived responses like event streams end then.
+    pub shutdown_tx: tokio::sync::watch::Sender<bool>,
 }
 
 impl Default for AppData {
     fn default() -> Self {
         Self { 
+            image_min_width: 1, 
+            image_min_height: 1, 
             image_max_width: 1024, 
             image_max_height: 1024, 
+            image_max_pixels: 1024 * 1024, 
+            canvas_max_size_cm: Size { width: 100.0, height: 100.0 },
             palette_dmc_full: Arc::new(PaletteDmc::default()),
-            image_storage_service: Mutex::new(ImageStorageService::new()),
+            image_storage_service: Arc::new(Mutex::new(ImageStorageService::new())),
             processing_runner_service: Mutex::new(WorkDispatcher::new()),
+            shutdown_tx: tokio::sync::watch::Sender::new(false),
         }
     }
 }
 
+impl AppData {
+    /// Runs `f` with the image storage locked on a blocking thread. Storage evicts images
+    /// to disk and loads them back, which must not block the async runtime.
+    pub async fn with_image_storage<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut ImageStorageService) -> T + Send + 'static,
+        T: Send + 'static
+    {
+        let mut image_storage_service_guard = self.image_storage_service.clone().lock_owned().await;
+        tokio::task::spawn_blocking(move || f(&mut image_storage_service_guard))
+            .await
+            .expect("blocking task panicked")
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AppServeError {
     #[error("IoError reson='{0}'")]
     IoError(#[from] tokio::io::Error),
+
+    #[error("StorageError reason='{0}'")]
+    StorageError(#[from] StorageBackendError),
 }
 
 #[derive(Debug)]
@@ -78,15 +120,61 @@ impl AppServeHandler {
     }
 }
 
+/// Periodically removes images which outlived their TTL, until stopped.
+async fn reap_expired_images(
+    app_data: Arc<AppData>, 
+    sweep_interval: std::time::Duration, 
+    mut stop_rx: tokio::sync::oneshot::Receiver<()>
+) {
+    let mut interval = tokio::time::interval(sweep_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = &mut stop_rx => break,
+            _ = interval.tick() => {
+                let (removed_count, images_count) = app_data.with_image_storage(|image_storage_service| {
+                    (image_storage_service.remove_expired(chrono::Utc::now()), image_storage_service.images_count())
+                }).await;
+                if removed_count > 0 {
+                    tracing::info!("Removed {removed_count} expired images, {images_count} left");
+                }
+            }
+        }
+    }
+
+    tracing::debug!("Image reaper stopped");
+}
+
 pub async fn app_serve(settings: Settings) -> Result<AppServeHandler, AppServeError> {
     let dmc_palette_filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(&settings.dmc_palette_path);
 
     let palette_dmc_full = PaletteDmc::load_from_file(dmc_palette_filepath).expect("DMC Fullpalette file should exist");
+
+    let storage_backend = open_backend(settings.storage_backend, std::path::Path::new(&settings.storage_path))?;
+    let mut image_storage_service = ImageStorageService::new()
+        .with_ttl(chrono::TimeDelta::from_std(settings.image_ttl).unwrap_or(chrono::TimeDelta::MAX))
+        .with_memory_budget(settings.image_memory_budget_bytes)
+        .with_backend(storage_backend);
+    let loaded_count = image_storage_service.load_persisted()?;
+    tracing::info!("Loaded {loaded_count} stored images from {:?} storage", settings.storage_backend);
+
     let app_data = Arc::new(AppData {
+        image_min_width: settings.image_min_size.width,
+        image_min_height: settings.image_min_size.height,
         image_max_width: settings.image_max_size.width,
         image_max_height: settings.image_max_size.height,
+        image_max_pixels: settings.image_max_pixels,
+        canvas_max_size_cm: settings.canvas_max_size_cm,
         palette_dmc_full: Arc::new(palette_dmc_full),
-        ..Default::default()
+        image_storage_service: Arc::new(Mutex::new(image_storage_service)),
+        processing_runner_service: Mutex::new(WorkDispatcher::with_options(DispatcherOptions {
+            workers_count: settings.workers_count,
+            kind_limits: settings.work_kind_limits.clone(),
+            client_queue_quota: settings.client_max_queued_works,
+            finished_work_ttl: settings.work_result_ttl,
+        })),
+        shutdown_tx: tokio::sync::watch::Sender::new(false),
     });
 
     let app = router::get_router(settings.image_max_bytes, app_data.clone())
@@ -102,13 +190,23 @@ pub async fn app_serve(settings: Settings) -> Result<AppServeHandler, AppServeEr
     tracing::info!("{}({}) listening on {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), address);
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let (reaper_stop_tx, reaper_stop_rx) = tokio::sync::oneshot::channel();
+    let app_shutdown_tx = app_data.shutdown_tx.clone();
+    let reaper_handle = tokio::spawn(reap_expired_images(app_data, settings.image_sweep_interval, reaper_stop_rx));
 
     let task_handle = tokio::spawn(async move {
-        axum::serve(listener, app)
+        let serve_result = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
             .with_graceful_shutdown(async move {
                 shutdown_rx.await.ok();
+                app_shutdown_tx.send_replace(true);
             })
-            .await
+            .await;
+
+        reaper_stop_tx.send(()).ok();
+        if let Err(e) = reaper_handle.await {
+            tracing::error!("Image reaper failed: {e}");
+        }
+        serve_result
     });
     
     Ok(AppServeHandler {
@@ -1,13 +1,22 @@
 use axum::{
     extract::multipart::MultipartError, 
-    http::StatusCode, 
+    http::{
+        header, 
+        StatusCode
+    }, 
     response::{
         IntoResponse, 
         Response
     }
 };
 
-use crate::services::{processing::ProcessingError, ImageStorageServiceError};
+use crate::services::{
+    canvas::CanvasError, 
+    dmc::DmcError, 
+    history::HistoryError, 
+    processing::{transform::TransformError, ProcessingError}, 
+    ImageStorageServiceError
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
@@ -19,6 +28,27 @@ pub enum AppError {
     
     #[error(transparent)]
     ProcessingError(#[from] ProcessingError),
+
+    #[error(transparent)]
+    Dmc(#[from] DmcError),
+
+    #[error(transparent)]
+    RequestParam(#[from] RequestParamError),
+
+    #[error(transparent)]
+    Canvas(#[from] CanvasError),
+
+    #[error(transparent)]
+    Transform(#[from] TransformError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequestParamError {
+    #[error("Invalid value of '{name}', reason='{reason}'")]
+    InvalidValue {
+        name: &'static str,
+        reason: String,
+    },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -41,6 +71,45 @@ pub enum UploadImageError {
         actual: u32,
     },
 
+    #[error("Image too narrow min={min}, actual={actual}")]
+    ImageTooNarrow {
+        min: u32,
+        actual: u32,
+    },
+
+    #[error("Image too short min={min}, actual={actual}")]
+    ImageTooShort {
+        min: u32,
+        actual: u32,
+    },
+
+    #[error("Image has too many pixels max={max}, actual={actual}")]
+    ImageTooManyPixels {
+        max: u64,
+        actual: u64,
+    },
+
+    #[error("FormatUnknown")]
+    FormatUnknown,
+
+    #[error("Format not allowed format='{format}'")]
+    FormatNotAllowed {
+        format: String,
+    },
+
+    #[error("Media type not allowed content_type='{content_type}'")]
+    MediaTypeNotAllowed {
+        content_type: String,
+    },
+
+    #[error("Unexpected multipart field name='{name}'")]
+    UnexpectedField {
+        name: String,
+    },
+
+    #[error("TooManyFields")]
+    TooManyFields,
+
     #[error("FilenameMissing")]
     FilenameMissing,
 
@@ -74,6 +143,14 @@ impl IntoResponse for AppError {
                 UploadImageError::FilenameEmpty => StatusCode::BAD_REQUEST,
                 UploadImageError::ImageTooWide { max: _, actual: _ } => StatusCode::BAD_REQUEST,
                 UploadImageError::ImageTooHigh { max: _, actual: _ } => StatusCode::BAD_REQUEST,
+                UploadImageError::ImageTooNarrow { .. } => StatusCode::BAD_REQUEST,
+                UploadImageError::ImageTooShort { .. } => StatusCode::BAD_REQUEST,
+                UploadImageError::ImageTooManyPixels { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                UploadImageError::FormatUnknown => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                UploadImageError::FormatNotAllowed { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                UploadImageError::MediaTypeNotAllowed { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                UploadImageError::UnexpectedField { .. } => StatusCode::BAD_REQUEST,
+                UploadImageError::TooManyFields => StatusCode::BAD_REQUEST,
                 UploadImageError::FilenameMissing => StatusCode::BAD_REQUEST,
                 UploadImageError::FilenameExtensionMissing => StatusCode::BAD_REQUEST,
                 UploadImageError::MultipartFailed(_) => StatusCode::BAD_REQUEST,
@@ -81,18 +158,59 @@ impl IntoResponse for AppError {
                 UploadImageError::ImageError(_) => StatusCode::BAD_REQUEST,
             },
             Self::ProcessingError(e) => match e {
-                ProcessingError::Busy => StatusCode::PROCESSING,
+                ProcessingError::Busy { .. } => StatusCode::TOO_MANY_REQUESTS,
                 ProcessingError::NotAvailable => StatusCode::PROCESSING,
                 ProcessingError::ServiceFailed => StatusCode::INTERNAL_SERVER_ERROR,
+                ProcessingError::NotStarted => StatusCode::NOT_FOUND,
+                ProcessingError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                ProcessingError::Cancelled => StatusCode::GONE,
             },
             Self::ImageStorageServiceError(e) => match e {
                 ImageStorageServiceError::FilenameStemMissing => StatusCode::BAD_REQUEST,
                 ImageStorageServiceError::FilenameExtensionMissing => StatusCode::BAD_REQUEST,
                 ImageStorageServiceError::ImageNotFound => StatusCode::NOT_FOUND,
+                ImageStorageServiceError::ImageExpired => StatusCode::GONE,
+                ImageStorageServiceError::Spill(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                ImageStorageServiceError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                ImageStorageServiceError::History(e) => match e {
+                    HistoryError::RevisionNotFound(_) => StatusCode::NOT_FOUND,
+                    HistoryError::NothingToUndo => StatusCode::CONFLICT,
+                    HistoryError::NothingToRedo => StatusCode::CONFLICT,
+                },
+            },
+            Self::Dmc(e) => match e {
+                DmcError::DmcCodeNotFound(_) => StatusCode::BAD_REQUEST,
+                DmcError::PaletteEmpty => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::RequestParam(e) => match e {
+                RequestParamError::InvalidValue { name: _, reason: _ } => StatusCode::BAD_REQUEST,
             },
+            Self::Canvas(e) => match e {
+                CanvasError::InvalidDimension { name: _, value: _ } => StatusCode::BAD_REQUEST,
+                CanvasError::TooLarge { .. } => StatusCode::BAD_REQUEST,
+                CanvasError::NoDrillsLeft => StatusCode::BAD_REQUEST,
+            },
+            Self::Transform(e) => match e {
+                TransformError::CropOutOfBounds { .. } => StatusCode::BAD_REQUEST,
+                TransformError::InvalidValue { .. } => StatusCode::BAD_REQUEST,
+                TransformError::IndexOutOfRange { .. } => StatusCode::NOT_FOUND,
+                TransformError::InvalidOrder { .. } => StatusCode::BAD_REQUEST,
+                TransformError::ImageTooLarge { .. } => StatusCode::BAD_REQUEST,
+                TransformError::StackTooLong { .. } => StatusCode::BAD_REQUEST,
+            },
+        };
+
+        // Busy dispatcher tells the client when to retry, at least a second later.
+        let retry_after = match &self {
+            Self::ProcessingError(ProcessingError::Busy { retry_after }) => Some(retry_after.as_secs().max(1)),
+            _ => None,
         };
 
         let body = axum::Json(serde_json::json!({ "error" : self.to_string()}));
-        (status_code, body).into_response()
+        match retry_after {
+            Some(retry_after) => (status_code, [(header::RETRY_AFTER, retry_after.to_string())], body).into_response(),
+            None => (status_code, body).into_response(),
+        }
     }
 }
@@ -1,11 +1,23 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::extract;
 
 use axum::{
-    response::Html,
+    response::{
+        sse::{
+            Event,
+            KeepAlive,
+            Sse
+        },
+        Html
+    },
     extract::Multipart
 };
+use futures_util::Stream;
 
 use crate::app::AppData;
 
@@ -13,32 +25,125 @@ use crate::errors::{
     AppError, 
     UploadImageError
 };
-use crate::requests::ExtractQueryMaxColorsCount;
+use crate::requests::{
+    ClientKey, 
+    ExtractQueryMaxColorsCount, 
+    PdfRenderQuery, 
+    PreviewQuery, 
+    SavePaletteRequest, 
+    TransformReorderRequest, 
+    UploadImageQuery
+};
 use crate::results::{
+    dmc_bom_to_entries, 
+    CancelProcessingResult, 
     FinishPaletteExtractionResult, 
+    FinishRenderResult, 
+    DrillGridResult, 
     GetPaletteResult, 
+    ImageHistoryResult, 
+    ImageMetaResult, 
+    ImageWorkStatus, 
+    ProcessingDoneEvent, 
+    ProcessingStatusResult, 
     StartPaletteExtractionResult, 
+    StartProcessingResult, 
+    StorageStatsResult, 
+    TransformStackResult, 
     UploadImageResult
 };
 
-use crate::services::processing::worker::Work;
+use crate::services::canvas::{
+    AspectMode, 
+    CanvasSpec, 
+    DrillShape, 
+    ResampleFilter
+};
+use crate::services::dmc::PaletteDmc;
+use crate::services::image_info::ImageSourceInfo;
+use crate::services::processing::worker::{
+    Work, 
+    WorkResult
+};
+use crate::services::processing::transform::{
+    TransformError, 
+    TransformLimits, 
+    TransformOp, 
+    TransformStack
+};
+use crate::services::processing::image_manip::{
+    decode_oriented, 
+    flatten_transparency, 
+    format_from_mime_type, 
+    read_dimensions, 
+    DrillMask, 
+    TransparencyPolicy, 
+    UPLOAD_FORMATS
+};
+use crate::services::processing::status::WorkStatus;
+use crate::services::processing::{
+    ProcessingError, 
+    WorkDispatcher, 
+    WorkOrigin
+};
+use crate::services::history::RevisionId;
 use crate::services::{
+    HashedImage, 
     ImageId, 
-    ImageStorageMeta
+    ImageStorageServiceError, 
+    ImageWorkKind, 
+    ImageWorkRecord
 };
 
 pub async fn overall_status() -> Html<&'static str> {
     Html("<h1>Diamonds imager is running!</h1>")
 }
 
-pub async fn upload_image(
-    extract::State(app_data): extract::State<Arc<AppData>>,
-    mut multipart: Multipart
-) -> Result<UploadImageResult, AppError> {    
+pub async fn get_storage_stats(
+    extract::State(app_data): extract::State<Arc<AppData>>
+) -> StorageStatsResult {
+    let images = app_data.with_image_storage(|image_storage_service| image_storage_service.stats()).await;
+    StorageStatsResult { images }
+}
+
+/// Multipart field holding the uploaded image, the only one accepted.
+const UPLOAD_FIELD_NAME: &str = "file";
+
+/// Checks the declared media type, generic binary type is left for the format check.
+fn check_upload_content_type(content_type: Option<&str>) -> Result<(), UploadImageError> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if mime_type == "application/octet-stream" {
+        return Ok(());
+    }
+
+    match format_from_mime_type(&mime_type) {
+        Some(format) if UPLOAD_FORMATS.contains(&format) => Ok(()),
+        _ => Err(UploadImageError::MediaTypeNotAllowed { content_type: content_type.to_string() }),
+    }
+}
+
+/// Reads the single multipart field as an upright image, checks its format and size and reads info of the file.
+/// Transparent pixels are handled by the policy, drill mask is made only for no drill policy.
+async fn read_uploaded_image(
+    app_data: &AppData,
+    mut multipart: Multipart,
+    transparency: TransparencyPolicy
+) -> Result<(String, HashedImage, Option<DrillMask>, ImageSourceInfo), AppError> {
     let Some(field) = multipart.next_field().await.map_err(UploadImageError::from)? else {
         return Err(UploadImageError::ImageEmpty.into());
     };
 
+    let field_name = field.name().unwrap_or_default();
+    if field_name != UPLOAD_FIELD_NAME {
+        return Err(UploadImageError::UnexpectedField { name: field_name.to_string() }.into());
+    }
+
+    check_upload_content_type(field.content_type())?;
+
     // Extract image and meta from uploaded field
     let uploaded_filename = field.file_name()
     .ok_or(UploadImageError::FilenameMissing)?
@@ -49,10 +154,26 @@ pub async fn upload_image(
     }
 
     let bytes = field.bytes().await.map_err(UploadImageError::from)?;
-    
-    let image = image::load_from_memory(&bytes).map_err(UploadImageError::ImageError)?;
 
-    // Check image
+    if multipart.next_field().await.map_err(UploadImageError::from)?.is_some() {
+        return Err(UploadImageError::TooManyFields.into());
+    }
+
+    // Check file before decoding
+    let format = image::guess_format(&bytes).map_err(|_| UploadImageError::FormatUnknown)?;
+    if !UPLOAD_FORMATS.contains(&format) {
+        return Err(UploadImageError::FormatNotAllowed { format: format!("{format:?}").to_lowercase() }.into());
+    }
+
+    let (width, height) = read_dimensions(&bytes).map_err(UploadImageError::ImageError)?;
+    let pixels_count = width as u64 * height as u64;
+    if pixels_count > app_data.image_max_pixels {
+        return Err(UploadImageError::ImageTooManyPixels { max: app_data.image_max_pixels, actual: pixels_count }.into());
+    }
+
+    let image = decode_oriented(&bytes).map_err(UploadImageError::ImageError)?;
+
+    // Check upright image
     let (width, height) = (image.width(), image.height());
 
     if width > app_data.image_max_width {
@@ -63,26 +184,244 @@ pub async fn upload_image(
         return Err(UploadImageError::ImageTooHigh { max: app_data.image_max_height, actual: height }.into());
     }
 
-    let mut image_storage_service_guard = app_data.image_storage_service.lock().await;
-    let id = image_storage_service_guard.insert_image(uploaded_filename, image)?;
+    if width < app_data.image_min_width {
+        return Err(UploadImageError::ImageTooNarrow { min: app_data.image_min_width, actual: width }.into());
+    }
+
+    if height < app_data.image_min_height {
+        return Err(UploadImageError::ImageTooShort { min: app_data.image_min_height, actual: height }.into());
+    }
+
+    // Flattening, counting colors and hashing go through every pixel
+    let (image, drill_mask, source) = tokio::task::spawn_blocking(move || {
+        let (image, drill_mask) = flatten_transparency(image, transparency);
+        let source = ImageSourceInfo::read(&bytes, &image);
+        (HashedImage::new(image), drill_mask, source)
+    })
+    .await
+    .expect("blocking task panicked");
+
+    Ok((uploaded_filename, image, drill_mask, source))
+}
+
+pub async fn upload_image(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Query(query): extract::Query<UploadImageQuery>,
+    multipart: Multipart
+) -> Result<UploadImageResult, AppError> {    
+    let transparency = query.transparency_policy()?;
+    let (uploaded_filename, image, drill_mask, source) = read_uploaded_image(&app_data, multipart, transparency).await?;
+    let (width, height) = (image.width(), image.height());
+
+    let id = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.insert_image(uploaded_filename, image, drill_mask, source)
+    }).await?;
 
     Ok(UploadImageResult { id, width, height })
 }
 
+/// Replaces the image by a new revision, previous ones stay in history.
+pub async fn replace_image(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Query(query): extract::Query<UploadImageQuery>,
+    multipart: Multipart
+) -> Result<ImageHistoryResult, AppError> {
+    let transparency = query.transparency_policy()?;
+    let (_, image, drill_mask, source) = read_uploaded_image(&app_data, multipart, transparency).await?;
+
+    let history = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.replace_image(&id, image, drill_mask, source)?;
+        image_storage_service.get_history(&id)
+    }).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
+pub async fn get_image_history(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<ImageHistoryResult, AppError> {
+    let history = app_data.with_image_storage(move |image_storage_service| image_storage_service.get_history(&id)).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
+pub async fn undo_image_revision(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<ImageHistoryResult, AppError> {
+    let history = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.undo(&id)?;
+        image_storage_service.get_history(&id)
+    }).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
+pub async fn redo_image_revision(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<ImageHistoryResult, AppError> {
+    let history = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.redo(&id)?;
+        image_storage_service.get_history(&id)
+    }).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
+pub async fn jump_to_image_revision(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path((id, revision)): extract::Path<(ImageId, RevisionId)>
+) -> Result<ImageHistoryResult, AppError> {
+    let history = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.jump_to_revision(&id, revision)?;
+        image_storage_service.get_history(&id)
+    }).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
+/// Saves palette choice as a new revision, the palette is used when rendering without `palette` query.
+pub async fn save_image_palette(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Json(request): extract::Json<SavePaletteRequest>
+) -> Result<ImageHistoryResult, AppError> {
+    if let Some(codes) = &request.palette {
+        // Only known DMCs can be saved
+        app_data.palette_dmc_full.subset_by_codes(codes.iter().map(String::as_str))?;
+    }
+
+    let history = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.save_palette(&id, request.palette)?;
+        image_storage_service.get_history(&id)
+    }).await?;
+    Ok(ImageHistoryResult::from(&history))
+}
+
 pub async fn get_image_meta(
     extract::State(app_data): extract::State<Arc<AppData>>,
     extract::Path(id): extract::Path<ImageId>
-) -> Result<ImageStorageMeta, AppError> {
-    let image_storage_service_guard = app_data.image_storage_service.lock().await;
-    image_storage_service_guard.get_image_meta(&id).map_err(AppError::from)
+) -> Result<ImageMetaResult, AppError> {
+    let (meta, revision) = app_data.with_image_storage(move |image_storage_service| {
+        Ok::<_, ImageStorageServiceError>((image_storage_service.get_image_meta(&id)?, image_storage_service.get_revision(&id, None)?))
+    }).await?;
+
+    // Grid of the current revision as it would be processed
+    let (width, height) = revision.transforms.output_size(revision.image.width(), revision.image.height())?;
+
+    // Canvas the image is ordered on, the largest one until any is set
+    let canvas = meta.canvas.unwrap_or(CanvasSpec {
+        width_cm: app_data.canvas_max_size_cm.width,
+        height_cm: app_data.canvas_max_size_cm.height,
+        drill: DrillShape::default(),
+        margin_cm: 0.0,
+        filter: ResampleFilter::default(),
+        aspect: AspectMode::Fit
+    });
+    let grid = canvas.resampled_size(width, height)?;
+
+    Ok(ImageMetaResult {
+        meta,
+        drill_grid: DrillGridResult {
+            canvas_width_cm: canvas.width_cm,
+            canvas_height_cm: canvas.height_cm,
+            drill: canvas.drill,
+            width: grid.width,
+            height: grid.height
+        }
+    })
 }
 
 pub async fn delete_image(
     extract::State(app_data): extract::State<Arc<AppData>>,
     extract::Path(id): extract::Path<ImageId>
 ) -> Result<(), AppError> {
-    let mut image_storage_service_guard = app_data.image_storage_service.lock().await;
-    image_storage_service_guard.remove_image(&id).map_err(AppError::from)
+    app_data.with_image_storage(move |image_storage_service| image_storage_service.remove_image(&id)).await
+        .map_err(AppError::from)
+}
+
+/// Edits transform stack of the image. Edited stack is stored only when it is valid for the image
+/// and makes image no larger than an upload can be.
+async fn update_image_transforms<F>(
+    app_data: &AppData,
+    id: &ImageId,
+    update: F
+) -> Result<TransformStackResult, AppError>
+where 
+    F: FnOnce(&mut TransformStack) -> Result<(), TransformError> + Send + 'static
+{
+    let limits = TransformLimits {
+        max_width: app_data.image_max_width,
+        max_height: app_data.image_max_height,
+        max_pixels: app_data.image_max_pixels,
+    };
+    let id = id.clone();
+    app_data.with_image_storage(move |image_storage_service| {
+        let revision = image_storage_service.get_revision(&id, None)?;
+
+        let mut transforms = revision.transforms;
+        update(&mut transforms)?;
+        let (width, height) = transforms.output_size_within(revision.image.width(), revision.image.height(), limits)?;
+
+        image_storage_service.set_transforms(&id, transforms.clone())?;
+        Ok(TransformStackResult { transforms, width, height })
+    }).await
+}
+
+pub async fn get_image_transforms(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<TransformStackResult, AppError> {
+    let revision = app_data.with_image_storage(move |image_storage_service| image_storage_service.get_revision(&id, None)).await?;
+
+    let transforms = revision.transforms;
+    let (width, height) = transforms.output_size(revision.image.width(), revision.image.height())?;
+    Ok(TransformStackResult { transforms, width, height })
+}
+
+pub async fn push_image_transform(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Json(op): extract::Json<TransformOp>
+) -> Result<TransformStackResult, AppError> {
+    update_image_transforms(&app_data, &id, move |transforms| {
+        transforms.push(op);
+        Ok(())
+    }).await
+}
+
+pub async fn replace_image_transforms(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Json(new_transforms): extract::Json<TransformStack>
+) -> Result<TransformStackResult, AppError> {
+    update_image_transforms(&app_data, &id, move |transforms| {
+        *transforms = new_transforms;
+        Ok(())
+    }).await
+}
+
+pub async fn reorder_image_transforms(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Json(request): extract::Json<TransformReorderRequest>
+) -> Result<TransformStackResult, AppError> {
+    update_image_transforms(&app_data, &id, move |transforms| transforms.reorder(&request.order)).await
+}
+
+pub async fn remove_image_transform(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path((id, index)): extract::Path<(ImageId, usize)>
+) -> Result<TransformStackResult, AppError> {
+    update_image_transforms(&app_data, &id, move |transforms| transforms.remove(index).map(|_| ())).await
+}
+
+pub async fn reset_image_transforms(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<TransformStackResult, AppError> {
+    update_image_transforms(&app_data, &id, |transforms| {
+        transforms.clear();
+        Ok(())
+    }).await
 }
 
 pub async fn get_full_dmc_palette(
@@ -93,35 +432,530 @@ pub async fn get_full_dmc_palette(
     }
 }
 
+/// Starts work bound to the image, unless the image already awaits work of the same kind.
+/// With `supersede` the awaited work is discarded and the new one is started instead.
+/// Awaited work forgotten by the dispatcher doesn't hold the image back.
+/// Work gets the image of the revision and its drill mask with the transform stack applied,
+/// the current revision when `revision` is `None`.
+/// Returns `false` if the work was not started.
+async fn start_image_work<F>(
+    app_data: &AppData,
+    id: &ImageId,
+    revision: Option<RevisionId>,
+    kind: ImageWorkKind,
+    supersede: bool,
+    origin: WorkOrigin,
+    create_work: F
+) -> Result<bool, AppError> 
+where 
+    F: FnOnce(Arc<image::RgbImage>, Option<Arc<DrillMask>>) -> Work
+{
+    let image_id = id.clone();
+    let (cloned_image, drill_mask, transforms, pending_work_id) = app_data.with_image_storage(move |image_storage_service| {
+        let element = image_storage_service.access_image(&image_id)?;
+        let pending_work_id = match element.works.get(&kind) {
+            Some(ImageWorkRecord::Pending(work_id)) => Some(*work_id),
+            _ => None,
+        };
+
+        let revision = element.history.revision(revision)?;
+        Ok::<_, ImageStorageServiceError>((revision.image.clone(), revision.drill_mask.clone(), revision.transforms.clone(), pending_work_id))
+    }).await?;
+
+    // Refused early, before the image is transformed
+    if let (Some(pending_work_id), false) = (pending_work_id, supersede) {
+        if app_data.processing_runner_service.lock().await.get_work_status(pending_work_id).is_some() {
+            return Ok(false);
+        }
+    }
+
+    let (cloned_image, drill_mask) = if transforms.is_empty() {
+        (cloned_image, drill_mask)
+    } else {
+        let (transformed_image, transformed_mask) = tokio::task::spawn_blocking(move || {
+            let transformed_mask = drill_mask.map(|drill_mask| transforms.apply_to_drill_mask(&drill_mask)).transpose()?;
+            transforms.apply(&cloned_image).map(|transformed_image| (transformed_image, transformed_mask))
+        })
+            .await
+            .expect("blocking task panicked")?;
+        (Arc::new(transformed_image), transformed_mask.map(Arc::new))
+    };
+
+    // Dispatcher stays locked until the work is bound, so concurrent starts
+    // see the work of each other and no work is left unbound
+    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
+    let image_id = id.clone();
+    let work_record = app_data.with_image_storage(move |image_storage_service| image_storage_service.get_work_record(&image_id, kind)).await?;
+
+    // Only one work of a kind per image at once
+    if let Some(ImageWorkRecord::Pending(pending_work_id)) = work_record {
+        if supersede {
+            processing_runner_service_guard.discard_work(pending_work_id).await;
+        } else if processing_runner_service_guard.get_work_status(pending_work_id).is_some() {
+            return Ok(false);
+        }
+    }
+
+    let work_id = processing_runner_service_guard.enque_work_from(create_work(cloned_image, drill_mask), origin).await?;
+
+    let id = id.clone();
+    if let Err(e) = app_data.with_image_storage(move |image_storage_service| image_storage_service.bind_work(&id, kind, work_id)).await {
+        // Nobody would collect the work of a gone image
+        processing_runner_service_guard.discard_work(work_id).await;
+        return Err(e.into());
+    }
+
+    Ok(true)
+}
+
+/// Returns result of work bound to the image or `None` if it is still in progress.
+async fn poll_image_work(
+    app_data: &AppData,
+    id: &ImageId,
+    kind: ImageWorkKind,
+) -> Result<Option<Arc<WorkResult>>, AppError> {
+    // Dispatcher stays locked until the record is updated, so pending record
+    // is never seen after the dispatcher forgot the collected work
+    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
+    let image_id = id.clone();
+    let work_record = app_data.with_image_storage(move |image_storage_service| image_storage_service.get_work_record(&image_id, kind)).await?;
+
+    match work_record {
+        None => Err(ProcessingError::NotStarted.into()),
+        Some(ImageWorkRecord::Finished(work_result)) => Ok(Some(work_result)),
+        Some(ImageWorkRecord::Failed(reason)) => Err(ProcessingError::Failed(reason).into()),
+        Some(ImageWorkRecord::Cancelled) => Err(ProcessingError::Cancelled.into()),
+        Some(ImageWorkRecord::Pending(work_id)) => {
+            let work_result = processing_runner_service_guard.get_work_result(work_id, None).await;
+
+            match work_result {
+                Ok(work_result) => {
+                    let id = id.clone();
+                    let work_result = app_data.with_image_storage(move |image_storage_service| {
+                        image_storage_service.finish_work(&id, kind, work_id, work_result)
+                    }).await?;
+                    Ok(Some(work_result))
+                },
+                // Finished work not collected in time is forgotten
+                Err(ProcessingError::NotAvailable) if processing_runner_service_guard.get_work_status(work_id).is_none() => {
+                    Err(ProcessingError::NotStarted.into())
+                },
+                Err(ProcessingError::NotAvailable) => Ok(None),
+                Err(ProcessingError::Failed(reason)) => {
+                    let (id, failed_reason) = (id.clone(), reason.clone());
+                    app_data.with_image_storage(move |image_storage_service| {
+                        image_storage_service.fail_work(&id, kind, work_id, failed_reason)
+                    }).await?;
+                    Err(ProcessingError::Failed(reason).into())
+                },
+                Err(ProcessingError::Cancelled) => {
+                    let id = id.clone();
+                    app_data.with_image_storage(move |image_storage_service| image_storage_service.cancel_work(&id, kind, work_id)).await?;
+                    Err(ProcessingError::Cancelled.into())
+                },
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Cancels work bound to the image. Returns `false` if the work is no longer in progress.
+async fn cancel_image_work(
+    app_data: &AppData,
+    id: &ImageId,
+    kind: ImageWorkKind,
+) -> Result<bool, AppError> {
+    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
+    let image_id = id.clone();
+    let work_record = app_data.with_image_storage(move |image_storage_service| image_storage_service.get_work_record(&image_id, kind)).await?;
+
+    match work_record {
+        None => Err(ProcessingError::NotStarted.into()),
+        Some(ImageWorkRecord::Pending(work_id)) => {
+            let was_cancelled = processing_runner_service_guard.cancel_work(work_id);
+            if was_cancelled {
+                let id = id.clone();
+                app_data.with_image_storage(move |image_storage_service| image_storage_service.cancel_work(&id, kind, work_id)).await?;
+            }
+            Ok(was_cancelled)
+        },
+        Some(ImageWorkRecord::Finished(_) | ImageWorkRecord::Failed(_) | ImageWorkRecord::Cancelled) => Ok(false),
+    }
+}
+
+/// Statuses of works bound to the image, pending ones are looked up in the dispatcher.
+/// Pending work forgotten by the dispatcher is expired.
+async fn read_processing_status(app_data: &AppData, id: &ImageId) -> Result<ProcessingStatusResult, AppError> {
+    // Locked first, so no result is collected until pending works are looked up
+    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
+    let image_id = id.clone();
+    let work_records = app_data.with_image_storage(move |image_storage_service| {
+        image_storage_service.access_image(&image_id).map(|element| element.works.clone())
+    }).await?;
+
+    Ok(works_status(&processing_runner_service_guard, &work_records))
+}
+
+/// Like [`read_processing_status`], but the image is not touched. Work records are returned too.
+async fn peek_processing_status(
+    app_data: &AppData, 
+    id: &ImageId
+) -> Result<(ProcessingStatusResult, HashMap<ImageWorkKind, ImageWorkRecord>), AppError> {
+    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
+    let image_id = id.clone();
+    let work_records = app_data.with_image_storage(move |image_storage_service| image_storage_service.peek_works(&image_id)).await?;
+
+    let status = works_status(&processing_runner_service_guard, &work_records);
+    Ok((status, work_records))
+}
+
+fn works_status(
+    processing_runner_service: &WorkDispatcher, 
+    work_records: &HashMap<ImageWorkKind, ImageWorkRecord>
+) -> ProcessingStatusResult {
+    let works = ImageWorkKind::ALL.into_iter()
+        .filter_map(|kind| {
+            let status = match work_records.get(&kind)? {
+                ImageWorkRecord::Pending(work_id) => processing_runner_service.get_work_status(*work_id)
+                    .unwrap_or(WorkStatus::Expired),
+                ImageWorkRecord::Finished(_) => WorkStatus::Done,
+                ImageWorkRecord::Failed(reason) => WorkStatus::Failed { reason: reason.clone() },
+                ImageWorkRecord::Cancelled => WorkStatus::Cancelled,
+            };
+            Some(ImageWorkStatus { kind, status })
+        })
+        .collect();
+
+    ProcessingStatusResult { works }
+}
+
+pub async fn get_processing_status(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<ProcessingStatusResult, AppError> {
+    read_processing_status(&app_data, &id).await
+}
+
+/// Status is read again at least this often, so works newly bound to the image are pushed
+/// even when no status of the dispatcher changes.
+const PROCESSING_EVENTS_RECHECK: Duration = Duration::from_secs(1);
+
+/// Events waiting for a slow client.
+const PROCESSING_EVENTS_QUEUE_CAP: usize = 16;
+
+/// Where the finished work can be downloaded, result of extraction is sent as is.
+fn processing_done_event(id: &ImageId, kind: ImageWorkKind, work_result: &WorkResult) -> ProcessingDoneEvent {
+    let (palette, url, confetti_report) = match work_result {
+        WorkResult::PaletteExtract { dmc_bom } => (Some(dmc_bom_to_entries(dmc_bom)), None, None),
+        WorkResult::ImageDither { confetti_report, .. } => (None, Some(format!("/api/preview/{id}")), *confetti_report),
+        WorkResult::PdfRender { confetti_report, .. } => (None, Some(format!("/api/pdf/{id}")), *confetti_report),
+        #[cfg(test)]
+        WorkResult::TestWork => (None, None, None),
+    };
+
+    ProcessingDoneEvent { kind, palette, url, confetti_report }
+}
+
+/// Pushes events of the image until the client disconnects, the image is gone or the server
+/// shuts down. Finished works are collected here, so the client doesn't have to poll for them.
+/// The image is touched only by collecting, an open stream doesn't keep the image alive.
+async fn push_processing_events(app_data: Arc<AppData>, id: ImageId, events_tx: tokio::sync::mpsc::Sender<Event>) {
+    let mut status_changes = app_data.processing_runner_service.lock().await.subscribe_status_changes();
+    let mut shutdown_rx = app_data.shutdown_tx.subscribe();
+    let mut last_status = None;
+    // Restarted work has a new result, pushed results tell it apart from the old one
+    let mut pushed_results: HashMap<ImageWorkKind, Arc<WorkResult>> = HashMap::new();
+
+    loop {
+        // Marked before reading, so no change after reading is missed
+        status_changes.mark_unchanged();
+
+        let (status, work_records) = match peek_processing_status(&app_data, &id).await {
+            Ok(status) => status,
+            Err(e) => {
+                let _ = events_tx.send(Event::default().event("error").data(e.to_string())).await;
+                return;
+            }
+        };
+
+        let mut events = vec![];
+        for work in status.works.iter().filter(|work| work.status.is_finished()) {
+            let work_result = match work_records.get(&work.kind) {
+                Some(ImageWorkRecord::Finished(work_result)) => work_result.clone(),
+                // Failure is already reported in the status
+                Some(ImageWorkRecord::Pending(_)) => match poll_image_work(&app_data, &id, work.kind).await {
+                    Ok(Some(work_result)) => work_result,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            if pushed_results.get(&work.kind).is_some_and(|pushed| Arc::ptr_eq(pushed, &work_result)) {
+                continue;
+            }
+
+            let done = processing_done_event(&id, work.kind, &work_result);
+            events.push(Event::default().event("done").json_data(done).expect("done event is serializable"));
+            pushed_results.insert(work.kind, work_result);
+        }
+
+        if last_status.as_ref() != Some(&status) {
+            events.insert(0, Event::default().event("status").json_data(&status).expect("status event is serializable"));
+            last_status = Some(status);
+        }
+
+        for event in events {
+            if events_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+
+        tokio::select! {
+            _ = events_tx.closed() => return,
+            _ = shutdown_rx.wait_for(|is_shutdown| *is_shutdown) => return,
+            changed = tokio::time::timeout(PROCESSING_EVENTS_RECHECK, status_changes.changed()) => {
+                if let Ok(Err(_)) = changed {
+                    tracing::debug!("Dispatcher is gone, stopping events of image {id}");
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// Server-Sent Events of works bound to the image: `status` with statuses of all works
+/// whenever any of them changes (including progress), `done` with the result once a work
+/// finishes and `error` when the image is gone, which ends the stream.
+pub async fn stream_processing_events(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    // Unknown image is an error response, not an empty stream
+    read_processing_status(&app_data, &id).await?;
+
+    let (events_tx, events_rx) = tokio::sync::mpsc::channel(PROCESSING_EVENTS_QUEUE_CAP);
+    tokio::spawn(push_processing_events(app_data, id, events_tx));
+
+    let events = futures_util::stream::unfold(events_rx, |mut events_rx| async move {
+        events_rx.recv().await.map(|event| (Ok(event), events_rx))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Palette used to render the image. If DMC codes are given, palette consists only of them.
+/// Otherwise it is the palette saved in the revision, the extracted palette if extraction
+/// has finished, or full DMC palette.
+async fn get_image_render_palette(
+    app_data: &AppData, 
+    id: &ImageId, 
+    revision: Option<RevisionId>, 
+    dmc_codes: Option<&str>
+) -> Result<Arc<PaletteDmc>, AppError> {
+    if let Some(dmc_codes) = dmc_codes {
+        let codes = dmc_codes.split(',').filter(|code| !code.trim().is_empty());
+        return Ok(Arc::new(app_data.palette_dmc_full.subset_by_codes(codes)?));
+    }
+
+    let id = id.clone();
+    let (saved_palette, work_record) = app_data.with_image_storage(move |image_storage_service| {
+        let saved_palette = image_storage_service.get_revision(&id, revision)?.palette;
+        let work_record = image_storage_service.get_work_record(&id, ImageWorkKind::PaletteExtract)?;
+        Ok::<_, ImageStorageServiceError>((saved_palette, work_record))
+    }).await?;
+
+    if let Some(saved_palette) = saved_palette {
+        return Ok(Arc::new(app_data.palette_dmc_full.subset_by_codes(saved_palette.iter().map(String::as_str))?));
+    }
+
+    if let Some(ImageWorkRecord::Finished(work_result)) = work_record {
+        if let WorkResult::PaletteExtract { dmc_bom } = work_result.as_ref() {
+            if !dmc_bom.is_empty() {
+                return Ok(Arc::new(PaletteDmc::from(dmc_bom)));
+            }
+        }
+    }
+
+    Ok(app_data.palette_dmc_full.clone())
+}
+
+/// Filename of processing output, derived from the image ID.
+fn output_filename(id: &ImageId, extension: &str) -> String {
+    let filename_stem = Path::new(id).file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    format!("{filename_stem}.{extension}")
+}
+
 pub async fn start_extracting_dmc_palette(
     extract::State(app_data): extract::State<Arc<AppData>>,
     extract::Path(id): extract::Path<ImageId>,
     extract::Query(query_max_colors): extract::Query<ExtractQueryMaxColorsCount>,
-) -> Result<(), AppError> {
-    let cloned_image = {
-        let image_storage_service_guard = app_data.image_storage_service.lock().await;
-        let element = image_storage_service_guard.access_image(&id)?;
-        element.image.clone()
-    };
-
-    let processing_runner_service_guard = app_data.processing_runner_service.lock().await;
-    let start_result = processing_runner_service_guard.enque_work(Work::PaletteExtract {
+    ClientKey { address, session }: ClientKey,
+) -> Result<StartPaletteExtractionResult, AppError> {
+    let max_colors = query_max_colors.max_colors()?;
+    let kmeans = query_max_colors.kmeans_options()?;
+    let was_started = start_image_work(&app_data, &id, query_max_colors.revision, ImageWorkKind::PaletteExtract, false, WorkOrigin {
+        client: address,
+        session,
+        batch: query_max_colors.batch.unwrap_or(false),
+    }, |src_image, _| Work::PaletteExtract {
         palette_dmc: app_data.palette_dmc_full.clone(),
-        src_image: cloned_image, 
-        max_colors: query_max_colors.max_colors
-    }).await;
+        src_image, 
+        max_colors,
+        mode: query_max_colors.mode.unwrap_or_default(),
+        color_distance: query_max_colors.color_distance.unwrap_or_default(),
+        kmeans,
+    }).await?;
 
-    let work_id = start_result.map_err(AppError::from);
-    Ok(())
+    Ok(StartPaletteExtractionResult { was_started })
 }
 
 pub async fn poll_finish_extracting_dmc_palette(
     extract::State(app_data): extract::State<Arc<AppData>>,
     extract::Path(id): extract::Path<ImageId>
 ) -> Result<FinishPaletteExtractionResult, AppError> {
-    // let mut image_storage_service_guard = app_data.image_storage_service.lock().await;
-    // // TODO consider offloading to blocking, can be CPU intensive
-    // let palette = image_storage_service_guard.get_palette_from_image(&id)?;
-    // Ok(GetPaletteResult { palette })
-    todo!()
-}
\ No newline at end of file
+    let Some(work_result) = poll_image_work(&app_data, &id, ImageWorkKind::PaletteExtract).await? else {
+        return Ok(FinishPaletteExtractionResult { result: None });
+    };
+
+    match work_result.as_ref() {
+        WorkResult::PaletteExtract { dmc_bom } => Ok(FinishPaletteExtractionResult { 
+            result: Some(dmc_bom_to_entries(dmc_bom)) 
+        }),
+        _ => Err(ProcessingError::ServiceFailed.into()),
+    }
+}
+
+pub async fn cancel_extracting_dmc_palette(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<CancelProcessingResult, AppError> {
+    let was_cancelled = cancel_image_work(&app_data, &id, ImageWorkKind::PaletteExtract).await?;
+    Ok(CancelProcessingResult { was_cancelled })
+}
+
+pub async fn start_generating_preview(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Query(query): extract::Query<PreviewQuery>,
+    ClientKey { address, session }: ClientKey,
+) -> Result<StartProcessingResult, AppError> {
+    let drill_size = query.drill_size()?;
+    let dithering = query.dithering_options()?;
+    let confetti = query.confetti_options()?;
+    let canvas = query.canvas_spec()?;
+    if let Some(canvas) = &canvas {
+        canvas.validate(app_data.canvas_max_size_cm)?;
+    }
+    let palette_dmc = get_image_render_palette(&app_data, &id, query.revision, query.palette.as_deref()).await?;
+
+    let was_started = start_image_work(&app_data, &id, query.revision, ImageWorkKind::Preview, query.latest_wins.unwrap_or(false), WorkOrigin {
+        client: address,
+        session,
+        batch: query.batch.unwrap_or(false),
+    }, |src_image, drill_mask| Work::ImageDither {
+        palette_dmc,
+        src_image, 
+        drill_mask, 
+        drill_size,
+        dithering,
+        confetti,
+        canvas,
+    }).await?;
+
+    if let (true, Some(canvas)) = (was_started, canvas) {
+        app_data.with_image_storage(move |image_storage_service| image_storage_service.set_canvas(&id, canvas)).await?;
+    }
+
+    Ok(StartProcessingResult { was_started })
+}
+
+pub async fn poll_finish_generating_preview(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<FinishRenderResult, AppError> {
+    let Some(work_result) = poll_image_work(&app_data, &id, ImageWorkKind::Preview).await? else {
+        return Ok(FinishRenderResult::Pending);
+    };
+
+    match work_result.as_ref() {
+        WorkResult::ImageDither { preview_png, confetti_report, .. } => Ok(FinishRenderResult::Ready { 
+            content_type: "image/png",
+            filename: output_filename(&id, "png"), 
+            attachment: false,
+            content: preview_png.clone(),
+            confetti_report: *confetti_report,
+        }),
+        _ => Err(ProcessingError::ServiceFailed.into()),
+    }
+}
+
+pub async fn cancel_generating_preview(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<CancelProcessingResult, AppError> {
+    let was_cancelled = cancel_image_work(&app_data, &id, ImageWorkKind::Preview).await?;
+    Ok(CancelProcessingResult { was_cancelled })
+}
+
+pub async fn start_rendering_pdf(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>,
+    extract::Query(query): extract::Query<PdfRenderQuery>,
+    ClientKey { address, session }: ClientKey,
+) -> Result<StartProcessingResult, AppError> {
+    let dithering = query.dithering_options()?;
+    let confetti = query.confetti_options()?;
+    let canvas = query.canvas_spec()?;
+    if let Some(canvas) = &canvas {
+        canvas.validate(app_data.canvas_max_size_cm)?;
+    }
+    let palette_dmc = get_image_render_palette(&app_data, &id, query.revision, query.palette.as_deref()).await?;
+
+    let was_started = start_image_work(&app_data, &id, query.revision, ImageWorkKind::PdfRender, false, WorkOrigin {
+        client: address,
+        session,
+        batch: query.batch.unwrap_or(false),
+    }, |src_image, drill_mask| Work::PdfRender {
+        palette_dmc,
+        src_image, 
+        drill_mask, 
+        dithering,
+        confetti,
+        canvas,
+    }).await?;
+
+    if let (true, Some(canvas)) = (was_started, canvas) {
+        app_data.with_image_storage(move |image_storage_service| image_storage_service.set_canvas(&id, canvas)).await?;
+    }
+
+    Ok(StartProcessingResult { was_started })
+}
+
+pub async fn poll_finish_rendering_pdf(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<FinishRenderResult, AppError> {
+    let Some(work_result) = poll_image_work(&app_data, &id, ImageWorkKind::PdfRender).await? else {
+        return Ok(FinishRenderResult::Pending);
+    };
+
+    match work_result.as_ref() {
+        WorkResult::PdfRender { pdf, confetti_report, .. } => Ok(FinishRenderResult::Ready { 
+            content_type: "application/pdf",
+            filename: output_filename(&id, "pdf"), 
+            attachment: true,
+            content: pdf.clone(),
+            confetti_report: *confetti_report,
+        }),
+        _ => Err(ProcessingError::ServiceFailed.into()),
+    }
+}
+
+pub async fn cancel_rendering_pdf(
+    extract::State(app_data): extract::State<Arc<AppData>>,
+    extract::Path(id): extract::Path<ImageId>
+) -> Result<CancelProcessingResult, AppError> {
+    let was_cancelled = cancel_image_work(&app_data, &id, ImageWorkKind::PdfRender).await?;
+    Ok(CancelProcessingResult { was_cancelled })
+}
@@ -1,6 +1,406 @@
+use ditherum::{
+    algorithms::{
+        confetti::ConfettiOptions, 
+        dithering::{
+            DitheringMethod, 
+            DitheringOptions, 
+            ErrorDiffusion
+        }, 
+        kernel::ScanOrder, 
+        kmean::KMeansOptions
+    }, 
+    palette_utils::ColorDistance
+};
+use std::{
+    convert::Infallible, 
+    net::SocketAddr
+};
+
+use axum::{
+    extract::{
+        ConnectInfo, 
+        FromRequestParts
+    }, 
+    http::request::Parts
+};
 use serde::Deserialize;
 
+use crate::errors::RequestParamError;
+use crate::services::history::RevisionId;
+use crate::services::canvas::{
+    AspectMode, 
+    CanvasSpec, 
+    DrillShape, 
+    ResampleFilter
+};
+use crate::services::processing::image_manip::{
+    PaletteExtractMode, 
+    TransparencyPolicy, 
+    DRILL_SIZE_RANGE, 
+    PALETTE_EXTRACT_MAX_COLORS_RANGE
+};
+
+/// Header naming the session of the client, it tells apart clients behind the same address.
+const SESSION_ID_HEADER: &str = "x-session-id";
+const SESSION_ID_MAX_LEN: usize = 128;
+
+/// Key of the client which orders works, used to share workers fairly between clients.
+/// Client is the IP address of the peer, which the client can't choose. Session given by
+/// `X-Session-Id` header only tells apart clients behind the same address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientKey {
+    pub address: String,
+    pub session: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientKey {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let address = parts.extensions.get::<ConnectInfo<SocketAddr>>()
+            .map_or_else(|| "unknown".to_string(), |ConnectInfo(address)| format!("ip:{}", address.ip()));
+
+        let session = parts.headers.get(SESSION_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|session_id| !session_id.is_empty() && session_id.len() <= SESSION_ID_MAX_LEN)
+            .map(str::to_string);
+
+        Ok(Self { address, session })
+    }
+}
+
+/// Builds dithering options from optional query parameters, missing ones take defaults.
+fn dithering_options(
+    color_distance: Option<ColorDistance>,
+    method: Option<DitheringMethod>,
+    error_diffusion: Option<ErrorDiffusion>,
+    error_strength: Option<f32>,
+    scan_order: Option<ScanOrder>
+) -> Result<DitheringOptions, RequestParamError> {
+    let defaults = DitheringOptions::default();
+    let error_strength = error_strength.unwrap_or(defaults.error_strength);
+
+    if !DitheringOptions::ERROR_STRENGTH_RANGE.contains(&error_strength) {
+        return Err(RequestParamError::InvalidValue { 
+            name: "error_strength", 
+            reason: format!("{error_strength} is not in range {:?}", DitheringOptions::ERROR_STRENGTH_RANGE)
+        });
+    }
+
+    Ok(DitheringOptions {
+        color_distance: color_distance.unwrap_or(defaults.color_distance),
+        method: method.unwrap_or(defaults.method),
+        error_diffusion: error_diffusion.unwrap_or(defaults.error_diffusion),
+        error_strength,
+        scan_order: scan_order.unwrap_or(defaults.scan_order),
+    })
+}
+
+/// Builds confetti cleanup options, cleanup is done only when minimal region size is given.
+fn confetti_options(
+    min_region_size: Option<usize>,
+    color_distance: Option<ColorDistance>
+) -> Result<Option<ConfettiOptions>, RequestParamError> {
+    let Some(min_region_size) = min_region_size else {
+        return Ok(None);
+    };
+
+    if !ConfettiOptions::MIN_REGION_SIZE_RANGE.contains(&min_region_size) {
+        return Err(RequestParamError::InvalidValue { 
+            name: "confetti_min_size", 
+            reason: format!("{min_region_size} is not in range {:?}", ConfettiOptions::MIN_REGION_SIZE_RANGE)
+        });
+    }
+
+    Ok(Some(ConfettiOptions {
+        min_region_size,
+        color_distance: color_distance.unwrap_or_default(),
+    }))
+}
+
+/// Builds canvas specification, canvas is used only when both its width and height are given.
+fn canvas_spec(
+    width_cm: Option<f32>,
+    height_cm: Option<f32>,
+    drill: Option<DrillShape>,
+    margin_cm: Option<f32>,
+    filter: Option<ResampleFilter>,
+    aspect: Option<AspectMode>
+) -> Result<Option<CanvasSpec>, RequestParamError> {
+    let (width_cm, height_cm) = match (width_cm, height_cm) {
+        (Some(width_cm), Some(height_cm)) => (width_cm, height_cm),
+        (None, None) => return Ok(None),
+        (Some(_), None) => return Err(RequestParamError::InvalidValue { 
+            name: "canvas_height_cm", 
+            reason: "missing, canvas needs both width and height".to_string()
+        }),
+        (None, Some(_)) => return Err(RequestParamError::InvalidValue { 
+            name: "canvas_width_cm", 
+            reason: "missing, canvas needs both width and height".to_string()
+        }),
+    };
+
+    Ok(Some(CanvasSpec {
+        width_cm,
+        height_cm,
+        drill: drill.unwrap_or_default(),
+        margin_cm: margin_cm.unwrap_or(0.0),
+        filter: filter.unwrap_or_default(),
+        aspect: aspect.unwrap_or_default(),
+    }))
+}
+
+/// New order of transforms, `order[i]` is the current index of the transform placed at `i`.
+#[derive(Debug, Deserialize)]
+pub struct TransformReorderRequest {
+    pub order: Vec<usize>,
+}
+
+/// Palette choice saved in a new revision, `None` clears the saved choice.
+#[derive(Debug, Deserialize)]
+pub struct SavePaletteRequest {
+    /// DMC codes, e.g. `["DMC 310", "DMC 3865"]`.
+    pub palette: Option<Vec<String>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ExtractQueryMaxColorsCount {
     pub max_colors: Option<usize>,
-}
\ No newline at end of file
+
+    /// Extraction method, voting of pixels by default.
+    pub mode: Option<PaletteExtractMode>,
+
+    /// Color distance used to match pixels with DMC colors, OKLab by default.
+    pub color_distance: Option<ColorDistance>,
+
+    /// Seed of k-means clustering, the same seed gives the same palette.
+    pub seed: Option<u64>,
+
+    /// Count of k-means clusterings, the best one is kept. Single one by default.
+    pub restarts: Option<usize>,
+
+    /// Revision of the image, the current one by default.
+    pub revision: Option<RevisionId>,
+
+    /// Bulk work, it yields to any other work. Off by default.
+    pub batch: Option<bool>,
+}
+
+impl ExtractQueryMaxColorsCount {
+    pub fn max_colors(&self) -> Result<Option<usize>, RequestParamError> {
+        match self.max_colors {
+            Some(max_colors) if !PALETTE_EXTRACT_MAX_COLORS_RANGE.contains(&max_colors) => Err(RequestParamError::InvalidValue { 
+                name: "max_colors", 
+                reason: format!("{max_colors} is not in range {PALETTE_EXTRACT_MAX_COLORS_RANGE:?}")
+            }),
+            max_colors => Ok(max_colors),
+        }
+    }
+
+    pub fn kmeans_options(&self) -> Result<KMeansOptions, RequestParamError> {
+        let defaults = KMeansOptions::default();
+        let restarts = self.restarts.unwrap_or(defaults.restarts);
+
+        if !KMeansOptions::RESTARTS_RANGE.contains(&restarts) {
+            return Err(RequestParamError::InvalidValue { 
+                name: "restarts", 
+                reason: format!("{restarts} is not in range {:?}", KMeansOptions::RESTARTS_RANGE)
+            });
+        }
+
+        Ok(KMeansOptions {
+            seed: self.seed.unwrap_or(defaults.seed),
+            restarts,
+            ..defaults
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PdfRenderQuery {
+    /// Comma separated DMC codes, e.g. `DMC 310,DMC 3865`.
+    pub palette: Option<String>,
+
+    /// Revision of the image, the current one by default.
+    pub revision: Option<RevisionId>,
+
+    /// Color distance used to match pixels with DMC colors, OKLab by default.
+    pub color_distance: Option<ColorDistance>,
+
+    /// Dithering method, error diffusion by default.
+    pub dithering: Option<DitheringMethod>,
+
+    /// Error diffusion kernel, Floyd–Steinberg by default.
+    pub error_diffusion: Option<ErrorDiffusion>,
+
+    /// Strength of dithering in range `0.0..=1.0`, full strength by default.
+    pub error_strength: Option<f32>,
+
+    /// Order of processing pixels, serpentine by default.
+    pub scan_order: Option<ScanOrder>,
+
+    /// Regions of fewer drills are merged into neighbouring colors. No cleanup by default.
+    pub confetti_min_size: Option<usize>,
+
+    /// Width of ordered canvas, without canvas every pixel of the image is a drill.
+    pub canvas_width_cm: Option<f32>,
+
+    /// Height of ordered canvas.
+    pub canvas_height_cm: Option<f32>,
+
+    /// Shape of drills, square by default.
+    pub drill: Option<DrillShape>,
+
+    /// Undrilled border on every side of canvas, none by default.
+    pub margin_cm: Option<f32>,
+
+    /// Filter used to resample the image to the drill grid, Lanczos by default.
+    pub resample: Option<ResampleFilter>,
+
+    /// Handling of image with aspect ratio different from canvas, cropping by default.
+    pub aspect: Option<AspectMode>,
+
+    /// Bulk work, it yields to any other work. Off by default.
+    pub batch: Option<bool>,
+}
+
+impl PdfRenderQuery {
+    pub fn dithering_options(&self) -> Result<DitheringOptions, RequestParamError> {
+        dithering_options(self.color_distance, self.dithering, self.error_diffusion, self.error_strength, self.scan_order)
+    }
+
+    pub fn confetti_options(&self) -> Result<Option<ConfettiOptions>, RequestParamError> {
+        confetti_options(self.confetti_min_size, self.color_distance)
+    }
+
+    pub fn canvas_spec(&self) -> Result<Option<CanvasSpec>, RequestParamError> {
+        canvas_spec(self.canvas_width_cm, self.canvas_height_cm, self.drill, self.margin_cm, self.resample, self.aspect)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    /// Comma separated DMC codes, e.g. `DMC 310,DMC 3865`.
+    pub palette: Option<String>,
+
+    /// Revision of the image, the current one by default.
+    pub revision: Option<RevisionId>,
+
+    /// Size in pixels of a single rendered drill. Without it every drill is a single pixel.
+    pub drill_size: Option<u32>,
+
+    /// Color distance used to match pixels with DMC colors, OKLab by default.
+    pub color_distance: Option<ColorDistance>,
+
+    /// Dithering method, error diffusion by default.
+    pub dithering: Option<DitheringMethod>,
+
+    /// Error diffusion kernel, Floyd–Steinberg by default.
+    pub error_diffusion: Option<ErrorDiffusion>,
+
+    /// Strength of dithering in range `0.0..=1.0`, full strength by default.
+    pub error_strength: Option<f32>,
+
+    /// Order of processing pixels, serpentine by default.
+    pub scan_order: Option<ScanOrder>,
+
+    /// Regions of fewer drills are merged into neighbouring colors. No cleanup by default.
+    pub confetti_min_size: Option<usize>,
+
+    /// Width of ordered canvas, without canvas every pixel of the image is a drill.
+    pub canvas_width_cm: Option<f32>,
+
+    /// Height of ordered canvas.
+    pub canvas_height_cm: Option<f32>,
+
+    /// Shape of drills, square by default.
+    pub drill: Option<DrillShape>,
+
+    /// Undrilled border on every side of canvas, none by default.
+    pub margin_cm: Option<f32>,
+
+    /// Filter used to resample the image to the drill grid, Lanczos by default.
+    pub resample: Option<ResampleFilter>,
+
+    /// Handling of image with aspect ratio different from canvas, cropping by default.
+    pub aspect: Option<AspectMode>,
+
+    /// Cancels preview of the image still in progress instead of refusing the new one, off by default.
+    pub latest_wins: Option<bool>,
+
+    /// Bulk work, it yields to any other work. Off by default.
+    pub batch: Option<bool>,
+}
+
+impl PreviewQuery {
+    pub fn drill_size(&self) -> Result<Option<u32>, RequestParamError> {
+        match self.drill_size {
+            Some(drill_size) if !DRILL_SIZE_RANGE.contains(&drill_size) => Err(RequestParamError::InvalidValue { 
+                name: "drill_size", 
+                reason: format!("{drill_size} is not in range {DRILL_SIZE_RANGE:?}")
+            }),
+            drill_size => Ok(drill_size),
+        }
+    }
+
+    pub fn dithering_options(&self) -> Result<DitheringOptions, RequestParamError> {
+        dithering_options(self.color_distance, self.dithering, self.error_diffusion, self.error_strength, self.scan_order)
+    }
+
+    pub fn confetti_options(&self) -> Result<Option<ConfettiOptions>, RequestParamError> {
+        confetti_options(self.confetti_min_size, self.color_distance)
+    }
+
+    pub fn canvas_spec(&self) -> Result<Option<CanvasSpec>, RequestParamError> {
+        canvas_spec(self.canvas_width_cm, self.canvas_height_cm, self.drill, self.margin_cm, self.resample, self.aspect)
+    }
+}
+
+/// Handling of transparent pixels of uploaded image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransparencyMode {
+    /// Blended over the background color.
+    #[default]
+    Background,
+    /// Left without drill, out of BOM and blank on chart.
+    NoDrill,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadImageQuery {
+    /// Handling of transparent pixels, blending over background by default.
+    pub transparency: Option<TransparencyMode>,
+
+    /// Hex color `rrggbb`, optionally prefixed by `#`, white by default.
+    pub background: Option<String>,
+}
+
+impl UploadImageQuery {
+    pub fn transparency_policy(&self) -> Result<TransparencyPolicy, RequestParamError> {
+        match (self.transparency.unwrap_or_default(), &self.background) {
+            (TransparencyMode::Background, None) => Ok(TransparencyPolicy::default()),
+            (TransparencyMode::Background, Some(background)) => parse_hex_color(background)
+                .map(TransparencyPolicy::Background)
+                .ok_or_else(|| RequestParamError::InvalidValue {
+                    name: "background",
+                    reason: format!("'{background}' is not a hex color rrggbb")
+                }),
+            (TransparencyMode::NoDrill, None) => Ok(TransparencyPolicy::NoDrill),
+            (TransparencyMode::NoDrill, Some(_)) => Err(RequestParamError::InvalidValue {
+                name: "background",
+                reason: "only applies to transparency=background".to_string()
+            }),
+        }
+    }
+}
+
+fn parse_hex_color(color: &str) -> Option<image::Rgb<u8>> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).ok();
+    Some(image::Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
@@ -1,12 +1,14 @@
-use std::collections::HashMap;
-
+use ditherum::algorithms::confetti::ConfettiReport;
 use serde::{
     Deserialize, 
     Serialize
 };
 
 use axum::{
-    http::StatusCode, 
+    http::{
+        header, 
+        StatusCode
+    }, 
     response::{
         IntoResponse, 
         Response
@@ -14,8 +16,19 @@ use axum::{
 };
 
 use crate::services::{
-    dmc::{Dmc, PaletteDmc}, 
-    ImageId, ImageStorageMeta
+    canvas::DrillShape, 
+    dmc::{Dmc, DmcBom, PaletteDmc}, 
+    history::{
+        ImageHistory, 
+        RevisionId, 
+        RevisionKind
+    }, 
+    processing::{
+        image_manip::NO_DRILL, 
+        status::WorkStatus, 
+        transform::TransformStack
+    }, 
+    ImageId, ImageStorageMeta, ImageStorageStats, ImageWorkKind
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,13 +57,120 @@ impl IntoResponse for GetPaletteResult {
     }
 }
 
-impl IntoResponse for ImageStorageMeta {
+/// Usage statistics for administration.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageStatsResult {
+    pub images: ImageStorageStats,
+}
+
+impl IntoResponse for StorageStatsResult {
+    fn into_response(self) -> Response {
+        let body = axum::Json(self);
+        (StatusCode::OK, body).into_response()
+    }
+}
+
+/// Revision of an image without the image itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub id: RevisionId,
+    pub kind: RevisionKind,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    /// Size of the image before transforms.
+    pub width: u32,
+    pub height: u32,
+    /// Count of cells left without drill, zero unless uploaded with `transparency=no_drill`.
+    pub no_drill_count: usize,
+    pub transforms: TransformStack,
+    pub palette: Option<Vec<String>>,
+}
+
+/// Revisions of an image from the oldest one and the ID of the current one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageHistoryResult {
+    pub current: RevisionId,
+    pub revisions: Vec<RevisionInfo>,
+}
+
+impl From<&ImageHistory> for ImageHistoryResult {
+    fn from(history: &ImageHistory) -> Self {
+        let revisions = history.revisions().iter()
+            .map(|revision| RevisionInfo {
+                id: revision.id,
+                kind: revision.kind,
+                created_time: revision.created_time,
+                width: revision.image.width(),
+                height: revision.image.height(),
+                no_drill_count: revision.drill_mask.as_ref()
+                    .map_or(0, |drill_mask| drill_mask.pixels().filter(|pixel| **pixel == NO_DRILL).count()),
+                transforms: revision.transforms.clone(),
+                palette: revision.palette.clone(),
+            })
+            .collect();
+
+        Self { current: history.current().id, revisions }
+    }
+}
+
+impl IntoResponse for ImageHistoryResult {
+    fn into_response(self) -> Response {
+        let body = axum::Json(self);
+        (StatusCode::OK, body).into_response()
+    }
+}
+
+/// Transform stack of an image and size of the transformed image.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransformStackResult {
+    pub transforms: TransformStack,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl IntoResponse for TransformStackResult {
     fn into_response(self) -> Response {
         let body = axum::Json(self);
         (StatusCode::OK, body).into_response()
     }
 }
 
+/// Drills the image would take on the largest canvas, the aspect ratio of the image is kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DrillGridResult {
+    pub canvas_width_cm: f32,
+    pub canvas_height_cm: f32,
+    pub drill: DrillShape,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageMetaResult {
+    #[serde(flatten)]
+    pub meta: ImageStorageMeta,
+    pub drill_grid: DrillGridResult,
+}
+
+impl IntoResponse for ImageMetaResult {
+    fn into_response(self) -> Response {
+        let body = axum::Json(self);
+        (StatusCode::OK, body).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StartProcessingResult {
+    pub was_started: bool
+}
+
+impl IntoResponse for StartProcessingResult {
+    fn into_response(self) -> Response {
+        let status_code = if self.was_started { StatusCode::OK } else { StatusCode::TOO_MANY_REQUESTS };
+        let body = axum::Json(self);
+        (status_code, body).into_response()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StartPaletteExtractionResult {
     pub was_started: bool
@@ -64,15 +184,121 @@ impl IntoResponse for StartPaletteExtractionResult {
     }
 }
 
+/// Work is not cancelled only if it is finished already.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelProcessingResult {
+    pub was_cancelled: bool
+}
+
+impl IntoResponse for CancelProcessingResult {
+    fn into_response(self) -> Response {
+        let status_code = if self.was_cancelled { StatusCode::OK } else { StatusCode::CONFLICT };
+        let body = axum::Json(self);
+        (status_code, body).into_response()
+    }
+}
+
+/// Status of the work of a kind bound to an image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageWorkStatus {
+    pub kind: ImageWorkKind,
+    #[serde(flatten)]
+    pub status: WorkStatus,
+}
+
+/// Statuses of works bound to an image, kinds which were never started are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessingStatusResult {
+    pub works: Vec<ImageWorkStatus>,
+}
+
+impl IntoResponse for ProcessingStatusResult {
+    fn into_response(self) -> Response {
+        let body = axum::Json(self);
+        (StatusCode::OK, body).into_response()
+    }
+}
+
+/// Payload of `done` processing event, sent once per finished work.
+/// Extracted palette is sent as is, rendered files are downloaded from `url`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProcessingDoneEvent {
+    pub kind: ImageWorkKind,
+    pub palette: Option<Vec<DmcBomEntry>>,
+    pub url: Option<String>,
+    pub confetti_report: Option<ConfettiReport>,
+}
+
+/// Single position of a DMC BOM. JSON objects can't be keyed by `Dmc`,
+/// so BOM gets serialized as a list of entries.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DmcBomEntry {
+    pub dmc: Dmc,
+    pub count: u32,
+}
+
+/// Converts BOM to entries ordered from the most used DMC.
+pub fn dmc_bom_to_entries(dmc_bom: &DmcBom) -> Vec<DmcBomEntry> {
+    let mut entries = dmc_bom.iter()
+        .map(|(dmc, count)| DmcBomEntry { dmc: dmc.clone(), count: *count })
+        .collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.dmc.code.cmp(&b.dmc.code)));
+    entries
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FinishPaletteExtractionResult {
-    pub result: Option<HashMap<Dmc, usize>>
+    pub result: Option<Vec<DmcBomEntry>>
 }
 
 impl IntoResponse for FinishPaletteExtractionResult {
     fn into_response(self) -> Response {
-        let status_code = if self.result.is_some() { StatusCode::OK } else { StatusCode::PROCESSING };
+        let status_code = if self.result.is_some() { StatusCode::OK } else { StatusCode::ACCEPTED };
         let body = axum::Json(self);
         (status_code, body).into_response()
     }
 }
+
+/// Result of processing which produces a file, e.g. PDF or PNG.
+#[derive(Debug)]
+pub enum FinishRenderResult {
+    Pending,
+    Ready {
+        content_type: &'static str,
+        filename: String,
+        attachment: bool,
+        content: Vec<u8>,
+        /// Reported in `X-Confetti-*` headers when cleanup was done.
+        confetti_report: Option<ConfettiReport>,
+    },
+}
+
+impl IntoResponse for FinishRenderResult {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Pending => {
+                let body = axum::Json(serde_json::json!({ "result": null }));
+                (StatusCode::ACCEPTED, body).into_response()
+            },
+            Self::Ready { content_type, filename, attachment, content, confetti_report } => {
+                let disposition = if attachment { "attachment" } else { "inline" };
+                let mut headers = vec![
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (header::CONTENT_DISPOSITION, format!("{disposition}; filename=\"{filename}\"")),
+                ];
+                if let Some(report) = confetti_report {
+                    headers.extend([
+                        (header::HeaderName::from_static("x-confetti-before"), report.before.confetti_regions_count.to_string()),
+                        (header::HeaderName::from_static("x-confetti-after"), report.after.confetti_regions_count.to_string()),
+                        (header::HeaderName::from_static("x-confetti-changed-pixels"), report.changed_pixels_count.to_string()),
+                    ]);
+                }
+                let headers = headers.into_iter()
+                    .map(|(name, value)| (name, header::HeaderValue::from_str(&value).expect("header values are ASCII")))
+                    .collect::<header::HeaderMap>();
+                (StatusCode::OK, headers, content).into_response()
+            },
+        }
+    }
+}
@@ -5,6 +5,7 @@ use axum::{
     routing::{
         get,
         post,
+        put,
         delete
     },
     Router
@@ -14,12 +15,34 @@ use crate::{
     app::AppData, 
     handlers::{
         overall_status, 
+        get_storage_stats,
         upload_image,
         get_image_meta,
         delete_image,
+        replace_image,
+        get_image_history,
+        undo_image_revision,
+        redo_image_revision,
+        jump_to_image_revision,
+        save_image_palette,
+        get_image_transforms,
+        push_image_transform,
+        replace_image_transforms,
+        reorder_image_transforms,
+        remove_image_transform,
+        reset_image_transforms,
         get_full_dmc_palette,
         start_extracting_dmc_palette,
         poll_finish_extracting_dmc_palette,
+        cancel_extracting_dmc_palette,
+        start_generating_preview,
+        poll_finish_generating_preview,
+        cancel_generating_preview,
+        start_rendering_pdf,
+        poll_finish_rendering_pdf,
+        cancel_rendering_pdf,
+        get_processing_status,
+        stream_processing_events,
     }
 };
 
@@ -33,6 +56,31 @@ pub fn get_router(image_size_limit: usize, app_data: Arc<AppData>) -> Router {
         )
         .route("/extract/{uuid}", get(poll_finish_extracting_dmc_palette)
             .with_state(app_data.clone())
+        )
+        .route("/extract/{uuid}", delete(cancel_extracting_dmc_palette)
+            .with_state(app_data.clone())
+        );
+
+    let api_preview_routes = Router::new()
+        .route("/{uuid}", post(start_generating_preview)
+            .with_state(app_data.clone())
+        )
+        .route("/{uuid}", get(poll_finish_generating_preview)
+            .with_state(app_data.clone())
+        )
+        .route("/{uuid}", delete(cancel_generating_preview)
+            .with_state(app_data.clone())
+        );
+
+    let api_pdf_routes = Router::new()
+        .route("/{uuid}", post(start_rendering_pdf)
+            .with_state(app_data.clone())
+        )
+        .route("/{uuid}", get(poll_finish_rendering_pdf)
+            .with_state(app_data.clone())
+        )
+        .route("/{uuid}", delete(cancel_rendering_pdf)
+            .with_state(app_data.clone())
         );
 
     let api_routes = Router::new()
@@ -44,9 +92,49 @@ pub fn get_router(image_size_limit: usize, app_data: Arc<AppData>) -> Router {
             .with_state(app_data.clone())
         )
         .route("/image/{id}", delete(delete_image)
+            .put(replace_image)
+            .layer(DefaultBodyLimit::max(image_size_limit))
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/palette", put(save_image_palette)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/revisions", get(get_image_history)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/revisions/undo", post(undo_image_revision)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/revisions/redo", post(redo_image_revision)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/revisions/{revision}", post(jump_to_image_revision)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/transform", get(get_image_transforms)
+            .post(push_image_transform)
+            .put(replace_image_transforms)
+            .delete(reset_image_transforms)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/transform/reorder", post(reorder_image_transforms)
+            .with_state(app_data.clone())
+        )
+        .route("/image/{id}/transform/{index}", delete(remove_image_transform)
+            .with_state(app_data.clone())
+        )
+        .route("/processing/{id}", get(get_processing_status)
+            .with_state(app_data.clone())
+        )
+        .route("/processing/{id}/events", get(stream_processing_events)
+            .with_state(app_data.clone())
+        )
+        .route("/admin/storage", get(get_storage_stats)
             .with_state(app_data.clone())
         )
-        .nest("/palette", api_palette_routes);
+        .nest("/palette", api_palette_routes)
+        .nest("/preview", api_preview_routes)
+        .nest("/pdf", api_pdf_routes);
         
     Router::new()
         .route("/", get(overall_status))
@@ -0,0 +1,280 @@
+use image::imageops::FilterType;
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use crate::services::processing::image_manip::{
+    map_drill_mask,
+    DrillMask
+};
+use crate::settings::Size;
+
+/// Color of canvas around the image when padding it to the drill grid.
+const CANVAS_PAD_COLOR: image::Rgb<u8> = image::Rgb([255, 255, 255]);
+
+/// Shape of drills, determines the distance between centers of neighbouring drills.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrillShape {
+    /// Square drill of 2.5 mm, covers canvas fully.
+    #[default]
+    Square,
+    /// Round drill of 2.8 mm.
+    Round,
+}
+
+impl DrillShape {
+    /// Distance between centers of neighbouring drills in millimetres.
+    pub fn pitch_mm(&self) -> f32 {
+        match self {
+            DrillShape::Square => 2.5,
+            DrillShape::Round => 2.8,
+        }
+    }
+}
+
+/// Filter used when source image is resampled to the drill grid.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResampleFilter {
+    /// Keeps hard edges, good for pixel art.
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResampleFilter> for FilterType {
+    fn from(filter: ResampleFilter) -> Self {
+        match filter {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Triangle => FilterType::Triangle,
+            ResampleFilter::CatmullRom => FilterType::CatmullRom,
+            ResampleFilter::Gaussian => FilterType::Gaussian,
+            ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How the source image is fitted when its aspect ratio differs from the canvas.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AspectMode {
+    /// Image covers the whole drill grid, overflowing parts are cut off evenly.
+    #[default]
+    Crop,
+    /// Whole image is kept, drill grid shrinks to the aspect ratio of the image.
+    Fit,
+    /// Whole image is kept and centered, the rest of the drill grid is filled with white.
+    Pad,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CanvasError {
+    #[error("Invalid canvas dimension '{name}'={value}")]
+    InvalidDimension {
+        name: &'static str,
+        value: f32,
+    },
+
+    #[error("Canvas too large, max={max_width_cm}x{max_height_cm} cm, actual={width_cm}x{height_cm} cm")]
+    TooLarge {
+        max_width_cm: f32,
+        max_height_cm: f32,
+        width_cm: f32,
+        height_cm: f32,
+    },
+
+    #[error("NoDrillsLeft, margin leaves no space for drills")]
+    NoDrillsLeft,
+}
+
+/// Physical canvas ordered by a customer, the source image is resampled
+/// so every pixel becomes a single drill.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CanvasSpec {
+    pub width_cm: f32,
+    pub height_cm: f32,
+    pub drill: DrillShape,
+
+    /// Undrilled border on every side of the canvas.
+    pub margin_cm: f32,
+
+    pub filter: ResampleFilter,
+    pub aspect: AspectMode,
+}
+
+impl CanvasSpec {
+    /// Checks dimensions are sensible and fit into `max_size_cm`.
+    pub fn validate(&self, max_size_cm: Size<f32>) -> Result<(), CanvasError> {
+        let dimensions = [
+            ("canvas_width_cm", self.width_cm),
+            ("canvas_height_cm", self.height_cm)
+        ];
+        for (name, value) in dimensions {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(CanvasError::InvalidDimension { name, value });
+            }
+        }
+        if !self.margin_cm.is_finite() || self.margin_cm < 0.0 {
+            return Err(CanvasError::InvalidDimension { name: "margin_cm", value: self.margin_cm });
+        }
+
+        if self.width_cm > max_size_cm.width || self.height_cm > max_size_cm.height {
+            return Err(CanvasError::TooLarge {
+                max_width_cm: max_size_cm.width,
+                max_height_cm: max_size_cm.height,
+                width_cm: self.width_cm,
+                height_cm: self.height_cm
+            });
+        }
+
+        self.drill_grid().map(|_| ())
+    }
+
+    /// Count of drills across and down the drilled area of the canvas.
+    pub fn drill_grid(&self) -> Result<Size<u32>, CanvasError> {
+        let drills_count = |length_cm: f32| {
+            let drilled_mm = (length_cm - 2.0 * self.margin_cm) * 10.0;
+            // Tolerance for lengths which are exact multiples of the pitch
+            (drilled_mm / self.drill.pitch_mm() + 1e-4).floor().max(0.0) as u32
+        };
+
+        let grid = Size {
+            width: drills_count(self.width_cm),
+            height: drills_count(self.height_cm)
+        };
+        if grid.width == 0 || grid.height == 0 {
+            return Err(CanvasError::NoDrillsLeft);
+        }
+        Ok(grid)
+    }
+
+    /// Size of the image of `width` x `height` pixels once resampled, every pixel is a drill.
+    pub fn resampled_size(&self, width: u32, height: u32) -> Result<Size<u32>, CanvasError> {
+        let grid = self.drill_grid()?;
+        if self.aspect != AspectMode::Fit {
+            return Ok(grid);
+        }
+
+        let (src_width, src_height) = (width.max(1) as f32, height.max(1) as f32);
+        let scale = (grid.width as f32 / src_width).min(grid.height as f32 / src_height);
+        Ok(Size {
+            width: ((src_width * scale).round() as u32).clamp(1, grid.width),
+            height: ((src_height * scale).round() as u32).clamp(1, grid.height)
+        })
+    }
+
+    /// Resamples image so every pixel corresponds to a single drill of the canvas.
+    pub fn resample(&self, src_img: &image::RgbImage) -> Result<image::RgbImage, CanvasError> {
+        let grid = self.drill_grid()?;
+        let (src_width, src_height) = (src_img.width() as f32, src_img.height() as f32);
+        let scale_x = grid.width as f32 / src_width;
+        let scale_y = grid.height as f32 / src_height;
+        let filter = self.filter.into();
+
+        let scaled_size = |scale: f32| (
+            ((src_width * scale).round() as u32).max(1),
+            ((src_height * scale).round() as u32).max(1)
+        );
+
+        let resampled = match self.aspect {
+            AspectMode::Crop => {
+                let (width, height) = scaled_size(scale_x.max(scale_y));
+                let (width, height) = (width.max(grid.width), height.max(grid.height));
+                let scaled = image::imageops::resize(src_img, width, height, filter);
+                let (x, y) = ((width - grid.width) / 2, (height - grid.height) / 2);
+                image::imageops::crop_imm(&scaled, x, y, grid.width, grid.height).to_image()
+            },
+            AspectMode::Fit => {
+                let size = self.resampled_size(src_img.width(), src_img.height())?;
+                image::imageops::resize(src_img, size.width, size.height, filter)
+            },
+            AspectMode::Pad => {
+                let (width, height) = scaled_size(scale_x.min(scale_y));
+                let scaled = image::imageops::resize(src_img, width.min(grid.width), height.min(grid.height), filter);
+                let mut padded = image::RgbImage::from_pixel(grid.width, grid.height, CANVAS_PAD_COLOR);
+                let (x, y) = ((grid.width - scaled.width()) / 2, (grid.height - scaled.height()) / 2);
+                image::imageops::replace(&mut padded, &scaled, x as i64, y as i64);
+                padded
+            },
+        };
+
+        Ok(resampled)
+    }
+
+    /// Resamples drill mask the same way as its image, cells are never blended.
+    /// Padding gets drills like the padded image.
+    pub fn resample_drill_mask(&self, drill_mask: &DrillMask) -> Result<DrillMask, CanvasError> {
+        let nearest = CanvasSpec { filter: ResampleFilter::Nearest, ..*self };
+        map_drill_mask(drill_mask, |img| nearest.resample(&img))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canvas(width_cm: f32, height_cm: f32, aspect: AspectMode) -> CanvasSpec {
+        CanvasSpec {
+            width_cm,
+            height_cm,
+            drill: DrillShape::Square,
+            margin_cm: 0.0,
+            filter: ResampleFilter::Nearest,
+            aspect
+        }
+    }
+
+    #[test]
+    fn test_drill_grid() {
+        let spec = canvas(30.0, 40.0, AspectMode::Crop);
+        let grid = spec.drill_grid().unwrap();
+        assert_eq!((grid.width, grid.height), (120, 160));
+
+        let spec = CanvasSpec { drill: DrillShape::Round, margin_cm: 2.0, ..spec };
+        let grid = spec.drill_grid().unwrap();
+        assert_eq!((grid.width, grid.height), (92, 128));
+
+        let spec = CanvasSpec { margin_cm: 15.0, ..spec };
+        assert!(matches!(spec.drill_grid(), Err(CanvasError::NoDrillsLeft)));
+    }
+
+    #[test]
+    fn test_validate_canvas() {
+        let max_size_cm = Size { width: 100.0, height: 100.0 };
+        assert!(canvas(30.0, 40.0, AspectMode::Crop).validate(max_size_cm).is_ok());
+        assert!(matches!(canvas(0.0, 40.0, AspectMode::Crop).validate(max_size_cm), Err(CanvasError::InvalidDimension { .. })));
+        assert!(matches!(canvas(130.0, 40.0, AspectMode::Crop).validate(max_size_cm), Err(CanvasError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn test_resample_aspect_modes() {
+        // Wide image with red left edge, canvas of 40x40 drills
+        let src_img = image::RgbImage::from_fn(200, 100, |x, _| {
+            if x < 10 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+        });
+
+        let cropped = canvas(10.0, 10.0, AspectMode::Crop).resample(&src_img).unwrap();
+        assert_eq!(cropped.dimensions(), (40, 40));
+        assert_eq!(*cropped.get_pixel(0, 20), image::Rgb([0, 0, 255]), "Left edge is cropped");
+
+        let fitted = canvas(10.0, 10.0, AspectMode::Fit).resample(&src_img).unwrap();
+        assert_eq!(fitted.dimensions(), (40, 20));
+        let fitted_size = canvas(10.0, 10.0, AspectMode::Fit).resampled_size(200, 100).unwrap();
+        assert_eq!((fitted_size.width, fitted_size.height), (40, 20));
+        assert_eq!(*fitted.get_pixel(0, 10), image::Rgb([255, 0, 0]));
+
+        let padded = canvas(10.0, 10.0, AspectMode::Pad).resample(&src_img).unwrap();
+        assert_eq!(padded.dimensions(), (40, 40));
+        let padded_mask = canvas(10.0, 10.0, AspectMode::Pad).resample_drill_mask(&DrillMask::new(200, 100)).unwrap();
+        assert_eq!(padded_mask.dimensions(), (40, 40));
+        assert_eq!(padded_mask.get_pixel(20, 0)[0], 255, "Padding is drilled");
+        assert_eq!(padded_mask.get_pixel(20, 20)[0], 0);
+        assert_eq!(*padded.get_pixel(20, 0), CANVAS_PAD_COLOR);
+        assert_eq!(*padded.get_pixel(0, 20), image::Rgb([255, 0, 0]));
+    }
+}
@@ -2,16 +2,42 @@ use std::{
     collections::{HashMap, HashSet}, fmt::Debug, hash::Hash, io::BufReader, ops::Deref, path::Path
 };
 
-use ditherum::palette_utils::{color_manip::{self, rgb_u8_to_srgb_u8}, PaletteSrgb};
-use palette::color_difference::EuclideanDistance;
+use ditherum::palette_utils::{
+    color_manip::{rgb_u8_to_srgb_float, rgb_u8_to_srgb_u8}, 
+    ColorDistance, 
+    PaletteMatcher, 
+    PaletteSrgb
+};
+use ditherum::progress::Progress;
 
 use serde::{
     Deserialize, 
     Serialize
 };
 
+use super::processing::image_manip::{
+    is_drilled, 
+    DrillMask
+};
+
 pub type DmcBom = HashMap<Dmc, u32>;
 
+/// Lookup of the closest DMC of a palette, made by [`PaletteDmc::matcher`].
+pub struct DmcMatcher<'a> {
+    /// Indices of colors found by `matcher` refer to these.
+    elements: Vec<&'a Dmc>,
+    matcher: PaletteMatcher,
+}
+
+impl<'a> DmcMatcher<'a> {
+    /// # Panics
+    /// Panics if the palette is empty.
+    pub fn find_closest(&self, random_color: palette::Srgb<u8>) -> &'a Dmc {
+        assert!(!self.elements.is_empty());
+        self.elements[self.matcher.find_closest_idx(random_color.into_format())]
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum DmcError {
     #[error("Io error, reason: {0}")]
@@ -31,6 +57,12 @@ pub enum DmcError {
 
     #[error("Data in DMC palette is not unique")]
     DmcDataNotUnique,
+
+    #[error("DMC code not found: {0}")]
+    DmcCodeNotFound(String),
+
+    #[error("DMC palette empty")]
+    PaletteEmpty,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -70,7 +102,13 @@ impl Deref for PaletteDmc {
 
 impl From<&DmcBom> for PaletteDmc {
     fn from(bom: &DmcBom) -> Self {
-        PaletteDmc { elements: bom.keys().into_iter().cloned().collect() }
+        PaletteDmc { elements: bom.keys().cloned().collect() }
+    }
+}
+
+impl FromIterator<Dmc> for PaletteDmc {
+    fn from_iter<I: IntoIterator<Item = Dmc>>(iter: I) -> Self {
+        PaletteDmc { elements: iter.into_iter().collect() }
     }
 }
 
@@ -93,43 +131,116 @@ impl PaletteDmc {
         Self::load_from_file(std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("./res/palette_dmc_full.json"))
     }
 
-    pub fn find_closest_dmc(&self, random_color: palette::Srgb<u8>) -> &Dmc {
-        assert!(!self.elements.is_empty());
-        let random_color_float = random_color.into_format();
-
-        self.elements.iter()
-            .min_by_key(|dmc| {
-                dmc.color
-                    .into_format::<f32>()
-                    .distance_squared(random_color_float) as i32
-            })
-            .expect("At least 1 element was in palette")
+    /// Prepares matcher of the palette elements, build it once for many lookups.
+    pub fn matcher(&self, color_distance: ColorDistance) -> DmcMatcher<'_> {
+        let elements = self.elements.iter().collect::<Vec<_>>();
+        let matcher = PaletteMatcher::new(
+            elements.iter().map(|dmc| dmc.color.into_format()), 
+            color_distance
+        );
+        DmcMatcher { elements, matcher }
     }
 
-    pub fn find_subset_closest_to_image_pixels(&self, image: &image::RgbImage, max_count: Option<usize>) -> HashMap<Dmc, u32> {
-        let mut colors_counts = HashMap::new();
-
-        image.enumerate_pixels().for_each(|(_, _, color)| {
-            let color_srgb = rgb_u8_to_srgb_u8(color);
-            let closest_color = self.find_closest_dmc(color_srgb);
-            if !colors_counts.contains_key(closest_color) {
-                colors_counts
-                    .entry(closest_color.clone())
-                    .and_modify(|cnt| { *cnt += 1; })
-                    .or_insert(1);
+    pub fn find_subset_closest_to_image_pixels(
+        &self, 
+        image: &image::RgbImage, 
+        max_count: Option<usize>,
+        color_distance: ColorDistance,
+        progress: &dyn Progress
+    ) -> HashMap<Dmc, u32> {
+        assert!(!self.elements.is_empty());
+
+        let DmcMatcher { elements, matcher } = self.matcher(color_distance);
+
+        // Images have far less unique colors than pixels, lookup each of them only once
+        let mut closest_by_color: HashMap<image::Rgb<u8>, usize> = HashMap::new();
+        let mut counts_by_idx: HashMap<usize, u32> = HashMap::new();
+
+        let rows_count = image.height();
+        for (row_idx, row) in image.rows().enumerate() {
+            row.for_each(|color| {
+                let closest_idx = *closest_by_color
+                    .entry(*color)
+                    .or_insert_with(|| matcher.find_closest_idx(rgb_u8_to_srgb_float(color)));
+                *counts_by_idx.entry(closest_idx).or_insert(0) += 1;
+            });
+            progress.report((row_idx + 1) as f32 / rows_count as f32);
+
+            // Counts of cancelled voting are incomplete, caller throws them away
+            if progress.is_cancelled() {
+                break;
             }
-        });
+        }
+
+        let colors_counts = counts_by_idx.into_iter()
+            .map(|(idx, cnt)| (elements[idx].clone(), cnt));
 
         if let Some(max_count) = max_count {
-            let mut colors_vec = colors_counts
-                .into_iter()
-                .collect::<Vec<_>>();
+            let mut colors_vec = colors_counts.collect::<Vec<_>>();
 
             colors_vec.sort_by_key(|(_, cnt)| std::cmp::Reverse(*cnt) );
             colors_vec.truncate(max_count);
-            HashMap::from_iter(colors_vec.into_iter())
+            HashMap::from_iter(colors_vec)
         } else {
-            colors_counts
+            colors_counts.collect()
+        }
+    }
+
+    /// Maps every color to the closest DMC which was not taken by any of preceding colors,
+    /// so colors closest to the same DMC end up as distinct ones. Colors should be ordered
+    /// by importance, e.g. by size of their clusters.
+    pub fn snap_to_unique_dmc<I>(&self, colors: I, color_distance: ColorDistance) -> PaletteDmc 
+    where 
+        I: IntoIterator<Item = palette::Srgb<f32>>
+    {
+        let mut candidates = self.elements.iter()
+            .map(|dmc| (dmc, color_distance.to_space(dmc.color.into_format())))
+            .collect::<Vec<_>>();
+
+        let mut snapped = HashSet::new();
+        for color in colors {
+            let color_in_space = color_distance.to_space(color);
+            let closest_idx = candidates.iter()
+                .map(|(_, dmc_in_space)| color_distance.distance_in_space(dmc_in_space, &color_in_space))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(idx, _)| idx);
+
+            let Some(closest_idx) = closest_idx else {
+                // Every DMC was taken
+                break;
+            };
+
+            let (dmc, _) = candidates.swap_remove(closest_idx);
+            snapped.insert(dmc.clone());
+        }
+
+        PaletteDmc { elements: snapped }
+    }
+
+    /// Finds DMC by its code. Code can be given with or without `DMC ` prefix.
+    pub fn find_dmc_by_code(&self, code: &str) -> Option<&Dmc> {
+        let code = code.trim();
+        self.elements.iter()
+            .find(|dmc| dmc.code == code || dmc.code.strip_prefix("DMC ") == Some(code))
+    }
+
+    /// Creates palette consisting only of DMCs with given codes.
+    pub fn subset_by_codes<'a, I>(&self, codes: I) -> Result<PaletteDmc, DmcError> 
+    where 
+        I: IntoIterator<Item = &'a str>
+    {
+        let elements = codes.into_iter()
+            .map(|code| self.find_dmc_by_code(code)
+                .cloned()
+                .ok_or_else(|| DmcError::DmcCodeNotFound(code.to_string()))
+            )
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        if elements.is_empty() {
+            Err(DmcError::PaletteEmpty)
+        } else {
+            Ok(PaletteDmc { elements })
         }
     }
 
@@ -138,11 +249,16 @@ impl PaletteDmc {
             .find(|dmc| &dmc.color == color)
     }
 
-    pub fn find_bom_of_image(&self, src_image: &image::RgbImage) -> (DmcBom, usize) {
+    /// Counts drills of every DMC, cells without drill are skipped.
+    pub fn find_bom_of_image(&self, src_image: &image::RgbImage, drill_mask: Option<&DrillMask>) -> (DmcBom, usize) {
         let mut dmc_bom = HashMap::new();
         let mut not_mapped_count = 0;
 
-        src_image.enumerate_pixels().for_each(|(_, _, color)| {
+        src_image.enumerate_pixels().for_each(|(x, y, color)| {
+            if !is_drilled(drill_mask, x, y) {
+                return;
+            }
+
             let srgb_color = rgb_u8_to_srgb_u8(color);
 
             if let Some(dmc) = self.find_dmc_by_color(&srgb_color) {
@@ -267,3 +383,55 @@ impl TryFrom<io::PaletteDmcDataIo> for PaletteDmc {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subset_by_codes() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+
+        let subset = palette_dmc.subset_by_codes(["DMC 310", "3865", "DMC 310"]).unwrap();
+        assert_eq!(subset.len(), 2);
+        assert!(subset.iter().any(|dmc| dmc.name == "Black"));
+        assert!(subset.iter().any(|dmc| dmc.name == "White"));
+    }
+
+    #[test]
+    fn test_subset_by_codes_unknown_or_empty_should_fail() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+
+        let subset = palette_dmc.subset_by_codes(["DMC 310", "DMC 0"]);
+        assert!(matches!(subset, Err(DmcError::DmcCodeNotFound(code)) if code == "DMC 0"));
+
+        let subset = palette_dmc.subset_by_codes([]);
+        assert!(matches!(subset, Err(DmcError::PaletteEmpty)));
+    }
+
+    #[test]
+    fn test_find_closest_dmc_of_exact_color() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+
+        for color_distance in [ColorDistance::Srgb, ColorDistance::Ciede2000, ColorDistance::Oklab] {
+            let matcher = palette_dmc.matcher(color_distance);
+            for dmc in palette_dmc.iter() {
+                assert_eq!(matcher.find_closest(dmc.color), dmc);
+            }
+        }
+    }
+
+    #[test]
+    fn test_subset_closest_to_image_pixels_counts_every_pixel() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+        let image = ditherum::image_utils::generate_gradient_image(
+            50, 4,
+            image::Rgb([0, 0, 0]),
+            image::Rgb([255, 255, 255])
+        );
+
+        let dmc_counts = palette_dmc.find_subset_closest_to_image_pixels(&image, None, ColorDistance::Oklab, &ditherum::progress::NoProgress);
+        assert!(dmc_counts.len() > 1);
+        assert_eq!(dmc_counts.values().sum::<u32>(), 50 * 4);
+    }
+}
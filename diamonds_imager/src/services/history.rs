@@ -0,0 +1,613 @@
+use std::{
+    path::{
+        Path, 
+        PathBuf
+    }, 
+    sync::Arc
+};
+
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use super::processing::{
+    image_manip::DrillMask, 
+    transform::TransformStack
+};
+use super::{
+    ContentHash, 
+    HashedImage
+};
+
+/// Identifier of a revision, unique within history of a single image.
+pub type RevisionId = u32;
+
+/// Revisions kept at most, the oldest ones are dropped first.
+pub const IMAGE_REVISIONS_MAX: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("RevisionNotFound {0}")]
+    RevisionNotFound(RevisionId),
+
+    #[error("NothingToUndo")]
+    NothingToUndo,
+
+    #[error("NothingToRedo")]
+    NothingToRedo,
+}
+
+/// Edit which created a revision.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RevisionKind {
+    Upload,
+    ReplaceImage,
+    Transforms,
+    Palette,
+}
+
+/// Immutable state of an image after an edit.
+#[derive(Debug, Clone)]
+pub struct ImageRevision {
+    pub id: RevisionId,
+    pub kind: RevisionKind,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    /// Original image of the revision, shared with revisions which did not replace it.
+    pub image: Arc<image::RgbImage>,
+    /// Content hash of `image`, kept with it so the image is never hashed again.
+    pub image_hash: ContentHash,
+    /// Cells of the image without drill, replaced together with the image.
+    pub drill_mask: Option<Arc<DrillMask>>,
+    /// Revision which introduced the image, identifies the image in persistent storage.
+    pub image_origin: RevisionId,
+    pub transforms: TransformStack,
+    /// DMC codes chosen for rendering, extracted or full palette is used without them.
+    pub palette: Option<Vec<String>>,
+}
+
+impl ImageRevision {
+    pub fn set_image(&mut self, image: HashedImage) {
+        self.image = image.image;
+        self.image_hash = image.hash;
+    }
+
+    fn has_same_image(&self, other: &ImageRevision) -> bool {
+        let same_mask = match (&self.drill_mask, &other.drill_mask) {
+            (Some(mask), Some(other_mask)) => Arc::ptr_eq(mask, other_mask),
+            (mask, other_mask) => mask.is_none() && other_mask.is_none(),
+        };
+        Arc::ptr_eq(&self.image, &other.image) && same_mask
+    }
+}
+
+/// Linear history of revisions with a cursor. Undo and redo move the cursor,
+/// a new revision made after undo drops the revisions which could be redone.
+#[derive(Debug, Clone)]
+pub struct ImageHistory {
+    revisions: Vec<ImageRevision>,
+    current: usize,
+    next_id: RevisionId,
+}
+
+impl ImageHistory {
+    pub fn new(image: HashedImage, drill_mask: Option<Arc<DrillMask>>) -> Self {
+        Self {
+            revisions: vec![ImageRevision {
+                id: 0,
+                kind: RevisionKind::Upload,
+                created_time: chrono::Utc::now(),
+                image: image.image,
+                image_hash: image.hash,
+                drill_mask,
+                image_origin: 0,
+                transforms: TransformStack::default(),
+                palette: None,
+            }],
+            current: 0,
+            next_id: 1,
+        }
+    }
+
+    pub fn current(&self) -> &ImageRevision {
+        &self.revisions[self.current]
+    }
+
+    pub fn revisions(&self) -> &[ImageRevision] {
+        &self.revisions
+    }
+
+    /// Revision of the ID or the current one.
+    pub fn revision(&self, id: Option<RevisionId>) -> Result<&ImageRevision, HistoryError> {
+        match id {
+            Some(id) => self.revisions.iter()
+                .find(|revision| revision.id == id)
+                .ok_or(HistoryError::RevisionNotFound(id)),
+            None => Ok(self.current()),
+        }
+    }
+
+    /// Makes a new current revision from the current one modified by `edit`.
+    pub fn push<F>(&mut self, kind: RevisionKind, edit: F) -> &ImageRevision
+    where
+        F: FnOnce(&mut ImageRevision)
+    {
+        let mut revision = self.current().clone();
+        revision.id = self.next_id;
+        revision.kind = kind;
+        revision.created_time = chrono::Utc::now();
+        edit(&mut revision);
+        if !revision.has_same_image(self.current()) {
+            revision.image_origin = revision.id;
+        }
+        self.next_id += 1;
+
+        self.revisions.truncate(self.current + 1);
+        self.revisions.push(revision);
+        if self.revisions.len() > IMAGE_REVISIONS_MAX {
+            self.revisions.remove(0);
+        }
+        self.current = self.revisions.len() - 1;
+
+        self.current()
+    }
+
+    pub fn undo(&mut self) -> Result<&ImageRevision, HistoryError> {
+        if self.current == 0 {
+            return Err(HistoryError::NothingToUndo);
+        }
+        self.current -= 1;
+        Ok(self.current())
+    }
+
+    pub fn redo(&mut self) -> Result<&ImageRevision, HistoryError> {
+        if self.current + 1 >= self.revisions.len() {
+            return Err(HistoryError::NothingToRedo);
+        }
+        self.current += 1;
+        Ok(self.current())
+    }
+
+    pub fn jump(&mut self, id: RevisionId) -> Result<&ImageRevision, HistoryError> {
+        self.current = self.revisions.iter()
+            .position(|revision| revision.id == id)
+            .ok_or(HistoryError::RevisionNotFound(id))?;
+        Ok(self.current())
+    }
+
+    /// Distinct images of all revisions, revisions which did not replace the image share it.
+    fn distinct_images(&self) -> Vec<&Arc<image::RgbImage>> {
+        let mut images: Vec<&Arc<image::RgbImage>> = vec![];
+        for revision in &self.revisions {
+            if !images.iter().any(|image| Arc::ptr_eq(image, &revision.image)) {
+                images.push(&revision.image);
+            }
+        }
+        images
+    }
+
+    /// Distinct drill masks of all revisions.
+    fn distinct_drill_masks(&self) -> Vec<&Arc<DrillMask>> {
+        let mut drill_masks: Vec<&Arc<DrillMask>> = vec![];
+        for drill_mask in self.revisions.iter().filter_map(|revision| revision.drill_mask.as_ref()) {
+            if !drill_masks.iter().any(|mask| Arc::ptr_eq(mask, drill_mask)) {
+                drill_masks.push(drill_mask);
+            }
+        }
+        drill_masks
+    }
+
+    /// A revision of every distinct image origin. Equal images shared in memory may still
+    /// have several origins, e.g. after the image was replaced by the original one.
+    pub fn images(&self) -> Vec<&ImageRevision> {
+        let mut revisions: Vec<&ImageRevision> = vec![];
+        for revision in &self.revisions {
+            if !revisions.iter().any(|known| known.image_origin == revision.image_origin) {
+                revisions.push(revision);
+            }
+        }
+        revisions
+    }
+
+    /// Replaces every distinct image by the one returned from `share`, so equal images
+    /// of different histories are held in memory once.
+    pub fn share_images<F>(&mut self, mut share: F)
+    where
+        F: FnMut(HashedImage) -> HashedImage
+    {
+        let mut replacements: Vec<(Arc<image::RgbImage>, HashedImage)> = vec![];
+        for revision in &self.revisions {
+            if replacements.iter().any(|(image, _)| Arc::ptr_eq(image, &revision.image)) {
+                continue;
+            }
+            let shared = share(HashedImage { image: revision.image.clone(), hash: revision.image_hash });
+            replacements.push((revision.image.clone(), shared));
+        }
+
+        for revision in &mut self.revisions {
+            if let Some((_, shared)) = replacements.iter().find(|(image, _)| Arc::ptr_eq(image, &revision.image)) {
+                revision.set_image(shared.clone());
+            }
+        }
+    }
+
+    /// Serializable history without images, images are identified by `image_origin`.
+    pub fn record(&self) -> HistoryRecord {
+        HistoryRecord {
+            revisions: self.revisions.iter()
+                .map(|revision| RevisionRecord {
+                    id: revision.id,
+                    kind: revision.kind,
+                    created_time: revision.created_time,
+                    image_origin: revision.image_origin,
+                    image_hash: Some(revision.image_hash),
+                    has_drill_mask: revision.drill_mask.is_some(),
+                    transforms: revision.transforms.clone(),
+                    palette: revision.palette.clone(),
+                })
+                .collect(),
+            current: self.current,
+            next_id: self.next_id,
+        }
+    }
+
+    /// Rebuilds history from its record, `load_image` is called once for every distinct image
+    /// and tells whether the image has a drill mask. Only images of records made before
+    /// hashes were recorded are hashed.
+    pub fn from_record<F, E>(record: HistoryRecord, mut load_image: F) -> Result<Self, E>
+    where
+        F: FnMut(RevisionId, bool) -> Result<(image::RgbImage, Option<DrillMask>), E>,
+        E: From<HistoryError>
+    {
+        if record.current >= record.revisions.len() {
+            return Err(HistoryError::RevisionNotFound(record.current as RevisionId).into());
+        }
+
+        let mut images: Vec<(RevisionId, HashedImage, Option<Arc<DrillMask>>)> = vec![];
+        let mut revisions = Vec::with_capacity(record.revisions.len());
+        for revision in record.revisions {
+            let (image, drill_mask) = match images.iter().find(|(origin, _, _)| *origin == revision.image_origin) {
+                Some((_, image, drill_mask)) => (image.clone(), drill_mask.clone()),
+                None => {
+                    let (image, drill_mask) = load_image(revision.image_origin, revision.has_drill_mask)?;
+                    let image = match revision.image_hash {
+                        Some(hash) => HashedImage { image: Arc::new(image), hash },
+                        None => HashedImage::new(image),
+                    };
+                    let drill_mask = drill_mask.map(Arc::new);
+                    images.push((revision.image_origin, image.clone(), drill_mask.clone()));
+                    (image, drill_mask)
+                },
+            };
+
+            revisions.push(ImageRevision {
+                id: revision.id,
+                kind: revision.kind,
+                created_time: revision.created_time,
+                image: image.image,
+                image_hash: image.hash,
+                drill_mask,
+                image_origin: revision.image_origin,
+                transforms: revision.transforms,
+                palette: revision.palette,
+            });
+        }
+
+        Ok(Self { revisions, current: record.current, next_id: record.next_id })
+    }
+
+    /// Bytes of decoded images and drill masks held by the history, shared ones are counted once.
+    pub fn memory_bytes(&self) -> usize {
+        let images_bytes: usize = self.distinct_images().iter()
+            .map(|image| image.as_raw().len())
+            .sum();
+        let drill_masks_bytes: usize = self.distinct_drill_masks().iter()
+            .map(|drill_mask| drill_mask.as_raw().len())
+            .sum();
+        images_bytes + drill_masks_bytes
+    }
+
+    /// Writes raw pixels of all images and drill masks into `dir`, the history can be dropped
+    /// afterwards and loaded back by [`SpilledHistory::load`].
+    pub fn spill(&self, dir: &Path) -> std::io::Result<SpilledHistory> {
+        let images = self.distinct_images();
+        let drill_masks = self.distinct_drill_masks();
+        let rasters = images.iter()
+            .map(|image| (image.width(), image.height(), image.as_raw()))
+            .chain(drill_masks.iter().map(|drill_mask| (drill_mask.width(), drill_mask.height(), drill_mask.as_raw())));
+
+        let mut spilled_images = Vec::with_capacity(images.len() + drill_masks.len());
+        for (width, height, raw) in rasters {
+            let path = dir.join(format!("{}.raw", uuid::Uuid::new_v4()));
+            let spilled_image = SpilledImage { path, width, height, bytes: raw.len() };
+            if let Err(e) = std::fs::write(&spilled_image.path, raw) {
+                spilled_images.iter().for_each(SpilledImage::discard);
+                return Err(e);
+            }
+            spilled_images.push(spilled_image);
+        }
+        let spilled_drill_masks = spilled_images.split_off(images.len());
+
+        let revisions = self.revisions.iter()
+            .map(|revision| SpilledRevision {
+                id: revision.id,
+                kind: revision.kind,
+                created_time: revision.created_time,
+                image_index: images.iter()
+                    .position(|image| Arc::ptr_eq(image, &revision.image))
+                    .expect("Image of every revision is spilled"),
+                image_hash: revision.image_hash,
+                drill_mask_index: revision.drill_mask.as_ref().map(|drill_mask| drill_masks.iter()
+                    .position(|mask| Arc::ptr_eq(mask, drill_mask))
+                    .expect("Drill mask of every revision is spilled")),
+                image_origin: revision.image_origin,
+                transforms: revision.transforms.clone(),
+                palette: revision.palette.clone(),
+            })
+            .collect();
+
+        Ok(SpilledHistory {
+            revisions,
+            images: spilled_images,
+            drill_masks: spilled_drill_masks,
+            current: self.current,
+            next_id: self.next_id,
+        })
+    }
+}
+
+/// Revision without its image, see [`HistoryRecord`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevisionRecord {
+    pub id: RevisionId,
+    pub kind: RevisionKind,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    pub image_origin: RevisionId,
+    /// Missing in records made before hashes were recorded.
+    #[serde(default)]
+    pub image_hash: Option<ContentHash>,
+    #[serde(default)]
+    pub has_drill_mask: bool,
+    pub transforms: TransformStack,
+    pub palette: Option<Vec<String>>,
+}
+
+/// History stored by a persistent storage backend, images are stored separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub revisions: Vec<RevisionRecord>,
+    pub current: usize,
+    pub next_id: RevisionId,
+}
+
+/// Raw pixels of an image or a drill mask.
+#[derive(Debug)]
+struct SpilledImage {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    bytes: usize,
+}
+
+impl SpilledImage {
+    fn load<P: image::Pixel<Subpixel = u8>>(&self) -> std::io::Result<image::ImageBuffer<P, Vec<u8>>> {
+        let bytes = std::fs::read(&self.path)?;
+        image::ImageBuffer::from_raw(self.width, self.height, bytes)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Spilled image {:?} is truncated", self.path)))
+    }
+
+    fn discard(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Cannot remove spilled image {:?}: {e}", self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SpilledRevision {
+    id: RevisionId,
+    kind: RevisionKind,
+    created_time: chrono::DateTime<chrono::Utc>,
+    image_index: usize,
+    image_hash: ContentHash,
+    drill_mask_index: Option<usize>,
+    image_origin: RevisionId,
+    transforms: TransformStack,
+    palette: Option<Vec<String>>,
+}
+
+/// History with images written to disk, made by [`ImageHistory::spill`].
+#[derive(Debug)]
+pub struct SpilledHistory {
+    revisions: Vec<SpilledRevision>,
+    images: Vec<SpilledImage>,
+    drill_masks: Vec<SpilledImage>,
+    current: usize,
+    next_id: RevisionId,
+}
+
+impl SpilledHistory {
+    /// Bytes of images and drill masks on disk, equal to memory taken once loaded.
+    pub fn bytes(&self) -> usize {
+        self.images.iter()
+            .chain(&self.drill_masks)
+            .map(|image| image.bytes)
+            .sum()
+    }
+
+    /// Reads images back, files are kept until [`SpilledHistory::discard`].
+    pub fn load(&self) -> std::io::Result<ImageHistory> {
+        let images = self.images.iter()
+            .map(|image| image.load::<image::Rgb<u8>>().map(Arc::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let drill_masks = self.drill_masks.iter()
+            .map(|drill_mask| drill_mask.load::<image::Luma<u8>>().map(Arc::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let revisions = self.revisions.iter()
+            .map(|revision| ImageRevision {
+                id: revision.id,
+                kind: revision.kind,
+                created_time: revision.created_time,
+                image: images[revision.image_index].clone(),
+                image_hash: revision.image_hash,
+                drill_mask: revision.drill_mask_index.map(|index| drill_masks[index].clone()),
+                image_origin: revision.image_origin,
+                transforms: revision.transforms.clone(),
+                palette: revision.palette.clone(),
+            })
+            .collect();
+
+        Ok(ImageHistory {
+            revisions,
+            current: self.current,
+            next_id: self.next_id,
+        })
+    }
+
+    /// Removes files of the spilled images.
+    pub fn discard(&self) {
+        self.images.iter().chain(&self.drill_masks).for_each(SpilledImage::discard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::processing::transform::TransformOp;
+
+    fn history() -> ImageHistory {
+        ImageHistory::new(HashedImage::new(image::RgbImage::new(4, 4)), None)
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut history = history();
+        assert!(matches!(history.undo(), Err(HistoryError::NothingToUndo)));
+
+        history.push(RevisionKind::Transforms, |revision| revision.transforms.push(TransformOp::FlipVertical));
+        history.push(RevisionKind::Palette, |revision| revision.palette = Some(vec!["DMC 310".to_string()]));
+        assert_eq!(history.current().id, 2);
+        assert_eq!(history.current().transforms.ops().len(), 1, "Edits are cumulative");
+
+        assert_eq!(history.undo().unwrap().id, 1);
+        assert_eq!(history.undo().unwrap().id, 0);
+        assert_eq!(history.redo().unwrap().id, 1);
+        assert_eq!(history.redo().unwrap().id, 2);
+        assert!(matches!(history.redo(), Err(HistoryError::NothingToRedo)));
+    }
+
+    #[test]
+    fn test_push_after_undo_drops_redo() {
+        let mut history = history();
+        history.push(RevisionKind::Transforms, |_| ());
+        history.push(RevisionKind::Transforms, |_| ());
+        history.jump(0).unwrap();
+
+        let revision = history.push(RevisionKind::Palette, |_| ());
+        assert_eq!(revision.id, 3, "IDs are never reused");
+        assert_eq!(history.revisions().iter().map(|r| r.id).collect::<Vec<_>>(), vec![0, 3]);
+        assert!(matches!(history.jump(1), Err(HistoryError::RevisionNotFound(1))));
+        assert_eq!(history.revision(Some(0)).unwrap().kind, RevisionKind::Upload);
+    }
+
+    #[test]
+    fn test_spill_and_load() {
+        let mut history = ImageHistory::new(HashedImage::new(image::RgbImage::from_pixel(4, 4, image::Rgb([1, 2, 3]))), None);
+        history.push(RevisionKind::Transforms, |revision| revision.transforms.push(TransformOp::FlipVertical));
+        history.push(RevisionKind::ReplaceImage, |revision| {
+            revision.set_image(HashedImage::new(image::RgbImage::new(2, 2)));
+            revision.drill_mask = Some(Arc::new(DrillMask::from_pixel(2, 2, image::Luma([0]))));
+        });
+        history.undo().unwrap();
+        assert_eq!(history.memory_bytes(), 4 * 4 * 3 + 2 * 2 * 3 + 2 * 2, "Shared image is counted once");
+
+        let dir = tempfile::tempdir().unwrap();
+        let spilled = history.spill(dir.path()).unwrap();
+        assert_eq!(spilled.bytes(), history.memory_bytes());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        let loaded = spilled.load().unwrap();
+        assert_eq!(loaded.current().id, 1);
+        assert_eq!(loaded.current().transforms, history.current().transforms);
+        assert_eq!(*loaded.current().image, *history.current().image);
+        assert_eq!(loaded.revisions()[2].image_hash, history.revisions()[2].image_hash, "Hash is kept");
+        assert!(Arc::ptr_eq(&loaded.revisions()[0].image, &loaded.revisions()[1].image));
+        assert_eq!(loaded.revisions()[2].image.dimensions(), (2, 2));
+        assert_eq!(loaded.revisions()[2].drill_mask, history.revisions()[2].drill_mask);
+        assert!(loaded.revisions()[1].drill_mask.is_none());
+
+        spilled.discard();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let mut history = history();
+        history.push(RevisionKind::Transforms, |revision| revision.transforms.push(TransformOp::FlipVertical));
+        history.push(RevisionKind::ReplaceImage, |revision| {
+            revision.set_image(HashedImage::new(image::RgbImage::new(2, 2)));
+            revision.drill_mask = Some(Arc::new(DrillMask::new(2, 2)));
+        });
+        history.push(RevisionKind::Palette, |revision| revision.palette = Some(vec!["DMC 310".to_string()]));
+        history.undo().unwrap();
+        assert_eq!(history.images().iter().map(|revision| revision.image_origin).collect::<Vec<_>>(), vec![0, 2]);
+
+        let record = serde_json::from_str(&serde_json::to_string(&history.record()).unwrap()).unwrap();
+        let mut loaded_origins = vec![];
+        let mut loaded = ImageHistory::from_record(record, |origin, has_drill_mask| {
+            loaded_origins.push((origin, has_drill_mask));
+            history.images().iter()
+                .find(|revision| revision.image_origin == origin)
+                .map(|revision| (
+                    image::RgbImage::clone(&revision.image),
+                    revision.drill_mask.as_deref().cloned()
+                ))
+                .ok_or(HistoryError::RevisionNotFound(origin))
+        }).unwrap();
+
+        assert_eq!(loaded_origins, vec![(0, false), (2, true)], "Every image is loaded once");
+        assert!(loaded.revisions()[3].drill_mask.is_some());
+        assert_eq!(loaded.current().id, history.current().id);
+        assert_eq!(loaded.revision(Some(3)).unwrap().palette, Some(vec!["DMC 310".to_string()]));
+        assert!(Arc::ptr_eq(&loaded.revisions()[2].image, &loaded.revisions()[3].image));
+        assert_eq!(loaded.revisions()[2].image_hash, history.revisions()[2].image_hash, "Hash is kept");
+        assert_eq!(loaded.redo().map(|revision| revision.id).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_record_without_hash_is_hashed_on_load() {
+        let history = history();
+        let mut record = history.record();
+        record.revisions[0].image_hash = None;
+
+        let loaded = ImageHistory::from_record(record, |_, _| {
+            Ok::<_, HistoryError>((image::RgbImage::new(4, 4), None))
+        }).unwrap();
+        assert_eq!(loaded.current().image_hash, history.current().image_hash);
+    }
+
+    #[test]
+    fn test_restored_image_keeps_its_origin() {
+        let original = HashedImage::new(image::RgbImage::new(4, 4));
+        let mut history = ImageHistory::new(original.clone(), None);
+        history.push(RevisionKind::ReplaceImage, |revision| revision.set_image(HashedImage::new(image::RgbImage::new(2, 2))));
+        history.push(RevisionKind::ReplaceImage, |revision| revision.set_image(original.clone()));
+        history.push(RevisionKind::ReplaceImage, |revision| revision.drill_mask = Some(Arc::new(DrillMask::new(4, 4))));
+
+        assert_eq!(history.images().iter().map(|revision| revision.image_origin).collect::<Vec<_>>(), vec![0, 1, 2, 3],
+            "Shared image brought back and image with a new mask are new origins");
+        assert_eq!(history.memory_bytes(), 4 * 4 * 3 + 2 * 2 * 3 + 4 * 4);
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut history = history();
+        for _ in 0..IMAGE_REVISIONS_MAX + 5 {
+            history.push(RevisionKind::Transforms, |_| ());
+        }
+        assert_eq!(history.revisions().len(), IMAGE_REVISIONS_MAX);
+        assert_eq!(history.current().id as usize, IMAGE_REVISIONS_MAX + 5);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    io::Cursor
+};
+
+use image::ImageDecoder;
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+/// Dominant colors reported at most.
+const DOMINANT_COLORS_COUNT: usize = 5;
+
+/// Bits of every channel dropped when similar colors are grouped for the dominant colors.
+const DOMINANT_COLOR_SHIFT: u32 = 4;
+
+/// Resolution stored in the file, in dots per inch.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dpi {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Fields of EXIF relevant for printing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ExifInfo {
+    /// EXIF orientation `1..=8` as stored in the file, `1` is upright.
+    /// Uploaded images are rotated upright already.
+    pub orientation: Option<u8>,
+    pub dpi: Option<Dpi>,
+}
+
+/// Color taking a large part of the image, similar colors are grouped together.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DominantColor {
+    /// Average color of the group as `#rrggbb`.
+    pub color: String,
+    /// Part of pixels in the group, `0.0..=1.0`.
+    pub share: f32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorStats {
+    pub unique_count: usize,
+    /// The most common color groups, the largest first.
+    pub dominant: Vec<DominantColor>,
+}
+
+/// Facts about an uploaded file which are lost once it is decoded.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageSourceInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Detected format, e.g. `jpeg` or `png`.
+    pub format: Option<String>,
+    pub byte_size: usize,
+    pub exif: ExifInfo,
+    pub colors: ColorStats,
+}
+
+impl ImageSourceInfo {
+    /// Reads info of the uploaded file `bytes` decoded into `image`. Missing or broken EXIF
+    /// is not an error, the fields are left empty.
+    pub fn read(bytes: &[u8], image: &image::RgbImage) -> Self {
+        let format = image::guess_format(bytes).ok();
+        let exif = format
+            .and_then(|format| read_exif_raw(bytes, format))
+            .map(parse_exif)
+            .unwrap_or_default();
+
+        Self {
+            width: image.width(),
+            height: image.height(),
+            format: format.map(|format| format!("{format:?}").to_lowercase()),
+            byte_size: bytes.len(),
+            exif,
+            colors: color_stats(image),
+        }
+    }
+}
+
+fn read_exif_raw(bytes: &[u8], format: image::ImageFormat) -> Option<Vec<u8>> {
+    let reader = image::ImageReader::with_format(Cursor::new(bytes), format);
+    let mut decoder = reader.into_decoder().ok()?;
+    decoder.exif_metadata().ok().flatten()
+}
+
+/// Parses raw EXIF, i.e. TIFF structure without the `Exif\0\0` header.
+pub fn parse_exif(raw: Vec<u8>) -> ExifInfo {
+    let exif = match exif::Reader::new().read_raw(raw) {
+        Ok(exif) => exif,
+        Err(e) => {
+            tracing::debug!("Ignoring unreadable EXIF: {e}");
+            return ExifInfo::default();
+        },
+    };
+
+    let field = |tag| exif.get_field(tag, exif::In::PRIMARY);
+    let rational = |tag| match field(tag).map(|field| &field.value) {
+        Some(exif::Value::Rational(values)) => values.first().map(|value| value.to_f64() as f32),
+        _ => None,
+    };
+
+    let orientation = field(exif::Tag::Orientation)
+        .and_then(|field| field.value.get_uint(0))
+        .and_then(|value| u8::try_from(value).ok())
+        .filter(|value| (1..=8).contains(value));
+
+    // Resolution unit 2 is inch (default), 3 is centimetre
+    let inches_per_unit = match field(exif::Tag::ResolutionUnit).and_then(|field| field.value.get_uint(0)) {
+        None | Some(2) => Some(1.0),
+        Some(3) => Some(2.54),
+        Some(_) => None,
+    };
+    let dpi = match (rational(exif::Tag::XResolution), rational(exif::Tag::YResolution), inches_per_unit) {
+        (Some(x), Some(y), Some(inches_per_unit)) if x > 0.0 && y > 0.0 => Some(Dpi {
+            x: x * inches_per_unit,
+            y: y * inches_per_unit
+        }),
+        _ => None,
+    };
+
+    ExifInfo { orientation, dpi }
+}
+
+/// Counts unique colors and groups similar colors to find the dominant ones.
+pub fn color_stats(image: &image::RgbImage) -> ColorStats {
+    let mut unique_colors = std::collections::HashSet::new();
+    // Sum of channels and count of pixels in every group
+    let mut groups: HashMap<[u8; 3], ([u64; 3], u64)> = HashMap::new();
+
+    for pixel in image.pixels() {
+        unique_colors.insert(pixel.0);
+
+        let key = pixel.0.map(|channel| channel >> DOMINANT_COLOR_SHIFT);
+        let (sum, count) = groups.entry(key).or_default();
+        for (sum, channel) in sum.iter_mut().zip(pixel.0) {
+            *sum += channel as u64;
+        }
+        *count += 1;
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|(a_key, (_, a_count)), (b_key, (_, b_count))| b_count.cmp(a_count).then(a_key.cmp(b_key)));
+
+    let pixels_count = (image.width() as u64 * image.height() as u64).max(1);
+    let dominant = groups.into_iter()
+        .take(DOMINANT_COLORS_COUNT)
+        .map(|(_, (sum, count))| {
+            let [r, g, b] = sum.map(|sum| (sum as f64 / count as f64).round() as u8);
+            DominantColor {
+                color: format!("#{r:02x}{g:02x}{b:02x}"),
+                share: count as f32 / pixels_count as f32,
+            }
+        })
+        .collect();
+
+    ColorStats { unique_count: unique_colors.len(), dominant }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Big endian TIFF with a single IFD of orientation and resolution.
+    fn exif_raw(orientation: u16, resolution: u32, unit: u16) -> Vec<u8> {
+        let mut raw = b"MM\0\x2a\0\0\0\x08".to_vec();
+        let entries_count = 4u16;
+        // Rationals follow the IFD: header + count + entries + next IFD offset
+        let rational_offset = 8 + 2 + entries_count as u32 * 12 + 4;
+
+        raw.extend(entries_count.to_be_bytes());
+        let mut entry = |tag: u16, kind: u16, value: [u8; 4]| {
+            raw.extend(tag.to_be_bytes());
+            raw.extend(kind.to_be_bytes());
+            raw.extend(1u32.to_be_bytes());
+            raw.extend(value);
+        };
+        let short = |value: u16| { let [a, b] = value.to_be_bytes(); [a, b, 0, 0] };
+        entry(0x0112, 3, short(orientation));
+        entry(0x011a, 5, rational_offset.to_be_bytes());
+        entry(0x011b, 5, (rational_offset + 8).to_be_bytes());
+        entry(0x0128, 3, short(unit));
+        raw.extend(0u32.to_be_bytes());
+        for _ in 0..2 {
+            raw.extend(resolution.to_be_bytes());
+            raw.extend(1u32.to_be_bytes());
+        }
+        raw
+    }
+
+    #[test]
+    fn test_parse_exif() {
+        assert_eq!(parse_exif(exif_raw(6, 300, 2)), ExifInfo { orientation: Some(6), dpi: Some(Dpi { x: 300.0, y: 300.0 }) });
+
+        let exif = parse_exif(exif_raw(1, 100, 3));
+        assert_eq!(exif.dpi, Some(Dpi { x: 254.0, y: 254.0 }), "Centimetres are converted");
+
+        assert_eq!(parse_exif(exif_raw(42, 300, 2)).orientation, None, "Invalid orientation is ignored");
+        assert_eq!(parse_exif(b"garbage".to_vec()), ExifInfo::default());
+    }
+
+    #[test]
+    fn test_color_stats() {
+        // Three quarters red with a slightly different shade, one quarter blue
+        let image = image::RgbImage::from_fn(4, 4, |x, y| match (x, y) {
+            (0, 0) => image::Rgb([252, 2, 0]),
+            (_, 3) => image::Rgb([0, 0, 255]),
+            _ => image::Rgb([250, 0, 0]),
+        });
+
+        let stats = color_stats(&image);
+        assert_eq!(stats.unique_count, 3);
+        assert_eq!(stats.dominant.len(), 2);
+        assert_eq!(stats.dominant[0], DominantColor { color: "#fa0000".to_string(), share: 0.75 });
+        assert_eq!(stats.dominant[1], DominantColor { color: "#0000ff".to_string(), share: 0.25 });
+    }
+
+    #[test]
+    fn test_read_source_info() {
+        let image = image::RgbImage::from_pixel(8, 6, image::Rgb([10, 20, 30]));
+        let mut png = Vec::new();
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+        image::ImageEncoder::set_exif_metadata(&mut encoder, exif_raw(8, 72, 2)).unwrap();
+        image::ImageEncoder::write_image(encoder, image.as_raw(), 8, 6, image::ExtendedColorType::Rgb8).unwrap();
+
+        let info = ImageSourceInfo::read(&png, &image);
+        assert_eq!((info.width, info.height), (8, 6));
+        assert_eq!(info.format.as_deref(), Some("png"));
+        assert_eq!(info.byte_size, png.len());
+        assert_eq!(info.exif.orientation, Some(8));
+        assert_eq!(info.exif.dpi, Some(Dpi { x: 72.0, y: 72.0 }));
+        assert_eq!(info.colors.unique_count, 1);
+    }
+}
@@ -1,34 +1,185 @@
+pub mod canvas;
 pub mod dmc;
+pub mod history;
+pub mod image_info;
 pub mod processing;
+pub mod storage;
 
 use std::{
-    collections::HashMap, 
-    path::Path, sync::Arc
+    collections::{
+        HashMap, 
+        HashSet
+    }, 
+    path::Path, 
+    sync::{
+        Arc, 
+        Weak
+    }
 };
 
 use serde::{
     Deserialize, 
     Serialize
 };
+use sha2::{
+    Digest, 
+    Sha256
+};
+
+use history::{
+    HistoryError, 
+    ImageHistory, 
+    ImageRevision, 
+    RevisionId, 
+    RevisionKind, 
+    SpilledHistory
+};
+use canvas::CanvasSpec;
+use image_info::ImageSourceInfo;
+use storage::{
+    memory::MemoryBackend, 
+    writer::{
+        StorageWrite, 
+        StorageWriter
+    }, 
+    ArtifactRecord, 
+    ImageRecord, 
+    StorageBackend, 
+    StorageBackendError
+};
+use processing::{
+    image_manip::DrillMask, 
+    transform::TransformStack, 
+    worker::{
+        WorkId, 
+        WorkResult
+    }
+};
 
 pub type ImageId = String;
 
+/// SHA-256 of dimensions and pixels of a decoded image.
+pub type ContentHash = [u8; 32];
+
+/// Decoded image with its content hash. Hashing goes through every pixel, so it is done
+/// before the image is handed over to the storage.
+#[derive(Debug, Clone)]
+pub struct HashedImage {
+    image: Arc<image::RgbImage>,
+    hash: ContentHash,
+}
+
+impl HashedImage {
+    pub fn new(image: image::RgbImage) -> Self {
+        let image = Arc::new(image);
+        let mut hasher = Sha256::new();
+        hasher.update(image.width().to_le_bytes());
+        hasher.update(image.height().to_le_bytes());
+        hasher.update(image.as_raw());
+        Self { image, hash: hasher.finalize().into() }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+}
+
+/// Images not accessed for longer are expired, unless configured otherwise.
+pub const IMAGE_TTL_DEFAULT: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+
+/// Decoded images kept in memory at most, unless configured otherwise.
+pub const IMAGE_MEMORY_BUDGET_DEFAULT: usize = 1024 * 1024 * 1024;
+
+/// Kind of processing which can be bound to an image. 
+/// Each image holds at most one record of every kind.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageWorkKind {
+    PaletteExtract,
+    Preview,
+    PdfRender,
+}
+
+impl ImageWorkKind {
+    pub const ALL: [ImageWorkKind; 3] = [Self::PaletteExtract, Self::Preview, Self::PdfRender];
+}
+
+/// Processing bound to an image - either still in flight, already finished, failed with the reason or cancelled.
+#[derive(Debug, Clone)]
+pub enum ImageWorkRecord {
+    Pending(WorkId),
+    Finished(Arc<WorkResult>),
+    Failed(String),
+    Cancelled,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageStorageMeta {
     pub filename: String,
     pub upload_time: chrono::DateTime<chrono::Utc>,
     pub last_touch_time: chrono::DateTime<chrono::Utc>,
+    /// File of the latest upload, replacing the image replaces it too.
+    #[serde(default)]
+    pub source: ImageSourceInfo,
+    /// Canvas of the latest preview or PDF ordered with one.
+    #[serde(default)]
+    pub canvas: Option<CanvasSpec>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ImageStorageElement {
-    pub image: Arc<image::RgbImage>,
     pub meta: ImageStorageMeta,
+    pub works: HashMap<ImageWorkKind, ImageWorkRecord>,
+    /// Revisions of edits, the uploaded image is never modified.
+    pub history: ImageHistory,
+}
+
+/// Element whose images were evicted to disk to stay within the memory budget.
+#[derive(Debug)]
+struct SpilledElement {
+    meta: ImageStorageMeta,
+    works: HashMap<ImageWorkKind, ImageWorkRecord>,
+    history: SpilledHistory,
+}
+
+/// Usage of the image storage.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ImageStorageStats {
+    pub images_count: usize,
+    pub resident_count: usize,
+    pub spilled_count: usize,
+    /// Bytes of decoded images in memory.
+    pub memory_used_bytes: usize,
+    pub memory_budget_bytes: usize,
+    /// Bytes of images evicted to disk.
+    pub spilled_bytes: usize,
+    pub evictions_count: u64,
+    pub reloads_count: u64,
+    /// Images found equal to an image already in memory, which got shared.
+    pub deduplicated_count: u64,
 }
 
 #[derive(Debug)]
 pub struct ImageStorageService {
     images: HashMap<ImageId, ImageStorageElement>,
+    spilled: HashMap<ImageId, SpilledElement>,
+    /// Created on the first eviction, removed with the service.
+    spill_dir: Option<tempfile::TempDir>,
+    memory_budget_bytes: usize,
+    evictions_count: u64,
+    reloads_count: u64,
+    /// Images in memory by their content, equal uploads share a single image.
+    content_index: HashMap<ContentHash, Weak<image::RgbImage>>,
+    deduplicated_count: u64,
+    /// IDs of expired images with their expiry time, so they are told apart from unknown IDs.
+    /// Kept for another TTL, then forgotten.
+    expired: HashMap<ImageId, chrono::DateTime<chrono::Utc>>,
+    ttl: chrono::TimeDelta,
+    /// Every change is written through in background, so images survive a restart.
+    writer: StorageWriter,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -41,15 +192,311 @@ pub enum ImageStorageServiceError {
 
     #[error("ImageNotFound")]
     ImageNotFound,
+
+    #[error("ImageExpired")]
+    ImageExpired,
+
+    #[error(transparent)]
+    History(#[from] HistoryError),
+
+    #[error("SpillFailed reason='{0}'")]
+    Spill(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Storage(#[from] StorageBackendError),
+}
+
+impl Default for ImageStorageService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ImageStorageService {
     pub fn new() -> Self {
         Self {
-            images: HashMap::new()
+            images: HashMap::new(),
+            spilled: HashMap::new(),
+            spill_dir: None,
+            memory_budget_bytes: IMAGE_MEMORY_BUDGET_DEFAULT,
+            evictions_count: 0,
+            reloads_count: 0,
+            content_index: HashMap::new(),
+            deduplicated_count: 0,
+            expired: HashMap::new(),
+            ttl: IMAGE_TTL_DEFAULT,
+            writer: StorageWriter::new(Box::new(MemoryBackend)),
+        }
+    }
+
+    /// Backend changes are written to, [`ImageStorageService::load_persisted`] loads images stored before.
+    pub fn with_backend(mut self, backend: Box<dyn StorageBackend>) -> Self {
+        self.writer = StorageWriter::new(backend);
+        self
+    }
+
+    /// Blocks until every change made so far is written to the backend.
+    pub fn flush_writes(&self) {
+        self.writer.flush();
+    }
+
+    /// Loads images stored in the backend. Images which cannot be loaded are skipped.
+    /// Returns count of loaded images.
+    pub fn load_persisted(&mut self) -> Result<usize, StorageBackendError> {
+        let records = self.writer.read(|backend| backend.list_records())?;
+        let mut loaded_count = 0;
+
+        for (id, record) in records {
+            match self.load_record(&id, record) {
+                Ok(element) => {
+                    self.images.insert(id, element);
+                    loaded_count += 1;
+                },
+                Err(e) => tracing::warn!("Cannot load stored image '{id}': {e}"),
+            }
+        }
+
+        self.enforce_memory_budget(None);
+        Ok(loaded_count)
+    }
+
+    fn load_record(&mut self, id: &ImageId, record: ImageRecord) -> Result<ImageStorageElement, StorageBackendError> {
+        let (mut history, works) = self.writer.read(|backend| {
+            let history = ImageHistory::from_record(record.history, |origin, has_drill_mask| {
+                let image = backend.load_image(id, origin)?;
+                let drill_mask = if has_drill_mask { Some(backend.load_drill_mask(id, origin)?) } else { None };
+                Ok::<_, StorageBackendError>((image, drill_mask))
+            })?;
+
+            let mut works = HashMap::new();
+            for (kind, artifact) in record.artifacts {
+                let work_result = artifact.into_work_result(|name| backend.load_blob(id, name))?;
+                works.insert(kind, ImageWorkRecord::Finished(Arc::new(work_result)));
+            }
+            Ok::<_, StorageBackendError>((history, works))
+        })?;
+        history.share_images(|image| self.share_image(image));
+
+        Ok(ImageStorageElement { meta: record.meta, works, history })
+    }
+
+    fn image_origins(&self, id: &ImageId) -> Vec<RevisionId> {
+        self.images.get(id)
+            .map(|element| element.history.images().into_iter().map(|revision| revision.image_origin).collect())
+            .unwrap_or_default()
+    }
+
+    /// Queues writing record of the image to the backend. Images introduced since `origins_before`
+    /// are saved, images no longer referenced by any revision are removed.
+    fn persist(&mut self, id: &ImageId, origins_before: &[RevisionId]) -> Result<(), ImageStorageServiceError> {
+        let element = self.images.get(id).ok_or(ImageStorageServiceError::ImageNotFound)?;
+        let images = element.history.images();
+
+        for revision in &images {
+            if !origins_before.contains(&revision.image_origin) {
+                self.writer.write(StorageWrite::Image { id: id.clone(), origin: revision.image_origin, image: revision.image.clone() });
+                if let Some(drill_mask) = &revision.drill_mask {
+                    self.writer.write(StorageWrite::DrillMask { id: id.clone(), origin: revision.image_origin, drill_mask: drill_mask.clone() });
+                }
+            }
+        }
+
+        let artifacts = element.works.iter()
+            .filter_map(|(kind, work_record)| match work_record {
+                ImageWorkRecord::Finished(work_result) => ArtifactRecord::from_work_result(work_result)
+                    .map(|(artifact, _)| (*kind, artifact)),
+                ImageWorkRecord::Pending(_) | ImageWorkRecord::Failed(_) | ImageWorkRecord::Cancelled => None,
+            })
+            .collect();
+        let record = ImageRecord { meta: element.meta.clone(), history: element.history.record(), artifacts };
+        self.writer.write(StorageWrite::Record { id: id.clone(), record: Box::new(record) });
+
+        for origin in origins_before {
+            if !images.iter().any(|revision| revision.image_origin == *origin) {
+                self.writer.write(StorageWrite::RemoveImage { id: id.clone(), origin: *origin });
+            }
+        }
+        Ok(())
+    }
+
+    /// Edits history of the image and writes the change through to the backend.
+    fn edit_history<F>(&mut self, id: &ImageId, edit: F) -> Result<(), ImageStorageServiceError>
+    where
+        F: FnOnce(&mut ImageHistory) -> Result<(), HistoryError>
+    {
+        self.access_image_mut(id)?;
+        let origins_before = self.image_origins(id);
+
+        let element = self.images.get_mut(id).expect("Accessed image is resident");
+        edit(&mut element.history)?;
+
+        self.enforce_memory_budget(Some(id));
+        self.persist(id, &origins_before)
+    }
+
+    pub fn with_ttl(mut self, ttl: chrono::TimeDelta) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Least recently touched images are evicted to disk once decoded images exceed the budget.
+    pub fn with_memory_budget(mut self, memory_budget_bytes: usize) -> Self {
+        self.memory_budget_bytes = memory_budget_bytes;
+        self
+    }
+
+    fn last_touch_time(&self, id: &ImageId) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.images.get(id).map(|element| element.meta.last_touch_time)
+            .or_else(|| self.spilled.get(id).map(|element| element.meta.last_touch_time))
+    }
+
+    fn is_expired(&self, last_touch_time: chrono::DateTime<chrono::Utc>, time_now: chrono::DateTime<chrono::Utc>) -> bool {
+        time_now - last_touch_time > self.ttl
+    }
+
+    /// Removes the image whether it is in memory or on disk, including its stored copy.
+    /// Returns `false` if there was none.
+    fn remove_element(&mut self, id: &ImageId) -> bool {
+        let was_removed = match self.spilled.remove(id) {
+            Some(spilled) => {
+                spilled.history.discard();
+                true
+            },
+            None => self.images.remove(id).is_some(),
+        };
+
+        if was_removed {
+            self.writer.write(StorageWrite::Remove { id: id.clone() });
+        }
+        was_removed
+    }
+
+    /// Bytes of decoded images and drill masks in memory, images shared by several uploads are counted once.
+    fn memory_used_bytes(&self) -> usize {
+        let mut counted = HashSet::new();
+        self.images.values()
+            .flat_map(|element| element.history.revisions())
+            .flat_map(|revision| {
+                let image = (Arc::as_ptr(&revision.image) as *const u8, revision.image.as_raw().len());
+                let drill_mask = revision.drill_mask.as_ref()
+                    .map(|drill_mask| (Arc::as_ptr(drill_mask) as *const u8, drill_mask.as_raw().len()));
+                std::iter::once(image).chain(drill_mask)
+            })
+            .filter(|(pointer, _)| counted.insert(*pointer))
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    /// Returns image of equal pixels if one is already in memory, otherwise the image itself
+    /// which can be shared from now on.
+    fn share_image(&mut self, hashed: HashedImage) -> HashedImage {
+        let HashedImage { image, hash } = hashed;
+        if let Some(shared) = self.content_index.get(&hash).and_then(Weak::upgrade) {
+            if !Arc::ptr_eq(&shared, &image) {
+                self.deduplicated_count += 1;
+            }
+            return HashedImage { image: shared, hash };
+        }
+
+        // Forget images dropped in the meantime
+        self.content_index.retain(|_, image| image.strong_count() > 0);
+        self.content_index.insert(hash, Arc::downgrade(&image));
+        HashedImage { image, hash }
+    }
+
+    fn spill_dir(&mut self) -> std::io::Result<&Path> {
+        if self.spill_dir.is_none() {
+            self.spill_dir = Some(tempfile::Builder::new().prefix("diamonds_imager_spill").tempdir()?);
+        }
+        Ok(self.spill_dir.as_ref().expect("Spill dir was created").path())
+    }
+
+    fn evict(&mut self, id: &ImageId) -> std::io::Result<()> {
+        let spill_dir = self.spill_dir()?.to_path_buf();
+        let history = self.images[id].history.spill(&spill_dir)?;
+        let element = self.images.remove(id).expect("Evicted image is present");
+
+        self.spilled.insert(id.clone(), SpilledElement { meta: element.meta, works: element.works, history });
+        self.evictions_count += 1;
+        Ok(())
+    }
+
+    fn reload(&mut self, id: &ImageId) -> std::io::Result<()> {
+        let mut history = self.spilled[id].history.load()?;
+        history.share_images(|image| self.share_image(image));
+        let spilled = self.spilled.remove(id).expect("Reloaded image is present");
+        spilled.history.discard();
+
+        self.images.insert(id.clone(), ImageStorageElement { meta: spilled.meta, works: spilled.works, history });
+        self.reloads_count += 1;
+        Ok(())
+    }
+
+    /// Evicts least recently touched images until decoded images fit into the budget.
+    /// Image `keep` stays in memory even if it alone exceeds the budget.
+    fn enforce_memory_budget(&mut self, keep: Option<&ImageId>) {
+        while self.memory_used_bytes() > self.memory_budget_bytes {
+            let Some(lru_id) = self.images.iter()
+                .filter(|(id, _)| Some(*id) != keep)
+                .min_by_key(|(_, element)| element.meta.last_touch_time)
+                .map(|(id, _)| id.clone()) else {
+                break;
+            };
+
+            if let Err(e) = self.evict(&lru_id) {
+                tracing::warn!("Cannot evict image '{lru_id}' to disk: {e}");
+                break;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ImageStorageStats {
+        ImageStorageStats {
+            images_count: self.images.len() + self.spilled.len(),
+            resident_count: self.images.len(),
+            spilled_count: self.spilled.len(),
+            memory_used_bytes: self.memory_used_bytes(),
+            memory_budget_bytes: self.memory_budget_bytes,
+            spilled_bytes: self.spilled.values().map(|element| element.history.bytes()).sum(),
+            evictions_count: self.evictions_count,
+            reloads_count: self.reloads_count,
+            deduplicated_count: self.deduplicated_count,
         }
     }
 
+    fn not_found_error(&self, id: &ImageId) -> ImageStorageServiceError {
+        if self.expired.contains_key(id) {
+            ImageStorageServiceError::ImageExpired
+        } else {
+            ImageStorageServiceError::ImageNotFound
+        }
+    }
+
+    /// Removes images not accessed for longer than TTL and forgets IDs expired more than TTL ago.
+    /// Returns count of removed images.
+    pub fn remove_expired(&mut self, time_now: chrono::DateTime<chrono::Utc>) -> usize {
+        let expired_ids = self.images.iter()
+            .map(|(id, element)| (id, element.meta.last_touch_time))
+            .chain(self.spilled.iter().map(|(id, element)| (id, element.meta.last_touch_time)))
+            .filter(|(_, last_touch_time)| self.is_expired(*last_touch_time, time_now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in &expired_ids {
+            self.remove_element(id);
+            self.expired.insert(id.clone(), time_now);
+        }
+
+        let ttl = self.ttl;
+        self.expired.retain(|_, expiry_time| time_now - *expiry_time <= ttl);
+
+        expired_ids.len()
+    }
+
+    pub fn images_count(&self) -> usize {
+        self.images.len() + self.spilled.len()
+    }
+
     fn generate_id(filename: &str) -> Result<ImageId, ImageStorageServiceError> {
         let uploaded_filename_path = Path::new(&filename);
 
@@ -65,39 +512,357 @@ impl ImageStorageService {
         Ok(new_filename)
     }
 
-    pub fn insert_image(&mut self, filename: String, img: image::DynamicImage) -> Result<ImageId, ImageStorageServiceError> {
+    pub fn insert_image(
+        &mut self,
+        filename: String,
+        img: HashedImage,
+        drill_mask: Option<DrillMask>,
+        source: ImageSourceInfo
+    ) -> Result<ImageId, ImageStorageServiceError> {
         let id = Self::generate_id(&filename)?;
 
         let time_now = chrono::Utc::now();
+        let image = self.share_image(img);
 
         self.images.insert(id.clone(), ImageStorageElement {
-            image: Arc::new(img.into()),
             meta: ImageStorageMeta { 
                 filename, 
                 upload_time: time_now, 
-                last_touch_time: time_now 
-            }
+                last_touch_time: time_now,
+                source,
+                canvas: None
+            },
+            works: HashMap::new(),
+            history: ImageHistory::new(image, drill_mask.map(Arc::new)),
         });
+        self.enforce_memory_budget(Some(&id));
+        self.persist(&id, &[])?;
 
         Ok(id)
     }
 
-    pub fn access_image(&self, id: &ImageId) -> Result<&ImageStorageElement, ImageStorageServiceError> {
-        self.images.get(id).ok_or(ImageStorageServiceError::ImageNotFound)
+    /// Accesses the image and refreshes its last touch time. Image which outlived TTL
+    /// is expired right away, even if the reaper has not removed it yet. Image evicted
+    /// to disk is loaded back, evicting other images if needed.
+    /// Works bound to the image, read without touching the image or loading it back to memory,
+    /// so watching the works neither keeps the image from expiring nor in memory.
+    pub fn peek_works(&self, id: &ImageId) -> Result<HashMap<ImageWorkKind, ImageWorkRecord>, ImageStorageServiceError> {
+        let last_touch_time = self.last_touch_time(id).ok_or_else(|| self.not_found_error(id))?;
+        if self.is_expired(last_touch_time, chrono::Utc::now()) {
+            return Err(ImageStorageServiceError::ImageExpired);
+        }
+
+        let works = self.images.get(id).map(|element| &element.works)
+            .or_else(|| self.spilled.get(id).map(|element| &element.works))
+            .expect("Image with touch time is present");
+        Ok(works.clone())
     }
 
-    pub fn get_image_meta(&self, id: &ImageId) -> Result<ImageStorageMeta, ImageStorageServiceError> {
-        self.images.get(id)
-            .ok_or(ImageStorageServiceError::ImageNotFound)
-            .map(|e| e.meta.clone())
+    pub fn access_image(&mut self, id: &ImageId) -> Result<&ImageStorageElement, ImageStorageServiceError> {
+        self.access_image_mut(id).map(|element| &*element)
+    }
+
+    pub fn access_image_mut(&mut self, id: &ImageId) -> Result<&mut ImageStorageElement, ImageStorageServiceError> {
+        let time_now = chrono::Utc::now();
+        let Some(last_touch_time) = self.last_touch_time(id) else {
+            return Err(self.not_found_error(id));
+        };
+
+        if self.is_expired(last_touch_time, time_now) {
+            self.remove_element(id);
+            self.expired.insert(id.clone(), time_now);
+            return Err(ImageStorageServiceError::ImageExpired);
+        }
+
+        if self.spilled.contains_key(id) {
+            self.reload(id)?;
+            self.enforce_memory_budget(Some(id));
+        }
+
+        let element = self.images.get_mut(id).expect("Image is present");
+        element.meta.last_touch_time = time_now;
+        Ok(element)
+    }
+
+    /// Binds work to the image. Previous record of the same kind gets replaced.
+    pub fn bind_work(&mut self, id: &ImageId, kind: ImageWorkKind, work_id: WorkId) -> Result<(), ImageStorageServiceError> {
+        let element = self.access_image_mut(id)?;
+        element.works.insert(kind, ImageWorkRecord::Pending(work_id));
+        Ok(())
+    }
+
+    pub fn get_work_record(&mut self, id: &ImageId, kind: ImageWorkKind) -> Result<Option<ImageWorkRecord>, ImageStorageServiceError> {
+        let element = self.access_image(id)?;
+        Ok(element.works.get(&kind).cloned())
+    }
+
+    /// Stores result of finished work. Result is stored only if the image still awaits 
+    /// work with `work_id` - it could be replaced by more recent work in the meantime.
+    pub fn finish_work(&mut self, id: &ImageId, kind: ImageWorkKind, work_id: WorkId, work_result: WorkResult) -> Result<Arc<WorkResult>, ImageStorageServiceError> {
+        let element = self.access_image_mut(id)?;
+        let work_result = Arc::new(work_result);
+
+        if let Some(ImageWorkRecord::Pending(pending_work_id)) = element.works.get(&kind) {
+            if *pending_work_id == work_id {
+                element.works.insert(kind, ImageWorkRecord::Finished(work_result.clone()));
+
+                if ArtifactRecord::from_work_result(&work_result).is_some() {
+                    self.writer.write(StorageWrite::Artifacts { id: id.clone(), work_result: work_result.clone() });
+                    let origins = self.image_origins(id);
+                    self.persist(id, &origins)?;
+                }
+            }
+        }
+
+        Ok(work_result)
+    }
+
+    /// Stores the reason of failed work, only if the image still awaits work with `work_id`.
+    /// Failed work is not persisted, it can be started again.
+    pub fn fail_work(&mut self, id: &ImageId, kind: ImageWorkKind, work_id: WorkId, reason: String) -> Result<(), ImageStorageServiceError> {
+        let element = self.access_image_mut(id)?;
+
+        if let Some(ImageWorkRecord::Pending(pending_work_id)) = element.works.get(&kind) {
+            if *pending_work_id == work_id {
+                element.works.insert(kind, ImageWorkRecord::Failed(reason));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Marks work as cancelled, only if the image still awaits work with `work_id`.
+    /// Cancelled work is not persisted, it can be started again.
+    pub fn cancel_work(&mut self, id: &ImageId, kind: ImageWorkKind, work_id: WorkId) -> Result<(), ImageStorageServiceError> {
+        let element = self.access_image_mut(id)?;
+
+        if let Some(ImageWorkRecord::Pending(pending_work_id)) = element.works.get(&kind) {
+            if *pending_work_id == work_id {
+                element.works.insert(kind, ImageWorkRecord::Cancelled);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Revision of the image, the current one when `revision` is `None`.
+    pub fn get_revision(&mut self, id: &ImageId, revision: Option<RevisionId>) -> Result<ImageRevision, ImageStorageServiceError> {
+        let element = self.access_image(id)?;
+        Ok(element.history.revision(revision)?.clone())
+    }
+
+    pub fn get_history(&mut self, id: &ImageId) -> Result<ImageHistory, ImageStorageServiceError> {
+        self.access_image(id).map(|e| e.history.clone())
+    }
+
+    /// Makes a revision with replaced transform stack, the stack should be validated against the image.
+    pub fn set_transforms(&mut self, id: &ImageId, transforms: TransformStack) -> Result<(), ImageStorageServiceError> {
+        self.edit_history(id, |history| {
+            history.push(RevisionKind::Transforms, |revision| revision.transforms = transforms);
+            Ok(())
+        })
+    }
+
+    /// Makes a revision with a new image, transforms of the previous image are dropped.
+    pub fn replace_image(
+        &mut self,
+        id: &ImageId,
+        img: HashedImage,
+        drill_mask: Option<DrillMask>,
+        source: ImageSourceInfo
+    ) -> Result<(), ImageStorageServiceError> {
+        self.access_image_mut(id)?.meta.source = source;
+        let image = self.share_image(img);
+        self.edit_history(id, |history| {
+            history.push(RevisionKind::ReplaceImage, |revision| {
+                revision.set_image(image);
+                revision.drill_mask = drill_mask.map(Arc::new);
+                revision.transforms = TransformStack::default();
+            });
+            Ok(())
+        })
+    }
+
+    /// Makes a revision with saved palette choice, `None` clears it.
+    pub fn save_palette(&mut self, id: &ImageId, palette: Option<Vec<String>>) -> Result<(), ImageStorageServiceError> {
+        self.edit_history(id, |history| {
+            history.push(RevisionKind::Palette, |revision| revision.palette = palette);
+            Ok(())
+        })
+    }
+
+    pub fn undo(&mut self, id: &ImageId) -> Result<(), ImageStorageServiceError> {
+        self.edit_history(id, |history| history.undo().map(|_| ()))
+    }
+
+    pub fn redo(&mut self, id: &ImageId) -> Result<(), ImageStorageServiceError> {
+        self.edit_history(id, |history| history.redo().map(|_| ()))
+    }
+
+    pub fn jump_to_revision(&mut self, id: &ImageId, revision: RevisionId) -> Result<(), ImageStorageServiceError> {
+        self.edit_history(id, |history| history.jump(revision).map(|_| ()))
+    }
+
+    pub fn get_image_meta(&mut self, id: &ImageId) -> Result<ImageStorageMeta, ImageStorageServiceError> {
+        self.access_image(id).map(|e| e.meta.clone())
+    }
+
+    /// Remembers canvas the image is ordered on, it stays until another one is set.
+    pub fn set_canvas(&mut self, id: &ImageId, canvas: CanvasSpec) -> Result<(), ImageStorageServiceError> {
+        self.access_image_mut(id)?.meta.canvas = Some(canvas);
+        let origins = self.image_origins(id);
+        self.persist(id, &origins)
     }
 
     pub fn remove_image(&mut self, id: &ImageId) -> Result<(), ImageStorageServiceError> {
-        if self.images.remove(id).is_none() {
-            Err(ImageStorageServiceError::ImageNotFound)
-        } else {
+        if self.remove_element(id) {
             Ok(())
+        } else {
+            Err(self.not_found_error(id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage_with_image() -> (ImageStorageService, ImageId) {
+        let mut storage = ImageStorageService::new().with_ttl(chrono::TimeDelta::minutes(10));
+        let id = storage.insert_image("test.png".to_string(), HashedImage::new(image::RgbImage::new(4, 4)), None, ImageSourceInfo::default()).unwrap();
+        (storage, id)
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let (mut storage, id) = storage_with_image();
+        let time_now = chrono::Utc::now();
+        assert_eq!(storage.remove_expired(time_now + chrono::TimeDelta::minutes(5)), 0);
+        assert_eq!(storage.remove_expired(time_now + chrono::TimeDelta::minutes(11)), 1);
+        assert_eq!(storage.images_count(), 0);
+        assert!(matches!(storage.access_image(&id), Err(ImageStorageServiceError::ImageExpired)));
+        assert!(matches!(storage.remove_image(&id), Err(ImageStorageServiceError::ImageExpired)));
+        assert!(matches!(storage.access_image(&"unknown.png".to_string()), Err(ImageStorageServiceError::ImageNotFound)));
+
+        // Expired IDs are forgotten after another TTL
+        storage.remove_expired(time_now + chrono::TimeDelta::minutes(30));
+        assert!(matches!(storage.access_image(&id), Err(ImageStorageServiceError::ImageNotFound)));
+    }
+
+    #[test]
+    fn test_access_refreshes_touch_time() {
+        let (mut storage, id) = storage_with_image();
+        let touch_time = storage.get_image_meta(&id).unwrap().last_touch_time;
+
+        // Pretend the image was uploaded long ago but accessed just now
+        storage.access_image_mut(&id).unwrap().meta.last_touch_time = touch_time - chrono::TimeDelta::minutes(9);
+        let meta = storage.get_image_meta(&id).unwrap();
+        assert!(meta.last_touch_time >= touch_time);
+        assert_eq!(storage.remove_expired(touch_time + chrono::TimeDelta::minutes(5)), 0);
+
+        storage.access_image_mut(&id).unwrap().meta.last_touch_time = touch_time - chrono::TimeDelta::minutes(11);
+        assert!(matches!(storage.access_image(&id), Err(ImageStorageServiceError::ImageExpired)), "Expired before reaper runs");
+    }
+
+    #[test]
+    fn test_persisted_images_survive_restart() {
+        for kind in [storage::StorageBackendKind::Filesystem, storage::StorageBackendKind::Sqlite] {
+            let dir = tempfile::tempdir().unwrap();
+            let open_storage = || ImageStorageService::new().with_backend(storage::open_backend(kind, dir.path()).unwrap());
+
+            let mut storage = open_storage();
+            let id = storage.insert_image("test.png".to_string(), HashedImage::new(image::RgbImage::from_pixel(4, 4, image::Rgb([9, 8, 7]))), None, ImageSourceInfo::default()).unwrap();
+            storage.set_transforms(&id, TransformStack::new(vec![processing::transform::TransformOp::FlipVertical])).unwrap();
+            storage.replace_image(&id, HashedImage::new(image::RgbImage::new(2, 3)), None, ImageSourceInfo::default()).unwrap();
+            storage.save_palette(&id, Some(vec!["DMC 310".to_string()])).unwrap();
+            storage.undo(&id).unwrap();
+
+            let dmc_bom = [(dmc::Dmc { name: "Black".to_string(), code: "DMC 310".to_string(), color: palette::Srgb::new(0, 0, 0) }, 12)].into();
+            storage.bind_work(&id, ImageWorkKind::PaletteExtract, 1).unwrap();
+            storage.finish_work(&id, ImageWorkKind::PaletteExtract, 1, WorkResult::PaletteExtract { dmc_bom }).unwrap();
+            let removed_id = storage.insert_image("removed.png".to_string(), HashedImage::new(image::RgbImage::new(4, 4)), None, ImageSourceInfo::default()).unwrap();
+            storage.remove_image(&removed_id).unwrap();
+            let history = storage.get_history(&id).unwrap();
+            drop(storage);
+
+            let mut storage = open_storage();
+            assert_eq!(storage.load_persisted().unwrap(), 1, "{kind:?}");
+            let loaded_history = storage.get_history(&id).unwrap();
+            assert_eq!(
+                serde_json::to_value(loaded_history.record()).unwrap(),
+                serde_json::to_value(history.record()).unwrap(),
+                "{kind:?}"
+            );
+            assert_eq!(*loaded_history.revision(Some(0)).unwrap().image, image::RgbImage::from_pixel(4, 4, image::Rgb([9, 8, 7])));
+            assert_eq!(loaded_history.current().image.dimensions(), (2, 3));
+            let Some(ImageWorkRecord::Finished(work_result)) = storage.get_work_record(&id, ImageWorkKind::PaletteExtract).unwrap() else {
+                panic!("Finished work is stored, {kind:?}");
+            };
+            assert!(matches!(&*work_result, WorkResult::PaletteExtract { dmc_bom } if dmc_bom.len() == 1));
+
+            storage.remove_image(&id).unwrap();
+            storage.flush_writes();
+            assert_eq!(open_storage().load_persisted().unwrap(), 0, "{kind:?}");
         }
     }
+
+    #[test]
+    fn test_equal_images_are_shared() {
+        let mut storage = ImageStorageService::new();
+        let pixels = image::RgbImage::from_fn(4, 4, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let id1 = storage.insert_image("a.png".to_string(), HashedImage::new(pixels.clone()), None, ImageSourceInfo::default()).unwrap();
+        let id2 = storage.insert_image("b.png".to_string(), HashedImage::new(pixels.clone()), None, ImageSourceInfo::default()).unwrap();
+        let id3 = storage.insert_image("c.png".to_string(), HashedImage::new(image::RgbImage::new(4, 4)), None, ImageSourceInfo::default()).unwrap();
+        assert_ne!(id1, id2);
+
+        let image1 = storage.get_revision(&id1, None).unwrap().image;
+        assert!(Arc::ptr_eq(&image1, &storage.get_revision(&id2, None).unwrap().image));
+        assert!(!Arc::ptr_eq(&image1, &storage.get_revision(&id3, None).unwrap().image));
+        assert_eq!(storage.get_image_meta(&id2).unwrap().filename, "b.png");
+
+        let stats = storage.stats();
+        assert_eq!(stats.deduplicated_count, 1);
+        assert_eq!(stats.memory_used_bytes, 2 * 4 * 4 * 3);
+
+        // Replacing by the same pixels keeps the image, removing one upload keeps the other
+        storage.replace_image(&id3, HashedImage::new(pixels), None, ImageSourceInfo::default()).unwrap();
+        assert!(Arc::ptr_eq(&image1, &storage.get_revision(&id3, None).unwrap().image));
+        drop(image1);
+        storage.remove_image(&id1).unwrap();
+        assert_eq!(storage.get_revision(&id2, None).unwrap().image.dimensions(), (4, 4));
+    }
+
+    #[test]
+    fn test_least_recently_touched_is_spilled() {
+        let image_bytes = 4 * 4 * 3;
+        let mut storage = ImageStorageService::new().with_memory_budget(2 * image_bytes);
+        let ids = (0..3)
+            .map(|i| storage.insert_image("test.png".to_string(), HashedImage::new(image::RgbImage::from_pixel(4, 4, image::Rgb([i, 0, 0]))), None, ImageSourceInfo::default()).unwrap())
+            .collect::<Vec<_>>();
+
+        let stats = storage.stats();
+        assert_eq!((stats.resident_count, stats.spilled_count, stats.evictions_count), (2, 1, 1));
+        assert_eq!(stats.memory_used_bytes, 2 * image_bytes);
+        assert_eq!(stats.spilled_bytes, image_bytes);
+
+        // The first one was touched least recently, it comes back and evicts the second one
+        storage.set_transforms(&ids[0], TransformStack::default()).unwrap();
+        let stats = storage.stats();
+        assert_eq!((stats.resident_count, stats.spilled_count, stats.reloads_count), (2, 1, 1));
+        assert_eq!(storage.get_revision(&ids[0], Some(1)).unwrap().image.dimensions(), (4, 4));
+        assert_eq!(storage.stats().reloads_count, 1, "Resident image is not reloaded");
+
+        storage.remove_image(&ids[1]).unwrap();
+        assert_eq!(storage.stats().images_count, 2);
+        assert_eq!(storage.stats().spilled_bytes, 0);
+
+        // Image larger than the budget stays in memory alone
+        let mut storage = ImageStorageService::new().with_memory_budget(image_bytes / 2);
+        let id = storage.insert_image("test.png".to_string(), HashedImage::new(image::RgbImage::new(4, 4)), None, ImageSourceInfo::default()).unwrap();
+        assert_eq!(storage.stats().resident_count, 1);
+        storage.insert_image("test.png".to_string(), HashedImage::new(image::RgbImage::new(4, 4)), None, ImageSourceInfo::default()).unwrap();
+        assert_eq!(storage.stats().spilled_count, 1);
+        assert!(storage.access_image(&id).is_ok());
+    }
 }
 
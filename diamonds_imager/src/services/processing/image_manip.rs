@@ -1,14 +1,154 @@
-use ditherum::algorithms::dithering::dithering_floyd_steinberg_srgb;
+use ditherum::{
+    algorithms::{
+        confetti::{
+            remove_confetti_masked, 
+            ConfettiOptions, 
+            ConfettiReport
+        }, 
+        dithering::{
+            dithering_srgb_masked_with_progress, 
+            DitheringOptions
+        }, 
+        kmean::KMeansOptions
+    }, 
+    palette_utils::{
+        ColorDistance, 
+        PaletteClustering, 
+        PaletteSrgb
+    }, 
+    progress::{
+        Progress, 
+        ProgressRange
+    }
+};
+use serde::{
+    Deserialize, 
+    Serialize
+};
 
 use crate::services::dmc::{
     DmcBom, 
     PaletteDmc
 };
 
+/// Cells of an image which get a drill, every pixel is either [`DRILL`] or [`NO_DRILL`].
+/// Images without any transparent cell have no mask.
+pub type DrillMask = image::GrayImage;
+
+pub const DRILL: image::Luma<u8> = image::Luma([255]);
+pub const NO_DRILL: image::Luma<u8> = image::Luma([0]);
+
+/// Pixels less opaque than this get no drill with [`TransparencyPolicy::NoDrill`].
+const NO_DRILL_ALPHA_THRESHOLD: u8 = 128;
+
+/// Color of cells without drill in dithered image.
+const NO_DRILL_COLOR: image::Rgb<u8> = image::Rgb([255, 255, 255]);
+
+pub fn is_drilled(drill_mask: Option<&DrillMask>, x: u32, y: u32) -> bool {
+    drill_mask.is_none_or(|drill_mask| drill_mask.get_pixel(x, y)[0] >= 128)
+}
+
+/// Runs geometric operation made for RGB images on the mask, so it keeps matching the image.
+/// Blended values are rounded back to drill or no drill.
+pub fn map_drill_mask<F, E>(drill_mask: &DrillMask, map: F) -> Result<DrillMask, E>
+where
+    F: FnOnce(image::RgbImage) -> Result<image::RgbImage, E>
+{
+    let mapped = map(image::DynamicImage::ImageLuma8(drill_mask.clone()).into_rgb8())?;
+    Ok(DrillMask::from_fn(mapped.width(), mapped.height(), |x, y| {
+        if mapped.get_pixel(x, y)[0] >= 128 { DRILL } else { NO_DRILL }
+    }))
+}
+
+/// Formats accepted for upload, others are rejected before decoding.
+pub const UPLOAD_FORMATS: [image::ImageFormat; 6] = [
+    image::ImageFormat::Png,
+    image::ImageFormat::Jpeg,
+    image::ImageFormat::WebP,
+    image::ImageFormat::Bmp,
+    image::ImageFormat::Gif,
+    image::ImageFormat::Tiff,
+];
+
+/// Format declared by MIME type, `image/jpg` is taken as JPEG since clients send it often.
+pub fn format_from_mime_type(mime_type: &str) -> Option<image::ImageFormat> {
+    match mime_type {
+        "image/jpg" => Some(image::ImageFormat::Jpeg),
+        mime_type => image::ImageFormat::from_mime_type(mime_type),
+    }
+}
+
+/// Reads size of the image from its header, pixels are not decoded.
+pub fn read_dimensions(bytes: &[u8]) -> Result<(u32, u32), image::ImageError> {
+    image::ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+}
+
+/// Decodes image of any supported format and rotates it upright by its EXIF orientation.
+pub fn decode_oriented(bytes: &[u8]) -> Result<image::DynamicImage, image::ImageError> {
+    let mut decoder = image::ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = image::ImageDecoder::orientation(&mut decoder)?;
+    let mut img = image::DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// What happens with transparent pixels of uploaded image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparencyPolicy {
+    /// Pixels are blended over the color.
+    Background(image::Rgb<u8>),
+    /// Mostly transparent pixels get no drill, they are left out of BOM and blank on chart.
+    /// Their color is blended over white, so it does not spread by dithering.
+    NoDrill,
+}
+
+impl Default for TransparencyPolicy {
+    fn default() -> Self {
+        Self::Background(image::Rgb([255, 255, 255]))
+    }
+}
+
+/// Drops alpha channel of the image according to the policy. Mask is made only
+/// for [`TransparencyPolicy::NoDrill`] and only if some pixel gets no drill.
+pub fn flatten_transparency(img: image::DynamicImage, policy: TransparencyPolicy) -> (image::RgbImage, Option<DrillMask>) {
+    if !img.color().has_alpha() {
+        return (img.into_rgb8(), None);
+    }
+
+    let background = match policy {
+        TransparencyPolicy::Background(background) => background,
+        TransparencyPolicy::NoDrill => NO_DRILL_COLOR,
+    };
+    let rgba = img.into_rgba8();
+    let flattened = image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let image::Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        let alpha = a as f32 / 255.0;
+        image::Rgb(std::array::from_fn(|c| {
+            ([r, g, b][c] as f32 * alpha + background[c] as f32 * (1.0 - alpha)).round() as u8
+        }))
+    });
+
+    let drill_mask = match policy {
+        TransparencyPolicy::NoDrill if rgba.pixels().any(|pixel| pixel[3] < NO_DRILL_ALPHA_THRESHOLD) => {
+            Some(DrillMask::from_fn(rgba.width(), rgba.height(), |x, y| {
+                if rgba.get_pixel(x, y)[3] < NO_DRILL_ALPHA_THRESHOLD { NO_DRILL } else { DRILL }
+            }))
+        },
+        _ => None,
+    };
+
+    (flattened, drill_mask)
+}
+
 /// Dither the source image using a DMC palette and produce a BOM.
 ///
 /// This function first converts the given `PaletteDmc` into an sRGB palette,
-/// then applies Floyd–Steinberg dithering to the `src_img`, and finally
+/// then applies error diffusion or ordered dithering to the `src_img`, optionally
+/// merges isolated drills ("confetti") into neighbouring colors, and finally
 /// computes a Bill of Materials (BOM) mapping each DMC color to the count
 /// of pixels using that color in the dithered image.
 ///
@@ -16,26 +156,337 @@ use crate::services::dmc::{
 ///
 /// * `palette_dmc` – Reference to the `PaletteDmc` to use for palette lookup.
 /// * `src_img` – The source `RgbImage` to which dithering will be applied.
+/// * `drill_mask` – Cells without drill, they take no part in dithering and cleanup,
+///   they are left out of BOM and painted white.
+/// * `dithering` – Method, strength and color distance of dithering.
+/// * `confetti` – Cleanup of small regions after dithering, `None` skips it.
+/// * `progress` – Reported by every dithered row and after every following step.
+///   Cancelled dithering returns unfinished image with empty BOM.
 ///
 /// # Returns
 ///
 /// A tuple containing:
 /// 1. The dithered `RgbImage`.
 /// 2. A `DmcBom` (i.e., `HashMap<Dmc,u32>`) where each key is a `Dmc`
-///    color present in the image and each value is the number of pixels
-///    in the dithered image that use that color.
+///    color present in the image and each value is the number of drilled
+///    pixels in the dithered image that use that color.
+/// 3. Confetti statistics before and after the cleanup, when it was done.
 ///
 /// # Panics (in debug builds) but should not
 ///
 /// This function includes a `debug_assert_eq!` to verify that every pixel
 /// in the dithered image maps back to a color in the original DMC palette.
 /// If any unmapped colors remain, it will panic in non-optimized builds.
-pub fn image_dither_using_dmc_palette(palette_dmc: &PaletteDmc, src_img: &image::RgbImage) -> (image::RgbImage, DmcBom) {
+pub fn image_dither_using_dmc_palette(
+    palette_dmc: &PaletteDmc, 
+    src_img: &image::RgbImage, 
+    drill_mask: Option<&DrillMask>,
+    dithering: &DitheringOptions,
+    confetti: Option<&ConfettiOptions>,
+    progress: &dyn Progress
+) -> (image::RgbImage, DmcBom, Option<ConfettiReport>) {
     let palette_srgb = palette_dmc.downgrade_to_srgb_palette();
-    let dithered_image = dithering_floyd_steinberg_srgb(src_img, &palette_srgb);
+    let mut dithered_image = dithering_srgb_masked_with_progress(src_img, &palette_srgb, dithering, drill_mask, &ProgressRange::new(progress, 0.0, 0.9));
+    if progress.is_cancelled() {
+        return (dithered_image, DmcBom::new(), None);
+    }
+
+    // Cleanup only reuses colors of the image, BOM stays within the palette
+    let confetti_report = confetti.map(|confetti| {
+        let report = remove_confetti_masked(&mut dithered_image, confetti, drill_mask);
+        tracing::info!("Confetti cleanup done, {report:?}");
+        report
+    });
+    progress.report(0.95);
 
-    let (dmc_bom, not_mapped_count) = palette_dmc.find_bom_of_image(&dithered_image);
+    let (dmc_bom, not_mapped_count) = palette_dmc.find_bom_of_image(&dithered_image, drill_mask);
     debug_assert_eq!(not_mapped_count, 0,  "dithered image contained colors outside the DMC palette, found {not_mapped_count} colors.");
 
-    (dithered_image, dmc_bom)
-}
\ No newline at end of file
+    if let Some(drill_mask) = drill_mask {
+        for (x, y, pixel) in dithered_image.enumerate_pixels_mut() {
+            if !is_drilled(Some(drill_mask), x, y) {
+                *pixel = NO_DRILL_COLOR;
+            }
+        }
+    }
+
+    progress.report(1.0);
+    (dithered_image, dmc_bom, confetti_report)
+}
+
+/// Count of clusters when extracting palette by clustering without colors limit.
+pub const PALETTE_EXTRACT_CLUSTERS_DEFAULT: usize = 24;
+/// Colors limit of extracted palette.
+pub const PALETTE_EXTRACT_MAX_COLORS_RANGE: std::ops::RangeInclusive<usize> = 1..=256;
+
+/// How DMC palette is extracted from an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteExtractMode {
+    /// Every pixel votes for its closest DMC, the most voted ones are taken.
+    #[default]
+    Nearest,
+    /// Pixels are clustered by k-means, clusters are snapped to distinct DMCs.
+    KMeans,
+    /// Pixels are clustered by median cut, clusters are snapped to distinct DMCs.
+    MedianCut,
+}
+
+/// Extracts a subset of DMC palette best representing the source image.
+///
+/// # Arguments
+///
+/// * `palette_dmc` – DMC palette the subset is chosen from.
+/// * `src_img` – The source `RgbImage`.
+/// * `max_colors` – Limit of extracted colors, for clustering modes the count of clusters.
+/// * `mode` – Extraction method.
+/// * `color_distance` – How pixel colors are matched with DMC colors.
+/// * `kmeans` – Seed and restarts of k-means clustering, same seed gives same palette.
+/// * `progress` – Reported by every row of voting pixels and every iteration of k-means.
+///   Cancelled extraction returns incomplete or empty BOM.
+///
+/// # Returns
+///
+/// A `DmcBom` of extracted DMCs, each one with the count of pixels closest to it.
+pub fn extract_dmc_palette(
+    palette_dmc: &PaletteDmc,
+    src_img: &image::RgbImage,
+    max_colors: Option<usize>,
+    mode: PaletteExtractMode,
+    color_distance: ColorDistance,
+    kmeans: &KMeansOptions,
+    progress: &dyn Progress
+) -> DmcBom {
+    let clustering = match mode {
+        PaletteExtractMode::Nearest => {
+            return palette_dmc.find_subset_closest_to_image_pixels(src_img, max_colors, color_distance, progress);
+        },
+        PaletteExtractMode::KMeans => PaletteClustering::KMeans,
+        PaletteExtractMode::MedianCut => PaletteClustering::MedianCut,
+    };
+
+    let clusters_count = max_colors.unwrap_or(PALETTE_EXTRACT_CLUSTERS_DEFAULT);
+    let clustering_progress = ProgressRange::new(progress, 0.0, 0.8);
+    let clusters = PaletteSrgb::from_image_clustered_with_progress(src_img, clusters_count, clustering, kmeans, &clustering_progress)
+        .or_else(|e| {
+            tracing::warn!("Clustering {clustering:?} failed, reason='{e}', falling back to median cut");
+            PaletteSrgb::from_image_clustered_with_progress(src_img, clusters_count, PaletteClustering::MedianCut, kmeans, &clustering_progress)
+        })
+        .expect("Median cut does not fail on non-empty image");
+    if progress.is_cancelled() {
+        return DmcBom::new();
+    }
+
+    let palette_snapped = palette_dmc.snap_to_unique_dmc(clusters.as_ref().iter().copied(), color_distance);
+    palette_snapped.find_subset_closest_to_image_pixels(src_img, None, color_distance, &ProgressRange::new(progress, 0.8, 1.0))
+}
+
+/// Preview is never upscaled beyond this pixels count.
+pub const DRILLS_PREVIEW_MAX_PIXELS: u64 = 16_000_000;
+/// Size in pixels of a single drill rendered in preview.
+pub const DRILL_SIZE_RANGE: std::ops::RangeInclusive<u32> = 1..=64;
+
+const DRILL_CANVAS_COLOR: image::Rgb<u8> = image::Rgb([226, 224, 220]);
+
+/// Light which falls on a single pixel of a rendered drill.
+#[derive(Debug, Clone, Copy)]
+enum DrillShade {
+    Canvas,
+    Lit(f32),
+}
+
+/// Computes how every pixel of a square drill is lit. Drill is a bevelled square,
+/// top-left edges catch the light, bottom-right ones are in shadow, flat top
+/// has a small highlight.
+fn drill_shading_mask(drill_size: u32) -> Vec<DrillShade> {
+    let size = drill_size as f32;
+    let gap = (size * 0.06).max(if drill_size > 4 { 1.0 } else { 0.0 });
+    let bevel = size * 0.18;
+
+    (0..drill_size * drill_size)
+        .map(|idx| {
+            let (x, y) = ((idx % drill_size) as f32 + 0.5, (idx / drill_size) as f32 + 0.5);
+
+            if x < gap || y < gap || x > size - gap || y > size - gap {
+                return DrillShade::Canvas;
+            }
+
+            let (from_left, from_top) = (x - gap, y - gap);
+            let (from_right, from_bottom) = (size - gap - x, size - gap - y);
+            let closest_edge = from_left.min(from_top).min(from_right).min(from_bottom);
+
+            if closest_edge < bevel {
+                if closest_edge == from_left || closest_edge == from_top {
+                    DrillShade::Lit(0.35)
+                } else {
+                    DrillShade::Lit(-0.35)
+                }
+            } else {
+                // Highlight near top-left corner of flat top
+                let (hx, hy) = (x / size - 0.35, y / size - 0.35);
+                let highlight = (1.0 - (hx * hx + hy * hy).sqrt() / 0.15).max(0.0);
+                DrillShade::Lit(0.05 + 0.5 * highlight)
+            }
+        })
+        .collect()
+}
+
+fn shade_drill_color(color: &image::Rgb<u8>, light: f32) -> image::Rgb<u8> {
+    if light >= 0.0 {
+        ditherum::palette_utils::color_manip::mix_rgb_colors(light, *color, image::Rgb([255, 255, 255]))
+    } else {
+        ditherum::palette_utils::color_manip::mix_rgb_colors(-light, *color, image::Rgb([0, 0, 0]))
+    }
+}
+
+/// Renders every pixel of the dithered image as a drill, so the preview looks like
+/// the finished canvas.
+///
+/// `drill_size` is reduced if the rendered preview would exceed [`DRILLS_PREVIEW_MAX_PIXELS`].
+/// Cells without drill show bare canvas.
+pub fn render_drills_preview(dithered_img: &image::RgbImage, drill_mask: Option<&DrillMask>, drill_size: u32) -> image::RgbImage {
+    let (width, height) = dithered_img.dimensions();
+    let max_drill_size = ((DRILLS_PREVIEW_MAX_PIXELS as f64 / (width as f64 * height as f64)).sqrt() as u32).max(1);
+    let drill_size = drill_size.clamp(1, max_drill_size);
+
+    if drill_size == 1 {
+        return dithered_img.clone();
+    }
+
+    let mask = drill_shading_mask(drill_size);
+
+    image::RgbImage::from_fn(width * drill_size, height * drill_size, |x, y| {
+        let (drill_x, drill_y) = (x / drill_size, y / drill_size);
+        if !is_drilled(drill_mask, drill_x, drill_y) {
+            return DRILL_CANVAS_COLOR;
+        }
+
+        let color = dithered_img.get_pixel(drill_x, drill_y);
+        match mask[((y % drill_size) * drill_size + x % drill_size) as usize] {
+            DrillShade::Canvas => DRILL_CANVAS_COLOR,
+            DrillShade::Lit(light) => shade_drill_color(color, light),
+        }
+    })
+}
+
+/// Encodes image as PNG.
+pub fn encode_png(img: &image::RgbImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut png, image::ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ditherum::progress::NoProgress;
+
+    #[test]
+    fn test_extract_dmc_palette_by_clustering() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+        // Half black, half white, small red accent
+        let src_img = image::RgbImage::from_fn(60, 40, |x, y| {
+            if (28..32).contains(&x) && (18..20).contains(&y) {
+                image::Rgb([200, 16, 46])
+            } else if x < 30 {
+                image::Rgb([5, 5, 5])
+            } else {
+                image::Rgb([250, 250, 250])
+            }
+        });
+
+        for mode in [PaletteExtractMode::KMeans, PaletteExtractMode::MedianCut] {
+            let dmc_bom = extract_dmc_palette(&palette_dmc, &src_img, Some(3), mode, ColorDistance::Oklab, &Default::default(), &NoProgress);
+            assert!(dmc_bom.len() <= 3, "{mode:?}");
+            assert_eq!(dmc_bom.values().sum::<u32>(), 60 * 40, "{mode:?}");
+        }
+
+        // Accent gets its own DMC
+        let dmc_bom = extract_dmc_palette(&palette_dmc, &src_img, Some(3), PaletteExtractMode::KMeans, ColorDistance::Oklab, &Default::default(), &NoProgress);
+        assert_eq!(dmc_bom.len(), 3);
+    }
+
+    #[test]
+    fn test_render_drills_preview_size() {
+        let dithered_img = image::RgbImage::from_pixel(10, 6, image::Rgb([200, 10, 10]));
+
+        let preview = render_drills_preview(&dithered_img, None, 8);
+        assert_eq!(preview.dimensions(), (80, 48));
+        
+        // Gaps between drills show canvas
+        assert_eq!(*preview.get_pixel(0, 0), DRILL_CANVAS_COLOR);
+        assert_ne!(*preview.get_pixel(4, 4), DRILL_CANVAS_COLOR);
+    }
+
+    #[test]
+    fn test_render_drills_preview_without_drill_shows_canvas() {
+        let dithered_img = image::RgbImage::from_pixel(2, 1, image::Rgb([200, 10, 10]));
+        let drill_mask = DrillMask::from_fn(2, 1, |x, _| if x == 0 { NO_DRILL } else { DRILL });
+
+        let preview = render_drills_preview(&dithered_img, Some(&drill_mask), 8);
+        assert!((0..8).all(|y| (0..8).all(|x| *preview.get_pixel(x, y) == DRILL_CANVAS_COLOR)));
+        assert_ne!(*preview.get_pixel(12, 4), DRILL_CANVAS_COLOR);
+    }
+
+    #[test]
+    fn test_flatten_transparency() {
+        // Opaque red, half transparent red, fully transparent red
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(3, 1, |x, _| {
+            image::Rgba([255, 0, 0, [255, 128, 0][x as usize]])
+        }));
+
+        let (flattened, drill_mask) = flatten_transparency(img.clone(), TransparencyPolicy::Background(image::Rgb([0, 0, 255])));
+        assert_eq!(flattened.pixels().map(|pixel| pixel.0).collect::<Vec<_>>(), vec![[255, 0, 0], [128, 0, 127], [0, 0, 255]]);
+        assert!(drill_mask.is_none());
+
+        let (flattened, drill_mask) = flatten_transparency(img, TransparencyPolicy::NoDrill);
+        assert_eq!(*flattened.get_pixel(2, 0), NO_DRILL_COLOR);
+        let drill_mask = drill_mask.unwrap();
+        assert_eq!((0..3).map(|x| is_drilled(Some(&drill_mask), x, 0)).collect::<Vec<_>>(), vec![true, true, false]);
+
+        let (_, drill_mask) = flatten_transparency(image::RgbaImage::new(2, 2).into(), TransparencyPolicy::default());
+        assert!(drill_mask.is_none(), "Only no drill policy makes mask");
+    }
+
+    #[test]
+    fn test_dither_leaves_cells_without_drill_out_of_bom() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+        let src_img = image::RgbImage::from_pixel(4, 4, image::Rgb([200, 16, 46]));
+        let drill_mask = DrillMask::from_fn(4, 4, |x, _| if x < 2 { NO_DRILL } else { DRILL });
+
+        let (dithered_img, dmc_bom, _) = image_dither_using_dmc_palette(&palette_dmc, &src_img, Some(&drill_mask), &Default::default(), None, &NoProgress);
+        assert_eq!(dmc_bom.values().sum::<u32>(), 8);
+        assert_eq!(*dithered_img.get_pixel(0, 0), NO_DRILL_COLOR);
+    }
+
+    #[test]
+    fn test_dither_cells_without_drill_do_not_touch_drilled_ones() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+        // Color of DMC 666, it leaves no error of its own
+        let red = image::Rgb([230, 0, 38]);
+        // Two drilled cells surrounded by transparent ones
+        let is_drilled_cell = |x, y| x == 2 && (1..3).contains(&y);
+        let src_img = image::RgbImage::from_fn(5, 4, |x, y| if is_drilled_cell(x, y) { red } else { NO_DRILL_COLOR });
+        let drill_mask = DrillMask::from_fn(5, 4, |x, y| if is_drilled_cell(x, y) { DRILL } else { NO_DRILL });
+        let confetti = ConfettiOptions { min_region_size: 3, ..Default::default() };
+
+        let (dithered_img, dmc_bom, confetti_report) = image_dither_using_dmc_palette(&palette_dmc, &src_img, Some(&drill_mask), &Default::default(), Some(&confetti), &NoProgress);
+        assert_eq!(dmc_bom.len(), 1, "No error spreads from cells without drill, {dmc_bom:?}");
+        assert_eq!(dmc_bom.iter().map(|(dmc, count)| (dmc.code.as_str(), *count)).next(), Some(("DMC 666", 2)));
+
+        let confetti_report = confetti_report.unwrap();
+        assert_eq!(confetti_report.before.regions_count, 1, "Cells without drill are no region");
+        assert_eq!(confetti_report.changed_pixels_count, 0, "Drilled cells have nothing to merge into");
+        assert_ne!(*dithered_img.get_pixel(2, 1), NO_DRILL_COLOR);
+        assert_eq!(*dithered_img.get_pixel(1, 1), NO_DRILL_COLOR);
+    }
+
+    #[test]
+    fn test_render_drills_preview_too_big_should_reduce_drill_size() {
+        let dithered_img = image::RgbImage::new(1500, 1500);
+
+        let preview = render_drills_preview(&dithered_img, None, 16);
+        assert!(preview.width() as u64 * preview.height() as u64 <= DRILLS_PREVIEW_MAX_PIXELS);
+        assert_eq!(preview.width() % 1500, 0);
+    }
+}
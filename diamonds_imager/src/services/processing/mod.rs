@@ -2,6 +2,10 @@
 /// Handles queuing work, assigning it to workers, and collecting results.
 pub mod worker;
 pub mod image_manip;
+pub mod pdf_chart;
+pub mod scheduler;
+pub mod status;
+pub mod transform;
 
 use std::{
     collections::HashMap, 
@@ -9,9 +13,19 @@ use std::{
     time::Duration
 };
 
+use scheduler::{
+    PendingWork, 
+    Scheduler
+};
+use status::{
+    WorkStatus, 
+    WorkStatusBoard
+};
 use worker::{
     Work, 
     WorkId, 
+    WorkKind, 
+    WorkPriority, 
     WorkResult, 
     WorkResultWrapped, 
     WorkWrapped, 
@@ -19,56 +33,158 @@ use worker::{
 };
 
 const WORK_ORDERS_QUEUE_CAP: usize = 32;
-const WORKERS_COUNT: usize = 2;
+/// Works waiting for a worker at most, more are refused as busy.
+const WORK_PENDING_CAP: usize = 32;
+const WORKERS_COUNT_DEFAULT: usize = 2;
+/// Hint for refused client when to try again.
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(2);
 const WORKERS_RESULTS_QUEUE_CAP: usize = WORK_ORDERS_QUEUE_CAP;
+/// Finished works not collected for longer are forgotten with their results, unless configured otherwise.
+pub const FINISHED_WORK_TTL_DEFAULT: Duration = Duration::from_secs(60 * 60);
+/// Finished works are looked for at least this often, or once per their TTL if it is shorter.
+const FINISHED_WORK_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Errors that can occur during processing work.
 #[derive(Debug, thiserror::Error)]
 pub enum ProcessingError {
-    #[error("Busy")]
-    Busy,
+    #[error("Busy, retry after {}s", retry_after.as_secs())]
+    Busy {
+        retry_after: Duration,
+    },
 
     #[error("Service failed")]
     ServiceFailed,
 
     #[error("Not available")]
     NotAvailable,
+
+    #[error("Not started")]
+    NotStarted,
+
+    #[error("Failed, reason='{0}'")]
+    Failed(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+/// Size of the worker pool, limits of works of a kind processed at once
+/// and of works a single client can queue, how long finished works wait for collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatcherOptions {
+    pub workers_count: usize,
+    /// Kinds without limit may take every worker.
+    pub kind_limits: HashMap<WorkKind, usize>,
+    pub client_queue_quota: usize,
+    pub finished_work_ttl: Duration,
+}
+
+impl Default for DispatcherOptions {
+    fn default() -> Self {
+        Self {
+            workers_count: WORKERS_COUNT_DEFAULT,
+            kind_limits: HashMap::new(),
+            client_queue_quota: WORK_PENDING_CAP,
+            finished_work_ttl: FINISHED_WORK_TTL_DEFAULT,
+        }
+    }
+}
+
+/// Client which ordered the work, e.g. its address. Clients share workers fairly and each
+/// has its own queue quota, sessions of the same client take turns within its share.
+/// Batch work yields to any other work, otherwise priority is given by the kind of work.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkOrigin {
+    pub client: String,
+    pub session: Option<String>,
+    pub batch: bool,
 }
 
 /// Internal structure representing an ordered work to be processed.
 #[derive(Debug)]
 struct WorkOrder {
     work: Work,
-    assign_id_tx: tokio::sync::oneshot::Sender<WorkId>,
+    origin: WorkOrigin,
+    assign_id_tx: tokio::sync::oneshot::Sender<Result<WorkId, ProcessingError>>,
 }
 
+/// Finished works waiting for collection, failed ones with the reason.
+type OrdersResults = Arc<tokio::sync::Mutex<HashMap<WorkId, Result<WorkResult, String>>>>;
+
 /// Dispatcher responsible for managing work submission and result collection.
+/// Status of every work is tracked from enqueuing until its result is collected.
 #[derive(Debug)]
 pub struct WorkDispatcher {
     processings_queue_tx: tokio::sync::mpsc::Sender<WorkOrder>,
     dipatcher_task: tokio::task::JoinHandle<()>,
-    orders_results: Arc<tokio::sync::Mutex<HashMap<WorkId, WorkResult>>>,
+    orders_results: OrdersResults,
     on_result_notify: Arc<tokio::sync::Notify>,
+    statuses: WorkStatusBoard,
+}
+
+impl Default for WorkDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WorkDispatcher {
-    /// Collects the result from a worker and stores it.
+    /// Collects the result from a worker and stores it. Work is done
+    /// or failed only once its result can be collected.
+    /// Result of cancelled work is dropped, nobody collects it.
     async fn process_collected_result(
         work_result: WorkResultWrapped,
-        orders_results_shared: &Arc<tokio::sync::Mutex<HashMap<WorkId, WorkResult>>>
+        orders_results_shared: &OrdersResults,
+        statuses: &WorkStatusBoard
     ) {
         let mut orders_results_shared_guard = orders_results_shared.lock().await;
         debug_assert!(!orders_results_shared_guard.contains_key(&work_result.id), "Keys should be unique!");
-        orders_results_shared_guard.insert(work_result.id, work_result.work_result);
+
+        let status = match &work_result.work_result {
+            Ok(_) => WorkStatus::Done,
+            Err(reason) => WorkStatus::Failed { reason: reason.clone() },
+        };
+        if statuses.update(work_result.id, status) {
+            orders_results_shared_guard.insert(work_result.id, work_result.work_result);
+        } else {
+            tracing::info!("Dropping result of cancelled work {}", work_result.id);
+            statuses.remove(work_result.id);
+        }
+    }
+
+    /// Forgets works finished longer than `ttl` ago together with their results nobody collected.
+    async fn remove_finished_works(ttl: Duration, orders_results_shared: &OrdersResults, statuses: &WorkStatusBoard) {
+        let Some(deadline) = std::time::Instant::now().checked_sub(ttl) else {
+            return;
+        };
+
+        let removed = statuses.remove_finished_before(deadline);
+        if removed.is_empty() {
+            return;
+        }
+
+        let mut orders_results_shared_guard = orders_results_shared.lock().await;
+        for work_id in &removed {
+            orders_results_shared_guard.remove(work_id);
+        }
+        tracing::info!("Forgot {} finished works not collected in {}s", removed.len(), ttl.as_secs());
     }
 
-    /// Assigns a work order to an available worker.
-    async fn assign_order_to_worker(
+    /// Queues a work order, unless the queue or the quota of the client is full.
+    /// Sender of the order gets the assigned id.
+    fn accept_order(
         work_order: WorkOrder,
         next_unique_work_id: &mut u64,
-        workers: &[Worker],
-        assigning_start_idx: usize
+        scheduler: &mut Scheduler,
+        statuses: &WorkStatusBoard
     ) {
+        if let Err(e) = scheduler.check_room(&work_order.origin.client) {
+            tracing::info!("Refusing work of client '{}', reason='{e}'", work_order.origin.client);
+            // Sender may be gone already
+            let _ = work_order.assign_id_tx.send(Err(e));
+            return;
+        }
+
         // Assign a new unique work ID
         let new_id = {
             let tmp_next_id = *next_unique_work_id;
@@ -76,44 +192,38 @@ impl WorkDispatcher {
             tmp_next_id
         };
 
+        // Status is known before the sender gets the id
+        statuses.set(new_id, WorkStatus::Queued);
+
         // Inform sender, work was received and id assigned
         // Err means sender no longer is interested in this offer
-        if let Err(_) = work_order.assign_id_tx.send(new_id) {
+        if work_order.assign_id_tx.send(Ok(new_id)).is_err() {
             // Discard recently assigned id and continue
+            statuses.remove(new_id);
             return;
         }
 
         // Sender got notified, probably will poll for finished work with assigned id
-        // Let one of the workers do work
-        let mut work_wrapped = WorkWrapped {
-            work: work_order.work,
-            id: new_id
-        };
-        
-        // Attempt to pass WorkOrder to one of workers
-        loop {
-            for worker_idx in (0..WORKERS_COUNT).map(|i| (assigning_start_idx + i) % WORKERS_COUNT) {
-                let worker = &workers[worker_idx];
-                if let Err(e) = worker.try_enque_work(work_wrapped) {
-                    work_wrapped = match e {
-                        tokio::sync::mpsc::error::TrySendError::Full(work_wrapped_not_enqueued) => work_wrapped_not_enqueued,
-                        tokio::sync::mpsc::error::TrySendError::Closed(_) => {
-                            panic!("Worker should not be closed before dispatcher")
-                        },
-                    }
-                } else {
-                    tracing::info!("Work {new_id} was assigned to worker {worker}");
-                    return;
-                }
-            }
+        // One of idle workers will take it from the queue
+        let priority = if work_order.origin.batch { WorkPriority::Batch } else { work_order.work.kind().priority() };
+        scheduler.enqueue(PendingWork {
+            work_wrapped: WorkWrapped {
+                work: work_order.work,
+                id: new_id
+            },
+            client: work_order.origin.client,
+            session: work_order.origin.session,
+            priority,
+        });
+    }
 
-            // All workers busy, try again laater
-            tokio::time::sleep(Duration::from_millis(250)).await;
-        }
+    /// Creates a new work dispatcher with default count of workers and no limits of kinds.
+    pub fn new() -> Self {
+        Self::with_options(DispatcherOptions::default())
     }
 
     /// Creates a new work dispatcher with configured workers.
-    pub fn new() -> Self {
+    pub fn with_options(options: DispatcherOptions) -> Self {
         let (work_orders_queue_tx, mut work_orders_queue_rx) = tokio::sync::mpsc::channel::<WorkOrder>(WORK_ORDERS_QUEUE_CAP);
         
         // Orders collector
@@ -123,68 +233,130 @@ impl WorkDispatcher {
         let on_result_notify = Arc::new(tokio::sync::Notify::new());
         let on_result_notify_shared = on_result_notify.clone();
 
+        let statuses = WorkStatusBoard::default();
+        let statuses_shared = statuses.clone();
+
+        let workers_count = if options.workers_count == 0 {
+            tracing::warn!("Workers count can't be 0, using 1 worker");
+            1
+        } else {
+            options.workers_count
+        };
+        // Limit 0 would never let the kind be processed
+        let kind_limits = options.kind_limits.into_iter()
+            .map(|(kind, limit)| (kind, limit.max(1)))
+            .collect();
+        let finished_work_ttl = options.finished_work_ttl;
+
         let dipatcher_task = tokio::task::spawn(async move {
             // Unique work_id
             let mut next_unique_work_id = 0;
 
-            // Method to assing work to tasks, for sure can be done better
-            // LATER: track free workers
-            let mut assigning_fuzz_factor = 0;
-
             // Channels to collect results from multiple workers
             let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKERS_RESULTS_QUEUE_CAP);
 
-            {
-                // Spawn workers
-                let workers = (0..WORKERS_COUNT).map(|id| Worker::new(id as u32, work_result_tx.clone())).collect::<Vec<_>>();
-                
-                // Enter dispatcher loop
-                loop {
-                    tokio::select! {
-                        work_order = work_orders_queue_rx.recv() => match work_order {
-                            Some(work_order) => {
-                                Self::assign_order_to_worker(work_order, &mut next_unique_work_id, &workers, assigning_fuzz_factor).await;
-                                assigning_fuzz_factor += 1;
-                                assigning_fuzz_factor %= WORKERS_COUNT;
-                            },
-                            None => {
-                                tracing::info!("Stopping work dispatcher, workers will got stopped soon");
-                                break;
-                            }
+            // Spawn workers
+            let workers = (0..workers_count).map(|id| Worker::new(id as u32, work_result_tx.clone(), statuses_shared.clone())).collect::<Vec<_>>();
+            let mut scheduler = Scheduler::new(workers, WORK_PENDING_CAP, options.client_queue_quota.max(1), kind_limits, statuses_shared.clone());
+            tracing::info!("Work dispatcher started with {workers_count} workers");
+
+            let mut sweep_interval = tokio::time::interval(finished_work_ttl.clamp(Duration::from_millis(1), FINISHED_WORK_SWEEP_INTERVAL));
+            sweep_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            
+            // Enter dispatcher loop
+            loop {
+                tokio::select! {
+                    _ = sweep_interval.tick() => {
+                        Self::remove_finished_works(finished_work_ttl, &orders_results_shared, &statuses_shared).await;
+                        continue;
+                    },
+                    work_order = work_orders_queue_rx.recv() => match work_order {
+                        Some(work_order) => {
+                            Self::accept_order(work_order, &mut next_unique_work_id, &mut scheduler, &statuses_shared);
                         },
-                        work_result = work_result_rx.recv() => match work_result {
-                            Some(work_result) => {
-                                Self::process_collected_result(work_result, &orders_results_shared).await;
-                                on_result_notify_shared.notify_waiters();
-                            },
-                            None => {
-                                tracing::warn!("Unexpected workers closing!");
-                                break;
-                            }
+                        None => {
+                            tracing::info!("Stopping work dispatcher, workers will got stopped soon");
+                            break;
+                        }
+                    },
+                    work_result = work_result_rx.recv() => match work_result {
+                        Some(work_result) => {
+                            scheduler.finish(work_result.id);
+                            Self::process_collected_result(work_result, &orders_results_shared, &statuses_shared).await;
+                            on_result_notify_shared.notify_waiters();
+                        },
+                        None => {
+                            tracing::warn!("Unexpected workers closing!");
+                            break;
                         }
                     }
                 }
 
-                // Shutdown workers
-                for worker in workers {
-                    worker.shutdown().await;
-                }
-
+                // Either a work was queued or a worker got idle
+                scheduler.dispatch();
             }
+
+            // Shutdown workers
+            scheduler.shutdown().await;
         });
 
         Self { 
             processings_queue_tx: work_orders_queue_tx, 
             dipatcher_task,
             orders_results,
-            on_result_notify
+            on_result_notify,
+            statuses
+        }
+    }
+
+    /// Status of the work, `None` for unknown work or work whose result was collected already.
+    pub fn get_work_status(&self, work_id: WorkId) -> Option<WorkStatus> {
+        self.statuses.get(work_id)
+    }
+
+    /// Cancels queued or running work, workers stop it at the next check of progress.
+    /// Result of cancelled work is never available. Returns `false` for unknown or finished work.
+    pub fn cancel_work(&self, work_id: WorkId) -> bool {
+        let was_cancelled = self.statuses.cancel(work_id);
+        if was_cancelled {
+            tracing::info!("Work {work_id} was cancelled");
         }
+        was_cancelled
+    }
+
+    /// Cancels unfinished work or drops the result of finished one, when nobody needs it anymore.
+    pub async fn discard_work(&self, work_id: WorkId) {
+        if !self.cancel_work(work_id) && self.get_work_result(work_id, None).await.is_ok() {
+            tracing::info!("Result of work {work_id} was discarded");
+        }
+    }
+
+    /// Receiver marked as changed whenever status of any work changes.
+    pub fn subscribe_status_changes(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.statuses.subscribe()
+    }
+
+    /// Takes the stored result, its status is forgotten with it. Cancelled work results in
+    /// [`ProcessingError::Cancelled`] until the worker gives it up.
+    fn take_work_result(orders_results: &mut HashMap<WorkId, Result<WorkResult, String>>, statuses: &WorkStatusBoard, work_id: WorkId) -> Option<Result<WorkResult, ProcessingError>> {
+        if statuses.is_cancelled(work_id) {
+            return Some(Err(ProcessingError::Cancelled));
+        }
+        let work_result = orders_results.remove(&work_id)?;
+        statuses.remove(work_id);
+        Some(work_result.map_err(ProcessingError::Failed))
     }
 
     /// Enqueues a work item and returns the assigned work ID.
     pub async fn enque_work(&self, work_to_do: Work) -> Result<WorkId, ProcessingError> {
+        self.enque_work_from(work_to_do, WorkOrigin::default()).await
+    }
+
+    /// Enqueues a work item of the client and returns the assigned work ID.
+    /// Work over the quota of the client is refused with [`ProcessingError::Busy`].
+    pub async fn enque_work_from(&self, work_to_do: Work, origin: WorkOrigin) -> Result<WorkId, ProcessingError> {
         let (assign_id_tx, assign_id_rx) = tokio::sync::oneshot::channel();
-        let work_order = WorkOrder { work: work_to_do, assign_id_tx };
+        let work_order = WorkOrder { work: work_to_do, origin, assign_id_tx };
 
         // Enque work order
         let result = tokio::time::timeout(Duration::from_millis(250), async {
@@ -192,18 +364,19 @@ impl WorkDispatcher {
         }).await;
 
         // Work queue can be full
-        let _ = match result {
+        match result {
             Ok(Ok(_)) => Ok(()),
             Ok(Err(_)) => Err(ProcessingError::ServiceFailed),
-            Err(_) => Err(ProcessingError::Busy),
+            Err(_) => Err(ProcessingError::Busy { retry_after: BUSY_RETRY_AFTER }),
         }?;
 
-        // Await assigned id
+        // Await assigned id, full queue refuses the work
         assign_id_rx.await
-            .map_err(|_| ProcessingError::ServiceFailed)
+            .map_err(|_| ProcessingError::ServiceFailed)?
     }
 
-    /// Tries to retrieve the result for a given work ID.
+    /// Tries to retrieve the result for a given work ID, failed work results in [`ProcessingError::Failed`]
+    /// and cancelled one in [`ProcessingError::Cancelled`].
     /// If a timeout is specified, waits for the result up to the given duration.
     /// Awaiting results is event driven by receiving notification from dispatcher task.
     pub async fn get_work_result(&self, work_id: WorkId, timeout_duration: Option<Duration>) -> Result<WorkResult, ProcessingError> {
@@ -213,8 +386,8 @@ impl WorkDispatcher {
             loop {
                 {
                     let mut orders_results_shared_guard = self.orders_results.lock().await;
-                    if let Some(work_result) = orders_results_shared_guard.remove(&work_id) {
-                        return Ok(work_result);
+                    if let Some(work_result) = Self::take_work_result(&mut orders_results_shared_guard, &self.statuses, work_id) {
+                        return work_result;
                     }
                 }
 
@@ -226,14 +399,14 @@ impl WorkDispatcher {
 
                 let remaining_time = deadline - now;
         
-                if let Err(_) = tokio::time::timeout(remaining_time, self.on_result_notify.notified()).await {
+                if tokio::time::timeout(remaining_time, self.on_result_notify.notified()).await.is_err() {
                     return Err(ProcessingError::NotAvailable);
                 }
             }
         } else {
             // Instant try
             let mut orders_results_shared_guard = self.orders_results.lock().await;
-            orders_results_shared_guard.remove(&work_id).ok_or(ProcessingError::NotAvailable)
+            Self::take_work_result(&mut orders_results_shared_guard, &self.statuses, work_id).unwrap_or(Err(ProcessingError::NotAvailable))
         }
     }
 
@@ -296,7 +469,10 @@ mod tests {
         let work = Work::PaletteExtract { 
             palette_dmc, 
             src_image, 
-            max_colors: Some(5)
+            max_colors: Some(5),
+            mode: Default::default(),
+            color_distance: ditherum::palette_utils::ColorDistance::default(),
+            kmeans: Default::default(),
         };
 
         let work_id = dispatcher.enque_work(work).await.expect("Failed to enqueue work");
@@ -350,4 +526,331 @@ mod tests {
         
         tokio::time::sleep(Duration::from_millis(10)).await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_dispatcher_tracks_status_until_result_is_collected() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::new();
+
+        let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(200) }).await.expect("Failed to enqueue work");
+        assert!(matches!(dispatcher.get_work_status(work_id), Some(WorkStatus::Assigned { .. } | WorkStatus::Running { .. })));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(matches!(dispatcher.get_work_status(work_id), Some(WorkStatus::Running { progress: 50, .. })));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(dispatcher.get_work_status(work_id), Some(WorkStatus::Done));
+
+        let work_result = dispatcher.get_work_result(work_id, None).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        assert_eq!(dispatcher.get_work_status(work_id), None, "Status is forgotten with collected result");
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_failed_work_results_err() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::new();
+
+        let work_id = dispatcher.enque_work(Work::TestFailure { reason: "broken on purpose" }).await.expect("Failed to enqueue work");
+        let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Err(ProcessingError::Failed(reason)) if reason == "broken on purpose"));
+
+        // Workers keep working after failure
+        let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(10) }).await.expect("Failed to enqueue work");
+        let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_cancels_running_and_queued_work() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::new();
+
+        // Occupy every worker, so the last work waits in the queue
+        let mut work_ids = vec![];
+        for _ in 0..=WORKERS_COUNT_DEFAULT {
+            let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_secs(10) }).await.expect("Failed to enqueue work");
+            work_ids.push(work_id);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = tokio::time::Instant::now();
+        for work_id in &work_ids {
+            assert!(dispatcher.cancel_work(*work_id));
+            assert_eq!(dispatcher.get_work_status(*work_id), Some(WorkStatus::Cancelled));
+            assert!(!dispatcher.cancel_work(*work_id), "Already cancelled");
+        }
+
+        let work_result = dispatcher.get_work_result(work_ids[0], None).await;
+        assert!(matches!(work_result, Err(ProcessingError::Cancelled)));
+
+        // Workers stopped cancelled works and are free again
+        let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(10) }).await.expect("Failed to enqueue work");
+        let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(2000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        assert!(started.elapsed() < Duration::from_secs(5), "Cancelled works did not run to the end");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for work_id in work_ids {
+            assert_eq!(dispatcher.get_work_status(work_id), None, "Status of cancelled work is forgotten once the worker gives it up");
+        }
+        assert!(!dispatcher.cancel_work(work_id), "Finished work can't be cancelled");
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_honours_workers_count() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { workers_count: 3, ..Default::default() });
+
+        let mut work_ids = vec![];
+        for _ in 0..4 {
+            let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(400) }).await.expect("Failed to enqueue work");
+            work_ids.push(work_id);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let statuses = work_ids.iter().map(|work_id| dispatcher.get_work_status(*work_id).unwrap()).collect::<Vec<_>>();
+        assert!(statuses[..3].iter().all(|status| matches!(status, WorkStatus::Running { .. })), "{statuses:?}");
+        assert_eq!(statuses[3], WorkStatus::Queued, "Every worker is busy");
+
+        // Queued work is taken by the first idle worker
+        for work_id in work_ids {
+            let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+            assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_limited_kind_does_not_block_other_kinds() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions {
+            workers_count: 2,
+            kind_limits: HashMap::from([(WorkKind::Test, 1)]),
+            ..Default::default()
+        });
+
+        let slow_work_ids = [
+            dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(500) }).await.expect("Failed to enqueue work"),
+            dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(500) }).await.expect("Failed to enqueue work"),
+        ];
+
+        let palette_dmc = Arc::new(PaletteDmc::load_from_file_default().unwrap());
+        let src_image = Arc::new(ditherum::image_utils::generate_gradient_image(20, 20, image::Rgb([0,0,0]), image::Rgb([255,0,0])));
+        let extract_work_id = dispatcher.enque_work(Work::PaletteExtract { 
+            palette_dmc, 
+            src_image, 
+            max_colors: Some(5),
+            mode: Default::default(),
+            color_distance: ditherum::palette_utils::ColorDistance::default(),
+            kmeans: Default::default(),
+        }).await.expect("Failed to enqueue work");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(dispatcher.get_work_status(slow_work_ids[0]), Some(WorkStatus::Running { .. })));
+        assert_eq!(dispatcher.get_work_status(slow_work_ids[1]), Some(WorkStatus::Queued), "Kind is at its limit");
+
+        // Extraction queued later passed by the limited work
+        let work_result = dispatcher.get_work_result(extract_work_id, Some(Duration::from_millis(300))).await;
+        assert!(matches!(work_result, Ok(WorkResult::PaletteExtract { .. })), "{work_result:?}");
+        assert_eq!(dispatcher.get_work_status(slow_work_ids[1]), Some(WorkStatus::Queued));
+
+        for work_id in slow_work_ids {
+            let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(2000))).await;
+            assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_full_queue_is_busy() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { workers_count: 1, ..Default::default() });
+
+        // One work is running, the rest fills the queue
+        let mut work_ids = vec![];
+        for _ in 0..=WORK_PENDING_CAP {
+            let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_secs(10) }).await.expect("Failed to enqueue work");
+            work_ids.push(work_id);
+        }
+
+        let work_result = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(10) }).await;
+        assert!(matches!(work_result, Err(ProcessingError::Busy { .. })));
+
+        // Cancelled works make room
+        for work_id in work_ids {
+            assert!(dispatcher.cancel_work(work_id));
+        }
+        let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(10) }).await.expect("Failed to enqueue work");
+        let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(2000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+
+        dispatcher.shutdown().await;
+    }
+
+    fn origin(client: &str, batch: bool) -> WorkOrigin {
+        WorkOrigin { client: client.to_string(), session: None, batch }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_batch_work_yields_to_other_work() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { workers_count: 1, ..Default::default() });
+
+        let blocking_work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(300) }).await.expect("Failed to enqueue work");
+        let batch_work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(200) }, origin("client", true)).await.expect("Failed to enqueue work");
+        let work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(200) }, origin("client", false)).await.expect("Failed to enqueue work");
+
+        // Batch work queued earlier waits for the other one
+        let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)), "{work_result:?}");
+        let batch_status = dispatcher.get_work_status(batch_work_id).expect("Batch work should be known");
+        assert!(!batch_status.is_finished(), "{batch_status:?}");
+
+        for work_id in [blocking_work_id, batch_work_id] {
+            let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+            assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_shares_workers_between_clients() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { workers_count: 1, ..Default::default() });
+
+        let mut first_client_work_ids = vec![];
+        for delay in [300, 200, 200] {
+            let work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(delay) }, origin("first", false)).await.expect("Failed to enqueue work");
+            first_client_work_ids.push(work_id);
+        }
+        let second_client_work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(200) }, origin("second", false)).await.expect("Failed to enqueue work");
+
+        // Work of the second client goes right after the running one of the first client
+        let work_result = dispatcher.get_work_result(second_client_work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)), "{work_result:?}");
+        for work_id in &first_client_work_ids[1..] {
+            let status = dispatcher.get_work_status(*work_id).expect("Work should be known");
+            assert!(!status.is_finished(), "{status:?}");
+        }
+
+        for work_id in first_client_work_ids {
+            let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+            assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_sessions_of_client_take_turns_within_its_quota() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { 
+            workers_count: 1, 
+            client_queue_quota: 3, 
+            ..Default::default() 
+        });
+        let session_origin = |session: &str| WorkOrigin { session: Some(session.to_string()), ..origin("client", false) };
+
+        let blocking_work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(300) }, session_origin("first")).await.expect("Failed to enqueue work");
+        let mut first_session_work_ids = vec![];
+        for _ in 0..2 {
+            let work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(200) }, session_origin("first")).await.expect("Failed to enqueue work");
+            first_session_work_ids.push(work_id);
+        }
+        let second_session_work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(200) }, session_origin("second")).await.expect("Failed to enqueue work");
+
+        // Quota is shared by sessions of the client
+        let work_result = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(10) }, session_origin("third")).await;
+        assert!(matches!(work_result, Err(ProcessingError::Busy { .. })), "{work_result:?}");
+
+        // Work of the second session goes right after the running one of the first session
+        let work_result = dispatcher.get_work_result(second_session_work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)), "{work_result:?}");
+        let status = dispatcher.get_work_status(first_session_work_ids[1]).expect("Work should be known");
+        assert!(!status.is_finished(), "{status:?}");
+
+        for work_id in std::iter::once(blocking_work_id).chain(first_session_work_ids) {
+            let work_result = dispatcher.get_work_result(work_id, Some(Duration::from_millis(1000))).await;
+            assert!(matches!(work_result, Ok(WorkResult::TestWork)));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_client_over_quota_is_busy() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { 
+            workers_count: 1, 
+            client_queue_quota: 1, 
+            ..Default::default() 
+        });
+
+        // One work is running, the other one fills the quota
+        let mut work_ids = vec![];
+        for _ in 0..2 {
+            let work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_secs(10) }, origin("first", false)).await.expect("Failed to enqueue work");
+            work_ids.push(work_id);
+        }
+
+        let work_result = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(10) }, origin("first", false)).await;
+        assert!(matches!(work_result, Err(ProcessingError::Busy { retry_after }) if retry_after == BUSY_RETRY_AFTER), "{work_result:?}");
+
+        // Other client has its own quota
+        let work_id = dispatcher.enque_work_from(Work::TestWork { delay: Duration::from_millis(10) }, origin("second", false)).await.expect("Failed to enqueue work");
+        work_ids.push(work_id);
+
+        for work_id in work_ids {
+            assert!(dispatcher.cancel_work(work_id));
+        }
+
+        dispatcher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_forgets_results_not_collected_in_time() {
+        init_tracing();
+
+        let dispatcher = WorkDispatcher::with_options(DispatcherOptions { 
+            finished_work_ttl: Duration::from_millis(50), 
+            ..Default::default() 
+        });
+
+        let work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(10) }).await.expect("Failed to enqueue work");
+        let running_work_id = dispatcher.enque_work(Work::TestWork { delay: Duration::from_millis(400) }).await.expect("Failed to enqueue work");
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(dispatcher.get_work_status(work_id), Some(WorkStatus::Done));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(dispatcher.get_work_status(work_id), None, "Status is forgotten with uncollected result");
+        assert!(dispatcher.orders_results.lock().await.is_empty());
+        assert!(matches!(dispatcher.get_work_result(work_id, None).await, Err(ProcessingError::NotAvailable)));
+        assert!(dispatcher.get_work_status(running_work_id).is_some(), "Unfinished work is kept");
+
+        let work_result = dispatcher.get_work_result(running_work_id, Some(Duration::from_millis(1000))).await;
+        assert!(matches!(work_result, Ok(WorkResult::TestWork)), "Work is kept until it finishes");
+
+        dispatcher.shutdown().await;
+    }
+}
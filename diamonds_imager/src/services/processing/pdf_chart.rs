@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+
+use pdf_writer::{
+    Content,
+    Filter,
+    Finish,
+    Name,
+    Pdf,
+    Rect,
+    Ref,
+    Str
+};
+
+use ditherum::palette_utils::color_manip::srgb_u8_to_rgb_u8;
+
+use crate::services::{
+    dmc::{
+        Dmc,
+        DmcBom
+    },
+    processing::image_manip::{
+        is_drilled,
+        DrillMask
+    }
+};
+
+// A4 portrait, units are PDF points (1/72 inch)
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const PAGE_MARGIN: f32 = 36.0;
+const PAGE_HEADER_HEIGHT: f32 = 34.0;
+
+const CHART_CELL_SIZE: f32 = 12.0;
+const CHART_COLUMNS_PER_PAGE: u32 = 40;
+const CHART_ROWS_PER_PAGE: u32 = 54;
+const CHART_LABELS_SPACE: f32 = 22.0;
+const CHART_MAJOR_LINE_EVERY: u32 = 10;
+
+const LEGEND_ROW_HEIGHT: f32 = 18.0;
+
+const PREVIEW_MAX_HEIGHT: f32 = 520.0;
+const PREVIEW_JPEG_QUALITY: u8 = 90;
+
+const FONT_REGULAR: Name = Name(b"F1");
+const FONT_BOLD: Name = Name(b"F2");
+const IMAGE_PREVIEW: Name = Name(b"Im1");
+
+/// Approximate width of Helvetica glyph relative to font size, good enough to center labels.
+const FONT_GLYPH_WIDTH_FACTOR: f32 = 0.55;
+
+const SYMBOLS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Errors that can occur while rendering PDF chart.
+#[derive(Debug, thiserror::Error)]
+pub enum PdfChartError {
+    #[error("Image contains color not found in BOM at x={x}, y={y}")]
+    ColorNotInBom {
+        x: u32,
+        y: u32,
+    },
+
+    #[error("ImageEmpty")]
+    ImageEmpty,
+
+    #[error("ImageError, reason='{0}'")]
+    ImageError(#[from] image::ImageError),
+}
+
+/// Position of the DMC chart legend.
+struct LegendEntry<'a> {
+    symbol: String,
+    dmc: &'a Dmc,
+    count: u32,
+}
+
+/// Single page of the document, content is rendered using coordinates
+/// measured from top-left corner of the page.
+struct PageCanvas {
+    content: Content,
+    uses_preview: bool,
+}
+
+impl PageCanvas {
+    fn new() -> Self {
+        Self {
+            content: Content::new(),
+            uses_preview: false,
+        }
+    }
+
+    fn text(&mut self, font: Name, size: f32, x: f32, top: f32, text: &str) {
+        let encoded = encode_win_ansi(text);
+        self.content
+            .begin_text()
+            .set_font(font, size)
+            .set_text_matrix([1.0, 0.0, 0.0, 1.0, x, PAGE_HEIGHT - top - size])
+            .show(Str(&encoded))
+            .end_text();
+    }
+
+    fn text_centered(&mut self, font: Name, size: f32, center_x: f32, center_top: f32, text: &str) {
+        let width = text_width(size, text);
+        self.text(font, size, center_x - width / 2.0, center_top - size / 2.0 + size * 0.1, text);
+    }
+
+    fn text_right_aligned(&mut self, font: Name, size: f32, right_x: f32, top: f32, text: &str) {
+        let width = text_width(size, text);
+        self.text(font, size, right_x - width, top, text);
+    }
+
+    fn fill_rect(&mut self, x: f32, top: f32, width: f32, height: f32, color: [f32; 3]) {
+        self.content
+            .set_fill_rgb(color[0], color[1], color[2])
+            .rect(x, PAGE_HEIGHT - top - height, width, height)
+            .fill_nonzero();
+    }
+
+    fn stroke_rect(&mut self, x: f32, top: f32, width: f32, height: f32, line_width: f32, gray: f32) {
+        self.content
+            .set_line_width(line_width)
+            .set_stroke_gray(gray)
+            .rect(x, PAGE_HEIGHT - top - height, width, height)
+            .stroke();
+    }
+
+    fn line(&mut self, from: (f32, f32), to: (f32, f32), line_width: f32, gray: f32) {
+        self.content
+            .set_line_width(line_width)
+            .set_stroke_gray(gray)
+            .move_to(from.0, PAGE_HEIGHT - from.1)
+            .line_to(to.0, PAGE_HEIGHT - to.1)
+            .stroke();
+    }
+
+    fn image(&mut self, x: f32, top: f32, width: f32, height: f32) {
+        self.uses_preview = true;
+        self.content
+            .save_state()
+            .transform([width, 0.0, 0.0, height, x, PAGE_HEIGHT - top - height])
+            .x_object(IMAGE_PREVIEW)
+            .restore_state();
+    }
+
+    fn page_title(&mut self, title: &str, subtitle: &str) {
+        self.text(FONT_BOLD, 14.0, PAGE_MARGIN, PAGE_MARGIN, title);
+        self.text(FONT_REGULAR, 9.0, PAGE_MARGIN, PAGE_MARGIN + 18.0, subtitle);
+    }
+}
+
+fn text_width(size: f32, text: &str) -> f32 {
+    text.chars().count() as f32 * size * FONT_GLYPH_WIDTH_FACTOR
+}
+
+/// Builtin PDF fonts use WinAnsi encoding. Characters outside of Latin-1 are replaced.
+fn encode_win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| if (c as u32) < 256 { c as u8 } else { b'?' })
+        .collect()
+}
+
+fn symbol_for_index(idx: usize) -> String {
+    let symbols_count = SYMBOLS.len();
+    if idx < symbols_count {
+        (SYMBOLS[idx] as char).to_string()
+    } else {
+        let first = SYMBOLS[(idx / symbols_count - 1) % symbols_count] as char;
+        let second = SYMBOLS[idx % symbols_count] as char;
+        format!("{first}{second}")
+    }
+}
+
+fn dmc_fill_color(dmc: &Dmc) -> [f32; 3] {
+    let color = dmc.color.into_format::<f32>();
+    [color.red, color.green, color.blue]
+}
+
+/// Symbol should stay readable on both dark and bright drills.
+fn dmc_symbol_gray(dmc: &Dmc) -> f32 {
+    let luminance = 0.299 * dmc.color.red as f32 + 0.587 * dmc.color.green as f32 + 0.114 * dmc.color.blue as f32;
+    if luminance > 140.0 { 0.0 } else { 1.0 }
+}
+
+/// Legend entries ordered from the most used DMC, so the most common
+/// drills get the simplest symbols.
+fn create_legend(dmc_bom: &DmcBom) -> Vec<LegendEntry<'_>> {
+    let mut bom_ordered = dmc_bom.iter().collect::<Vec<_>>();
+    bom_ordered.sort_by(|(a_dmc, a_cnt), (b_dmc, b_cnt)| b_cnt.cmp(a_cnt).then_with(|| a_dmc.code.cmp(&b_dmc.code)));
+
+    bom_ordered.into_iter()
+        .enumerate()
+        .map(|(idx, (dmc, count))| LegendEntry {
+            symbol: symbol_for_index(idx),
+            dmc,
+            count: *count,
+        })
+        .collect()
+}
+
+fn render_cover_page(
+    dithered_image: &image::RgbImage,
+    legend: &[LegendEntry],
+    chart_sheets: (u32, u32),
+) -> PageCanvas {
+    let mut page = PageCanvas::new();
+    let (width, height) = dithered_image.dimensions();
+    let total_drills: u32 = legend.iter().map(|entry| entry.count).sum();
+    let blank_cells = width * height - total_drills;
+
+    page.text(FONT_BOLD, 20.0, PAGE_MARGIN, PAGE_MARGIN, "Diamond painting chart");
+
+    let mut summary = vec![
+        format!("Grid size: {width} x {height} drills"),
+        format!("Total drills: {total_drills}"),
+        format!("Colors: {}", legend.len()),
+        format!("Chart sheets: {} x {} ({} columns x {} rows each)", chart_sheets.0, chart_sheets.1, CHART_COLUMNS_PER_PAGE, CHART_ROWS_PER_PAGE),
+    ];
+    if blank_cells > 0 {
+        summary.insert(2, format!("Blank cells without drill: {blank_cells}"));
+    }
+
+    let mut top = PAGE_MARGIN + 36.0;
+    for line in summary {
+        page.text(FONT_REGULAR, 11.0, PAGE_MARGIN, top, &line);
+        top += 16.0;
+    }
+
+    // Preview keeps aspect ratio of the drills grid
+    let max_width = PAGE_WIDTH - 2.0 * PAGE_MARGIN;
+    let scale = (max_width / width as f32).min(PREVIEW_MAX_HEIGHT / height as f32);
+    let (preview_width, preview_height) = (width as f32 * scale, height as f32 * scale);
+    let preview_x = (PAGE_WIDTH - preview_width) / 2.0;
+    top += 12.0;
+
+    page.image(preview_x, top, preview_width, preview_height);
+    page.stroke_rect(preview_x, top, preview_width, preview_height, 0.5, 0.3);
+
+    page
+}
+
+fn render_legend_pages(legend: &[LegendEntry]) -> Vec<PageCanvas> {
+    let table_top = PAGE_MARGIN + PAGE_HEADER_HEIGHT + LEGEND_ROW_HEIGHT;
+    let rows_per_page = ((PAGE_HEIGHT - PAGE_MARGIN - table_top) / LEGEND_ROW_HEIGHT).floor() as usize;
+    let columns_x = [PAGE_MARGIN, PAGE_MARGIN + 50.0, PAGE_MARGIN + 140.0];
+    let count_right_x = PAGE_WIDTH - PAGE_MARGIN;
+
+    legend.chunks(rows_per_page)
+        .enumerate()
+        .map(|(chunk_idx, entries)| {
+            let mut page = PageCanvas::new();
+            page.page_title("Color legend", &format!("Colors {}-{} of {}",
+                chunk_idx * rows_per_page + 1,
+                chunk_idx * rows_per_page + entries.len(),
+                legend.len()
+            ));
+
+            let header_top = PAGE_MARGIN + PAGE_HEADER_HEIGHT;
+            page.text(FONT_BOLD, 10.0, columns_x[0], header_top, "Symbol");
+            page.text(FONT_BOLD, 10.0, columns_x[1], header_top, "DMC code");
+            page.text(FONT_BOLD, 10.0, columns_x[2], header_top, "Name");
+            page.text_right_aligned(FONT_BOLD, 10.0, count_right_x, header_top, "Drills");
+            page.line((PAGE_MARGIN, table_top - 4.0), (count_right_x, table_top - 4.0), 0.8, 0.0);
+
+            for (row_idx, entry) in entries.iter().enumerate() {
+                let top = table_top + row_idx as f32 * LEGEND_ROW_HEIGHT;
+                let swatch_size = LEGEND_ROW_HEIGHT - 4.0;
+
+                page.fill_rect(columns_x[0], top, swatch_size, swatch_size, dmc_fill_color(entry.dmc));
+                page.stroke_rect(columns_x[0], top, swatch_size, swatch_size, 0.3, 0.3);
+                page.content.set_fill_gray(dmc_symbol_gray(entry.dmc));
+                page.text_centered(FONT_BOLD, 8.0, columns_x[0] + swatch_size / 2.0, top + swatch_size / 2.0, &entry.symbol);
+                page.content.set_fill_gray(0.0);
+
+                page.text(FONT_REGULAR, 10.0, columns_x[1], top + 2.0, &entry.dmc.code);
+                page.text(FONT_REGULAR, 10.0, columns_x[2], top + 2.0, &entry.dmc.name);
+                page.text_right_aligned(FONT_REGULAR, 10.0, count_right_x, top + 2.0, &entry.count.to_string());
+            }
+
+            page
+        })
+        .collect()
+}
+
+/// Draws small map of all chart sheets with the current one highlighted.
+fn render_sheets_map(page: &mut PageCanvas, sheets: (u32, u32), current: (u32, u32)) {
+    let cell = (28.0 / sheets.0.max(sheets.1) as f32).clamp(3.0, 8.0);
+    let map_x = PAGE_WIDTH - PAGE_MARGIN - cell * sheets.0 as f32;
+    let map_top = PAGE_MARGIN;
+
+    for sheet_row in 0..sheets.1 {
+        for sheet_col in 0..sheets.0 {
+            let (x, top) = (map_x + sheet_col as f32 * cell, map_top + sheet_row as f32 * cell);
+            if (sheet_col, sheet_row) == current {
+                page.fill_rect(x, top, cell, cell, [0.2, 0.2, 0.2]);
+            }
+            page.stroke_rect(x, top, cell, cell, 0.3, 0.2);
+        }
+    }
+}
+
+fn render_chart_page(
+    symbols_grid: &[Vec<Option<usize>>],
+    legend: &[LegendEntry],
+    sheets: (u32, u32),
+    sheet: (u32, u32),
+) -> PageCanvas {
+    let mut page = PageCanvas::new();
+    let grid_height = symbols_grid.len() as u32;
+    let grid_width = symbols_grid[0].len() as u32;
+
+    let columns = (sheet.0 * CHART_COLUMNS_PER_PAGE)..((sheet.0 + 1) * CHART_COLUMNS_PER_PAGE).min(grid_width);
+    let rows = (sheet.1 * CHART_ROWS_PER_PAGE)..((sheet.1 + 1) * CHART_ROWS_PER_PAGE).min(grid_height);
+
+    page.page_title(
+        &format!("Chart sheet column {}/{}, row {}/{}", sheet.0 + 1, sheets.0, sheet.1 + 1, sheets.1),
+        &format!("Drills columns {}-{}, rows {}-{}", columns.start + 1, columns.end, rows.start + 1, rows.end),
+    );
+    render_sheets_map(&mut page, sheets, sheet);
+
+    let grid_x = PAGE_MARGIN + CHART_LABELS_SPACE;
+    let grid_top = PAGE_MARGIN + PAGE_HEADER_HEIGHT + CHART_LABELS_SPACE;
+    let cell_x = |col: u32| grid_x + (col - columns.start) as f32 * CHART_CELL_SIZE;
+    let cell_top = |row: u32| grid_top + (row - rows.start) as f32 * CHART_CELL_SIZE;
+
+    // Drills with symbols, cells without drill stay blank
+    for row in rows.clone() {
+        for col in columns.clone() {
+            let Some(legend_idx) = symbols_grid[row as usize][col as usize] else {
+                continue;
+            };
+            let entry = &legend[legend_idx];
+            let (x, top) = (cell_x(col), cell_top(row));
+            let font_size = if entry.symbol.len() > 1 { 5.5 } else { 7.5 };
+
+            page.fill_rect(x, top, CHART_CELL_SIZE, CHART_CELL_SIZE, dmc_fill_color(entry.dmc));
+            page.content.set_fill_gray(dmc_symbol_gray(entry.dmc));
+            page.text_centered(FONT_BOLD, font_size, x + CHART_CELL_SIZE / 2.0, top + CHART_CELL_SIZE / 2.0, &entry.symbol);
+        }
+    }
+    page.content.set_fill_gray(0.0);
+
+    // Grid lines, every n-th line is thicker to ease counting
+    let (grid_right, grid_bottom) = (cell_x(columns.end), cell_top(rows.end));
+    for col in columns.start..=columns.end {
+        let is_major = col % CHART_MAJOR_LINE_EVERY == 0;
+        let (line_width, gray) = if is_major { (0.9, 0.0) } else { (0.25, 0.5) };
+        page.line((cell_x(col), grid_top), (cell_x(col), grid_bottom), line_width, gray);
+    }
+    for row in rows.start..=rows.end {
+        let is_major = row % CHART_MAJOR_LINE_EVERY == 0;
+        let (line_width, gray) = if is_major { (0.9, 0.0) } else { (0.25, 0.5) };
+        page.line((grid_x, cell_top(row)), (grid_right, cell_top(row)), line_width, gray);
+    }
+
+    // Coordinates, numbered from 1 across the whole chart
+    for col in columns.clone() {
+        let number = col + 1;
+        if number % CHART_MAJOR_LINE_EVERY == 0 || col == columns.start {
+            page.text_centered(FONT_REGULAR, 6.0, cell_x(col) + CHART_CELL_SIZE / 2.0, grid_top - CHART_LABELS_SPACE / 2.0, &number.to_string());
+        }
+    }
+    for row in rows.clone() {
+        let number = row + 1;
+        if number % CHART_MAJOR_LINE_EVERY == 0 || row == rows.start {
+            page.text_right_aligned(FONT_REGULAR, 6.0, grid_x - 3.0, cell_top(row) + 3.0, &number.to_string());
+        }
+    }
+
+    page
+}
+
+/// Renders a printable, multipage PDF chart of the diamond painting.
+///
+/// The document consists of:
+/// 1. Cover page with summary and a preview of the dithered image.
+/// 2. Color legend - symbols, DMC codes, names and drills counts.
+/// 3. Symbol grid split into sheets, each with global drill coordinates
+///    and a map of the sheet position in the whole chart.
+///
+/// # Arguments
+///
+/// * `dithered_image` – Image in which every drilled pixel is a color of some DMC from `dmc_bom`.
+/// * `drill_mask` – Cells without drill, they are left blank.
+/// * `dmc_bom` – BOM of the `dithered_image`.
+///
+/// # Returns
+///
+/// Bytes of the PDF document or an error if the image does not match the BOM.
+pub fn render_pdf_chart(dithered_image: &image::RgbImage, drill_mask: Option<&DrillMask>, dmc_bom: &DmcBom) -> Result<Vec<u8>, PdfChartError> {
+    let (width, height) = dithered_image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(PdfChartError::ImageEmpty);
+    }
+
+    let legend = create_legend(dmc_bom);
+    let legend_idx_by_color = legend.iter()
+        .enumerate()
+        .map(|(idx, entry)| (srgb_u8_to_rgb_u8(&entry.dmc.color), idx))
+        .collect::<HashMap<_, _>>();
+
+    let mut symbols_grid = vec![vec![None; width as usize]; height as usize];
+    for (x, y, color) in dithered_image.enumerate_pixels() {
+        if !is_drilled(drill_mask, x, y) {
+            continue;
+        }
+        let legend_idx = legend_idx_by_color.get(color)
+            .ok_or(PdfChartError::ColorNotInBom { x, y })?;
+        symbols_grid[y as usize][x as usize] = Some(*legend_idx);
+    }
+
+    let sheets = (width.div_ceil(CHART_COLUMNS_PER_PAGE), height.div_ceil(CHART_ROWS_PER_PAGE));
+
+    let mut pages = vec![render_cover_page(dithered_image, &legend, sheets)];
+    pages.extend(render_legend_pages(&legend));
+    for sheet_row in 0..sheets.1 {
+        for sheet_col in 0..sheets.0 {
+            pages.push(render_chart_page(&symbols_grid, &legend, sheets, (sheet_col, sheet_row)));
+        }
+    }
+
+    // Page numbers
+    let pages_count = pages.len();
+    for (page_idx, page) in pages.iter_mut().enumerate() {
+        page.text_centered(FONT_REGULAR, 8.0, PAGE_WIDTH / 2.0, PAGE_HEIGHT - PAGE_MARGIN / 2.0, &format!("Page {} / {}", page_idx + 1, pages_count));
+    }
+
+    write_pdf(pages, dithered_image)
+}
+
+fn write_pdf(pages: Vec<PageCanvas>, preview_image: &image::RgbImage) -> Result<Vec<u8>, PdfChartError> {
+    let mut next_ref = {
+        let mut next_id = 0;
+        move || {
+            next_id += 1;
+            Ref::new(next_id)
+        }
+    };
+
+    let catalog_id = next_ref();
+    let page_tree_id = next_ref();
+    let font_regular_id = next_ref();
+    let font_bold_id = next_ref();
+    let preview_id = next_ref();
+    let pages_ids = pages.iter().map(|_| (next_ref(), next_ref())).collect::<Vec<_>>();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(pages_ids.iter().map(|(page_id, _)| *page_id))
+        .count(pages_ids.len() as i32);
+
+    pdf.type1_font(font_regular_id)
+        .base_font(Name(b"Helvetica"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+    pdf.type1_font(font_bold_id)
+        .base_font(Name(b"Helvetica-Bold"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    let mut preview_jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut preview_jpeg, PREVIEW_JPEG_QUALITY)
+        .encode_image(preview_image)?;
+    let mut preview_writer = pdf.image_xobject(preview_id, &preview_jpeg);
+    preview_writer
+        .width(preview_image.width() as i32)
+        .height(preview_image.height() as i32)
+        .color_space_name(Name(b"DeviceRGB"))
+        .bits_per_component(8);
+    preview_writer.filter(Filter::DctDecode);
+    preview_writer.finish();
+
+    for (page, (page_id, content_id)) in pages.into_iter().zip(pages_ids) {
+        let mut page_writer = pdf.page(page_id);
+        page_writer
+            .parent(page_tree_id)
+            .media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT))
+            .contents(content_id);
+
+        let mut resources = page_writer.resources();
+        resources.fonts()
+            .pair(FONT_REGULAR, font_regular_id)
+            .pair(FONT_BOLD, font_bold_id);
+        if page.uses_preview {
+            resources.x_objects().pair(IMAGE_PREVIEW, preview_id);
+        }
+        resources.finish();
+        page_writer.finish();
+
+        let content = miniz_oxide::deflate::compress_to_vec_zlib(&page.content.finish(), COMPRESSION_LEVEL);
+        pdf.stream(content_id, &content).filter(Filter::FlateDecode);
+    }
+
+    Ok(pdf.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{
+        dmc::PaletteDmc,
+        processing::image_manip::image_dither_using_dmc_palette
+    };
+
+    #[test]
+    fn test_symbols_are_unique() {
+        let symbols = (0..500).map(symbol_for_index).collect::<std::collections::HashSet<_>>();
+        assert_eq!(symbols.len(), 500);
+    }
+
+    #[test]
+    fn test_render_pdf_chart_of_dithered_gradient() {
+        let palette_dmc = PaletteDmc::load_from_file_default().unwrap();
+        let src_image = ditherum::image_utils::generate_gradient_image(
+            90,
+            70,
+            image::Rgb([0, 33, 255]),
+            image::Rgb([255, 55, 0]),
+        );
+
+        let (dithered_image, dmc_bom, _) = image_dither_using_dmc_palette(&palette_dmc, &src_image, None, &Default::default(), None, &ditherum::progress::NoProgress);
+        let pdf = render_pdf_chart(&dithered_image, None, &dmc_bom).unwrap();
+
+        assert!(pdf.starts_with(b"%PDF-"));
+
+        // Cover, legend and 3x2 chart sheets
+        let pages_count = pdf.windows(b"/Type /Page\n".len())
+            .filter(|w| w == b"/Type /Page\n")
+            .count();
+        let expected_legend_pages = 1;
+        assert_eq!(pages_count, 1 + expected_legend_pages + 6);
+    }
+
+    #[test]
+    fn test_render_pdf_chart_color_outside_bom_should_fail() {
+        let dithered_image = image::RgbImage::from_pixel(4, 4, image::Rgb([1, 2, 3]));
+        let result = render_pdf_chart(&dithered_image, None, &DmcBom::new());
+        assert!(matches!(result, Err(PdfChartError::ColorNotInBom { x: 0, y: 0 })));
+    }
+
+    #[test]
+    fn test_render_pdf_chart_without_drills_is_blank() {
+        let dithered_image = image::RgbImage::from_pixel(4, 4, image::Rgb([1, 2, 3]));
+        let drill_mask = DrillMask::from_pixel(4, 4, crate::services::processing::image_manip::NO_DRILL);
+        let pdf = render_pdf_chart(&dithered_image, Some(&drill_mask), &DmcBom::new()).unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    cmp::Reverse,
+    collections::{
+        HashMap,
+        VecDeque
+    }
+};
+
+use super::{
+    ProcessingError,
+    BUSY_RETRY_AFTER,
+    status::{
+        WorkStatus,
+        WorkStatusBoard
+    },
+    worker::{
+        WorkId,
+        WorkKind,
+        WorkPriority,
+        WorkWrapped,
+        Worker
+    }
+};
+
+/// Queued work with the client it is scheduled for.
+#[derive(Debug)]
+pub struct PendingWork {
+    pub work_wrapped: WorkWrapped,
+    pub client: String,
+    pub session: Option<String>,
+    pub priority: WorkPriority,
+}
+
+impl PendingWork {
+    fn session_key(&self) -> SessionKey {
+        (self.client.clone(), self.session.clone())
+    }
+}
+
+/// Session of the client, sessions of the same client take turns.
+type SessionKey = (String, Option<String>);
+
+/// Worker processing the work, kept to free the worker and limits once it finishes.
+#[derive(Debug)]
+struct RunningWork {
+    worker_idx: usize,
+    kind: WorkKind,
+    client: String,
+}
+
+/// Shared queue of works feeding idle workers, owned by the dispatcher task.
+/// Works of the highest priority are taken first, among them works of the client
+/// with the fewest running works, then of the session served the longest ago
+/// and then in order of arrival. Works of a kind at its limit are skipped
+/// until a work of the kind finishes.
+#[derive(Debug)]
+pub struct Scheduler {
+    workers: Vec<Worker>,
+    /// Indexes of workers without work, the longest idle first.
+    idle_workers: VecDeque<usize>,
+    pending: VecDeque<PendingWork>,
+    pending_cap: usize,
+    /// Works of a single client queued at most.
+    client_quota: usize,
+    running: HashMap<WorkId, RunningWork>,
+    running_by_kind: HashMap<WorkKind, usize>,
+    running_by_client: HashMap<String, usize>,
+    /// Dispatch count when a work of the session was assigned last, for sessions with queued works
+    /// or of clients with running works.
+    last_served: HashMap<SessionKey, u64>,
+    dispatched_count: u64,
+    kind_limits: HashMap<WorkKind, usize>,
+    statuses: WorkStatusBoard,
+}
+
+impl Scheduler {
+    pub fn new(
+        workers: Vec<Worker>,
+        pending_cap: usize,
+        client_quota: usize,
+        kind_limits: HashMap<WorkKind, usize>,
+        statuses: WorkStatusBoard
+    ) -> Self {
+        Self {
+            idle_workers: (0..workers.len()).collect(),
+            workers,
+            pending: VecDeque::new(),
+            pending_cap,
+            client_quota,
+            running: HashMap::new(),
+            running_by_kind: HashMap::new(),
+            running_by_client: HashMap::new(),
+            last_served: HashMap::new(),
+            dispatched_count: 0,
+            kind_limits,
+            statuses,
+        }
+    }
+
+    /// Checks another work of the client fits into the queue and the quota of the client,
+    /// cancelled works don't take room.
+    pub fn check_room(&mut self, client: &str) -> Result<(), ProcessingError> {
+        self.drop_cancelled();
+
+        let client_pending_count = self.pending.iter().filter(|pending| pending.client == client).count();
+        if self.pending.len() >= self.pending_cap || client_pending_count >= self.client_quota {
+            return Err(ProcessingError::Busy { retry_after: BUSY_RETRY_AFTER });
+        }
+        Ok(())
+    }
+
+    /// Queues the work, it should fit in, see [`Self::check_room`].
+    pub fn enqueue(&mut self, pending_work: PendingWork) {
+        debug_assert!(self.pending.len() < self.pending_cap, "Queue should have room for work");
+        self.pending.push_back(pending_work);
+    }
+
+    /// Frees the worker of finished work.
+    pub fn finish(&mut self, work_id: WorkId) {
+        let Some(running_work) = self.running.remove(&work_id) else {
+            tracing::warn!("Finished work {work_id} was not running");
+            return;
+        };
+
+        if let Some(running_count) = self.running_by_kind.get_mut(&running_work.kind) {
+            *running_count = running_count.saturating_sub(1);
+        }
+        if let Some(running_count) = self.running_by_client.get_mut(&running_work.client) {
+            *running_count = running_count.saturating_sub(1);
+            if *running_count == 0 {
+                self.running_by_client.remove(&running_work.client);
+            }
+        }
+        self.idle_workers.push_back(running_work.worker_idx);
+    }
+
+    fn has_capacity(&self, kind: WorkKind) -> bool {
+        let running_count = self.running_by_kind.get(&kind).copied().unwrap_or(0);
+        self.kind_limits.get(&kind).is_none_or(|limit| running_count < *limit)
+    }
+
+    /// Cancelled works are never processed, their status is forgotten.
+    fn drop_cancelled(&mut self) {
+        let statuses = &self.statuses;
+        self.pending.retain(|pending| {
+            let is_cancelled = statuses.is_cancelled(pending.work_wrapped.id);
+            if is_cancelled {
+                tracing::info!("Work {} was cancelled before assigning", pending.work_wrapped.id);
+                statuses.remove(pending.work_wrapped.id);
+            }
+            !is_cancelled
+        });
+    }
+
+    /// Position of the work which should be processed next, if any can be.
+    fn next_position(&self) -> Option<usize> {
+        self.pending.iter()
+            .enumerate()
+            .filter(|(_, pending)| self.has_capacity(pending.work_wrapped.work.kind()))
+            .min_by_key(|(position, pending)| (
+                Reverse(pending.priority),
+                self.running_by_client.get(&pending.client).copied().unwrap_or(0),
+                self.last_served.get(&pending.session_key()).copied().unwrap_or(0),
+                *position
+            ))
+            .map(|(position, _)| position)
+    }
+
+    /// Assigns queued works to idle workers while there are both.
+    pub fn dispatch(&mut self) {
+        self.drop_cancelled();
+
+        while let Some(&worker_idx) = self.idle_workers.front() {
+            let Some(position) = self.next_position() else {
+                break;
+            };
+            let pending_work = self.pending.remove(position).expect("position should be within queue");
+            let session_key = pending_work.session_key();
+            let PendingWork { work_wrapped, client, .. } = pending_work;
+            let (work_id, kind) = (work_wrapped.id, work_wrapped.work.kind());
+
+            let worker = &self.workers[worker_idx];
+            // Set before enqueuing, so it can't overwrite status set by the worker
+            if !self.statuses.update(work_id, WorkStatus::Assigned { worker: worker.id }) {
+                tracing::info!("Work {work_id} was cancelled before assigning");
+                self.statuses.remove(work_id);
+                continue;
+            }
+
+            if let Err(e) = worker.try_enque_work(work_wrapped) {
+                panic!("Idle worker should accept work, reason='{e}'");
+            }
+            tracing::info!("Work {work_id} was assigned to worker {worker}");
+
+            self.idle_workers.pop_front();
+            *self.running_by_kind.entry(kind).or_default() += 1;
+            *self.running_by_client.entry(client.clone()).or_default() += 1;
+            self.dispatched_count += 1;
+            self.last_served.insert(session_key, self.dispatched_count);
+            self.running.insert(work_id, RunningWork { worker_idx, kind, client });
+        }
+
+        // Clients without works start over
+        let (pending, running_by_client) = (&self.pending, &self.running_by_client);
+        self.last_served.retain(|(client, session), _| {
+            running_by_client.contains_key(client) 
+                || pending.iter().any(|pending| &pending.client == client && &pending.session == session)
+        });
+    }
+
+    /// Shuts down workers, queued works are dropped.
+    pub async fn shutdown(self) {
+        for worker in self.workers {
+            worker.shutdown().await;
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard
+    },
+    time::Instant
+};
+
+use ditherum::progress::Progress;
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use super::worker::WorkId;
+
+/// Lifecycle of a work from enqueuing until it ends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WorkStatus {
+    /// Waiting in the dispatcher queue for a worker.
+    Queued,
+    /// Waiting in the queue of the worker.
+    Assigned {
+        worker: u32,
+    },
+    /// Being processed, `progress` in percent.
+    Running {
+        worker: u32,
+        progress: u8,
+    },
+    Done,
+    Failed {
+        reason: String,
+    },
+    /// Dropped before it was done, its result will never come.
+    Cancelled,
+    /// Finished, but its result was not collected in time and is forgotten.
+    Expired,
+}
+
+impl WorkStatus {
+    /// Work reached its end, successful or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Done | Self::Failed { .. } | Self::Cancelled | Self::Expired)
+    }
+}
+
+/// Statuses with the time each work has finished at.
+#[derive(Debug, Default)]
+struct BoardEntries {
+    statuses: HashMap<WorkId, WorkStatus>,
+    finished_at: HashMap<WorkId, Instant>,
+}
+
+impl BoardEntries {
+    fn insert(&mut self, id: WorkId, status: WorkStatus) -> Option<WorkStatus> {
+        if status.is_finished() {
+            self.finished_at.entry(id).or_insert_with(Instant::now);
+        } else {
+            self.finished_at.remove(&id);
+        }
+        self.statuses.insert(id, status)
+    }
+
+    fn remove(&mut self, id: WorkId) -> Option<WorkStatus> {
+        self.finished_at.remove(&id);
+        self.statuses.remove(&id)
+    }
+}
+
+/// Statuses of works shared by the dispatcher and workers. Workers update it
+/// from blocking threads, so it is guarded by a blocking mutex held only shortly.
+/// Every change bumps the version watched by subscribers.
+#[derive(Debug, Clone)]
+pub struct WorkStatusBoard {
+    entries: Arc<Mutex<BoardEntries>>,
+    version_tx: Arc<tokio::sync::watch::Sender<u64>>,
+}
+
+impl Default for WorkStatusBoard {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            version_tx: Arc::new(tokio::sync::watch::Sender::new(0)),
+        }
+    }
+}
+
+impl WorkStatusBoard {
+    fn lock(&self) -> MutexGuard<'_, BoardEntries> {
+        self.entries.lock().expect("status board is not poisoned")
+    }
+
+    pub fn set(&self, id: WorkId, status: WorkStatus) {
+        let previous = self.lock().insert(id, status.clone());
+        if previous.as_ref() != Some(&status) {
+            self.notify_changed();
+        }
+    }
+
+    /// Sets status unless the work was cancelled, cancelled status is final.
+    /// Returns `false` for cancelled work.
+    pub fn update(&self, id: WorkId, status: WorkStatus) -> bool {
+        let changed = {
+            let mut entries = self.lock();
+            match entries.statuses.get(&id) {
+                Some(WorkStatus::Cancelled) => return false,
+                Some(current) if *current == status => false,
+                _ => {
+                    entries.insert(id, status);
+                    true
+                },
+            }
+        };
+
+        if changed {
+            self.notify_changed();
+        }
+        true
+    }
+
+    /// Marks known unfinished work as cancelled. Returns `false` if there is nothing to cancel.
+    pub fn cancel(&self, id: WorkId) -> bool {
+        {
+            let mut entries = self.lock();
+            match entries.statuses.get(&id) {
+                Some(status) if !status.is_finished() => entries.insert(id, WorkStatus::Cancelled),
+                _ => return false,
+            };
+        }
+
+        self.notify_changed();
+        true
+    }
+
+    pub fn is_cancelled(&self, id: WorkId) -> bool {
+        matches!(self.get(id), Some(WorkStatus::Cancelled))
+    }
+
+    pub fn get(&self, id: WorkId) -> Option<WorkStatus> {
+        self.lock().statuses.get(&id).cloned()
+    }
+
+    pub fn remove(&self, id: WorkId) -> Option<WorkStatus> {
+        let removed = self.lock().remove(id);
+        if removed.is_some() {
+            self.notify_changed();
+        }
+        removed
+    }
+
+    /// Forgets works which finished before `deadline` and returns them, e.g. results nobody collected.
+    pub fn remove_finished_before(&self, deadline: Instant) -> Vec<WorkId> {
+        let removed = {
+            let mut entries = self.lock();
+            let removed = entries.finished_at.iter()
+                .filter(|(_, finished_at)| **finished_at < deadline)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            for id in &removed {
+                entries.remove(*id);
+            }
+            removed
+        };
+
+        if !removed.is_empty() {
+            self.notify_changed();
+        }
+        removed
+    }
+
+    /// Receiver marked as changed whenever any status changes, e.g. to push updates to clients.
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.version_tx.subscribe()
+    }
+
+    fn notify_changed(&self) {
+        // Modifying never fails, even with no subscribers
+        self.version_tx.send_modify(|version| *version = version.wrapping_add(1));
+    }
+
+    /// Raises progress of running work, progress never goes back. Finished or unknown work is left as is.
+    pub fn set_progress(&self, id: WorkId, worker: u32, progress: u8) {
+        let changed = {
+            let mut entries = self.lock();
+            let Some(status) = entries.statuses.get_mut(&id) else {
+                return;
+            };
+
+            let progress = progress.min(100);
+            match status {
+                WorkStatus::Running { progress: current, .. } if *current < progress => {
+                    *current = progress;
+                    true
+                },
+                WorkStatus::Queued | WorkStatus::Assigned { .. } => {
+                    *status = WorkStatus::Running { worker, progress };
+                    true
+                },
+                WorkStatus::Running { .. } | WorkStatus::Done | WorkStatus::Failed { .. } | WorkStatus::Cancelled | WorkStatus::Expired => false,
+            }
+        };
+
+        if changed {
+            self.notify_changed();
+        }
+    }
+}
+
+/// Progress of a single work, it is reported to the board in whole percents.
+pub struct WorkProgress {
+    pub id: WorkId,
+    pub worker: u32,
+    pub board: WorkStatusBoard,
+}
+
+impl Progress for WorkProgress {
+    fn report(&self, fraction: f32) {
+        let percent = (fraction.clamp(0.0, 1.0) * 100.0).floor() as u8;
+        self.board.set_progress(self.id, self.worker, percent);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.board.is_cancelled(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_only_grows_while_running() {
+        let board = WorkStatusBoard::default();
+        board.set(1, WorkStatus::Assigned { worker: 3 });
+
+        let progress = WorkProgress { id: 1, worker: 3, board: board.clone() };
+        progress.report(0.425);
+        assert_eq!(board.get(1), Some(WorkStatus::Running { worker: 3, progress: 42 }));
+
+        progress.report(0.1);
+        assert_eq!(board.get(1), Some(WorkStatus::Running { worker: 3, progress: 42 }), "Progress does not go back");
+
+        board.set(1, WorkStatus::Done);
+        progress.report(0.9);
+        assert_eq!(board.get(1), Some(WorkStatus::Done), "Finished work is left as is");
+        assert!(board.remove(1).unwrap().is_finished());
+        assert_eq!(board.get(1), None);
+    }
+
+    #[test]
+    fn test_subscribers_are_notified_only_about_changes() {
+        let board = WorkStatusBoard::default();
+        let mut changes = board.subscribe();
+
+        board.set(1, WorkStatus::Queued);
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        board.set(1, WorkStatus::Queued);
+        assert!(!changes.has_changed().unwrap(), "Same status is no change");
+
+        board.set_progress(1, 0, 10);
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        board.set_progress(1, 0, 10);
+        board.set_progress(1, 0, 5);
+        board.remove(2);
+        assert!(!changes.has_changed().unwrap(), "Progress not raised and unknown work is no change");
+
+        board.remove(1);
+        assert!(changes.has_changed().unwrap());
+    }
+
+    #[test]
+    fn test_cancelled_status_is_final() {
+        let board = WorkStatusBoard::default();
+        assert!(!board.cancel(1), "Unknown work can't be cancelled");
+
+        assert!(board.update(1, WorkStatus::Queued));
+        assert!(board.cancel(1));
+        assert!(board.is_cancelled(1));
+        assert!(!board.cancel(1), "Cancelled work is finished already");
+
+        assert!(!board.update(1, WorkStatus::Running { worker: 0, progress: 0 }));
+        assert!(!board.update(1, WorkStatus::Done));
+        assert_eq!(board.get(1), Some(WorkStatus::Cancelled));
+
+        board.set(2, WorkStatus::Done);
+        assert!(!board.cancel(2), "Done work can't be cancelled");
+    }
+
+    #[test]
+    fn test_only_works_finished_before_deadline_are_removed() {
+        let board = WorkStatusBoard::default();
+        board.set(1, WorkStatus::Done);
+        board.set(2, WorkStatus::Running { worker: 0, progress: 10 });
+        board.set(3, WorkStatus::Queued);
+        assert!(board.cancel(3));
+        let deadline = Instant::now();
+        board.set(4, WorkStatus::Failed { reason: "boom".to_string() });
+
+        let mut removed = board.remove_finished_before(deadline);
+        removed.sort();
+        assert_eq!(removed, vec![1, 3]);
+        assert_eq!(board.get(1), None);
+        assert!(board.get(2).is_some(), "Unfinished work is kept");
+        assert!(board.get(4).is_some(), "Work finished after deadline is kept");
+
+        board.set(2, WorkStatus::Done);
+        assert_eq!(board.remove_finished_before(deadline), Vec::<WorkId>::new(), "Finish time is when the work finished");
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let status = serde_json::to_value(WorkStatus::Running { worker: 1, progress: 50 }).unwrap();
+        assert_eq!(status, serde_json::json!({ "state": "running", "worker": 1, "progress": 50 }));
+
+        let status = serde_json::to_value(WorkStatus::Failed { reason: "boom".to_string() }).unwrap();
+        assert_eq!(status, serde_json::json!({ "state": "failed", "reason": "boom" }));
+    }
+}
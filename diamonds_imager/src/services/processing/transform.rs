@@ -0,0 +1,507 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use super::image_manip::{
+    map_drill_mask,
+    DrillMask
+};
+
+/// Color of areas uncovered by arbitrary rotation.
+const ROTATION_FILL_COLOR: image::Rgb<u8> = image::Rgb([255, 255, 255]);
+
+/// Tolerance of recognising rotation by a multiple of 90° as exact one.
+const ROTATION_RIGHT_ANGLE_EPSILON: f32 = 1e-3;
+
+pub const BRIGHTNESS_RANGE: std::ops::RangeInclusive<f32> = -1.0..=1.0;
+pub const CONTRAST_RANGE: std::ops::RangeInclusive<f32> = -1.0..=1.0;
+pub const SATURATION_RANGE: std::ops::RangeInclusive<f32> = -1.0..=1.0;
+pub const GAMMA_RANGE: std::ops::RangeInclusive<f32> = 0.1..=10.0;
+
+/// Operations of a single stack at most.
+pub const TRANSFORM_STACK_MAX_LEN: usize = 32;
+
+/// Largest image a transform stack may produce at any of its steps, e.g. the limits of uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransformError {
+    #[error("Crop {width}x{height} at ({x}, {y}) is out of image {image_width}x{image_height}")]
+    CropOutOfBounds {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        image_width: u32,
+        image_height: u32,
+    },
+
+    #[error("Invalid value of '{op}'={value}, expected range {range}")]
+    InvalidValue {
+        op: &'static str,
+        value: f32,
+        range: String,
+    },
+
+    #[error("Transform index {index} out of stack of {len}")]
+    IndexOutOfRange {
+        index: usize,
+        len: usize,
+    },
+
+    #[error("Order is not a permutation of {len} transforms")]
+    InvalidOrder {
+        len: usize,
+    },
+
+    #[error("Transformed image {width}x{height} is too large")]
+    ImageTooLarge {
+        width: u64,
+        height: u64,
+    },
+
+    #[error("Stack of {len} transforms is longer than {max}")]
+    StackTooLong {
+        len: usize,
+        max: usize,
+    },
+}
+
+/// Single operation of a transform stack. Tone adjustments are done on sRGB
+/// values in range `0.0..=1.0`, zero adjustment keeps the image unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TransformOp {
+    /// Keeps only the rectangle, in pixels of the image at this point of the stack.
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Clockwise rotation. Multiples of 90° are lossless, other angles enlarge
+    /// the image to fit the rotated one and fill corners with white.
+    Rotate {
+        degrees: f32,
+    },
+    FlipHorizontal,
+    FlipVertical,
+    /// Added to every channel, in range `-1.0..=1.0`.
+    Brightness {
+        value: f32,
+    },
+    /// Spreads channels from the middle grey, in range `-1.0..=1.0`, `-1.0` gives plain grey.
+    Contrast {
+        value: f32,
+    },
+    /// Spreads channels from luma, in range `-1.0..=1.0`, `-1.0` gives grayscale.
+    Saturation {
+        value: f32,
+    },
+    /// Gamma correction in range `0.1..=10.0`, greater values brighten mid-tones.
+    Gamma {
+        value: f32,
+    },
+}
+
+fn check_range(op: &'static str, value: f32, range: std::ops::RangeInclusive<f32>) -> Result<(), TransformError> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(TransformError::InvalidValue { op, value, range: format!("{range:?}") })
+    }
+}
+
+/// Rotation normalised to `0.0..360.0` and count of clockwise right angles, if it is their multiple.
+fn normalize_rotation(degrees: f32) -> (f32, Option<u32>) {
+    let degrees = degrees.rem_euclid(360.0);
+    let right_angles = (degrees / 90.0).round();
+    if (degrees - right_angles * 90.0).abs() < ROTATION_RIGHT_ANGLE_EPSILON {
+        (degrees, Some(right_angles as u32 % 4))
+    } else {
+        (degrees, None)
+    }
+}
+
+/// Size of the image bounding arbitrary rotated one, fails if it doesn't fit `u32`.
+fn rotated_size(width: u32, height: u32, degrees: f32) -> Result<(u32, u32), TransformError> {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (width as f32, height as f32);
+    let rotated_width = (width * cos.abs() + height * sin.abs()).round().max(1.0);
+    let rotated_height = (width * sin.abs() + height * cos.abs()).round().max(1.0);
+    if rotated_width >= u32::MAX as f32 || rotated_height >= u32::MAX as f32 {
+        return Err(TransformError::ImageTooLarge { width: rotated_width as u64, height: rotated_height as u64 });
+    }
+    Ok((rotated_width as u32, rotated_height as u32))
+}
+
+/// Rotates into image of `size`, the one given by [`rotated_size`].
+fn rotate_arbitrary(src_img: &image::RgbImage, degrees: f32, (width, height): (u32, u32)) -> image::RgbImage {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (src_cx, src_cy) = (src_img.width() as f32 / 2.0, src_img.height() as f32 / 2.0);
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+
+    image::RgbImage::from_fn(width, height, |x, y| {
+        // Inverse rotation of the pixel center into the source image
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        let src_x = dx * cos + dy * sin + src_cx - 0.5;
+        let src_y = -dx * sin + dy * cos + src_cy - 0.5;
+        sample_bilinear(src_img, src_x, src_y)
+    })
+}
+
+fn sample_bilinear(img: &image::RgbImage, x: f32, y: f32) -> image::Rgb<u8> {
+    let (max_x, max_y) = (img.width() as f32 - 1.0, img.height() as f32 - 1.0);
+    if x < -0.5 || y < -0.5 || x > max_x + 0.5 || y > max_y + 0.5 {
+        return ROTATION_FILL_COLOR;
+    }
+
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let [tl, tr, bl, br] = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].map(|(x, y)| img.get_pixel(x, y).0);
+    image::Rgb(std::array::from_fn(|c| {
+        let top = tl[c] as f32 * (1.0 - fx) + tr[c] as f32 * fx;
+        let bottom = bl[c] as f32 * (1.0 - fx) + br[c] as f32 * fx;
+        (top * (1.0 - fy) + bottom * fy).round() as u8
+    }))
+}
+
+/// Applies tone adjustment to every pixel, `adjust` gets channels in range `0.0..=1.0`.
+fn adjust_tone<F>(img: &mut image::RgbImage, adjust: F)
+where
+    F: Fn([f32; 3]) -> [f32; 3]
+{
+    img.pixels_mut().for_each(|pixel| {
+        let adjusted = adjust(pixel.0.map(|c| c as f32 / 255.0));
+        pixel.0 = adjusted.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    });
+}
+
+impl TransformOp {
+    /// Checks the operation against the image size at its point of the stack
+    /// and returns the size of the image it produces.
+    pub fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32), TransformError> {
+        match *self {
+            TransformOp::Crop { x, y, width: crop_width, height: crop_height } => {
+                let fits = crop_width > 0 && crop_height > 0
+                    && x.checked_add(crop_width).is_some_and(|right| right <= width)
+                    && y.checked_add(crop_height).is_some_and(|bottom| bottom <= height);
+                if !fits {
+                    return Err(TransformError::CropOutOfBounds {
+                        x, y, width: crop_width, height: crop_height, image_width: width, image_height: height
+                    });
+                }
+                Ok((crop_width, crop_height))
+            },
+            TransformOp::Rotate { degrees } => {
+                if !degrees.is_finite() {
+                    return Err(TransformError::InvalidValue { op: "rotate", value: degrees, range: "finite".to_string() });
+                }
+                match normalize_rotation(degrees) {
+                    (_, Some(right_angles)) if right_angles % 2 == 1 => Ok((height, width)),
+                    (_, Some(_)) => Ok((width, height)),
+                    (degrees, None) => rotated_size(width, height, degrees),
+                }
+            },
+            TransformOp::FlipHorizontal | TransformOp::FlipVertical => Ok((width, height)),
+            TransformOp::Brightness { value } => check_range("brightness", value, BRIGHTNESS_RANGE).map(|_| (width, height)),
+            TransformOp::Contrast { value } => check_range("contrast", value, CONTRAST_RANGE).map(|_| (width, height)),
+            TransformOp::Saturation { value } => check_range("saturation", value, SATURATION_RANGE).map(|_| (width, height)),
+            TransformOp::Gamma { value } => check_range("gamma", value, GAMMA_RANGE).map(|_| (width, height)),
+        }
+    }
+
+    /// Whether the operation moves pixels rather than changing their colors.
+    pub fn is_geometric(&self) -> bool {
+        matches!(self, TransformOp::Crop { .. } | TransformOp::Rotate { .. } | TransformOp::FlipHorizontal | TransformOp::FlipVertical)
+    }
+
+    pub fn apply(&self, mut img: image::RgbImage) -> Result<image::RgbImage, TransformError> {
+        let output_size = self.output_size(img.width(), img.height())?;
+
+        let img = match *self {
+            TransformOp::Crop { x, y, width, height } => image::imageops::crop_imm(&img, x, y, width, height).to_image(),
+            TransformOp::Rotate { degrees } => match normalize_rotation(degrees) {
+                (_, Some(0)) => img,
+                (_, Some(1)) => image::imageops::rotate90(&img),
+                (_, Some(2)) => image::imageops::rotate180(&img),
+                (_, Some(_)) => image::imageops::rotate270(&img),
+                (degrees, None) => rotate_arbitrary(&img, degrees, output_size),
+            },
+            TransformOp::FlipHorizontal => image::imageops::flip_horizontal(&img),
+            TransformOp::FlipVertical => image::imageops::flip_vertical(&img),
+            TransformOp::Brightness { value } => {
+                adjust_tone(&mut img, |rgb| rgb.map(|c| c + value));
+                img
+            },
+            TransformOp::Contrast { value } => {
+                adjust_tone(&mut img, |rgb| rgb.map(|c| (c - 0.5) * (1.0 + value) + 0.5));
+                img
+            },
+            TransformOp::Saturation { value } => {
+                adjust_tone(&mut img, |rgb| {
+                    let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+                    rgb.map(|c| luma + (c - luma) * (1.0 + value))
+                });
+                img
+            },
+            TransformOp::Gamma { value } => {
+                adjust_tone(&mut img, |rgb| rgb.map(|c| c.powf(1.0 / value)));
+                img
+            },
+        };
+
+        Ok(img)
+    }
+}
+
+/// Ordered operations applied to the original image before any processing.
+/// The original image is never modified, so the stack can be edited freely.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TransformStack {
+    ops: Vec<TransformOp>,
+}
+
+impl TransformStack {
+    pub fn new(ops: Vec<TransformOp>) -> Self {
+        Self { ops }
+    }
+
+    pub fn ops(&self) -> &[TransformOp] {
+        &self.ops
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn push(&mut self, op: TransformOp) {
+        self.ops.push(op);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<TransformOp, TransformError> {
+        if index >= self.ops.len() {
+            return Err(TransformError::IndexOutOfRange { index, len: self.ops.len() });
+        }
+        Ok(self.ops.remove(index))
+    }
+
+    /// Reorders operations, `order[i]` is the current index of the operation placed at `i`.
+    pub fn reorder(&mut self, order: &[usize]) -> Result<(), TransformError> {
+        let len = self.ops.len();
+        let mut used = vec![false; len];
+        for index in order {
+            match used.get_mut(*index) {
+                Some(used) if !*used => *used = true,
+                _ => return Err(TransformError::InvalidOrder { len }),
+            }
+        }
+        if order.len() != len {
+            return Err(TransformError::InvalidOrder { len });
+        }
+
+        self.ops = order.iter().map(|index| self.ops[*index]).collect();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// Checks every operation and returns size of the transformed image.
+    pub fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32), TransformError> {
+        self.ops.iter()
+            .try_fold((width, height), |(width, height), op| op.output_size(width, height))
+    }
+
+    /// Checks every operation like [`Self::output_size`], the stack length and size of the image
+    /// after every operation against `limits`, so applying the stack never makes a huge image.
+    pub fn output_size_within(&self, width: u32, height: u32, limits: TransformLimits) -> Result<(u32, u32), TransformError> {
+        if self.ops.len() > TRANSFORM_STACK_MAX_LEN {
+            return Err(TransformError::StackTooLong { len: self.ops.len(), max: TRANSFORM_STACK_MAX_LEN });
+        }
+
+        self.ops.iter()
+            .try_fold((width, height), |(width, height), op| {
+                let (width, height) = op.output_size(width, height)?;
+                let fits = width <= limits.max_width 
+                    && height <= limits.max_height 
+                    && width as u64 * height as u64 <= limits.max_pixels;
+                if !fits {
+                    return Err(TransformError::ImageTooLarge { width: width as u64, height: height as u64 });
+                }
+                Ok((width, height))
+            })
+    }
+
+    pub fn apply(&self, src_img: &image::RgbImage) -> Result<image::RgbImage, TransformError> {
+        self.ops.iter()
+            .try_fold(src_img.clone(), |img, op| op.apply(img))
+    }
+
+    /// Applies crops, rotations and flips to the drill mask of the image, tone adjustments
+    /// do not move cells. Corners uncovered by arbitrary rotation get drills like the image.
+    pub fn apply_to_drill_mask(&self, drill_mask: &DrillMask) -> Result<DrillMask, TransformError> {
+        map_drill_mask(drill_mask, |img| {
+            self.ops.iter()
+                .filter(|op| op.is_geometric())
+                .try_fold(img, |img, op| op.apply(img))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: image::Rgb<u8> = image::Rgb([255, 0, 0]);
+    const BLUE: image::Rgb<u8> = image::Rgb([0, 0, 255]);
+
+    /// Blue image with red top-left corner pixel.
+    fn marked_image(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| if x == 0 && y == 0 { RED } else { BLUE })
+    }
+
+    #[test]
+    fn test_crop_rotate_flip() {
+        let img = marked_image(6, 4);
+
+        let cropped = TransformOp::Crop { x: 0, y: 0, width: 3, height: 2 }.apply(img.clone()).unwrap();
+        assert_eq!(cropped.dimensions(), (3, 2));
+        assert_eq!(*cropped.get_pixel(0, 0), RED);
+
+        let rotated = TransformOp::Rotate { degrees: 90.0 }.apply(img.clone()).unwrap();
+        assert_eq!(rotated.dimensions(), (4, 6));
+        assert_eq!(*rotated.get_pixel(3, 0), RED, "Clockwise rotation moves top-left to top-right");
+
+        let rotated = TransformOp::Rotate { degrees: -90.0 }.apply(img.clone()).unwrap();
+        assert_eq!(*rotated.get_pixel(0, 5), RED);
+
+        let flipped = TransformOp::FlipHorizontal.apply(img.clone()).unwrap();
+        assert_eq!(*flipped.get_pixel(5, 0), RED);
+        let flipped = TransformOp::FlipVertical.apply(img).unwrap();
+        assert_eq!(*flipped.get_pixel(0, 3), RED);
+    }
+
+    #[test]
+    fn test_arbitrary_rotation_enlarges_image() {
+        let img = image::RgbImage::from_pixel(20, 20, BLUE);
+        let op = TransformOp::Rotate { degrees: 45.0 };
+        assert_eq!(op.output_size(20, 20).unwrap(), (28, 28));
+
+        let rotated = op.apply(img).unwrap();
+        assert_eq!(rotated.dimensions(), (28, 28));
+        assert_eq!(*rotated.get_pixel(0, 0), ROTATION_FILL_COLOR);
+        assert_eq!(*rotated.get_pixel(14, 14), BLUE);
+    }
+
+    #[test]
+    fn test_stack_output_is_capped() {
+        let limits = TransformLimits { max_width: 5000, max_height: 5000, max_pixels: 25_000_000 };
+
+        // Every arbitrary rotation enlarges the image
+        let rotations = TransformStack::new(vec![TransformOp::Rotate { degrees: 45.0 }; 10]);
+        assert!(rotations.output_size(5000, 5000).is_ok(), "Stack alone is valid");
+        assert!(matches!(rotations.output_size_within(5000, 5000, limits), Err(TransformError::ImageTooLarge { .. })));
+        assert!(matches!(rotations.output_size_within(300, 300, limits), Err(TransformError::ImageTooLarge { .. })));
+
+        // Image within width and height, but over pixels
+        let limits = TransformLimits { max_pixels: 4000 * 4000, ..limits };
+        let rotation = TransformStack::new(vec![TransformOp::Rotate { degrees: 45.0 }]);
+        assert!(matches!(rotation.output_size_within(3000, 3000, limits), Err(TransformError::ImageTooLarge { .. })));
+        assert_eq!(rotation.output_size_within(2000, 2000, limits).unwrap(), (2828, 2828));
+
+        let flips = TransformStack::new(vec![TransformOp::FlipVertical; TRANSFORM_STACK_MAX_LEN + 1]);
+        assert!(matches!(flips.output_size_within(10, 10, limits), Err(TransformError::StackTooLong { .. })));
+
+        assert!(matches!(TransformOp::Rotate { degrees: 45.0 }.output_size(u32::MAX, u32::MAX), Err(TransformError::ImageTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_tone_adjustments() {
+        let img = image::RgbImage::from_pixel(2, 2, image::Rgb([200, 100, 50]));
+        let neutral = [
+            TransformOp::Brightness { value: 0.0 },
+            TransformOp::Contrast { value: 0.0 },
+            TransformOp::Saturation { value: 0.0 },
+            TransformOp::Gamma { value: 1.0 }
+        ];
+        for op in neutral {
+            assert_eq!(op.apply(img.clone()).unwrap(), img, "{op:?}");
+        }
+
+        let brighter = TransformOp::Brightness { value: 0.2 }.apply(img.clone()).unwrap();
+        assert!(brighter.get_pixel(0, 0).0.iter().zip(img.get_pixel(0, 0).0).all(|(a, b)| *a > b));
+
+        let gray = TransformOp::Saturation { value: -1.0 }.apply(img.clone()).unwrap();
+        let [r, g, b] = gray.get_pixel(0, 0).0;
+        assert!(r == g && g == b);
+
+        let flat = TransformOp::Contrast { value: -1.0 }.apply(img).unwrap();
+        assert_eq!(*flat.get_pixel(1, 1), image::Rgb([128, 128, 128]));
+
+        assert!(matches!(TransformOp::Gamma { value: 0.0 }.output_size(2, 2), Err(TransformError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn test_stack_validation_and_editing() {
+        let mut stack = TransformStack::default();
+        stack.push(TransformOp::Rotate { degrees: 90.0 });
+        stack.push(TransformOp::Crop { x: 0, y: 0, width: 4, height: 6 });
+        assert_eq!(stack.output_size(6, 4).unwrap(), (4, 6));
+
+        // Crop would not fit before rotation
+        stack.reorder(&[1, 0]).unwrap();
+        assert!(matches!(stack.output_size(6, 4), Err(TransformError::CropOutOfBounds { .. })));
+
+        assert!(matches!(stack.reorder(&[0, 0]), Err(TransformError::InvalidOrder { len: 2 })));
+        assert!(matches!(stack.reorder(&[0]), Err(TransformError::InvalidOrder { len: 2 })));
+
+        assert_eq!(stack.remove(0).unwrap(), TransformOp::Crop { x: 0, y: 0, width: 4, height: 6 });
+        assert!(matches!(stack.remove(3), Err(TransformError::IndexOutOfRange { index: 3, len: 1 })));
+
+        let img = marked_image(6, 4);
+        assert_eq!(stack.apply(&img).unwrap().dimensions(), (4, 6));
+        stack.clear();
+        assert_eq!(stack.apply(&img).unwrap(), img);
+    }
+
+    #[test]
+    fn test_drill_mask_follows_geometry() {
+        use super::super::image_manip::{is_drilled, NO_DRILL, DRILL};
+
+        // Top-left cell without drill
+        let drill_mask = DrillMask::from_fn(6, 4, |x, y| if x == 0 && y == 0 { NO_DRILL } else { DRILL });
+        let stack = TransformStack::new(vec![
+            TransformOp::Brightness { value: -1.0 },
+            TransformOp::Rotate { degrees: 90.0 },
+            TransformOp::FlipVertical,
+        ]);
+
+        let transformed = stack.apply_to_drill_mask(&drill_mask).unwrap();
+        assert_eq!(transformed.dimensions(), (4, 6));
+        assert!(!is_drilled(Some(&transformed), 3, 5), "Tone adjustment leaves the mask alone");
+        assert_eq!(transformed.pixels().filter(|pixel| **pixel == NO_DRILL).count(), 1);
+    }
+
+    #[test]
+    fn test_ops_serialization() {
+        let ops: Vec<TransformOp> = serde_json::from_str(r#"[
+            {"op": "crop", "x": 1, "y": 2, "width": 3, "height": 4},
+            {"op": "flip_horizontal"},
+            {"op": "gamma", "value": 2.2}
+        ]"#).unwrap();
+        assert_eq!(ops[1], TransformOp::FlipHorizontal);
+        assert_eq!(ops[2], TransformOp::Gamma { value: 2.2 });
+    }
+}
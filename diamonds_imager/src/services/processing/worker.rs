@@ -3,16 +3,55 @@ use std::{
     sync::Arc
 };
 
+use ditherum::{
+    algorithms::{
+        confetti::{
+            ConfettiOptions, 
+            ConfettiReport
+        }, 
+        dithering::DitheringOptions, 
+        kmean::KMeansOptions
+    }, 
+    palette_utils::ColorDistance, 
+    progress::{
+        Progress, 
+        ProgressRange
+    }
+};
+
 use crate::services::{
+    canvas::CanvasSpec, 
     dmc::{
         DmcBom, 
         PaletteDmc
     }, 
-    processing::image_manip::image_dither_using_dmc_palette
+    processing::{
+        image_manip::{
+            encode_png, 
+            extract_dmc_palette, 
+            image_dither_using_dmc_palette, 
+            render_drills_preview, 
+            DrillMask, 
+            PaletteExtractMode
+        }, 
+        pdf_chart::render_pdf_chart, 
+        status::{
+            WorkProgress, 
+            WorkStatus, 
+            WorkStatusBoard
+        }
+    }
 };
 
 const WORKER_INPUT_QUEUE_CAP: usize = 8;
 
+/// Reason of the work which was cancelled, its result is never collected.
+pub const WORK_CANCELLED_REASON: &str = "Cancelled";
+
+/// Steps of sleeping in test work, cancellation is checked between them.
+#[cfg(test)]
+const TEST_WORK_STEPS: u32 = 10;
+
 /// Identifier for a piece of work. A dispatcher of work 
 /// should make them unique to easy distinguish of workers.
 pub type WorkId = u64;
@@ -24,43 +63,145 @@ pub enum Work {
     /// - `palette_dmc`: reference DMC palette
     /// - `src_image`: source image to sample
     /// - `max_colors`: optional cap on number of colors to extract
+    /// - `mode`: voting of pixels or clustering method
+    /// - `color_distance`: how pixel colors are matched with DMC colors
+    /// - `kmeans`: seed and restarts of k-means clustering
     PaletteExtract {
         palette_dmc: Arc<PaletteDmc>, 
         src_image: Arc<image::RgbImage>,
-        max_colors: Option<usize>
+        max_colors: Option<usize>,
+        mode: PaletteExtractMode,
+        color_distance: ColorDistance,
+        kmeans: KMeansOptions,
     },
 
-    /// Apply Floyd–Steinberg dithering using the given DMC palette.
+    /// Apply error diffusion or ordered dithering using the given DMC palette
+    /// and render PNG preview of the result.
     ///
     /// - `palette_dmc`: reference DMC palette
     /// - `src_image`: source image to dither
+    /// - `drill_mask`: optional cells of `src_image` without drill
+    /// - `drill_size`: optional size in pixels of a drill rendered in preview
+    /// - `dithering`: method, strength and color distance of dithering
+    /// - `confetti`: optional cleanup of isolated drills after dithering
+    /// - `canvas`: optional physical canvas the image is resampled to before dithering
     ImageDither {
         palette_dmc: Arc<PaletteDmc>, 
         src_image: Arc<image::RgbImage>,
+        drill_mask: Option<Arc<DrillMask>>,
+        drill_size: Option<u32>,
+        dithering: DitheringOptions,
+        confetti: Option<ConfettiOptions>,
+        canvas: Option<CanvasSpec>,
     },
 
-    /// A dummy test workload that sleeps for a duration.
+    /// Dither image using the given DMC palette and render printable PDF chart.
+    ///
+    /// - `palette_dmc`: reference DMC palette
+    /// - `src_image`: source image to dither
+    /// - `drill_mask`: optional cells of `src_image` without drill
+    /// - `dithering`: method, strength and color distance of dithering
+    /// - `confetti`: optional cleanup of isolated drills after dithering
+    /// - `canvas`: optional physical canvas the image is resampled to before dithering
+    PdfRender {
+        palette_dmc: Arc<PaletteDmc>, 
+        src_image: Arc<image::RgbImage>,
+        drill_mask: Option<Arc<DrillMask>>,
+        dithering: DitheringOptions,
+        confetti: Option<ConfettiOptions>,
+        canvas: Option<CanvasSpec>,
+    },
+
+    /// A dummy test workload that sleeps for a duration, reporting half of progress in the middle.
     /// Only available under `cfg(test)`.
     #[cfg(test)]
     TestWork {
         delay: std::time::Duration
+    },
+
+    /// A dummy test workload that panics with the reason.
+    /// Only available under `cfg(test)`.
+    #[cfg(test)]
+    TestFailure {
+        reason: &'static str
+    },
+}
+
+/// Kind of work, works of a kind processed at once can be limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WorkKind {
+    PaletteExtract,
+    ImageDither,
+    PdfRender,
+    #[cfg(test)]
+    Test,
+}
+
+impl WorkKind {
+    /// Interactive works are waited for by the user, final ones are ordered once.
+    pub fn priority(self) -> WorkPriority {
+        match self {
+            WorkKind::PaletteExtract | WorkKind::ImageDither => WorkPriority::Interactive,
+            WorkKind::PdfRender => WorkPriority::Final,
+            #[cfg(test)]
+            WorkKind::Test => WorkPriority::Interactive,
+        }
+    }
+}
+
+/// Urgency of work, queued works of a higher priority are processed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WorkPriority {
+    /// Bulk processing nobody waits for.
+    Batch,
+    Final,
+    Interactive,
+}
+
+impl Work {
+    pub fn kind(&self) -> WorkKind {
+        match self {
+            Work::PaletteExtract { .. } => WorkKind::PaletteExtract,
+            Work::ImageDither { .. } => WorkKind::ImageDither,
+            Work::PdfRender { .. } => WorkKind::PdfRender,
+            #[cfg(test)]
+            Work::TestWork { .. } | Work::TestFailure { .. } => WorkKind::Test,
+        }
     }
 }
 
 impl Debug for Work {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Work::PaletteExtract { palette_dmc, src_image, max_colors } => {
+            Work::PaletteExtract { palette_dmc, src_image, max_colors, mode, color_distance, kmeans } => {
                 f.debug_struct("PaletteExtract")
                     .field("palette_dmc", &format_args!("Palette of {} DMCs", palette_dmc.len()))
                     .field("image_size", &format_args!("{}x{}", src_image.width(), src_image.height()))
                     .field("max_colors", max_colors)
+                    .field("mode", mode)
+                    .field("color_distance", color_distance)
+                    .field("kmeans", kmeans)
                     .finish()
             },
-            Work::ImageDither { palette_dmc, src_image } => {
+            Work::ImageDither { palette_dmc, src_image, drill_mask, drill_size, dithering, confetti, canvas } => {
                 f.debug_struct("ImageDither")
                     .field("palette_dmc", &format_args!("Palette of {} DMCs", palette_dmc.len()))
                     .field("image_size", &format_args!("{}x{}", src_image.width(), src_image.height()))
+                    .field("has_drill_mask", &drill_mask.is_some())
+                    .field("drill_size", drill_size)
+                    .field("dithering", dithering)
+                    .field("confetti", confetti)
+                    .field("canvas", canvas)
+                    .finish()
+            },
+            Work::PdfRender { palette_dmc, src_image, drill_mask, dithering, confetti, canvas } => {
+                f.debug_struct("PdfRender")
+                    .field("palette_dmc", &format_args!("Palette of {} DMCs", palette_dmc.len()))
+                    .field("image_size", &format_args!("{}x{}", src_image.width(), src_image.height()))
+                    .field("has_drill_mask", &drill_mask.is_some())
+                    .field("dithering", dithering)
+                    .field("confetti", confetti)
+                    .field("canvas", canvas)
                     .finish()
             },
             #[cfg(test)]
@@ -69,10 +210,31 @@ impl Debug for Work {
                     .field("delay", delay)
                     .finish()
             },
+            #[cfg(test)]
+            Work::TestFailure { reason } => {
+                f.debug_struct("TestFailure")
+                    .field("reason", reason)
+                    .finish()
+            },
         }
     }
 }
 
+/// Resamples image and its drill mask to the drill grid of canvas, without canvas they are used as is.
+fn resample_to_canvas(
+    src_image: Arc<image::RgbImage>,
+    drill_mask: Option<Arc<DrillMask>>,
+    canvas: Option<&CanvasSpec>
+) -> (Arc<image::RgbImage>, Option<Arc<DrillMask>>) {
+    match canvas {
+        Some(canvas) => (
+            Arc::new(canvas.resample(&src_image).expect("canvas should be validated before enqueuing")),
+            drill_mask.map(|drill_mask| Arc::new(canvas.resample_drill_mask(&drill_mask).expect("canvas should be validated before enqueuing")))
+        ),
+        None => (src_image, drill_mask),
+    }
+}
+
 /// A `Work` message tagged with its `WorkId`.
 #[derive(Debug)]
 pub struct WorkWrapped {
@@ -88,6 +250,13 @@ pub enum WorkResult {
     ImageDither {
         dithered_image: image::RgbImage,
         dmc_bom: DmcBom,
+        preview_png: Vec<u8>,
+        confetti_report: Option<ConfettiReport>,
+    },
+    PdfRender {
+        pdf: Vec<u8>,
+        dmc_bom: DmcBom,
+        confetti_report: Option<ConfettiReport>,
     },
     #[cfg(test)]
     TestWork,
@@ -101,10 +270,19 @@ impl Debug for WorkResult {
                     .field("dmc_bom", &format_args!("BOM of {} DMCs", dmc_bom.len()))
                     .finish()
             },
-            WorkResult::ImageDither { dithered_image, dmc_bom } => {
+            WorkResult::ImageDither { dithered_image, dmc_bom, preview_png, confetti_report } => {
                 f.debug_struct("ImageDither")
                     .field("dithered_image_size", &format_args!("{}x{}", dithered_image.width(), dithered_image.height()))
                     .field("dmc_bom", &format_args!("BOM of {} DMCs", dmc_bom.len()))
+                    .field("preview_png_size", &format_args!("{} bytes", preview_png.len()))
+                    .field("confetti_report", confetti_report)
+                    .finish()
+            },
+            WorkResult::PdfRender { pdf, dmc_bom, confetti_report } => {
+                f.debug_struct("PdfRender")
+                    .field("pdf_size", &format_args!("{} bytes", pdf.len()))
+                    .field("dmc_bom", &format_args!("BOM of {} DMCs", dmc_bom.len()))
+                    .field("confetti_report", confetti_report)
                     .finish()
             },
             #[cfg(test)]
@@ -116,15 +294,15 @@ impl Debug for WorkResult {
     }
 }
 
-/// A `WorkResult` tagged with its originating `WorkId`.
+/// A `WorkResult` tagged with its originating `WorkId`, or the reason why the work failed.
 #[derive(Debug)]
 pub struct WorkResultWrapped {
     pub id: WorkId,
-    pub work_result: WorkResult,
+    pub work_result: Result<WorkResult, String>,
 }
 
 /// A background worker that processes `WorkWrapped` messages on a blocking thread
-/// and sends back `WorkResultWrapped` messages.
+/// and sends back `WorkResultWrapped` messages. Progress of the work is reported to the status board.
 #[derive(Debug)]
 pub struct Worker {
     pub id: u32,
@@ -132,6 +310,13 @@ pub struct Worker {
     pub work_tx: tokio::sync::mpsc::Sender<WorkWrapped>,
 }
 
+/// Message of a panic, which is the reason of failed work.
+fn panic_reason(panic: Box<dyn std::any::Any + Send>) -> String {
+    panic.downcast_ref::<&str>().map(|reason| reason.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Processing panicked".to_string())
+}
+
 impl std::fmt::Display for Worker {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Worker {}", self.id)
@@ -140,42 +325,106 @@ impl std::fmt::Display for Worker {
 
 impl Worker {
     /// Execute a single `WorkWrapped`, spawning blocking work and returning the result.
-    async fn do_work(work_to_do: WorkWrapped) -> WorkResult {
+    /// Panic of the work is its failure, the worker goes on.
+    async fn do_work(work_to_do: WorkWrapped, worker_id: u32, statuses: &WorkStatusBoard) -> Result<WorkResult, String> {
         let span = tracing::info_span!("worker.do_work", id = work_to_do.id);
         let _span_enter = span.enter();
         
         let work = work_to_do.work;
         tracing::info!("Start doing processing {work:?}...");
 
-        // Expect this task will not fail or abort. It should finish its work in finite time.
+        if !statuses.update(work_to_do.id, WorkStatus::Running { worker: worker_id, progress: 0 }) {
+            tracing::info!("Skipping processing, it was cancelled while queued");
+            return Err(WORK_CANCELLED_REASON.to_string());
+        }
+        let progress = WorkProgress { id: work_to_do.id, worker: worker_id, board: statuses.clone() };
+
+        // Stages return `None` once cancelled
         let result = tokio::task::spawn_blocking(move || {
-             match work {
-                Work::PaletteExtract { palette_dmc, src_image, max_colors } => {
-                    let dmc_counts = palette_dmc.find_subset_closest_to_image_pixels(&src_image, max_colors);
-                    WorkResult::PaletteExtract { dmc_bom: dmc_counts }
+            let work_result = match work {
+                Work::PaletteExtract { palette_dmc, src_image, max_colors, mode, color_distance, kmeans } => {
+                    let dmc_counts = extract_dmc_palette(&palette_dmc, &src_image, max_colors, mode, color_distance, &kmeans, &progress);
+                    Some(WorkResult::PaletteExtract { dmc_bom: dmc_counts })
                 },
-                Work::ImageDither { palette_dmc, src_image } => {
-                    let (dithered_image, dmc_bom) = image_dither_using_dmc_palette(&palette_dmc, &src_image);
-                    WorkResult::ImageDither { dithered_image, dmc_bom }
+                Work::ImageDither { palette_dmc, src_image, drill_mask, drill_size, dithering, confetti, canvas } => {
+                    let (src_image, drill_mask) = resample_to_canvas(src_image, drill_mask, canvas.as_ref());
+                    progress.report(0.1);
+                    let dithering_progress = ProgressRange::new(&progress, 0.1, 0.9);
+                    let (dithered_image, dmc_bom, confetti_report) = image_dither_using_dmc_palette(&palette_dmc, &src_image, drill_mask.as_deref(), &dithering, confetti.as_ref(), &dithering_progress);
+                    if progress.is_cancelled() {
+                        return None;
+                    }
+                    let preview_png = match drill_size {
+                        Some(drill_size) => encode_png(&render_drills_preview(&dithered_image, drill_mask.as_deref(), drill_size)),
+                        None => encode_png(&dithered_image),
+                    }.expect("PNG encoding in memory should not fail");
+                    progress.report(1.0);
+                    Some(WorkResult::ImageDither { dithered_image, dmc_bom, preview_png, confetti_report })
+                },
+                Work::PdfRender { palette_dmc, src_image, drill_mask, dithering, confetti, canvas } => {
+                    let (src_image, drill_mask) = resample_to_canvas(src_image, drill_mask, canvas.as_ref());
+                    progress.report(0.1);
+                    let dithering_progress = ProgressRange::new(&progress, 0.1, 0.7);
+                    let (dithered_image, dmc_bom, confetti_report) = image_dither_using_dmc_palette(&palette_dmc, &src_image, drill_mask.as_deref(), &dithering, confetti.as_ref(), &dithering_progress);
+                    if progress.is_cancelled() {
+                        return None;
+                    }
+                    let pdf = render_pdf_chart(&dithered_image, drill_mask.as_deref(), &dmc_bom)
+                        .expect("dithered image should consist only of BOM colors");
+                    progress.report(1.0);
+                    Some(WorkResult::PdfRender { pdf, dmc_bom, confetti_report })
                 },
                 #[cfg(test)]
                 Work::TestWork { delay } => {
-                    std::thread::sleep(delay);
-                    WorkResult::TestWork
+                    // Sleeps in steps, so it can be cancelled
+                    for step in 0..TEST_WORK_STEPS {
+                        if progress.is_cancelled() {
+                            return None;
+                        }
+                        if step == TEST_WORK_STEPS / 2 {
+                            progress.report(0.5);
+                        }
+                        std::thread::sleep(delay / TEST_WORK_STEPS);
+                    }
+                    Some(WorkResult::TestWork)
                 },
-            }
-        }).await
-        .expect("blocking task panicked");
+                #[cfg(test)]
+                Work::TestFailure { reason } => {
+                    panic!("{reason}");
+                },
+            };
+
+            // Unfinished result of cancelled work is thrown away
+            work_result.filter(|_| !progress.is_cancelled())
+        }).await;
 
-        tracing::info!("Finished doing processing, result = {result:?}!");
-        result
+        match result {
+            Ok(Some(result)) => {
+                tracing::info!("Finished doing processing, result = {result:?}!");
+                Ok(result)
+            },
+            Ok(None) => {
+                tracing::info!("Processing was cancelled");
+                Err(WORK_CANCELLED_REASON.to_string())
+            },
+            Err(e) if e.is_panic() => {
+                let reason = panic_reason(e.into_panic());
+                tracing::error!("Processing failed, reason='{reason}'");
+                Err(reason)
+            },
+            Err(e) => {
+                tracing::error!("Processing was aborted, reason='{e}'");
+                Err(e.to_string())
+            },
+        }
     }
     
     /// Create a new worker with the given `id`.
     ///
     /// Spawns a background task that pulls work from the queue,
     /// executes it via `do_work`, and forwards results to the result channel.
-    pub fn new(id: u32, work_result_tx: tokio::sync::mpsc::Sender<WorkResultWrapped>) -> Self {
+    /// Running work and its progress is reported to `statuses`.
+    pub fn new(id: u32, work_result_tx: tokio::sync::mpsc::Sender<WorkResultWrapped>, statuses: WorkStatusBoard) -> Self {
         let (work_tx, mut work_rx) = tokio::sync::mpsc::channel::<WorkWrapped>(WORKER_INPUT_QUEUE_CAP);
 
         let task = tokio::task::spawn(async move {
@@ -184,14 +433,14 @@ impl Worker {
                 match work_rx.recv().await {
                     Some(work_to_do) => {
                         let work_id = work_to_do.id;
-                        let work_result = Self::do_work(work_to_do).await;
+                        let work_result = Self::do_work(work_to_do, id, &statuses).await;
 
                         let work_result_wrapped = WorkResultWrapped {
                             id: work_id,
                             work_result
                         };
 
-                        if let Err(_) = work_result_tx.send(work_result_wrapped).await {
+                        if work_result_tx.send(work_result_wrapped).await.is_err() {
                             tracing::info!("Stopping worker {id}, noone need results of his work :(");
                             break;
                         }
@@ -229,6 +478,13 @@ impl Worker {
 #[cfg(test)]
 mod tests_worker {
     use super::*;
+    use ditherum::algorithms::{
+        dithering::{
+            DitheringMethod, 
+            ErrorDiffusion
+        }, 
+        kernel::ScanOrder
+    };
     use std::time::Duration;
     use tracing_subscriber;
     
@@ -248,12 +504,37 @@ mod tests_worker {
 
             let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
             
-            let worker = Worker::new(0, work_result_tx);
+            let worker = Worker::new(0, work_result_tx, WorkStatusBoard::default());
             let enque_result = worker.try_enque_work(WorkWrapped { id: 12, work: Work::TestWork { delay: Duration::from_millis(500) } });
             assert!(enque_result.is_ok());
 
             let work_result = work_result_rx.recv().await.unwrap();
-            assert!(matches!(work_result, WorkResultWrapped { id: 12, work_result: WorkResult::TestWork }));
+            assert!(matches!(work_result, WorkResultWrapped { id: 12, work_result: Ok(WorkResult::TestWork) }));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn test_worker_failed_work_does_not_stop_worker() {
+        {
+            init_tracing();
+
+            let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
+            let statuses = WorkStatusBoard::default();
+
+            let worker = Worker::new(5, work_result_tx, statuses.clone());
+            assert!(worker.try_enque_work(WorkWrapped { id: 31, work: Work::TestFailure { reason: "broken on purpose" } }).is_ok());
+            assert!(worker.try_enque_work(WorkWrapped { id: 32, work: Work::TestWork { delay: Duration::from_millis(200) } }).is_ok());
+
+            let work_result = work_result_rx.recv().await.unwrap();
+            assert!(matches!(&work_result, WorkResultWrapped { id: 31, work_result: Err(reason) } if reason == "broken on purpose"), "{work_result:?}");
+
+            // Half of the test work is reported in its middle
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(statuses.get(32), Some(WorkStatus::Running { worker: 5, progress: 50 }));
+
+            let work_result = work_result_rx.recv().await.unwrap();
+            assert!(matches!(work_result, WorkResultWrapped { id: 32, work_result: Ok(WorkResult::TestWork) }));
         }
         tokio::time::sleep(Duration::from_millis(10)).await;
     }
@@ -274,11 +555,11 @@ mod tests_worker {
 
             let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
             
-            let worker = Worker::new(1, work_result_tx);
+            let worker = Worker::new(1, work_result_tx, WorkStatusBoard::default());
 
             let work = WorkWrapped {
                 id: 13,
-                work: Work::PaletteExtract { palette_dmc, src_image, max_colors }
+                work: Work::PaletteExtract { palette_dmc, src_image, max_colors, mode: PaletteExtractMode::KMeans, color_distance: ColorDistance::default(), kmeans: KMeansOptions::default() }
             };
 
             let enque_result = worker.try_enque_work(work);
@@ -286,7 +567,7 @@ mod tests_worker {
 
             let work_result = work_result_rx.recv().await.unwrap();
             // Etracted colors are hard to predict
-            assert!(matches!(work_result, WorkResultWrapped { id: 13, work_result: WorkResult::PaletteExtract { dmc_bom: _ } }));
+            assert!(matches!(work_result, WorkResultWrapped { id: 13, work_result: Ok(WorkResult::PaletteExtract { dmc_bom: _ }) }));
         }
         tokio::time::sleep(Duration::from_millis(10)).await;
     }
@@ -306,18 +587,30 @@ mod tests_worker {
             
             let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
             
-            let worker = Worker::new(2, work_result_tx);
+            let worker = Worker::new(2, work_result_tx, WorkStatusBoard::default());
 
             let work = WorkWrapped {
                 id: 14,
-                work: Work::ImageDither { palette_dmc: palette_dmc.clone(), src_image }
+                work: Work::ImageDither { palette_dmc: palette_dmc.clone(), src_image, drill_mask: None, drill_size: Some(4), dithering: DitheringOptions {
+                    color_distance: ColorDistance::Ciede2000,
+                    method: DitheringMethod::ErrorDiffusion,
+                    error_diffusion: ErrorDiffusion::Stucki,
+                    error_strength: 0.8,
+                    scan_order: ScanOrder::Serpentine,
+                }, confetti: Some(ConfettiOptions::default()), canvas: None }
             };
 
             let enque_result = worker.try_enque_work(work);
             assert!(enque_result.is_ok());
 
             let work_result = work_result_rx.recv().await.unwrap();
-            if let WorkResult::ImageDither { dithered_image, dmc_bom } = work_result.work_result {
+            if let Ok(WorkResult::ImageDither { dithered_image, dmc_bom, preview_png, confetti_report }) = work_result.work_result {
+                let confetti_report = confetti_report.expect("Confetti cleanup was requested");
+                assert!(confetti_report.after.single_pixels_count <= confetti_report.before.single_pixels_count);
+
+                let preview = image::load_from_memory(&preview_png).unwrap();
+                assert_eq!(preview.width(), dithered_image.width() * 4);
+
                 let pixels_count = dithered_image.width() * dithered_image.height();
                 let diamonds_used_count = dmc_bom.iter().fold(0, |acc, (_, cnt)| acc + cnt);
                 assert_eq!(pixels_count, diamonds_used_count);
@@ -343,7 +636,7 @@ mod tests_worker {
             
             let (work_result_tx, _work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
             
-            let worker = Worker::new(3, work_result_tx);
+            let worker = Worker::new(3, work_result_tx, WorkStatusBoard::default());
             let enque_result = worker.try_enque_work(WorkWrapped { id: 21, work: Work::TestWork { delay: Duration::from_millis(500) } });
             assert!(enque_result.is_ok());
 
@@ -360,12 +653,12 @@ mod tests_worker {
             
             let (work_result_tx, mut work_result_rx) = tokio::sync::mpsc::channel(WORKER_TEST_QUEUE_CAP);
             
-            let worker = Worker::new(4, work_result_tx);
+            let worker = Worker::new(4, work_result_tx, WorkStatusBoard::default());
             let enque_result = worker.try_enque_work(WorkWrapped { id: 22, work: Work::TestWork { delay: Duration::from_millis(500) } });
             assert!(enque_result.is_ok());
 
             let work_result = work_result_rx.recv().await.unwrap();
-            assert!(matches!(work_result, WorkResultWrapped { id: _, work_result: WorkResult::TestWork }));
+            assert!(matches!(work_result, WorkResultWrapped { id: _, work_result: Ok(WorkResult::TestWork) }));
             
             worker.shutdown().await;
             // LATER: capture if work was done, no matter when worker finish, he should finish its work
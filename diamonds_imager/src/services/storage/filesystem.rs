@@ -0,0 +1,166 @@
+use std::{
+    io::ErrorKind,
+    path::{
+        Component,
+        Path,
+        PathBuf
+    }
+};
+
+use super::{
+    ImageRecord,
+    StorageBackend,
+    StorageBackendError
+};
+use crate::services::{
+    history::RevisionId,
+    ImageId
+};
+
+const RECORD_FILENAME: &str = "record.json";
+
+/// Writes into a temporary file first, so a crash never leaves a half written file behind.
+fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Directory per image holding its images and blobs, shared by backends storing files.
+#[derive(Debug)]
+pub(super) struct BlobDir {
+    root: PathBuf,
+}
+
+impl BlobDir {
+    pub(super) fn open(root: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        Ok(Self { root: root.to_path_buf() })
+    }
+
+    /// Directory of the image, IDs which are not a plain filename are refused.
+    pub(super) fn image_dir(&self, id: &ImageId) -> std::io::Result<PathBuf> {
+        let mut components = Path::new(id).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.root.join(id)),
+            _ => Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Image ID '{id}' is not a filename"))),
+        }
+    }
+
+    fn file_path(&self, id: &ImageId, name: &str) -> std::io::Result<PathBuf> {
+        let image_dir = self.image_dir(id)?;
+        std::fs::create_dir_all(&image_dir)?;
+        Ok(image_dir.join(name))
+    }
+
+    fn image_filename(origin: RevisionId) -> String {
+        format!("image_{origin}.png")
+    }
+
+    pub(super) fn save_image(&self, id: &ImageId, origin: RevisionId, image: &image::RgbImage) -> Result<(), StorageBackendError> {
+        let path = self.file_path(id, &Self::image_filename(origin))?;
+        let png = crate::services::processing::image_manip::encode_png(image)?;
+        Ok(write_atomically(&path, &png)?)
+    }
+
+    pub(super) fn load_image(&self, id: &ImageId, origin: RevisionId) -> Result<image::RgbImage, StorageBackendError> {
+        let png = self.load_blob(id, &Self::image_filename(origin))?;
+        Ok(image::load_from_memory_with_format(&png, image::ImageFormat::Png)?.into_rgb8())
+    }
+
+    pub(super) fn remove_image(&self, id: &ImageId, origin: RevisionId) -> Result<(), StorageBackendError> {
+        self.remove_file(id, &Self::image_filename(origin))
+    }
+
+    pub(super) fn save_blob(&self, id: &ImageId, name: &str, bytes: &[u8]) -> Result<(), StorageBackendError> {
+        Ok(write_atomically(&self.file_path(id, name)?, bytes)?)
+    }
+
+    pub(super) fn load_blob(&self, id: &ImageId, name: &str) -> Result<Vec<u8>, StorageBackendError> {
+        match std::fs::read(self.image_dir(id)?.join(name)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(StorageBackendError::BlobNotFound { id: id.clone(), name: name.to_string() }),
+            result => Ok(result?),
+        }
+    }
+
+    pub(super) fn remove_file(&self, id: &ImageId, name: &str) -> Result<(), StorageBackendError> {
+        match std::fs::remove_file(self.image_dir(id)?.join(name)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    pub(super) fn remove(&self, id: &ImageId) -> Result<(), StorageBackendError> {
+        match std::fs::remove_dir_all(self.image_dir(id)?) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Stores every image in its own directory, the record as JSON.
+#[derive(Debug)]
+pub struct FilesystemBackend {
+    blobs: BlobDir,
+}
+
+impl FilesystemBackend {
+    pub fn open(root: &Path) -> Result<Self, StorageBackendError> {
+        Ok(Self { blobs: BlobDir::open(root)? })
+    }
+}
+
+impl StorageBackend for FilesystemBackend {
+    fn list_records(&mut self) -> Result<Vec<(ImageId, ImageRecord)>, StorageBackendError> {
+        let mut records = vec![];
+        for entry in std::fs::read_dir(&self.blobs.root)? {
+            let entry = entry?;
+            let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+
+            match self.blobs.load_blob(&id, RECORD_FILENAME) {
+                Ok(bytes) => match serde_json::from_slice(&bytes) {
+                    Ok(record) => records.push((id, record)),
+                    Err(e) => tracing::warn!("Skipping corrupted record of image '{id}': {e}"),
+                },
+                // Directory of an image whose record was never written
+                Err(StorageBackendError::BlobNotFound { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(records)
+    }
+
+    fn save_record(&mut self, id: &ImageId, record: &ImageRecord) -> Result<(), StorageBackendError> {
+        self.blobs.save_blob(id, RECORD_FILENAME, &serde_json::to_vec(record)?)
+    }
+
+    fn save_image(&mut self, id: &ImageId, origin: RevisionId, image: &image::RgbImage) -> Result<(), StorageBackendError> {
+        self.blobs.save_image(id, origin, image)
+    }
+
+    fn load_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<image::RgbImage, StorageBackendError> {
+        self.blobs.load_image(id, origin)
+    }
+
+    fn remove_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<(), StorageBackendError> {
+        self.blobs.remove_image(id, origin)
+    }
+
+    fn save_blob(&mut self, id: &ImageId, name: &str, bytes: &[u8]) -> Result<(), StorageBackendError> {
+        self.blobs.save_blob(id, name, bytes)
+    }
+
+    fn load_blob(&mut self, id: &ImageId, name: &str) -> Result<Vec<u8>, StorageBackendError> {
+        self.blobs.load_blob(id, name)
+    }
+
+    fn remove_blob(&mut self, id: &ImageId, name: &str) -> Result<(), StorageBackendError> {
+        self.blobs.remove_file(id, name)
+    }
+
+    fn remove(&mut self, id: &ImageId) -> Result<(), StorageBackendError> {
+        self.blobs.remove(id)
+    }
+}
@@ -0,0 +1,52 @@
+use super::{
+    ImageRecord,
+    StorageBackend,
+    StorageBackendError
+};
+use crate::services::{
+    history::RevisionId,
+    ImageId
+};
+
+/// Keeps nothing, `ImageStorageService` already holds everything in memory.
+/// Images are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryBackend;
+
+impl StorageBackend for MemoryBackend {
+    fn list_records(&mut self) -> Result<Vec<(ImageId, ImageRecord)>, StorageBackendError> {
+        Ok(vec![])
+    }
+
+    fn save_record(&mut self, _id: &ImageId, _record: &ImageRecord) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+
+    fn save_image(&mut self, _id: &ImageId, _origin: RevisionId, _image: &image::RgbImage) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+
+    fn load_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<image::RgbImage, StorageBackendError> {
+        Err(StorageBackendError::BlobNotFound { id: id.clone(), name: format!("image of revision {origin}") })
+    }
+
+    fn remove_image(&mut self, _id: &ImageId, _origin: RevisionId) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+
+    fn save_blob(&mut self, _id: &ImageId, _name: &str, _bytes: &[u8]) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+
+    fn load_blob(&mut self, id: &ImageId, name: &str) -> Result<Vec<u8>, StorageBackendError> {
+        Err(StorageBackendError::BlobNotFound { id: id.clone(), name: name.to_string() })
+    }
+
+    fn remove_blob(&mut self, _id: &ImageId, _name: &str) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+
+    fn remove(&mut self, _id: &ImageId) -> Result<(), StorageBackendError> {
+        Ok(())
+    }
+}
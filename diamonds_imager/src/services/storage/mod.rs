@@ -0,0 +1,239 @@
+/// Persistent storage of images, their revisions and finished works.
+/// `ImageStorageService` keeps working copies in memory and writes every change
+/// through to the selected backend, so they survive a restart.
+pub mod filesystem;
+pub mod memory;
+pub mod sqlite;
+pub mod writer;
+
+use std::{
+    fmt::Debug,
+    path::Path,
+    str::FromStr
+};
+
+use ditherum::algorithms::confetti::ConfettiReport;
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use super::{
+    dmc::{
+        Dmc,
+        DmcBom
+    },
+    history::{
+        HistoryError,
+        HistoryRecord,
+        RevisionId
+    },
+    processing::{
+        image_manip::DrillMask,
+        worker::WorkResult
+    },
+    ImageId,
+    ImageStorageMeta,
+    ImageWorkKind
+};
+
+const BLOB_DITHERED_IMAGE: &str = "dithered.rgb";
+const BLOB_PREVIEW_PNG: &str = "preview.png";
+const BLOB_PDF: &str = "chart.pdf";
+
+fn drill_mask_blob_name(origin: RevisionId) -> String {
+    format!("drill_mask_{origin}.png")
+}
+
+/// Named opaque data stored with an image.
+pub type Blob<'a> = (&'static str, &'a [u8]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageBackendError {
+    #[error("Io reason='{0}'")]
+    Io(#[from] std::io::Error),
+
+    #[error("Sqlite reason='{0}'")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Serialization reason='{0}'")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Image reason='{0}'")]
+    Image(#[from] image::ImageError),
+
+    #[error(transparent)]
+    History(#[from] HistoryError),
+
+    #[error("BlobNotFound '{name}' of image '{id}'")]
+    BlobNotFound {
+        id: ImageId,
+        name: String,
+    },
+
+    #[error("UnknownBackend '{0}'")]
+    UnknownBackend(String),
+}
+
+/// Backend selected in settings.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackendKind {
+    /// Nothing survives a restart.
+    #[default]
+    Memory,
+    /// Records as JSON files, images and artifacts as files next to them.
+    Filesystem,
+    /// Records in an embedded SQLite database, images and artifacts as files.
+    Sqlite,
+}
+
+impl FromStr for StorageBackendKind {
+    type Err = StorageBackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "filesystem" => Ok(Self::Filesystem),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(StorageBackendError::UnknownBackend(s.to_string())),
+        }
+    }
+}
+
+/// Finished work without its large outputs, those are stored as blobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ArtifactRecord {
+    PaletteExtract {
+        dmc_bom: Vec<(Dmc, u32)>,
+    },
+    ImageDither {
+        dmc_bom: Vec<(Dmc, u32)>,
+        confetti_report: Option<ConfettiReport>,
+        width: u32,
+        height: u32,
+    },
+    PdfRender {
+        dmc_bom: Vec<(Dmc, u32)>,
+        confetti_report: Option<ConfettiReport>,
+    },
+}
+
+impl ArtifactRecord {
+    /// Record and blobs of finished work, `None` for work which is not worth storing.
+    pub fn from_work_result(work_result: &WorkResult) -> Option<(Self, Vec<Blob<'_>>)> {
+        let bom_entries = |dmc_bom: &DmcBom| dmc_bom.iter()
+            .map(|(dmc, count)| (dmc.clone(), *count))
+            .collect();
+
+        match work_result {
+            WorkResult::PaletteExtract { dmc_bom } => Some((
+                Self::PaletteExtract { dmc_bom: bom_entries(dmc_bom) },
+                vec![]
+            )),
+            WorkResult::ImageDither { dithered_image, dmc_bom, preview_png, confetti_report } => Some((
+                Self::ImageDither {
+                    dmc_bom: bom_entries(dmc_bom),
+                    confetti_report: *confetti_report,
+                    width: dithered_image.width(),
+                    height: dithered_image.height(),
+                },
+                vec![(BLOB_DITHERED_IMAGE, dithered_image.as_raw()), (BLOB_PREVIEW_PNG, preview_png)]
+            )),
+            WorkResult::PdfRender { pdf, dmc_bom, confetti_report } => Some((
+                Self::PdfRender { dmc_bom: bom_entries(dmc_bom), confetti_report: *confetti_report },
+                vec![(BLOB_PDF, pdf)]
+            )),
+            #[cfg(test)]
+            WorkResult::TestWork => None,
+        }
+    }
+
+    /// Rebuilds finished work, `load_blob` reads blobs stored with the record.
+    pub fn into_work_result<F>(self, mut load_blob: F) -> Result<WorkResult, StorageBackendError>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>, StorageBackendError>
+    {
+        Ok(match self {
+            Self::PaletteExtract { dmc_bom } => WorkResult::PaletteExtract {
+                dmc_bom: dmc_bom.into_iter().collect()
+            },
+            Self::ImageDither { dmc_bom, confetti_report, width, height } => {
+                let dithered_image = image::RgbImage::from_raw(width, height, load_blob(BLOB_DITHERED_IMAGE)?)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Dithered image is truncated"))?;
+                WorkResult::ImageDither {
+                    dithered_image,
+                    dmc_bom: dmc_bom.into_iter().collect(),
+                    preview_png: load_blob(BLOB_PREVIEW_PNG)?,
+                    confetti_report,
+                }
+            },
+            Self::PdfRender { dmc_bom, confetti_report } => WorkResult::PdfRender {
+                pdf: load_blob(BLOB_PDF)?,
+                dmc_bom: dmc_bom.into_iter().collect(),
+                confetti_report,
+            },
+        })
+    }
+}
+
+/// Everything about an image except images and blobs of artifacts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRecord {
+    pub meta: ImageStorageMeta,
+    pub history: HistoryRecord,
+    pub artifacts: Vec<(ImageWorkKind, ArtifactRecord)>,
+}
+
+/// Storage of images which survives a restart. Images of revisions are identified
+/// by the revision which introduced them, see `ImageRevision::image_origin`.
+pub trait StorageBackend: Debug + Send {
+    /// All stored records, called once at start.
+    fn list_records(&mut self) -> Result<Vec<(ImageId, ImageRecord)>, StorageBackendError>;
+
+    /// Creates or replaces the record of the image.
+    fn save_record(&mut self, id: &ImageId, record: &ImageRecord) -> Result<(), StorageBackendError>;
+
+    fn save_image(&mut self, id: &ImageId, origin: RevisionId, image: &image::RgbImage) -> Result<(), StorageBackendError>;
+
+    fn load_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<image::RgbImage, StorageBackendError>;
+
+    fn remove_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<(), StorageBackendError>;
+
+    /// Creates or replaces opaque data of the image, e.g. rendered PDF.
+    fn save_blob(&mut self, id: &ImageId, name: &str, bytes: &[u8]) -> Result<(), StorageBackendError>;
+
+    fn load_blob(&mut self, id: &ImageId, name: &str) -> Result<Vec<u8>, StorageBackendError>;
+
+    /// Removes the blob, missing blob is not an error.
+    fn remove_blob(&mut self, id: &ImageId, name: &str) -> Result<(), StorageBackendError>;
+
+    /// Drill mask is kept as a blob next to the image it belongs to.
+    fn save_drill_mask(&mut self, id: &ImageId, origin: RevisionId, drill_mask: &DrillMask) -> Result<(), StorageBackendError> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        drill_mask.write_to(&mut png, image::ImageFormat::Png)?;
+        self.save_blob(id, &drill_mask_blob_name(origin), png.get_ref())
+    }
+
+    fn load_drill_mask(&mut self, id: &ImageId, origin: RevisionId) -> Result<DrillMask, StorageBackendError> {
+        let png = self.load_blob(id, &drill_mask_blob_name(origin))?;
+        Ok(image::load_from_memory_with_format(&png, image::ImageFormat::Png)?.into_luma8())
+    }
+
+    fn remove_drill_mask(&mut self, id: &ImageId, origin: RevisionId) -> Result<(), StorageBackendError> {
+        self.remove_blob(id, &drill_mask_blob_name(origin))
+    }
+
+    /// Removes the record, images and blobs of the image.
+    fn remove(&mut self, id: &ImageId) -> Result<(), StorageBackendError>;
+}
+
+/// Opens backend of the kind, `path` is a directory where data are kept.
+pub fn open_backend(kind: StorageBackendKind, path: &Path) -> Result<Box<dyn StorageBackend>, StorageBackendError> {
+    Ok(match kind {
+        StorageBackendKind::Memory => Box::new(memory::MemoryBackend),
+        StorageBackendKind::Filesystem => Box::new(filesystem::FilesystemBackend::open(path)?),
+        StorageBackendKind::Sqlite => Box::new(sqlite::SqliteBackend::open(path)?),
+    })
+}
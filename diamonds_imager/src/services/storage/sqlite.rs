@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use super::{
+    filesystem::BlobDir,
+    ImageRecord,
+    StorageBackend,
+    StorageBackendError
+};
+use crate::services::{
+    history::RevisionId,
+    ImageId
+};
+
+const DATABASE_FILENAME: &str = "storage.sqlite3";
+
+/// Stores records in an embedded SQLite database, images and blobs as files next to it.
+#[derive(Debug)]
+pub struct SqliteBackend {
+    connection: rusqlite::Connection,
+    blobs: BlobDir,
+}
+
+impl SqliteBackend {
+    pub fn open(root: &Path) -> Result<Self, StorageBackendError> {
+        let blobs = BlobDir::open(&root.join("images"))?;
+        let connection = rusqlite::Connection::open(root.join(DATABASE_FILENAME))?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS images (
+                id TEXT PRIMARY KEY NOT NULL,
+                record TEXT NOT NULL,
+                updated_time TEXT NOT NULL
+            );"
+        )?;
+
+        Ok(Self { connection, blobs })
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn list_records(&mut self) -> Result<Vec<(ImageId, ImageRecord)>, StorageBackendError> {
+        let mut statement = self.connection.prepare("SELECT id, record FROM images")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut records = vec![];
+        for row in rows {
+            let (id, record) = row?;
+            match serde_json::from_str(&record) {
+                Ok(record) => records.push((id, record)),
+                Err(e) => tracing::warn!("Skipping corrupted record of image '{id}': {e}"),
+            }
+        }
+        Ok(records)
+    }
+
+    fn save_record(&mut self, id: &ImageId, record: &ImageRecord) -> Result<(), StorageBackendError> {
+        self.connection.execute(
+            "INSERT INTO images (id, record, updated_time) VALUES (?1, ?2, ?3)
+            ON CONFLICT(id) DO UPDATE SET record = excluded.record, updated_time = excluded.updated_time",
+            (id, serde_json::to_string(record)?, chrono::Utc::now().to_rfc3339())
+        )?;
+        Ok(())
+    }
+
+    fn save_image(&mut self, id: &ImageId, origin: RevisionId, image: &image::RgbImage) -> Result<(), StorageBackendError> {
+        self.blobs.save_image(id, origin, image)
+    }
+
+    fn load_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<image::RgbImage, StorageBackendError> {
+        self.blobs.load_image(id, origin)
+    }
+
+    fn remove_image(&mut self, id: &ImageId, origin: RevisionId) -> Result<(), StorageBackendError> {
+        self.blobs.remove_image(id, origin)
+    }
+
+    fn save_blob(&mut self, id: &ImageId, name: &str, bytes: &[u8]) -> Result<(), StorageBackendError> {
+        self.blobs.save_blob(id, name, bytes)
+    }
+
+    fn load_blob(&mut self, id: &ImageId, name: &str) -> Result<Vec<u8>, StorageBackendError> {
+        self.blobs.load_blob(id, name)
+    }
+
+    fn remove_blob(&mut self, id: &ImageId, name: &str) -> Result<(), StorageBackendError> {
+        self.blobs.remove_file(id, name)
+    }
+
+    fn remove(&mut self, id: &ImageId) -> Result<(), StorageBackendError> {
+        self.connection.execute("DELETE FROM images WHERE id = ?1", [id])?;
+        self.blobs.remove(id)
+    }
+}
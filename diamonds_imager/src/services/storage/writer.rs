@@ -0,0 +1,161 @@
+use std::{
+    sync::{
+        mpsc,
+        Arc,
+        Mutex
+    },
+    thread::JoinHandle
+};
+
+use super::{
+    ArtifactRecord,
+    ImageRecord,
+    StorageBackend,
+    StorageBackendError
+};
+use crate::services::{
+    history::RevisionId,
+    processing::{
+        image_manip::DrillMask,
+        worker::WorkResult
+    },
+    ImageId
+};
+
+/// Change of a stored image, applied by [`StorageWriter`] in order of requests.
+#[derive(Debug)]
+pub enum StorageWrite {
+    Record {
+        id: ImageId,
+        record: Box<ImageRecord>,
+    },
+    Image {
+        id: ImageId,
+        origin: RevisionId,
+        image: Arc<image::RgbImage>,
+    },
+    DrillMask {
+        id: ImageId,
+        origin: RevisionId,
+        drill_mask: Arc<DrillMask>,
+    },
+    /// Removes the image of the revision with its drill mask.
+    RemoveImage {
+        id: ImageId,
+        origin: RevisionId,
+    },
+    /// Blobs of finished work, its record is a part of the image record.
+    Artifacts {
+        id: ImageId,
+        work_result: Arc<WorkResult>,
+    },
+    Remove {
+        id: ImageId,
+    },
+    /// Answered once every write requested before is applied.
+    Flush(mpsc::Sender<()>),
+}
+
+impl StorageWrite {
+    fn id(&self) -> Option<&ImageId> {
+        match self {
+            Self::Record { id, .. } 
+                | Self::Image { id, .. } 
+                | Self::DrillMask { id, .. } 
+                | Self::RemoveImage { id, .. } 
+                | Self::Artifacts { id, .. } 
+                | Self::Remove { id } => Some(id),
+            Self::Flush(_) => None,
+        }
+    }
+
+    fn apply(self, backend: &mut dyn StorageBackend) -> Result<(), StorageBackendError> {
+        match self {
+            Self::Record { id, record } => backend.save_record(&id, &record),
+            Self::Image { id, origin, image } => backend.save_image(&id, origin, &image),
+            Self::DrillMask { id, origin, drill_mask } => backend.save_drill_mask(&id, origin, &drill_mask),
+            Self::RemoveImage { id, origin } => {
+                backend.remove_image(&id, origin)?;
+                backend.remove_drill_mask(&id, origin)
+            },
+            Self::Artifacts { id, work_result } => {
+                let blobs = ArtifactRecord::from_work_result(&work_result)
+                    .map(|(_, blobs)| blobs)
+                    .unwrap_or_default();
+                blobs.into_iter().try_for_each(|(name, bytes)| backend.save_blob(&id, name, bytes))
+            },
+            Self::Remove { id } => backend.remove(&id),
+            Self::Flush(done_tx) => {
+                // Requester may be gone already
+                let _ = done_tx.send(());
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Writes changes to the backend on its own thread, so encoding images and disk writes
+/// neither hold the image storage nor block the async runtime. Writes are applied in order,
+/// the ones requested before dropping the writer are applied before the drop returns.
+#[derive(Debug)]
+pub struct StorageWriter {
+    backend: Arc<Mutex<Box<dyn StorageBackend>>>,
+    writes_tx: Option<mpsc::Sender<StorageWrite>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StorageWriter {
+    pub fn new(backend: Box<dyn StorageBackend>) -> Self {
+        let backend = Arc::new(Mutex::new(backend));
+        let (writes_tx, writes_rx) = mpsc::channel::<StorageWrite>();
+
+        let thread_backend = backend.clone();
+        let thread = std::thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn(move || {
+                for write in writes_rx {
+                    let id = write.id().cloned().unwrap_or_default();
+                    let mut backend_guard = thread_backend.lock().expect("Storage backend lock is not poisoned");
+                    if let Err(e) = write.apply(backend_guard.as_mut()) {
+                        tracing::warn!("Cannot store change of image '{id}': {e}");
+                    }
+                }
+            })
+            .expect("Storage writer thread should spawn");
+
+        Self { backend, writes_tx: Some(writes_tx), thread: Some(thread) }
+    }
+
+    /// Queues the write, it is applied after writes queued before.
+    pub fn write(&self, write: StorageWrite) {
+        let writes_tx = self.writes_tx.as_ref().expect("Writer is running until dropped");
+        if writes_tx.send(write).is_err() {
+            tracing::error!("Storage writer is gone, change is not stored");
+        }
+    }
+
+    /// Blocks until every write queued before is applied.
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.write(StorageWrite::Flush(done_tx));
+        let _ = done_rx.recv();
+    }
+
+    /// Reads from the backend directly, e.g. images stored before start.
+    pub fn read<T>(&self, read: impl FnOnce(&mut dyn StorageBackend) -> T) -> T {
+        let mut backend_guard = self.backend.lock().expect("Storage backend lock is not poisoned");
+        read(backend_guard.as_mut())
+    }
+}
+
+impl Drop for StorageWriter {
+    fn drop(&mut self) {
+        // Thread ends once the queued writes are applied
+        drop(self.writes_tx.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("Storage writer thread panicked");
+            }
+        }
+    }
+}
@@ -1,3 +1,10 @@
+use std::collections::HashMap;
+
+use crate::services::{
+    processing::worker::WorkKind, 
+    storage::StorageBackendKind
+};
+
 #[derive(Debug, Clone, Copy)]
 pub struct Size<T> {
     pub width: T,
@@ -11,9 +18,29 @@ pub struct Settings {
     pub image_max_bytes: usize,
     pub image_min_size: Size<u32>,
     pub image_max_size: Size<u32>,
+    /// Decoded pixels of an upload at most, checked before decoding.
+    pub image_max_pixels: u64,
     pub log_level: String,
     pub workers_count: usize,
+    /// Works of a kind processed at once at most, e.g. so PDF renders can't take every worker.
+    pub work_kind_limits: HashMap<WorkKind, usize>,
+    /// Works a single client can have waiting for a worker, more are refused as busy.
+    pub client_max_queued_works: usize,
+    /// Finished works whose result is not collected for longer are forgotten.
+    pub work_result_ttl: std::time::Duration,
     pub dmc_palette_path: String,
+    /// Largest canvas which can be ordered.
+    pub canvas_max_size_cm: Size<f32>,
+    /// Images not accessed for longer are removed.
+    pub image_ttl: std::time::Duration,
+    /// How often expired images are looked for.
+    pub image_sweep_interval: std::time::Duration,
+    /// Decoded images kept in memory, least recently touched ones are evicted to disk.
+    pub image_memory_budget_bytes: usize,
+    /// Where images are stored to survive a restart.
+    pub storage_backend: StorageBackendKind,
+    /// Directory of the filesystem and SQLite backends.
+    pub storage_path: String,
     // max processings count, service busy
 }
 
@@ -33,6 +60,35 @@ fn load_setting_u32(key: &str) -> u32 {
     load_setting_string(key).parse().unwrap_or_else(|_| panic!("'{key}' value is not number"))
 }
 
+fn load_setting_f32_or_default(key: &str, default: f32) -> f32 {
+    dotenv::var(key)
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("'{key}' value is not number")))
+        .unwrap_or(default)
+}
+
+fn load_setting_u64_or_default(key: &str, default: u64) -> u64 {
+    dotenv::var(key)
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("'{key}' value is not number")))
+        .unwrap_or(default)
+}
+
+fn load_setting_u16_optional(key: &str) -> Option<u16> {
+    dotenv::var(key)
+        .ok()
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("'{key}' value is not number")))
+}
+
+fn load_work_kind_limits() -> HashMap<WorkKind, usize> {
+    [
+        ("WORKERS_MAX_PALETTE_EXTRACT", WorkKind::PaletteExtract),
+        ("WORKERS_MAX_PREVIEW", WorkKind::ImageDither),
+        ("WORKERS_MAX_PDF", WorkKind::PdfRender),
+    ]
+        .into_iter()
+        .filter_map(|(key, kind)| load_setting_u16_optional(key).map(|limit| (kind, limit as usize)))
+        .collect()
+}
+
 fn load_size_u32(key_width: &str, key_height: &str) -> Size<u32> {
     Size {
         width: load_setting_u32(key_width),
@@ -40,6 +96,15 @@ fn load_size_u32(key_width: &str, key_height: &str) -> Size<u32> {
     }
 }
 
+const CANVAS_MAX_SIZE_CM_DEFAULT: f32 = 150.0;
+const IMG_MAX_MEGAPIXELS_DEFAULT: u64 = 25;
+const IMG_TTL_SECS_DEFAULT: u64 = 60 * 60;
+const IMG_SWEEP_INTERVAL_SECS_DEFAULT: u64 = 60;
+const IMG_MEMORY_BUDGET_MIB_DEFAULT: u64 = 1024;
+const CLIENT_MAX_QUEUED_WORKS_DEFAULT: u64 = 8;
+const WORK_RESULT_TTL_SECS_DEFAULT: u64 = 60 * 60;
+const STORAGE_PATH_DEFAULT: &str = "./storage";
+
 const DOT_ENV_ALTERNATIVE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/.env");
 
 impl Settings {
@@ -56,9 +121,26 @@ impl Settings {
             image_max_bytes: (load_setting_u16("IMG_MAX_KIB") as usize) * 1024,
             image_min_size: load_size_u32("IMG_MIN_WIDTH", "IMG_MIN_HEIGHT"),
             image_max_size: load_size_u32("IMG_MAX_WIDTH", "IMG_MAX_HEIGHT"),
+            image_max_pixels: load_setting_u64_or_default("IMG_MAX_MEGAPIXELS", IMG_MAX_MEGAPIXELS_DEFAULT) * 1_000_000,
             log_level: dotenv::var("LOG_LEVEL").unwrap_or("info".to_string()),
             workers_count: load_setting_u16("WORKERS_COUNT") as usize,
+            work_kind_limits: load_work_kind_limits(),
+            client_max_queued_works: load_setting_u64_or_default("CLIENT_MAX_QUEUED_WORKS", CLIENT_MAX_QUEUED_WORKS_DEFAULT) as usize,
+            work_result_ttl: std::time::Duration::from_secs(load_setting_u64_or_default("WORK_RESULT_TTL_SECS", WORK_RESULT_TTL_SECS_DEFAULT)),
             dmc_palette_path: load_setting_string("DMC_PALETTE_PATH"),
+            canvas_max_size_cm: Size {
+                width: load_setting_f32_or_default("CANVAS_MAX_WIDTH_CM", CANVAS_MAX_SIZE_CM_DEFAULT),
+                height: load_setting_f32_or_default("CANVAS_MAX_HEIGHT_CM", CANVAS_MAX_SIZE_CM_DEFAULT),
+            },
+            image_ttl: std::time::Duration::from_secs(load_setting_u64_or_default("IMG_TTL_SECS", IMG_TTL_SECS_DEFAULT)),
+            image_sweep_interval: std::time::Duration::from_secs(
+                load_setting_u64_or_default("IMG_SWEEP_INTERVAL_SECS", IMG_SWEEP_INTERVAL_SECS_DEFAULT)
+            ),
+            image_memory_budget_bytes: (load_setting_u64_or_default("IMG_MEMORY_BUDGET_MIB", IMG_MEMORY_BUDGET_MIB_DEFAULT) as usize) * 1024 * 1024,
+            storage_backend: dotenv::var("STORAGE_BACKEND")
+                .map(|value| value.parse().unwrap_or_else(|e| panic!("'STORAGE_BACKEND' {e}")))
+                .unwrap_or_default(),
+            storage_path: load_setting_string_or_default("STORAGE_PATH", STORAGE_PATH_DEFAULT.to_string()),
         }
     }
 }
@@ -71,9 +153,19 @@ impl Default for Settings {
             image_max_bytes: 4096 * 1024,
             image_min_size: Size { width: 100, height: 100 },
             image_max_size: Size { width: 5000, height: 5000 },
+            image_max_pixels: IMG_MAX_MEGAPIXELS_DEFAULT * 1_000_000,
             log_level: "info".to_string(),
             workers_count: 2,
+            work_kind_limits: HashMap::new(),
+            client_max_queued_works: CLIENT_MAX_QUEUED_WORKS_DEFAULT as usize,
+            work_result_ttl: std::time::Duration::from_secs(WORK_RESULT_TTL_SECS_DEFAULT),
             dmc_palette_path: "./res/palette_dmc_full.json".to_string(),
+            canvas_max_size_cm: Size { width: CANVAS_MAX_SIZE_CM_DEFAULT, height: CANVAS_MAX_SIZE_CM_DEFAULT },
+            image_ttl: std::time::Duration::from_secs(IMG_TTL_SECS_DEFAULT),
+            image_sweep_interval: std::time::Duration::from_secs(IMG_SWEEP_INTERVAL_SECS_DEFAULT),
+            image_memory_budget_bytes: (IMG_MEMORY_BUDGET_MIB_DEFAULT as usize) * 1024 * 1024,
+            storage_backend: StorageBackendKind::default(),
+            storage_path: STORAGE_PATH_DEFAULT.to_string(),
         }
     }
 }
\ No newline at end of file
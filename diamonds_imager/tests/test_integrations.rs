@@ -4,8 +4,22 @@ use std::{
 };
 
 use diamonds_imager::app::app_serve;
-use diamonds_imager::results::{GetPaletteResult, UploadImageResult};
-use diamonds_imager::services::{ImageId, ImageStorageMeta};
+use diamonds_imager::results::{
+    CancelProcessingResult, 
+    FinishPaletteExtractionResult, 
+    GetPaletteResult, 
+    ImageHistoryResult, 
+    ImageMetaResult, 
+    ProcessingDoneEvent, 
+    ProcessingStatusResult, 
+    StartPaletteExtractionResult, 
+    StartProcessingResult, 
+    StorageStatsResult, 
+    TransformStackResult, 
+    UploadImageResult
+};
+use diamonds_imager::services::processing::status::WorkStatus;
+use diamonds_imager::services::{ImageId, ImageWorkKind};
 use diamonds_imager::settings::Settings;
 use reqwest::Client;
 
@@ -38,6 +52,63 @@ async fn upload_test_image(root_url: &str, client: &Client, filename: &str, endp
         .await
 }
 
+/// Half transparent PNG of 200x100 pixels, the left half is transparent, the right one opaque red.
+/// EXIF orientation 6 asks for a clockwise rotation to show it upright.
+fn transparent_rotated_png() -> Vec<u8> {
+    let image = image::RgbaImage::from_fn(200, 100, |x, _| {
+        if x < 100 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([200, 0, 0, 255]) }
+    });
+
+    // Big endian TIFF with a single IFD holding the orientation
+    let mut exif = b"MM\0\x2a\0\0\0\x08".to_vec();
+    exif.extend(1u16.to_be_bytes());
+    exif.extend(0x0112u16.to_be_bytes());
+    exif.extend(3u16.to_be_bytes());
+    exif.extend(1u32.to_be_bytes());
+    exif.extend([0, 6, 0, 0]);
+    exif.extend(0u32.to_be_bytes());
+
+    let mut png = Vec::new();
+    let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+    image::ImageEncoder::set_exif_metadata(&mut encoder, exif).unwrap();
+    image::ImageEncoder::write_image(encoder, image.as_raw(), 200, 100, image::ExtendedColorType::Rgba8).unwrap();
+    png
+}
+
+/// PNG of a single color.
+fn plain_png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(width, height, image::Rgb([40, 80, 120]))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    png.into_inner()
+}
+
+fn upload_part(bytes: Vec<u8>, mime: &str) -> reqwest::multipart::Part {
+    reqwest::multipart::Part::bytes(bytes)
+        .file_name("upload.png")
+        .mime_str(mime)
+        .unwrap()
+}
+
+async fn upload_form(root_url: &str, client: &Client, form: reqwest::multipart::Form) -> reqwest::Response {
+    client.post(format!("{root_url}/api/upload"))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn upload_png_bytes(root_url: &str, client: &Client, png: Vec<u8>, query: &[(&str, &str)]) -> Result<reqwest::Response, reqwest::Error> {
+    let form = reqwest::multipart::Form::new().part("file", upload_part(png, "image/png"));
+
+    client.post(format!("{root_url}/api/upload"))
+        .query(query)
+        .multipart(form)
+        .send()
+        .await
+}
+
 async fn get_test_full_dmc_palette(root_url: &str, client: &Client) -> Result<GetPaletteResult, reqwest::Error> {
     let response = client.get(format!("{root_url}/api/palette/dmc"))
         .send()
@@ -52,7 +123,7 @@ async fn upload_basic_good_image(root_url: &str, client: &Client) -> Result<Uplo
     response.json().await
 }
 
-async fn get_test_image_meta(root_url: &str, client: &Client, id: &ImageId) -> Result<Option<ImageStorageMeta>, reqwest::Error> {
+async fn get_test_image_meta(root_url: &str, client: &Client, id: &ImageId) -> Result<Option<ImageMetaResult>, reqwest::Error> {
     let response = client.get(format!("{root_url}/api/image/{id}"))
     .send()
     .await?;
@@ -72,6 +143,53 @@ async fn delete_test_image(root_url: &str, client: &Client, id: &ImageId) -> Res
     Ok(response.status().is_success())
 }
 
+/// Polls `url` until processing is ready - anything else than `202 Accepted`.
+async fn poll_until_ready(client: &Client, url: &str) -> reqwest::Response {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(60);
+
+    loop {
+        let response = client.get(url).send().await.unwrap();
+        if response.status() != reqwest::StatusCode::ACCEPTED {
+            assert!(response.status().is_success(), "Polling failed: {:?}", response);
+            return response;
+        }
+
+        assert!(tokio::time::Instant::now() < deadline, "Processing took too long");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+/// Reads the next Server-Sent Event as its name and data, keep-alive comments are skipped.
+async fn next_sse_event(response: &mut reqwest::Response, buffer: &mut String) -> (String, String) {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(60);
+
+    loop {
+        while let Some(end) = buffer.find("\n\n") {
+            let frame = buffer[..end].to_string();
+            buffer.drain(..end + 2);
+
+            let mut event = String::new();
+            let mut data = String::new();
+            for line in frame.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    event = value.trim().to_string();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push_str(value.trim());
+                }
+            }
+            if !event.is_empty() {
+                return (event, data);
+            }
+        }
+
+        let chunk = tokio::time::timeout_at(deadline, response.chunk()).await
+            .expect("Event took too long")
+            .unwrap()
+            .expect("Stream should not end");
+        buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+}
+
 static SERVER_LOCK: tokio::sync::OnceCell<tokio::sync::Mutex<()>> = tokio::sync::OnceCell::const_new();
 
 async fn acquire_server_lock<'a>() -> tokio::sync::MutexGuard<'a, ()> {
@@ -82,13 +200,21 @@ async fn acquire_server_lock<'a>() -> tokio::sync::MutexGuard<'a, ()> {
 }
 
 async fn setup_server_environment_with_client<F, Fut>(test_procedure: F)
+where 
+    F: FnOnce(String, Client) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static
+{
+    setup_server_environment_with_settings(Settings::default(), test_procedure).await
+}
+
+async fn setup_server_environment_with_settings<F, Fut>(settings: Settings, test_procedure: F)
 where 
     F: FnOnce(String, Client) -> Fut + Send + 'static,
     Fut: Future<Output = ()> + Send + 'static
 {
     let _guard = acquire_server_lock().await;
     {
-        let serve_handle = app_serve(Settings::default()).await.unwrap();
+        let serve_handle = app_serve(settings).await.unwrap();
         let client = reqwest::Client::new();
     
         test_procedure(serve_handle.get_url(), client).await;
@@ -225,6 +351,73 @@ mod test_uploading_image {
         }).await;
     }
 
+    #[tokio::test]
+    async fn test_upload_too_small_image_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let min_size = Settings::default().image_min_size;
+
+            for (width, height) in [(min_size.width - 1, min_size.height), (min_size.width, min_size.height - 1)] {
+                let form = reqwest::multipart::Form::new().part("file", upload_part(plain_png(width, height), "image/png"));
+                let response = upload_form(&root_url, &client, form).await;
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "Size {width}x{height}");
+            }
+
+            let form = reqwest::multipart::Form::new().part("file", upload_part(plain_png(min_size.width, min_size.height), "image/png"));
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::OK);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_too_many_pixels_should_result_err() {
+        let settings = Settings { image_max_pixels: 300 * 200, ..Default::default() };
+
+        setup_server_environment_with_settings(settings, |root_url, client| async move {
+            let form = reqwest::multipart::Form::new().part("file", upload_part(plain_png(300, 201), "image/png"));
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+            let form = reqwest::multipart::Form::new().part("file", upload_part(plain_png(300, 200), "image/png"));
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::OK);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_not_allowed_format_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let mut tga = std::io::Cursor::new(Vec::new());
+            image::RgbImage::new(120, 120).write_to(&mut tga, image::ImageFormat::Tga).unwrap();
+
+            let cases = [
+                (tga.into_inner(), "application/octet-stream"),
+                (b"not an image at all".to_vec(), "image/png"),
+                (plain_png(120, 120), "text/plain"),
+                (plain_png(120, 120), "image/x-tga"),
+            ];
+            for (bytes, mime) in cases {
+                let form = reqwest::multipart::Form::new().part("file", upload_part(bytes, mime));
+                let response = upload_form(&root_url, &client, form).await;
+                assert_eq!(response.status(), reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE, "Media type {mime}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_unexpected_fields_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let form = reqwest::multipart::Form::new().part("image", upload_part(plain_png(120, 120), "image/png"));
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            let form = reqwest::multipart::Form::new()
+                .part("file", upload_part(plain_png(120, 120), "image/png"))
+                .part("file", upload_part(plain_png(120, 120), "image/png"));
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            let form = reqwest::multipart::Form::new()
+                .part("file", upload_part(plain_png(120, 120), "image/png"))
+                .text("comment", "hello");
+            assert_eq!(upload_form(&root_url, &client, form).await.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
     #[tokio::test]
     async fn test_upload_multiple_identical_image_different_ids() {
         setup_server_environment_with_client( |root_url, client| async move {
@@ -235,6 +428,28 @@ mod test_uploading_image {
             assert_eq!(upload_img_result1.height, upload_img_result2.height);
         }).await;
     }
+
+    #[tokio::test]
+    async fn test_upload_identical_image_is_stored_once() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result1 = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let upload_img_result2 = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let stats: StorageStatsResult = client.get(format!("{root_url}/api/admin/storage")).send().await.unwrap().json().await.unwrap();
+            assert_eq!(stats.images.images_count, 2);
+            assert_eq!(stats.images.deduplicated_count, 1);
+            assert_eq!(stats.images.memory_used_bytes, (upload_img_result1.width * upload_img_result1.height * 3) as usize);
+
+            // Uploads stay independent
+            let crop = serde_json::json!({ "op": "crop", "x": 0, "y": 0, "width": 50, "height": 40 });
+            client.post(format!("{root_url}/api/image/{}/transform", upload_img_result1.id)).json(&crop).send().await.unwrap();
+            let transforms: TransformStackResult = client.get(format!("{root_url}/api/image/{}/transform", upload_img_result2.id))
+                .send().await.unwrap().json().await.unwrap();
+            assert!(transforms.transforms.is_empty());
+            assert!(delete_test_image(&root_url, &client, &upload_img_result1.id).await.unwrap());
+            assert!(get_test_image_meta(&root_url, &client, &upload_img_result2.id).await.unwrap().is_some());
+        }).await;
+    }
 }
 
 #[cfg(test)]
@@ -266,6 +481,7 @@ mod test_image_access_delete {
             // Get image meta
             let image_meta_should_be_ok = get_test_image_meta(&root_url, &client, &id).await.unwrap().unwrap();
             println!("{image_meta_should_be_ok:?}");
+            assert_eq!(image_meta_should_be_ok.meta.filename, "pinkflower_300.jpg");
 
             let was_deleted = delete_test_image(&root_url, &client, &id).await.unwrap();
             assert!(was_deleted);
@@ -277,21 +493,1044 @@ mod test_image_access_delete {
             assert!(image_meta_result_should_be_none.is_none());
         }).await;
     }
+
+    #[tokio::test]
+    async fn test_get_image_meta_describes_source() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let meta = get_test_image_meta(&root_url, &client, &upload_result.id).await.unwrap().unwrap();
+
+            let file_size = std::fs::metadata(Path::new(TEST_IMAGES_PATH).join("pinkflower_300.jpg")).unwrap().len();
+            let source = &meta.meta.source;
+            assert_eq!((source.width, source.height), (upload_result.width, upload_result.height));
+            assert_eq!(source.format.as_deref(), Some("jpeg"));
+            assert_eq!(source.byte_size as u64, file_size);
+            assert!(source.colors.unique_count > 1);
+            assert!(!source.colors.dominant.is_empty());
+            assert!(source.colors.dominant.windows(2).all(|pair| pair[0].share >= pair[1].share));
+
+            // Whole image fits the largest canvas with its aspect ratio kept
+            let grid = &meta.drill_grid;
+            let max_grid = (grid.canvas_width_cm * 4.0) as u32;
+            assert!(grid.width <= max_grid && grid.height <= max_grid);
+            assert!(grid.width == max_grid || grid.height == max_grid);
+            let aspect = upload_result.width as f32 / upload_result.height as f32;
+            assert!((grid.width as f32 / grid.height as f32 - aspect).abs() < 0.05);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_image_meta_drill_grid_follows_ordered_canvas() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_result.id);
+
+            let response = client.post(&preview_url)
+                .query(&[("canvas_width_cm", "10"), ("canvas_height_cm", "10")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let meta = get_test_image_meta(&root_url, &client, &upload_result.id).await.unwrap().unwrap();
+            let grid = &meta.drill_grid;
+            assert_eq!((grid.canvas_width_cm, grid.canvas_height_cm), (10.0, 10.0));
+            assert_eq!((grid.width, grid.height), (40, 40), "Grid of the ordered canvas");
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_applies_orientation_and_no_drill() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let response = upload_png_bytes(&root_url, &client, transparent_rotated_png(), &[("transparency", "no_drill")]).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let upload_result: UploadImageResult = response.json().await.unwrap();
+            assert_eq!((upload_result.width, upload_result.height), (100, 200), "Image is rotated upright");
+
+            let image_url = format!("{root_url}/api/image/{}", upload_result.id);
+            let history: ImageHistoryResult = client.get(format!("{image_url}/revisions")).send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.revisions[0].no_drill_count, 100 * 100);
+
+            let meta = get_test_image_meta(&root_url, &client, &upload_result.id).await.unwrap().unwrap();
+            assert_eq!(meta.meta.source.exif.orientation, Some(6));
+
+            // Transparent left half is the top half once rotated, it stays blank
+            let preview_url = format!("{root_url}/api/preview/{}", upload_result.id);
+            let response = client.post(&preview_url).query(&[("palette", "DMC 310")]).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap().to_rgb8();
+            assert_eq!(preview.dimensions(), (100, 200));
+            for (_, y, pixel) in preview.enumerate_pixels() {
+                let expected = if y < 100 { image::Rgb([255, 255, 255]) } else { image::Rgb([0, 0, 0]) };
+                assert_eq!(*pixel, expected, "Pixel at row {y}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_composites_transparency_on_background() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let response = upload_png_bytes(&root_url, &client, transparent_rotated_png(), &[("background", "#00ff00")]).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let upload_result: UploadImageResult = response.json().await.unwrap();
+
+            let meta = get_test_image_meta(&root_url, &client, &upload_result.id).await.unwrap().unwrap();
+            let mut colors = meta.meta.source.colors.dominant.iter().map(|dominant| dominant.color.as_str()).collect::<Vec<_>>();
+            colors.sort();
+            assert_eq!(colors, ["#00ff00", "#c80000"]);
+
+            let history: ImageHistoryResult = client.get(format!("{root_url}/api/image/{}/revisions", upload_result.id))
+                .send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.revisions[0].no_drill_count, 0);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_with_invalid_transparency_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let queries: [&[(&str, &str)]; 2] = [
+                &[("background", "not a color")],
+                &[("transparency", "no_drill"), ("background", "ffffff")],
+            ];
+            for query in queries {
+                let response = upload_png_bytes(&root_url, &client, transparent_rotated_png(), query).await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "Query {query:?}");
+            }
+        }).await;
+    }
+}
+
+
+mod test_image_transform {
+    use crate::*;
+
+    #[tokio::test]
+    async fn test_transform_stack_applies_to_preview() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let transform_url = format!("{root_url}/api/image/{}/transform", upload_img_result.id);
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let ops = [
+                serde_json::json!({ "op": "crop", "x": 10, "y": 20, "width": 120, "height": 80 }),
+                serde_json::json!({ "op": "rotate", "degrees": 90 }),
+                serde_json::json!({ "op": "contrast", "value": 0.3 }),
+            ];
+            for op in ops {
+                let response = client.post(&transform_url).json(&op).send().await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+            }
+
+            let transforms: TransformStackResult = client.get(&transform_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(transforms.transforms.ops().len(), 3);
+            assert_eq!((transforms.width, transforms.height), (80, 120));
+
+            let response = client.post(&preview_url).send().await.unwrap();
+            let start_result: StartProcessingResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!((preview.width(), preview.height()), (80, 120));
+
+            // Rotation first, then crop
+            let response = client.post(format!("{transform_url}/reorder"))
+                .json(&serde_json::json!({ "order": [1, 0, 2] }))
+                .send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let transforms: TransformStackResult = response.json().await.unwrap();
+            assert_eq!((transforms.width, transforms.height), (120, 80));
+
+            let response = client.delete(format!("{transform_url}/2")).send().await.unwrap();
+            let transforms: TransformStackResult = response.json().await.unwrap();
+            assert_eq!(transforms.transforms.ops().len(), 2);
+
+            let response = client.delete(&transform_url).send().await.unwrap();
+            let transforms: TransformStackResult = response.json().await.unwrap();
+            assert!(transforms.transforms.is_empty());
+            assert_eq!((transforms.width, transforms.height), (upload_img_result.width, upload_img_result.height));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transform_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let transform_url = format!("{root_url}/api/image/{}/transform", upload_img_result.id);
+
+            let crop_outside = serde_json::json!({ "op": "crop", "x": upload_img_result.width, "y": 0, "width": 10, "height": 10 });
+            let response = client.post(&transform_url).json(&crop_outside).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            let response = client.put(&transform_url)
+                .json(&serde_json::json!([{ "op": "flip_vertical" }, { "op": "gamma", "value": 50.0 }]))
+                .send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            let response = client.post(format!("{transform_url}/reorder"))
+                .json(&serde_json::json!({ "order": [1] }))
+                .send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            // Every arbitrary rotation enlarges the image beyond upload limits at last
+            let rotations = vec![serde_json::json!({ "op": "rotate", "degrees": 45 }); 10];
+            let response = client.put(&transform_url).json(&rotations).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            let flips = vec![serde_json::json!({ "op": "flip_vertical" }); 100];
+            let response = client.put(&transform_url).json(&flips).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            // Rejected edits are not stored
+            let transforms: TransformStackResult = client.get(&transform_url).send().await.unwrap().json().await.unwrap();
+            assert!(transforms.transforms.is_empty());
+        }).await;
+    }
+}
+
+#[cfg(test)]
+mod test_image_expiry {
+    use std::time::Duration;
+
+    use crate::*;
+
+    fn short_ttl_settings() -> Settings {
+        Settings {
+            image_ttl: Duration::from_millis(800),
+            image_sweep_interval: Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expired_image_should_result_gone() {
+        setup_server_environment_with_settings(short_ttl_settings(), |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let image_url = format!("{root_url}/api/image/{}", upload_img_result.id);
+
+            // Every access refreshes the TTL
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let response = client.get(&image_url).send().await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+            }
+
+            tokio::time::sleep(Duration::from_millis(1200)).await;
+            let response = client.get(&image_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::GONE);
+            let response = client.post(format!("{root_url}/api/preview/{}", upload_img_result.id)).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::GONE);
+
+            let response = client.get(format!("{root_url}/api/image/unknown_id.png")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        }).await;
+    }
+}
+
+#[cfg(test)]
+mod test_image_memory_budget {
+    use crate::*;
+
+    #[tokio::test]
+    async fn test_evicted_image_is_reloaded() {
+        // Budget fits no image, only the last touched one stays in memory
+        let settings = Settings { image_memory_budget_bytes: 1, ..Default::default() };
+        setup_server_environment_with_settings(settings, |root_url, client| async move {
+            let upload_img_result1 = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let upload_img_result2 = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let stats_url = format!("{root_url}/api/admin/storage");
+
+            let stats: StorageStatsResult = client.get(&stats_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(stats.images.images_count, 2);
+            assert_eq!(stats.images.spilled_count, 1);
+            assert_eq!(stats.images.memory_used_bytes, (upload_img_result2.width * upload_img_result2.height * 3) as usize);
+            assert_eq!(stats.images.spilled_bytes, stats.images.memory_used_bytes);
+
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result1.id);
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!((preview.width(), preview.height()), (upload_img_result1.width, upload_img_result1.height));
+
+            let stats: StorageStatsResult = client.get(&stats_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(stats.images.reloads_count, 1);
+            assert_eq!(stats.images.evictions_count, 2);
+            assert_eq!(stats.images.resident_count, 1);
+        }).await;
+    }
 }
 
+#[cfg(test)]
+mod test_persistent_storage {
+    use diamonds_imager::services::storage::StorageBackendKind;
+
+    use crate::*;
+
+    #[tokio::test]
+    async fn test_images_survive_restart() {
+        let _guard = acquire_server_lock().await;
+        let storage_dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            storage_backend: StorageBackendKind::Sqlite,
+            storage_path: storage_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+
+        let serve_handle = app_serve(settings.clone()).await.unwrap();
+        let root_url = serve_handle.get_url();
+        let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+        let image_url = format!("{root_url}/api/image/{}", upload_img_result.id);
+        let crop = serde_json::json!({ "op": "crop", "x": 0, "y": 0, "width": 50, "height": 40 });
+        client.post(format!("{image_url}/transform")).json(&crop).send().await.unwrap();
+
+        let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+        client.post(&preview_url).send().await.unwrap();
+        let preview_png = poll_until_ready(&client, &preview_url).await.bytes().await.unwrap();
+        let history: ImageHistoryResult = client.get(format!("{image_url}/revisions")).send().await.unwrap().json().await.unwrap();
+        serve_handle.shutdown_gracefully_await().await.unwrap();
+
+        let serve_handle = app_serve(settings).await.unwrap();
+        let root_url = serve_handle.get_url();
+        let image_url = format!("{root_url}/api/image/{}", upload_img_result.id);
+
+        let loaded_history: ImageHistoryResult = client.get(format!("{image_url}/revisions")).send().await.unwrap().json().await.unwrap();
+        assert_eq!(loaded_history.current, history.current);
+        assert_eq!(loaded_history.revisions.len(), history.revisions.len());
+        assert_eq!(loaded_history.revisions[1].transforms, history.revisions[1].transforms);
+
+        // Finished preview is served without rendering again
+        let response = client.get(format!("{root_url}/api/preview/{}", upload_img_result.id)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), preview_png);
+
+        assert!(delete_test_image(&root_url, &client, &upload_img_result.id).await.unwrap());
+        serve_handle.shutdown_gracefully_await().await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_image_history {
+    use crate::*;
+
+    #[tokio::test]
+    async fn test_undo_redo_jump() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let image_url = format!("{root_url}/api/image/{}", upload_img_result.id);
+            let revisions_url = format!("{image_url}/revisions");
+
+            let crop = serde_json::json!({ "op": "crop", "x": 0, "y": 0, "width": 50, "height": 40 });
+            let response = client.post(format!("{image_url}/transform")).json(&crop).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let response = client.put(format!("{image_url}/palette"))
+                .json(&serde_json::json!({ "palette": ["DMC 310", "DMC 3865"] }))
+                .send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let history: ImageHistoryResult = client.get(&revisions_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.current, 2);
+            assert_eq!(history.revisions.len(), 3);
+            assert_eq!(history.revisions[2].transforms.ops().len(), 1, "Palette revision keeps transforms");
+
+            let history: ImageHistoryResult = client.post(format!("{revisions_url}/undo")).send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.current, 1);
+            let history: ImageHistoryResult = client.post(format!("{revisions_url}/0")).send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.current, 0);
+
+            let response = client.post(format!("{revisions_url}/undo")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+
+            let history: ImageHistoryResult = client.post(format!("{revisions_url}/redo")).send().await.unwrap().json().await.unwrap();
+            assert_eq!(history.current, 1);
+
+            let response = client.post(format!("{revisions_url}/42")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+            let response = client.put(format!("{image_url}/palette"))
+                .json(&serde_json::json!({ "palette": ["DMC 99999"] }))
+                .send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_preview_of_revision() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let image_url = format!("{root_url}/api/image/{}", upload_img_result.id);
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let crop = serde_json::json!({ "op": "crop", "x": 0, "y": 0, "width": 50, "height": 40 });
+            client.post(format!("{image_url}/transform")).json(&crop).send().await.unwrap();
+
+            // Original upload, even though the current revision is cropped
+            let response = client.post(format!("{preview_url}?revision=0")).send().await.unwrap();
+            let start_result: StartProcessingResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!((preview.width(), preview.height()), (upload_img_result.width, upload_img_result.height));
+
+            let response = client.post(format!("{preview_url}?revision=42")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        }).await;
+    }
+}
 
 #[cfg(test)]
 mod test_processing {
     use super::*;
 
-    // #[tokio::test]
-    // async fn upload_good_image_check_status_should_be_ready_for_processing() {
-    //     setup_server_environment_with_client( |root_url, client| async move {
-    //         let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
-    //         println!("Got upload_img_result={upload_img_result:?}");
-
-    //         // Check status endpoint like this /processing/{id}/status
-    //         //TODO
-    //         }).await;
-    // }
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_poll_palette_extraction_not_started_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.get(format!("{root_url}/api/palette/extract/{}", upload_img_result.id))
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_and_poll_until_finished() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let max_colors = 8;
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let extract_url = format!("{root_url}/api/palette/extract/{}", upload_img_result.id);
+
+            let response = client.post(format!("{extract_url}?max_colors={max_colors}"))
+                .send()
+                .await.unwrap();
+            let start_result: StartPaletteExtractionResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+
+            let extraction_result = poll_until_ready(&client, &extract_url).await;
+            let extraction_result: FinishPaletteExtractionResult = extraction_result.json().await.unwrap();
+            let dmc_bom = extraction_result.result.expect("Extraction should be finished");
+            assert!(!dmc_bom.is_empty());
+            assert!(dmc_bom.len() <= max_colors);
+
+            // Finished result stays available
+            let response = client.get(&extract_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let extraction_result_again: FinishPaletteExtractionResult = response.json().await.unwrap();
+            assert_eq!(extraction_result_again.result, Some(dmc_bom));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_with_color_distance_counts_every_pixel() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let extract_url = format!("{root_url}/api/palette/extract/{}", upload_img_result.id);
+
+            let response = client.post(format!("{extract_url}?color_distance=ciede2000"))
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let extraction_result = poll_until_ready(&client, &extract_url).await;
+            let extraction_result: FinishPaletteExtractionResult = extraction_result.json().await.unwrap();
+            let dmc_bom = extraction_result.result.expect("Extraction should be finished");
+            let pixels_count = dmc_bom.iter().map(|entry| entry.count).sum::<u32>();
+            assert_eq!(pixels_count, upload_img_result.width * upload_img_result.height);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_by_clustering() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let extract_url = format!("{root_url}/api/palette/extract/{}", upload_img_result.id);
+
+            for mode in ["k_means", "median_cut"] {
+                // Only one extraction per image at once, wait for the previous one
+                let response = client.post(format!("{extract_url}?mode={mode}&max_colors=6"))
+                    .send()
+                    .await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+                let extraction_result = poll_until_ready(&client, &extract_url).await;
+                let extraction_result: FinishPaletteExtractionResult = extraction_result.json().await.unwrap();
+                let dmc_bom = extraction_result.result.expect("Extraction should be finished");
+                assert!(!dmc_bom.is_empty() && dmc_bom.len() <= 6, "{mode}");
+
+                let pixels_count = dmc_bom.iter().map(|entry| entry.count).sum::<u32>();
+                assert_eq!(pixels_count, upload_img_result.width * upload_img_result.height, "{mode}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_by_k_means_with_seed_is_reproducible() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let extract_url = format!("{root_url}/api/palette/extract/{}", upload_img_result.id);
+
+            let mut dmc_boms = vec![];
+            for _ in 0..2 {
+                let response = client.post(format!("{extract_url}?mode=k_means&max_colors=6&seed=7&restarts=2"))
+                    .send()
+                    .await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+                let extraction_result = poll_until_ready(&client, &extract_url).await;
+                let extraction_result: FinishPaletteExtractionResult = extraction_result.json().await.unwrap();
+                dmc_boms.push(extraction_result.result.expect("Extraction should be finished"));
+            }
+            assert_eq!(dmc_boms[0], dmc_boms[1]);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_with_too_many_restarts_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.post(format!("{root_url}/api/palette/extract/{}?mode=k_means&restarts=100", upload_img_result.id))
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_with_max_colors_out_of_range_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            for (mode, max_colors) in [("median_cut", 0), ("k_means", 0), ("nearest", 257)] {
+                let response = client.post(format!("{root_url}/api/palette/extract/{}?mode={mode}&max_colors={max_colors}", upload_img_result.id))
+                    .send()
+                    .await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{mode} {max_colors}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_extract_palette_with_unknown_color_distance_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.post(format!("{root_url}/api/palette/extract/{}?color_distance=cmyk", upload_img_result.id))
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_render_pdf_and_poll_until_finished() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let pdf_url = format!("{root_url}/api/pdf/{}", upload_img_result.id);
+
+            let response = client.post(&pdf_url).send().await.unwrap();
+            let start_result: StartProcessingResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+
+            let response = poll_until_ready(&client, &pdf_url).await;
+            assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "application/pdf");
+
+            let pdf = response.bytes().await.unwrap();
+            assert!(pdf.starts_with(b"%PDF-"));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_palette_subset_and_drills() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let drill_size = 4;
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let response = client.post(&preview_url)
+                .query(&[("palette", "DMC 310,DMC 3865,DMC 602".to_string()), ("drill_size", drill_size.to_string())])
+                .send()
+                .await.unwrap();
+            let start_result: StartProcessingResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+
+            let response = poll_until_ready(&client, &preview_url).await;
+            assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "image/png");
+
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap().to_rgb8();
+            assert_eq!(preview.width(), upload_img_result.width * drill_size);
+            assert_eq!(preview.height(), upload_img_result.height * drill_size);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_unknown_dmc_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.post(format!("{root_url}/api/preview/{}", upload_img_result.id))
+                .query(&[("palette", "DMC 310,not a DMC")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_processing_status_reports_progress_until_done() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let status_url = format!("{root_url}/api/processing/{}", upload_img_result.id);
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let status: ProcessingStatusResult = client.get(&status_url).send().await.unwrap().json().await.unwrap();
+            assert!(status.works.is_empty(), "Nothing was started yet");
+
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(60);
+            let mut last_progress = 0;
+            loop {
+                let status: ProcessingStatusResult = client.get(&status_url).send().await.unwrap().json().await.unwrap();
+                assert_eq!(status.works.len(), 1);
+                assert_eq!(status.works[0].kind, ImageWorkKind::Preview);
+
+                match status.works[0].status {
+                    WorkStatus::Queued | WorkStatus::Assigned { .. } => {},
+                    WorkStatus::Running { progress, .. } => {
+                        assert!(progress >= last_progress && progress <= 100, "Progress {progress} after {last_progress}");
+                        last_progress = progress;
+                    },
+                    WorkStatus::Done => break,
+                    ref status => panic!("Unexpected status {status:?}"),
+                }
+
+                assert!(tokio::time::Instant::now() < deadline, "Processing took too long");
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+
+            // Done means the result is ready
+            let response = client.get(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let status: ProcessingStatusResult = client.get(&status_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(status.works[0].status, WorkStatus::Done);
+
+            let response = client.get(format!("{root_url}/api/processing/not-an-image")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_processing_events_push_progress_and_result() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let mut events = client.get(format!("{root_url}/api/processing/{}/events", upload_img_result.id)).send().await.unwrap();
+            assert_eq!(events.status(), reqwest::StatusCode::OK);
+            assert_eq!(events.headers()[reqwest::header::CONTENT_TYPE], "text/event-stream");
+            let mut buffer = String::new();
+
+            let (event, data) = next_sse_event(&mut events, &mut buffer).await;
+            assert_eq!(event, "status");
+            let status: ProcessingStatusResult = serde_json::from_str(&data).unwrap();
+            assert!(status.works.is_empty(), "Nothing was started yet");
+
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let mut last_progress = 0;
+            let done = loop {
+                let (event, data) = next_sse_event(&mut events, &mut buffer).await;
+                match event.as_str() {
+                    "status" => {
+                        let status: ProcessingStatusResult = serde_json::from_str(&data).unwrap();
+                        assert_eq!(status.works.len(), 1);
+                        if let WorkStatus::Running { progress, .. } = status.works[0].status {
+                            assert!(progress >= last_progress, "Progress {progress} after {last_progress}");
+                            last_progress = progress;
+                        }
+                    },
+                    "done" => break serde_json::from_str::<ProcessingDoneEvent>(&data).unwrap(),
+                    event => panic!("Unexpected event {event}"),
+                }
+            };
+
+            assert_eq!(done.kind, ImageWorkKind::Preview);
+            assert!(done.palette.is_none());
+            let result_url = done.url.expect("Preview is downloaded from URL");
+            assert_eq!(result_url, format!("/api/preview/{}", upload_img_result.id));
+
+            // Result was collected by the stream, it is still available
+            let response = client.get(format!("{root_url}{result_url}")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "image/png");
+
+            let response = client.get(format!("{root_url}/api/processing/not-an-image/events")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_processing_events_end_on_shutdown() {
+        let _guard = acquire_server_lock().await;
+        let serve_handle = app_serve(Settings::default()).await.unwrap();
+        let root_url = serve_handle.get_url();
+        let client = reqwest::Client::new();
+
+        let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+        let mut events = client.get(format!("{root_url}/api/processing/{}/events", upload_img_result.id)).send().await.unwrap();
+        assert_eq!(events.status(), reqwest::StatusCode::OK);
+        let mut buffer = String::new();
+        let (event, _) = next_sse_event(&mut events, &mut buffer).await;
+        assert_eq!(event, "status");
+
+        // Open stream doesn't hold the server from shutting down
+        tokio::time::timeout(std::time::Duration::from_secs(10), serve_handle.shutdown_gracefully_await()).await
+            .expect("Shutdown took too long")
+            .unwrap();
+
+        let rest = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while events.chunk().await.unwrap().is_some() {}
+        }).await;
+        assert!(rest.is_ok(), "Stream should end");
+    }
+
+    /// Uploads image large enough to be processed for a long time.
+    async fn upload_slow_image(root_url: &str, client: &Client) -> UploadImageResult {
+        let response = upload_png_bytes(root_url, client, plain_png(2000, 2000), &[]).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        response.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cancel_pdf_rendering_and_start_again() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_slow_image(&root_url, &client).await;
+            let pdf_url = format!("{root_url}/api/pdf/{}", upload_img_result.id);
+            let status_url = format!("{root_url}/api/processing/{}", upload_img_result.id);
+
+            let response = client.delete(&pdf_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND, "Nothing to cancel yet");
+
+            let response = client.post(&pdf_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = client.delete(&pdf_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let cancel_result: CancelProcessingResult = response.json().await.unwrap();
+            assert!(cancel_result.was_cancelled);
+
+            let response = client.get(&pdf_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::GONE);
+
+            let status: ProcessingStatusResult = client.get(&status_url).send().await.unwrap().json().await.unwrap();
+            assert_eq!(status.works[0].status, WorkStatus::Cancelled);
+
+            let response = client.delete(&pdf_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::CONFLICT, "Cancelled already");
+
+            // Cancelled work can be started again
+            let response = client.post(&pdf_url)
+                .query(&[("canvas_width_cm", "10"), ("canvas_height_cm", "10")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = poll_until_ready(&client, &pdf_url).await;
+            assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "application/pdf");
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_latest_preview_wins_over_the_one_in_progress() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_slow_image(&root_url, &client).await;
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = client.post(&preview_url)
+                .query(&[("canvas_width_cm", "10"), ("canvas_height_cm", "10")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS, "Preview in progress is kept by default");
+
+            let response = client.post(&preview_url)
+                .query(&[("canvas_width_cm", "10"), ("canvas_height_cm", "10"), ("latest_wins", "true")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert!(preview.width() < upload_img_result.width, "Preview of the latest request is ready");
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_result_not_collected_in_time_is_expired() {
+        let settings = Settings { work_result_ttl: std::time::Duration::from_millis(200), ..Default::default() };
+        setup_server_environment_with_settings(settings, |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let status_url = format!("{root_url}/api/processing/{}", upload_img_result.id);
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+            let extract_url = format!("{root_url}/api/palette/extract/{}", upload_img_result.id);
+
+            // Extraction is collected in time
+            let response = client.post(format!("{extract_url}?max_colors=8")).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            poll_until_ready(&client, &extract_url).await;
+
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            // Status is polled, the result is left uncollected
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(60);
+            loop {
+                let response = client.get(&status_url).send().await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+                let status: ProcessingStatusResult = response.json().await.unwrap();
+                let work_status = |kind| status.works.iter().find(|work| work.kind == kind).map(|work| work.status.clone());
+                assert_eq!(work_status(ImageWorkKind::PaletteExtract), Some(WorkStatus::Done));
+
+                let preview_status = work_status(ImageWorkKind::Preview).expect("Preview was started");
+                if preview_status == WorkStatus::Expired {
+                    break;
+                }
+                assert_ne!(preview_status, WorkStatus::Cancelled);
+                assert!(tokio::time::Instant::now() < deadline, "Result was not forgotten");
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+
+            let response = client.get(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+            // Forgotten work doesn't hold the image back
+            let response = client.post(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let response = poll_until_ready(&client, &preview_url).await;
+            assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "image/png");
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_starts_of_image_work_start_it_once() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_slow_image(&root_url, &client).await;
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            // Every start takes a while transforming the image before the work is queued
+            let transform_url = format!("{root_url}/api/image/{}/transform", upload_img_result.id);
+            let response = client.post(&transform_url).json(&serde_json::json!({ "op": "rotate", "degrees": 90 })).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let starts = (0..8).map(|_| client.post(&preview_url).send());
+            let statuses = futures_util::future::join_all(starts).await.into_iter()
+                .map(|response| response.unwrap().status())
+                .collect::<Vec<_>>();
+            assert_eq!(statuses.iter().filter(|status| **status == reqwest::StatusCode::OK).count(), 1, "{statuses:?}");
+            assert!(statuses.iter().all(|status| [reqwest::StatusCode::OK, reqwest::StatusCode::TOO_MANY_REQUESTS].contains(status)));
+
+            let response = client.delete(&preview_url).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_client_over_queue_quota_is_told_to_retry_later() {
+        let settings = Settings { workers_count: 1, client_max_queued_works: 1, ..Default::default() };
+        setup_server_environment_with_settings(settings, |root_url, client| async move {
+            let mut preview_urls = vec![];
+            for _ in 0..3 {
+                let upload_img_result = upload_slow_image(&root_url, &client).await;
+                preview_urls.push(format!("{root_url}/api/preview/{}", upload_img_result.id));
+            }
+
+            // The first preview is running, the second one fills the quota
+            for preview_url in &preview_urls[..2] {
+                let response = client.post(preview_url).send().await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+            }
+
+            let response = client.post(&preview_urls[2]).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+            let retry_after = response.headers()[reqwest::header::RETRY_AFTER].to_str().unwrap();
+            assert!(retry_after.parse::<u64>().unwrap() >= 1, "{retry_after}");
+
+            // Session chosen by the client shares the quota of its address
+            let response = client.post(&preview_urls[2])
+                .header("X-Session-Id", "other-session")
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+
+            // Room is made by cancelled work
+            let response = client.delete(&preview_urls[1]).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let response = client.post(&preview_urls[2])
+                .header("X-Session-Id", "other-session")
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            for preview_url in [&preview_urls[0], &preview_urls[2]] {
+                let response = client.delete(preview_url).send().await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_error_diffusion_kernel() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let response = client.post(&preview_url)
+                .query(&[("error_diffusion", "atkinson"), ("error_strength", "0.5")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(preview.width(), upload_img_result.width);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_ordered_dithering() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let response = client.post(&preview_url)
+                .query(&[("dithering", "blue_noise"), ("palette", "DMC 310,DMC 3865,DMC 602")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+            let response = poll_until_ready(&client, &preview_url).await;
+            let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap().to_rgb8();
+            let colors = preview.pixels().collect::<std::collections::HashSet<_>>();
+            assert!(colors.len() <= 3);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_confetti_cleanup() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let response = client.post(&preview_url)
+                .query(&[("confetti_min_size", "3")])
+                .send()
+                .await.unwrap();
+            let start_result: StartProcessingResult = response.json().await.unwrap();
+            assert!(start_result.was_started);
+
+            let response = poll_until_ready(&client, &preview_url).await;
+            let header_count = |name: &str| response.headers()[name].to_str().unwrap().parse::<usize>().unwrap();
+            assert!(header_count("x-confetti-after") <= header_count("x-confetti-before"));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_confetti_min_size_out_of_range_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.post(format!("{root_url}/api/preview/{}", upload_img_result.id))
+                .query(&[("confetti_min_size", "1")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_for_canvas() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            // 10x5 cm with 0.5 cm margin of 2.5 mm drills is 36x16 drills
+            for aspect in ["crop", "pad"] {
+                let response = client.post(&preview_url)
+                    .query(&[
+                        ("canvas_width_cm", "10"), 
+                        ("canvas_height_cm", "5"), 
+                        ("margin_cm", "0.5"), 
+                        ("drill", "square"),
+                        ("resample", "triangle"),
+                        ("aspect", aspect)
+                    ])
+                    .send()
+                    .await.unwrap();
+                let start_result: StartProcessingResult = response.json().await.unwrap();
+                assert!(start_result.was_started);
+
+                let response = poll_until_ready(&client, &preview_url).await;
+                let preview = image::load_from_memory(&response.bytes().await.unwrap()).unwrap().to_rgb8();
+                assert_eq!(preview.dimensions(), (36, 16), "{aspect}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_for_invalid_canvas_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            let invalid_canvases = [
+                vec![("canvas_width_cm", "10")],
+                vec![("canvas_width_cm", "10"), ("canvas_height_cm", "1000")],
+                vec![("canvas_width_cm", "10"), ("canvas_height_cm", "10"), ("margin_cm", "5")],
+                vec![("canvas_width_cm", "10"), ("canvas_height_cm", "10"), ("drill", "hexagon")],
+            ];
+            for query in invalid_canvases {
+                let response = client.post(&preview_url)
+                    .query(&query)
+                    .send()
+                    .await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{query:?}");
+            }
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_error_strength_out_of_range_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+
+            let response = client.post(format!("{root_url}/api/preview/{}", upload_img_result.id))
+                .query(&[("error_strength", "1.5")])
+                .send()
+                .await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_preview_with_drill_size_out_of_range_should_result_err() {
+        setup_server_environment_with_client( |root_url, client| async move {
+            let upload_img_result = upload_basic_good_image(&root_url, &client).await.unwrap();
+            let preview_url = format!("{root_url}/api/preview/{}", upload_img_result.id);
+
+            for drill_size in ["0", "65", "4294967295"] {
+                let response = client.post(&preview_url)
+                    .query(&[("drill_size", drill_size)])
+                    .send()
+                    .await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{drill_size}");
+            }
+        }).await;
+    }
+}
@@ -1,7 +1,10 @@
 use std::{hint::black_box, time::Duration};
 use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId};
 
-use ditherum::algorithms::kernel;
+use ditherum::algorithms::{
+    dithering::ErrorDiffusion,
+    kernel::{self, ScanOrder}
+};
 
 fn kernel_2x2_benchmarking_gen_data() -> Vec<Vec<f32>> {
     let (width, height) = (1200, 800);
@@ -17,19 +20,57 @@ pub fn example_kernel2x2_dummy_float(kernel: kernel::MutKernel2x2<f32>) {
     *kernel.br -= delta;
 }
 
+#[inline(never)]
+pub fn example_kernel_diffusion_float(mut kernel: kernel::MutKernel<f32>, error_diffusion: ErrorDiffusion) {
+    let anchor = kernel.anchor();
+    let error = black_box(*anchor - anchor.round());
+    *anchor -= error;
+
+    let error_scale = error / error_diffusion.divisor();
+    for (dx, dy, weight) in error_diffusion.taps() {
+        if let Some(neighbour) = kernel.get_mut(*dx, *dy) {
+            *neighbour += weight * error_scale;
+        }
+    }
+}
+
 fn linkedlist_push_back_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("Kernel2x2_comparison");
     let loops = 1;
     let mut matrix = kernel_2x2_benchmarking_gen_data();
 
 
-    group.bench_with_input(BenchmarkId::new("Kernel Unsafe Dummy", loops), &loops, |b, &_loops| {
+    group.bench_with_input(BenchmarkId::new("Kernel Dummy", loops), &loops, |b, &_loops| {
         b.iter(|| {
             kernel::apply_2x2_kernel_processing(&mut matrix, example_kernel2x2_dummy_float);
         });
     });
 }
 
+fn kernel_footprints_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("KernelNxM_comparison");
+    let mut matrix = kernel_2x2_benchmarking_gen_data();
+
+    let cases = [
+        (ErrorDiffusion::SierraLite, ScanOrder::LeftToRight),
+        (ErrorDiffusion::FloydSteinberg, ScanOrder::LeftToRight),
+        (ErrorDiffusion::FloydSteinberg, ScanOrder::Serpentine),
+        (ErrorDiffusion::JarvisJudiceNinke, ScanOrder::LeftToRight),
+        (ErrorDiffusion::JarvisJudiceNinke, ScanOrder::Serpentine),
+    ];
+
+    for (error_diffusion, scan_order) in cases {
+        let parameter = format!("{error_diffusion:?}/{scan_order:?}");
+        group.bench_with_input(BenchmarkId::new("Kernel Diffusion", parameter), &(error_diffusion, scan_order), |b, &(error_diffusion, scan_order)| {
+            b.iter(|| {
+                kernel::apply_kernel_processing(&mut matrix, scan_order, |kernel| {
+                    example_kernel_diffusion_float(kernel, error_diffusion)
+                });
+            });
+        });
+    }
+}
+
 fn configure_criterion() -> Criterion {
     Criterion::default()
     .warm_up_time(Duration::new(3, 0))
@@ -40,6 +81,6 @@ fn configure_criterion() -> Criterion {
 criterion_group!(
     name = benches;
     config = configure_criterion();
-    targets = linkedlist_push_back_benchmark
+    targets = linkedlist_push_back_benchmark, kernel_footprints_benchmark
 );
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,330 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use crate::image_utils::is_masked_out;
+use crate::palette_utils::{
+    color_manip::rgb_u8_to_srgb_float,
+    ColorDistance
+};
+
+/// Cleanup passes at most, merging regions can leave new small regions behind.
+const CLEANUP_PASSES_MAX: usize = 8;
+
+/// Parameters of merging tiny connected regions ("confetti") into their neighbours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfettiOptions {
+    /// Connected regions of fewer pixels are confetti, `2` removes isolated single pixels only.
+    pub min_region_size: usize,
+
+    /// How the most similar neighbouring color is chosen.
+    pub color_distance: ColorDistance,
+}
+
+impl ConfettiOptions {
+    pub const MIN_REGION_SIZE_RANGE: std::ops::RangeInclusive<usize> = 2..=64;
+}
+
+impl Default for ConfettiOptions {
+    fn default() -> Self {
+        Self {
+            min_region_size: 2,
+            color_distance: ColorDistance::default(),
+        }
+    }
+}
+
+/// Statistics of 4-connected single color regions of an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfettiStats {
+    pub regions_count: usize,
+
+    /// Regions smaller than the minimal region size.
+    pub confetti_regions_count: usize,
+
+    /// Pixels in regions smaller than the minimal region size.
+    pub confetti_pixels_count: usize,
+
+    /// Regions of a single isolated pixel.
+    pub single_pixels_count: usize,
+}
+
+/// Outcome of [`remove_confetti`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfettiReport {
+    pub before: ConfettiStats,
+    pub after: ConfettiStats,
+
+    /// Pixels which got color of a neighbouring region.
+    pub changed_pixels_count: usize,
+}
+
+/// Label of masked out pixels, they belong to no region.
+const NO_REGION: usize = usize::MAX;
+
+/// Labeling of 4-connected single color regions, indexed like pixels.
+struct Regions {
+    labels: Vec<usize>,
+    sizes: Vec<usize>,
+}
+
+impl Regions {
+    fn new(img: &image::RgbImage, mask: Option<&image::GrayImage>) -> Self {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let is_masked_out_at = |idx: usize| is_masked_out(mask, (idx % width) as u32, (idx / width) as u32);
+        let mut labels = vec![NO_REGION; width * height];
+        let mut sizes = vec![];
+        let mut stack = vec![];
+
+        for start in 0..labels.len() {
+            if labels[start] != NO_REGION || is_masked_out_at(start) {
+                continue;
+            }
+
+            let label = sizes.len();
+            let color = pixel_at(img, start);
+            let mut size = 0;
+            labels[start] = label;
+            stack.push(start);
+
+            while let Some(idx) = stack.pop() {
+                size += 1;
+                for neighbour in neighbours(idx, width, height) {
+                    if labels[neighbour] == NO_REGION && !is_masked_out_at(neighbour) && pixel_at(img, neighbour) == color {
+                        labels[neighbour] = label;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+            sizes.push(size);
+        }
+
+        Self { labels, sizes }
+    }
+
+    fn stats(&self, min_region_size: usize) -> ConfettiStats {
+        let confetti_sizes = self.sizes.iter()
+            .filter(|size| **size < min_region_size);
+
+        ConfettiStats {
+            regions_count: self.sizes.len(),
+            confetti_regions_count: confetti_sizes.clone().count(),
+            confetti_pixels_count: confetti_sizes.sum(),
+            single_pixels_count: self.sizes.iter().filter(|size| **size == 1).count(),
+        }
+    }
+}
+
+fn pixel_at(img: &image::RgbImage, idx: usize) -> image::Rgb<u8> {
+    let width = img.width() as usize;
+    *img.get_pixel((idx % width) as u32, (idx / width) as u32)
+}
+
+fn set_pixel_at(img: &mut image::RgbImage, idx: usize, color: image::Rgb<u8>) {
+    let width = img.width() as usize;
+    img.put_pixel((idx % width) as u32, (idx / width) as u32, color);
+}
+
+/// Indices of 4-connected neighbours of the pixel.
+fn neighbours(idx: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = (idx % width, idx / width);
+    [
+        (x > 0).then(|| idx - 1),
+        (x + 1 < width).then(|| idx + 1),
+        (y > 0).then(|| idx - width),
+        (y + 1 < height).then(|| idx + width),
+    ].into_iter().flatten()
+}
+
+/// Computes statistics of regions in the image, regions smaller than
+/// `min_region_size` are counted as confetti.
+pub fn confetti_stats(img: &image::RgbImage, min_region_size: usize) -> ConfettiStats {
+    confetti_stats_masked(img, min_region_size, None)
+}
+
+/// Computes statistics of regions like [`confetti_stats`], pixels masked out by `mask`
+/// (see [`is_masked_out`]) are counted in no region.
+pub fn confetti_stats_masked(img: &image::RgbImage, min_region_size: usize, mask: Option<&image::GrayImage>) -> ConfettiStats {
+    Regions::new(img, mask).stats(min_region_size)
+}
+
+/// Merges connected regions smaller than `options.min_region_size` into the most similar
+/// color of their neighbouring regions. Only colors already present in the image are used,
+/// so an image quantised into a palette stays quantised.
+///
+/// Smaller regions are merged first. Merging can leave new small regions behind, so cleanup
+/// is repeated until nothing changes, at most [`CLEANUP_PASSES_MAX`] times.
+pub fn remove_confetti(img: &mut image::RgbImage, options: &ConfettiOptions) -> ConfettiReport {
+    remove_confetti_masked(img, options, None)
+}
+
+/// Merges confetti like [`remove_confetti`], pixels masked out by `mask` (see [`is_masked_out`])
+/// are neither merged nor merged into, and they are left out of the report.
+///
+/// # Panics
+/// Panics if the mask differs in size from the image.
+pub fn remove_confetti_masked(img: &mut image::RgbImage, options: &ConfettiOptions, mask: Option<&image::GrayImage>) -> ConfettiReport {
+    assert!(mask.is_none_or(|mask| mask.dimensions() == img.dimensions()), "Mask differs in size from the image");
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let before = confetti_stats_masked(img, options.min_region_size, mask);
+    let mut changed_pixels_count = 0;
+
+    for pass in 0..CLEANUP_PASSES_MAX {
+        let regions = Regions::new(img, mask);
+
+        let mut confetti_pixels = vec![vec![]; regions.sizes.len()];
+        regions.labels.iter()
+            .enumerate()
+            .filter(|(_, label)| **label != NO_REGION && regions.sizes[**label] < options.min_region_size)
+            .for_each(|(idx, label)| confetti_pixels[*label].push(idx));
+
+        let mut confetti_labels = (0..regions.sizes.len())
+            .filter(|label| !confetti_pixels[*label].is_empty())
+            .collect::<Vec<_>>();
+        confetti_labels.sort_by_key(|label| (regions.sizes[*label], *label));
+
+        let mut pass_changed_count = 0;
+        for label in confetti_labels {
+            let pixels = &confetti_pixels[label];
+            let color = pixel_at(img, pixels[0]);
+
+            // Colors around the region with length of shared border, current colors
+            // are read so merges done earlier in this pass are respected
+            let mut neighbour_colors: Vec<(image::Rgb<u8>, usize)> = vec![];
+            pixels.iter()
+                .flat_map(|idx| neighbours(*idx, width, height))
+                .filter(|neighbour| ![label, NO_REGION].contains(&regions.labels[*neighbour]))
+                .for_each(|neighbour| {
+                    let neighbour_color = pixel_at(img, neighbour);
+                    match neighbour_colors.iter_mut().find(|(c, _)| *c == neighbour_color) {
+                        Some((_, border)) => *border += 1,
+                        None => neighbour_colors.push((neighbour_color, 1)),
+                    }
+                });
+
+            // Already joined with a recoloured neighbour, or nothing to merge into
+            if neighbour_colors.is_empty() || neighbour_colors.iter().any(|(c, _)| *c == color) {
+                continue;
+            }
+
+            let color_srgb = rgb_u8_to_srgb_float(&color);
+            let (merged_color, _) = neighbour_colors.iter()
+                .map(|(c, border)| (*c, (options.color_distance.distance(color_srgb, rgb_u8_to_srgb_float(c)), *border)))
+                .min_by(|(a, (a_distance, a_border)), (b, (b_distance, b_border))| a_distance.total_cmp(b_distance)
+                    .then_with(|| b_border.cmp(a_border))
+                    .then_with(|| a.0.cmp(&b.0))
+                )
+                .expect("Neighbour colors are not empty");
+
+            pixels.iter().for_each(|idx| set_pixel_at(img, *idx, merged_color));
+            pass_changed_count += pixels.len();
+        }
+
+        log::debug!("Confetti cleanup pass {pass}, changed {pass_changed_count} pixels.");
+        if pass_changed_count == 0 {
+            break;
+        }
+        changed_pixels_count += pass_changed_count;
+    }
+
+    let after = confetti_stats_masked(img, options.min_region_size, mask);
+    ConfettiReport { before, after, changed_pixels_count }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: image::Rgb<u8> = image::Rgb([250, 250, 250]);
+    const PINK: image::Rgb<u8> = image::Rgb([250, 180, 190]);
+    const BLACK: image::Rgb<u8> = image::Rgb([5, 5, 5]);
+    const RED: image::Rgb<u8> = image::Rgb([220, 20, 30]);
+
+    #[test]
+    fn test_isolated_pixel_is_merged() {
+        let mut img = image::RgbImage::from_pixel(5, 5, WHITE);
+        img.put_pixel(2, 2, BLACK);
+
+        let report = remove_confetti(&mut img, &ConfettiOptions::default());
+        assert!(img.pixels().all(|p| *p == WHITE));
+        assert_eq!(report.before.single_pixels_count, 1);
+        assert_eq!(report.before.regions_count, 2);
+        assert_eq!(report.after, ConfettiStats { regions_count: 1, ..Default::default() });
+        assert_eq!(report.changed_pixels_count, 1);
+    }
+
+    #[test]
+    fn test_merged_into_most_similar_neighbour() {
+        // Left half pink, right half black, red specks on the border
+        let mut img = image::RgbImage::from_fn(6, 4, |x, _| if x < 3 { PINK } else { BLACK });
+        img.put_pixel(2, 1, RED);
+        img.put_pixel(3, 2, RED);
+
+        let options = ConfettiOptions { min_region_size: 2, color_distance: ColorDistance::Cie76 };
+        let report = remove_confetti(&mut img, &options);
+        assert_eq!(*img.get_pixel(2, 1), PINK);
+        assert_eq!(*img.get_pixel(3, 2), PINK);
+        assert_eq!(report.after.confetti_regions_count, 0);
+    }
+
+    #[test]
+    fn test_regions_of_min_size_are_kept() {
+        let mut img = image::RgbImage::from_pixel(6, 6, WHITE);
+        img.put_pixel(1, 1, BLACK);
+        img.put_pixel(2, 1, BLACK);
+        img.put_pixel(4, 4, RED);
+        let original = img.clone();
+
+        let stats = confetti_stats(&img, 3);
+        assert_eq!(stats, ConfettiStats { regions_count: 3, confetti_regions_count: 2, confetti_pixels_count: 3, single_pixels_count: 1 });
+
+        let report = remove_confetti(&mut img, &ConfettiOptions { min_region_size: 2, ..Default::default() });
+        assert_eq!(report.changed_pixels_count, 1);
+        assert_eq!(*img.get_pixel(1, 1), BLACK);
+        assert_eq!(*img.get_pixel(4, 4), WHITE);
+
+        // Uniform image and image without confetti are untouched
+        let mut img = original.clone();
+        img.put_pixel(4, 4, WHITE);
+        let report = remove_confetti(&mut img, &ConfettiOptions::default());
+        assert_eq!(report.changed_pixels_count, 0);
+        assert_eq!(report.before, report.after);
+
+        let mut img = image::RgbImage::from_pixel(3, 3, RED);
+        assert_eq!(remove_confetti(&mut img, &ConfettiOptions { min_region_size: 64, ..Default::default() }).changed_pixels_count, 0);
+    }
+
+    #[test]
+    fn test_checkerboard_is_cleaned() {
+        let mut img = image::RgbImage::from_fn(8, 8, |x, y| if (x + y) % 2 == 0 { WHITE } else { BLACK });
+        let report = remove_confetti(&mut img, &ConfettiOptions::default());
+        assert_eq!(report.before.single_pixels_count, 64);
+        assert!(report.after.single_pixels_count < report.before.single_pixels_count, "{report:?}");
+    }
+
+    #[test]
+    fn test_masked_out_pixels_are_not_merged() {
+        // Black speck surrounded by masked out pixels on the left and white on the right
+        let mut img = image::RgbImage::from_fn(4, 3, |x, _| if x < 2 { RED } else { WHITE });
+        img.put_pixel(2, 1, BLACK);
+        let mask = image::GrayImage::from_fn(4, 3, |x, _| image::Luma([if x < 2 { 0 } else { 255 }]));
+        let original = img.clone();
+
+        let report = remove_confetti_masked(&mut img, &ConfettiOptions { min_region_size: 2, ..Default::default() }, Some(&mask));
+        assert_eq!(*img.get_pixel(2, 1), WHITE, "Speck is merged into the unmasked neighbour only");
+        assert!((0..2).all(|x| (0..3).all(|y| img.get_pixel(x, y) == original.get_pixel(x, y))));
+        assert_eq!(report.before, ConfettiStats { regions_count: 2, confetti_regions_count: 1, confetti_pixels_count: 1, single_pixels_count: 1 });
+        assert_eq!(report.changed_pixels_count, 1);
+
+        // Region closed in masked out pixels has nothing to merge into
+        let mut img = image::RgbImage::from_pixel(3, 3, WHITE);
+        img.put_pixel(1, 1, BLACK);
+        let mask = image::GrayImage::from_fn(3, 3, |x, y| image::Luma([if (x, y) == (1, 1) { 255 } else { 0 }]));
+        let report = remove_confetti_masked(&mut img, &ConfettiOptions::default(), Some(&mask));
+        assert_eq!(*img.get_pixel(1, 1), BLACK);
+        assert_eq!(report.changed_pixels_count, 0);
+        assert_eq!(report.before.regions_count, 1);
+    }
+}
@@ -1,48 +1,600 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use crate::algorithms::kernel::{
+    self,
+    ScanOrder
+};
+
+use crate::algorithms::threshold_map::ThresholdMap;
+
 use crate::image_utils::{
-    image_rgb_to_matrix_srgb_f32, 
-    matrix_srgb_float_palette_quantization
+    image_rgb_to_matrix_srgb_f32,
+    is_masked_out
 };
 
 use crate::palette_utils::color_manip::{
-    srgb_add, 
-    srgb_mul_scalar, 
-    srgb_sub
+    rgb_u8_to_srgb_float,
+    srgb_add,
+    srgb_mul_scalar,
+    srgb_sub,
+    srgb_u8_to_rgb_u8
 };
 
-use crate::palette_utils::PaletteSrgb;
+use crate::palette_utils::{
+    ColorDistance,
+    PaletteSrgb
+};
+
+use crate::progress::{
+    NoProgress,
+    Progress
+};
+
+/// Way of distributing palette colors to approximate colors of an image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DitheringMethod {
+    /// Quantisation error is spread over neighbours, see [`ErrorDiffusion`].
+    #[default]
+    ErrorDiffusion,
+    /// Ordered dithering with 2x2 Bayer matrix.
+    Bayer2x2,
+    /// Ordered dithering with 4x4 Bayer matrix.
+    Bayer4x4,
+    /// Ordered dithering with 8x8 Bayer matrix.
+    Bayer8x8,
+    /// Ordered dithering with blue noise threshold map, regular but without visible pattern.
+    BlueNoise,
+}
+
+impl DitheringMethod {
+    /// Threshold map of ordered dithering methods, `None` for error diffusion.
+    pub fn threshold_map(&self) -> Option<std::sync::Arc<ThresholdMap>> {
+        match self {
+            DitheringMethod::ErrorDiffusion => None,
+            DitheringMethod::Bayer2x2 => Some(ThresholdMap::bayer(1).into()),
+            DitheringMethod::Bayer4x4 => Some(ThresholdMap::bayer(2).into()),
+            DitheringMethod::Bayer8x8 => Some(ThresholdMap::bayer(3).into()),
+            DitheringMethod::BlueNoise => Some(ThresholdMap::blue_noise()),
+        }
+    }
+}
+
+/// Error diffusion kernels. Each one spreads quantisation error of the processed
+/// pixel over its not yet processed neighbours, to the right and below.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorDiffusion {
+    /// 3x2 kernel, the classic one.
+    #[default]
+    FloydSteinberg,
+    /// 5x3 kernel, smooth but blurs details.
+    JarvisJudiceNinke,
+    /// 5x3 kernel, sharper than Jarvis–Judice–Ninke.
+    Stucki,
+    /// 5x2 kernel, simplified Stucki.
+    Burkes,
+    /// 5x3 kernel, also known as Sierra-3.
+    Sierra,
+    /// 5x2 kernel, also known as Sierra-2.
+    SierraTwoRow,
+    /// 3x2 kernel, the fastest one.
+    SierraLite,
+    /// 4x3 kernel diffusing only 3/4 of the error, keeps contrast and details.
+    Atkinson,
+}
+
+/// Neighbour receiving part of the quantisation error: `(dx, dy, weight)`.
+pub type DiffusionTap = (i32, i32, f32);
+
+impl ErrorDiffusion {
+    /// Neighbours receiving the error with weights not normalized yet, see [`ErrorDiffusion::divisor`].
+    pub fn taps(&self) -> &'static [DiffusionTap] {
+        match self {
+            ErrorDiffusion::FloydSteinberg => &[
+                (1, 0, 7.0),
+                (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0),
+            ],
+            ErrorDiffusion::JarvisJudiceNinke => &[
+                (1, 0, 7.0), (2, 0, 5.0),
+                (-2, 1, 3.0), (-1, 1, 5.0), (0, 1, 7.0), (1, 1, 5.0), (2, 1, 3.0),
+                (-2, 2, 1.0), (-1, 2, 3.0), (0, 2, 5.0), (1, 2, 3.0), (2, 2, 1.0),
+            ],
+            ErrorDiffusion::Stucki => &[
+                (1, 0, 8.0), (2, 0, 4.0),
+                (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+                (-2, 2, 1.0), (-1, 2, 2.0), (0, 2, 4.0), (1, 2, 2.0), (2, 2, 1.0),
+            ],
+            ErrorDiffusion::Burkes => &[
+                (1, 0, 8.0), (2, 0, 4.0),
+                (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+            ],
+            ErrorDiffusion::Sierra => &[
+                (1, 0, 5.0), (2, 0, 3.0),
+                (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 5.0), (1, 1, 4.0), (2, 1, 2.0),
+                (-1, 2, 2.0), (0, 2, 3.0), (1, 2, 2.0),
+            ],
+            ErrorDiffusion::SierraTwoRow => &[
+                (1, 0, 4.0), (2, 0, 3.0),
+                (-2, 1, 1.0), (-1, 1, 2.0), (0, 1, 3.0), (1, 1, 2.0), (2, 1, 1.0),
+            ],
+            ErrorDiffusion::SierraLite => &[
+                (1, 0, 2.0),
+                (-1, 1, 1.0), (0, 1, 1.0),
+            ],
+            ErrorDiffusion::Atkinson => &[
+                (1, 0, 1.0), (2, 0, 1.0),
+                (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0),
+                (0, 2, 1.0),
+            ],
+        }
+    }
+
+    /// Weights of taps are divided by it. For all kernels except Atkinson
+    /// it equals sum of the weights.
+    pub fn divisor(&self) -> f32 {
+        match self {
+            ErrorDiffusion::FloydSteinberg => 16.0,
+            ErrorDiffusion::JarvisJudiceNinke => 48.0,
+            ErrorDiffusion::Stucki => 42.0,
+            ErrorDiffusion::Burkes => 32.0,
+            ErrorDiffusion::Sierra => 32.0,
+            ErrorDiffusion::SierraTwoRow => 16.0,
+            ErrorDiffusion::SierraLite => 4.0,
+            ErrorDiffusion::Atkinson => 8.0,
+        }
+    }
+}
+
+/// Parameters of dithering an image into a palette.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DitheringOptions {
+    /// How colors of pixels are matched with palette colors.
+    pub color_distance: ColorDistance,
+
+    /// Error diffusion or one of ordered dithering methods.
+    pub method: DitheringMethod,
+
+    /// Kernel spreading the quantisation error, used only by error diffusion.
+    pub error_diffusion: ErrorDiffusion,
+
+    /// Strength of dithering, `0.0` turns dithering off and results in plain quantisation.
+    /// For error diffusion it is the fraction of diffused error, `1.0` diffuses the whole error.
+    /// For ordered dithering it scales the threshold map, `1.0` spans the typical distance
+    /// between palette colors.
+    pub error_strength: f32,
+
+    /// Order of processing pixels, serpentine one avoids directional "worm" artifacts.
+    /// Used only by error diffusion.
+    pub scan_order: ScanOrder,
+}
+
+impl DitheringOptions {
+    pub const ERROR_STRENGTH_RANGE: std::ops::RangeInclusive<f32> = 0.0..=1.0;
+}
 
-use crate::algorithms::kernel;
+impl Default for DitheringOptions {
+    fn default() -> Self {
+        Self {
+            color_distance: ColorDistance::default(),
+            method: DitheringMethod::default(),
+            error_diffusion: ErrorDiffusion::default(),
+            error_strength: 1.0,
+            scan_order: ScanOrder::Serpentine,
+        }
+    }
+}
 
+/// Dithers an image into the palette using `options.method`.
+///
+/// # Panics
+/// Panics if the palette is empty.
+pub fn dithering_srgb(
+    source_image: &image::RgbImage,
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    options: &DitheringOptions
+) -> image::RgbImage {
+    dithering_srgb_with_progress(source_image, palette_srgb_u8, options, &NoProgress)
+}
+
+/// Dithers an image into the palette by Floyd–Steinberg error diffusion in sRGB.
+///
+/// # Panics
+/// Panics if the palette is empty.
+#[deprecated(note = "use `dithering_srgb_with_progress` with `ErrorDiffusion::FloydSteinberg` options")]
 pub fn dithering_floyd_steinberg_srgb(source_image: &image::RgbImage, palette_srgb_u8: &PaletteSrgb<u8>) -> image::RgbImage {
+    let options = DitheringOptions {
+        color_distance: ColorDistance::Srgb,
+        method: DitheringMethod::ErrorDiffusion,
+        error_diffusion: ErrorDiffusion::FloydSteinberg,
+        error_strength: 1.0,
+        scan_order: ScanOrder::LeftToRight,
+    };
+    dithering_srgb_with_progress(source_image, palette_srgb_u8, &options, &NoProgress)
+}
+
+/// Dithers an image into the palette like [`dithering_srgb`], `progress` is reported after every row.
+/// Cancelled dithering leaves the rest of the image black.
+///
+/// # Panics
+/// Panics if the palette is empty.
+pub fn dithering_srgb_with_progress(
+    source_image: &image::RgbImage,
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    options: &DitheringOptions,
+    progress: &dyn Progress
+) -> image::RgbImage {
+    dithering_srgb_masked_with_progress(source_image, palette_srgb_u8, options, None, progress)
+}
+
+/// Dithers an image into the palette like [`dithering_srgb_with_progress`], pixels masked out
+/// by `mask` (see [`is_masked_out`]) keep their source color, and no error is diffused
+/// into them or out of them.
+///
+/// # Panics
+/// Panics if the palette is empty or the mask differs in size from the image.
+pub fn dithering_srgb_masked_with_progress(
+    source_image: &image::RgbImage,
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    options: &DitheringOptions,
+    mask: Option<&image::GrayImage>,
+    progress: &dyn Progress
+) -> image::RgbImage {
+    assert!(mask.is_none_or(|mask| mask.dimensions() == source_image.dimensions()), "Mask differs in size from the image");
+
+    match options.method.threshold_map() {
+        Some(threshold_map) => dithering_ordered_srgb(source_image, palette_srgb_u8, &threshold_map, options, mask, progress),
+        None => dithering_error_diffusion_srgb(source_image, palette_srgb_u8, options, mask, progress),
+    }
+}
+
+/// Reports progress once a row of `width` pixels is done, `done_count` counts processed pixels.
+/// Returns `true` if the work got cancelled, it is checked only once per row.
+fn report_row_done(progress: &dyn Progress, done_count: u64, width: u32, height: u32) -> bool {
+    if done_count.is_multiple_of(width as u64) {
+        progress.report(done_count as f32 / (width as u64 * height as u64) as f32);
+        return progress.is_cancelled();
+    }
+    false
+}
+
+/// Dithers an image into the palette by error diffusion.
+///
+/// Pixels are processed row by row in `options.scan_order`, kernel is mirrored on rows
+/// processed right to left. Error is diffused in sRGB, only the palette lookup is done
+/// in `options.color_distance` space. Pixels masked out by `mask` keep their source color
+/// and error skips them. `progress` is reported after every row.
+///
+/// # Panics
+/// Panics if the palette is empty.
+pub fn dithering_error_diffusion_srgb(
+    source_image: &image::RgbImage,
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    options: &DitheringOptions,
+    mask: Option<&image::GrayImage>,
+    progress: &dyn Progress
+) -> image::RgbImage {
+    assert!(!palette_srgb_u8.as_ref().is_empty());
+
     let mut matrix_float_srgb = image_rgb_to_matrix_srgb_f32(source_image);
     let palette_srgb_float = PaletteSrgb::<f32>::from(palette_srgb_u8);
+    let matcher = palette_srgb_float.matcher(options.color_distance);
 
-    kernel::apply_2x2_kernel_processing(&mut matrix_float_srgb, |kernel| {
-        let closest_tl_color = palette_srgb_float.find_closest(*kernel.tl);
-        let quant_error = srgb_sub(kernel.tl, &closest_tl_color);
-        *kernel.tl = closest_tl_color;
-        
-        // Spread quantisation error over remaining 3 pixels
-        // Keep errors weights low to prevent saturation
-        let (err_weight_tr, err_weight_bl, err_weight_br) = (
-            1.5 / 18.0,
-            2.5 / 18.0,
-            4.2 / 18.0,
-        );
-    
-        *kernel.tr = srgb_add(
-            kernel.tr, 
-            &srgb_mul_scalar(&quant_error, err_weight_tr)
+    let taps = options.error_diffusion.taps();
+    let error_scale = options.error_strength.clamp(0.0, 1.0) / options.error_diffusion.divisor();
+
+    let (width, height) = source_image.dimensions();
+    let mut dithered_image = image::RgbImage::new(width, height);
+    let mut done_count = 0;
+    let mut cancelled = false;
+
+    kernel::apply_kernel_processing(&mut matrix_float_srgb, options.scan_order, |mut kernel| {
+        if cancelled {
+            return;
+        }
+
+        let (x, y) = kernel.position();
+        if is_masked_out(mask, x as u32, y as u32) {
+            dithered_image.put_pixel(x as u32, y as u32, *source_image.get_pixel(x as u32, y as u32));
+        } else {
+            let current_color = *kernel.anchor();
+            let closest_idx = matcher.find_closest_idx(current_color);
+            dithered_image.put_pixel(x as u32, y as u32, srgb_u8_to_rgb_u8(&palette_srgb_u8.as_ref()[closest_idx]));
+
+            let quant_error = srgb_sub(&current_color, &palette_srgb_float.as_ref()[closest_idx]);
+            for (dx, dy, weight) in taps {
+                let neighbour_masked_out = kernel.offset_position(*dx, *dy)
+                    .is_some_and(|(neighbour_x, neighbour_y)| is_masked_out(mask, neighbour_x as u32, neighbour_y as u32));
+                if neighbour_masked_out {
+                    continue;
+                }
+                if let Some(neighbour) = kernel.get_mut(*dx, *dy) {
+                    *neighbour = srgb_add(neighbour, &srgb_mul_scalar(&quant_error, weight * error_scale));
+                }
+            }
+        }
+
+        done_count += 1;
+        cancelled = report_row_done(progress, done_count, width, height);
+    });
+
+    dithered_image
+}
+
+/// Average sRGB distance between a palette color and its closest other color.
+/// Ordered dithering perturbs pixels by this amount, so they can reach neighbouring colors.
+fn palette_spread(palette_srgb_float: &PaletteSrgb<f32>) -> f32 {
+    let colors = palette_srgb_float.as_ref();
+    if colors.len() < 2 {
+        return 0.0;
+    }
+
+    let distance = |a: &palette::Srgb<f32>, b: &palette::Srgb<f32>| {
+        ((a.red - b.red).powi(2) + (a.green - b.green).powi(2) + (a.blue - b.blue).powi(2)).sqrt()
+    };
+
+    let nearest_distances_sum = colors.iter()
+        .enumerate()
+        .map(|(i, a)| colors.iter()
+            .enumerate()
+            .filter(|(j, _)| i != *j)
+            .map(|(_, b)| distance(a, b))
+            .fold(f32::INFINITY, f32::min)
+        )
+        .sum::<f32>();
+
+    nearest_distances_sum / colors.len() as f32
+}
+
+/// Dithers an image into the palette by ordered dithering. Every pixel is
+/// brightened or darkened according to the tiled `threshold_map` before the palette lookup,
+/// so the result depends only on the pixel itself and its position. Pixels masked out
+/// by `mask` keep their source color. `progress` is reported after every row.
+///
+/// # Panics
+/// Panics if the palette is empty.
+pub fn dithering_ordered_srgb(
+    source_image: &image::RgbImage,
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    threshold_map: &ThresholdMap,
+    options: &DitheringOptions,
+    mask: Option<&image::GrayImage>,
+    progress: &dyn Progress
+) -> image::RgbImage {
+    assert!(!palette_srgb_u8.as_ref().is_empty());
+
+    let palette_srgb_float = PaletteSrgb::<f32>::from(palette_srgb_u8);
+    let matcher = palette_srgb_float.matcher(options.color_distance);
+    let amplitude = palette_spread(&palette_srgb_float) * options.error_strength.clamp(0.0, 1.0);
+
+    let (width, height) = source_image.dimensions();
+    let mut done_count = 0;
+    let mut cancelled = false;
+
+    image::RgbImage::from_fn(width, height, |x, y| {
+        if cancelled {
+            return image::Rgb([0, 0, 0]);
+        }
+
+        done_count += 1;
+        cancelled = report_row_done(progress, done_count, width, height);
+
+        if is_masked_out(mask, x, y) {
+            return *source_image.get_pixel(x, y);
+        }
+
+        let offset = amplitude * (threshold_map.threshold(x, y) - 0.5);
+        let color = rgb_u8_to_srgb_float(source_image.get_pixel(x, y));
+        let color = palette::Srgb::new(color.red + offset, color.green + offset, color.blue + offset);
+
+        srgb_u8_to_rgb_u8(&palette_srgb_u8.as_ref()[matcher.find_closest_idx(color)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_utils::generate_gradient_image;
+
+    const ALL_ERROR_DIFFUSIONS: [ErrorDiffusion; 8] = [
+        ErrorDiffusion::FloydSteinberg,
+        ErrorDiffusion::JarvisJudiceNinke,
+        ErrorDiffusion::Stucki,
+        ErrorDiffusion::Burkes,
+        ErrorDiffusion::Sierra,
+        ErrorDiffusion::SierraTwoRow,
+        ErrorDiffusion::SierraLite,
+        ErrorDiffusion::Atkinson,
+    ];
+
+    #[test]
+    fn test_error_diffusion_weights_sum_up_to_divisor() {
+        for error_diffusion in ALL_ERROR_DIFFUSIONS {
+            let weights_sum = error_diffusion.taps().iter().map(|(_, _, w)| w).sum::<f32>();
+            let taps_forward = error_diffusion.taps().iter().all(|(dx, dy, _)| *dy > 0 || (*dy == 0 && *dx > 0));
+            assert!(taps_forward, "{error_diffusion:?}");
+
+            if error_diffusion == ErrorDiffusion::Atkinson {
+                assert_eq!(weights_sum / error_diffusion.divisor(), 0.75);
+            } else {
+                assert_eq!(weights_sum, error_diffusion.divisor(), "{error_diffusion:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_diffusion_keeps_average_brightness() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = image::RgbImage::from_pixel(64, 64, image::Rgb([128, 128, 128]));
+
+        for error_diffusion in ALL_ERROR_DIFFUSIONS {
+            for scan_order in [ScanOrder::LeftToRight, ScanOrder::Serpentine] {
+                let options = DitheringOptions {
+                    color_distance: ColorDistance::LinearRgb,
+                    method: DitheringMethod::ErrorDiffusion,
+                    error_diffusion,
+                    error_strength: 1.0,
+                    scan_order,
+                };
+                let dithered = dithering_error_diffusion_srgb(&source_image, &palette, &options, None, &NoProgress);
+
+                let white_count = dithered.pixels().filter(|p| p.0 == [255, 255, 255]).count();
+                let white_ratio = white_count as f32 / (64 * 64) as f32;
+                // Error is diffused in sRGB, so half of drills should be white
+                assert!((0.4..0.6).contains(&white_ratio), "{error_diffusion:?} {scan_order:?} white ratio {white_ratio}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_strength_zero_is_plain_quantisation() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = generate_gradient_image(
+            32, 4,
+            image::Rgb([0, 0, 0]),
+            image::Rgb([255, 255, 255])
         );
-        *kernel.bl = srgb_add(
-            kernel.bl, 
-            &srgb_mul_scalar(&quant_error, err_weight_bl)
+
+        let options = DitheringOptions {
+            color_distance: ColorDistance::Srgb,
+            error_strength: 0.0,
+            ..Default::default()
+        };
+        let dithered = dithering_error_diffusion_srgb(&source_image, &palette, &options, None, &NoProgress);
+
+        for (x, _, pixel) in dithered.enumerate_pixels() {
+            let expected = if x < 16 { [0, 0, 0] } else { [255, 255, 255] };
+            assert_eq!(pixel.0, expected);
+        }
+    }
+
+    #[test]
+    fn test_ordered_dithering_keeps_average_brightness() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = image::RgbImage::from_pixel(64, 64, image::Rgb([128, 128, 128]));
+
+        for method in [DitheringMethod::Bayer2x2, DitheringMethod::Bayer4x4, DitheringMethod::Bayer8x8, DitheringMethod::BlueNoise] {
+            let options = DitheringOptions {
+                color_distance: ColorDistance::Srgb,
+                method,
+                ..Default::default()
+            };
+            let dithered = dithering_srgb(&source_image, &palette, &options);
+
+            let white_count = dithered.pixels().filter(|p| p.0 == [255, 255, 255]).count();
+            let white_ratio = white_count as f32 / (64 * 64) as f32;
+            assert!((0.45..0.55).contains(&white_ratio), "{method:?} white ratio {white_ratio}");
+        }
+    }
+
+    #[test]
+    fn test_ordered_dithering_pattern_is_regular() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = image::RgbImage::from_pixel(16, 16, image::Rgb([100, 100, 100]));
+
+        let options = DitheringOptions {
+            color_distance: ColorDistance::Srgb,
+            method: DitheringMethod::Bayer4x4,
+            ..Default::default()
+        };
+        let dithered = dithering_srgb(&source_image, &palette, &options);
+
+        for (x, y, pixel) in dithered.enumerate_pixels() {
+            assert_eq!(pixel, dithered.get_pixel(x % 4, y % 4));
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_floyd_steinberg_wrapper_dithers_by_floyd_steinberg() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = generate_gradient_image(
+            16, 4,
+            image::Rgb([0, 0, 0]),
+            image::Rgb([255, 255, 255])
         );
-        *kernel.br = srgb_add(
-            kernel.br, 
-            &srgb_mul_scalar(&quant_error, err_weight_br)
+
+        let options = DitheringOptions {
+            color_distance: ColorDistance::Srgb,
+            error_diffusion: ErrorDiffusion::FloydSteinberg,
+            scan_order: ScanOrder::LeftToRight,
+            ..Default::default()
+        };
+        assert_eq!(dithering_floyd_steinberg_srgb(&source_image, &palette), dithering_srgb(&source_image, &palette, &options));
+    }
+
+    #[test]
+    fn test_masked_out_pixels_keep_color_and_take_no_error() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = generate_gradient_image(
+            12, 6,
+            image::Rgb([40, 40, 40]),
+            image::Rgb([220, 220, 220])
         );
-    });
+        // Left columns are masked out, the rest is dithered as if they weren't there
+        let mask = image::GrayImage::from_fn(12, 6, |x, _| image::Luma([if x < 4 { 0 } else { 255 }]));
+        let unmasked_part = image::imageops::crop_imm(&source_image, 4, 0, 8, 6).to_image();
+
+        for scan_order in [ScanOrder::LeftToRight, ScanOrder::Serpentine] {
+            let options = DitheringOptions { scan_order, ..Default::default() };
+            let dithered = dithering_srgb_masked_with_progress(&source_image, &palette, &options, Some(&mask), &NoProgress);
+            let dithered_part = dithering_srgb(&unmasked_part, &palette, &options);
+
+            for (x, y, pixel) in dithered.enumerate_pixels() {
+                let expected = if x < 4 { source_image.get_pixel(x, y) } else { dithered_part.get_pixel(x - 4, y) };
+                assert_eq!(pixel, expected, "{scan_order:?} ({x}, {y})");
+            }
+        }
+
+        let options = DitheringOptions { method: DitheringMethod::Bayer4x4, ..Default::default() };
+        let dithered = dithering_srgb_masked_with_progress(&source_image, &palette, &options, Some(&mask), &NoProgress);
+        assert!((0..4).all(|x| (0..6).all(|y| dithered.get_pixel(x, y) == source_image.get_pixel(x, y))));
+    }
+
+    #[test]
+    fn test_dithering_reports_progress_after_every_row() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = image::RgbImage::from_pixel(8, 4, image::Rgb([100, 100, 100]));
+
+        for method in [DitheringMethod::ErrorDiffusion, DitheringMethod::Bayer2x2] {
+            let reported = std::sync::Mutex::new(Vec::new());
+            let progress = |fraction: f32| reported.lock().unwrap().push(fraction);
+            let options = DitheringOptions { method, ..Default::default() };
+            dithering_srgb_with_progress(&source_image, &palette, &options, &progress);
+
+            assert_eq!(*reported.lock().unwrap(), [0.25, 0.5, 0.75, 1.0], "{method:?}");
+        }
+    }
+
+    /// Cancels once half of the work is reported.
+    struct CancelInHalf(std::sync::Mutex<Vec<f32>>);
+
+    impl Progress for CancelInHalf {
+        fn report(&self, fraction: f32) {
+            self.0.lock().unwrap().push(fraction);
+        }
+
+        fn is_cancelled(&self) -> bool {
+            self.0.lock().unwrap().last().is_some_and(|fraction| *fraction >= 0.5)
+        }
+    }
+
+    #[test]
+    fn test_cancelled_dithering_stops_after_row() {
+        let palette = PaletteSrgb::from_colors(PaletteSrgb::BLACK_N_WHITE_COLORS);
+        let source_image = image::RgbImage::from_pixel(8, 4, image::Rgb([255, 255, 255]));
+
+        for method in [DitheringMethod::ErrorDiffusion, DitheringMethod::Bayer2x2] {
+            let progress = CancelInHalf(Default::default());
+            let options = DitheringOptions { method, ..Default::default() };
+            let dithered = dithering_srgb_with_progress(&source_image, &palette, &options, &progress);
 
-    matrix_srgb_float_palette_quantization(&matrix_float_srgb, palette_srgb_u8)
-}
\ No newline at end of file
+            assert_eq!(*progress.0.lock().unwrap(), [0.25, 0.5], "{method:?}");
+            assert_eq!(*dithered.get_pixel(7, 1), image::Rgb([255, 255, 255]), "Rows before cancelling are dithered");
+            assert_eq!(*dithered.get_pixel(0, 2), image::Rgb([0, 0, 0]), "Rows after cancelling are left black");
+        }
+    }
+}
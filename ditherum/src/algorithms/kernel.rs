@@ -1,5 +1,10 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+
 /// Represents a mutable 2x2 kernel over a matrix.
-/// 
+///
 /// This struct provides mutable references to four adjacent elements in a 2x2 region.
 pub struct MutKernel2x2<'a, T> {
     pub tl: &'a mut T,  // Top-left element
@@ -9,18 +14,21 @@ pub struct MutKernel2x2<'a, T> {
 }
 
 /// Applies a 2x2 kernel-based processing function to a mutable matrix.
-/// 
+///
 /// This function iterates over the matrix and calls the provided function on each 2x2 submatrix.
-/// If the kernel extends beyond the matrix bounds, default values are used.
-/// 
+/// If the kernel extends beyond the matrix bounds, fresh default values are used
+/// and changes made to them are dropped. Every kernel gets its own defaults, so nothing
+/// written beyond the bounds leaks into the next kernel, e.g. error diffused past the
+/// last column doesn't reappear past the last column of the next row.
+///
 /// # Parameters
 /// - `matrix`: A mutable reference to a 2D vector.
 /// - `processing`: A function that takes a `MutKernel2x2<T>` and modifies the matrix accordingly.
-/// 
+///
 /// # Panics
 /// Panics if the matrix has fewer than two rows or columns.
 pub fn apply_2x2_kernel_processing<T, P>(matrix: &mut [Vec<T>], mut processing: P)
-where 
+where
     T: Default,
     P: FnMut(MutKernel2x2<T>)
 {
@@ -29,33 +37,115 @@ where
     let width = matrix[0].len();
     assert!(width > 1);
 
-    let mut dummy_tr = T::default();
-    let mut dummy_bl = T::default();
-    let mut dummy_br = T::default();
-
     for y in 0..height {
-        let row_is_last = y == (height - 1);
+        let (rows_upper, rows_lower) = matrix.split_at_mut(y + 1);
+        let row = &mut rows_upper[y];
+        let mut row_next = rows_lower.first_mut();
 
         for x in 0..width {
-            let column_is_last = x == (width - 1);
-
-            let tl = (&mut matrix[y][x]) as *mut T;
-
-            let tr = if !column_is_last { &mut matrix[y][x + 1] } else { &mut dummy_tr } as *mut T;
-            
-            let bl = if !row_is_last { &mut matrix[y + 1][x] } else { &mut dummy_bl } as *mut T;
-            
-            let br = if !row_is_last && !column_is_last { &mut matrix[y + 1][x + 1] } else { &mut dummy_br } as *mut T;
-
-            unsafe {
-                let kernel = MutKernel2x2 {
-                    tl: &mut *tl,
-                    tr: &mut *tr,
-                    bl: &mut *bl,
-                    br: &mut *br
-                };
-                processing(kernel);
-            }
+            let mut dummy_tr = T::default();
+            let mut dummy_bl = T::default();
+            let mut dummy_br = T::default();
+
+            let (row_left, row_right) = row.split_at_mut(x + 1);
+            let tl = &mut row_left[x];
+            let tr = row_right.first_mut().unwrap_or(&mut dummy_tr);
+
+            let (bl, br) = match row_next.as_mut() {
+                Some(row_next) => {
+                    let (row_next_left, row_next_right) = row_next.split_at_mut(x + 1);
+                    (&mut row_next_left[x], row_next_right.first_mut().unwrap_or(&mut dummy_br))
+                },
+                None => (&mut dummy_bl, &mut dummy_br),
+            };
+
+            processing(MutKernel2x2 { tl, tr, bl, br });
+        }
+    }
+}
+
+/// Order in which elements of a matrix are visited, row by row from the top.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanOrder {
+    /// Every row is visited left to right.
+    #[default]
+    LeftToRight,
+    /// Rows alternate direction (boustrophedon): even rows left to right, odd rows right to left.
+    Serpentine,
+}
+
+/// Mutable window of arbitrary footprint over a matrix, anchored at the processed element.
+///
+/// Neighbours are addressed by offsets relative to the anchor. On rows visited right to left
+/// horizontal offsets are mirrored, so a kernel written for left to right traversal,
+/// e.g. "`dx = 1` is the next element", stays correct in serpentine order.
+pub struct MutKernel<'a, T> {
+    matrix: &'a mut [Vec<T>],
+    x: usize,
+    y: usize,
+    mirrored: bool,
+}
+
+impl<T> MutKernel<'_, T> {
+    /// Position `(x, y)` of the anchor in the matrix.
+    pub fn position(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    /// Whether the current row is visited right to left.
+    pub fn is_mirrored(&self) -> bool {
+        self.mirrored
+    }
+
+    pub fn anchor(&mut self) -> &mut T {
+        &mut self.matrix[self.y][self.x]
+    }
+
+    /// Element at offset `(dx, dy)` from the anchor, `None` when it lies outside the matrix.
+    pub fn get(&self, dx: i32, dy: i32) -> Option<&T> {
+        let (x, y) = self.offset_position(dx, dy)?;
+        Some(&self.matrix[y][x])
+    }
+
+    /// Mutable element at offset `(dx, dy)` from the anchor, `None` when it lies outside the matrix.
+    pub fn get_mut(&mut self, dx: i32, dy: i32) -> Option<&mut T> {
+        let (x, y) = self.offset_position(dx, dy)?;
+        Some(&mut self.matrix[y][x])
+    }
+
+    /// Position `(x, y)` of the element at offset `(dx, dy)` from the anchor,
+    /// `None` when it lies outside the matrix.
+    pub fn offset_position(&self, dx: i32, dy: i32) -> Option<(usize, usize)> {
+        let dx = if self.mirrored { -(dx as i64) } else { dx as i64 };
+
+        let y = usize::try_from(self.y as i64 + dy as i64).ok()?;
+        let row = self.matrix.get(y)?;
+        let x = usize::try_from(self.x as i64 + dx).ok()?;
+        (x < row.len()).then_some((x, y))
+    }
+}
+
+/// Applies a processing function with a kernel of arbitrary footprint anchored
+/// at every element of the matrix, visited in `scan_order`.
+///
+/// Neighbours outside the matrix are simply not accessible, see [`MutKernel::get_mut`].
+///
+/// # Parameters
+/// - `matrix`: A mutable reference to a 2D vector, rows may have different lengths.
+/// - `scan_order`: Order in which anchors are visited.
+/// - `processing`: A function that takes a `MutKernel<T>` and modifies the matrix accordingly.
+pub fn apply_kernel_processing<T, P>(matrix: &mut [Vec<T>], scan_order: ScanOrder, mut processing: P)
+where
+    P: FnMut(MutKernel<T>)
+{
+    for y in 0..matrix.len() {
+        let width = matrix[y].len();
+        let mirrored = scan_order == ScanOrder::Serpentine && y % 2 == 1;
+
+        for i in 0..width {
+            let x = if mirrored { width - 1 - i } else { i };
+            processing(MutKernel { matrix: &mut *matrix, x, y, mirrored });
         }
     }
 }
@@ -72,4 +162,65 @@ fn test_unsafe_kernel_processing_simple() {
     let processed_data = data;
     let expected_data = vec![vec![1, 2], vec![2, 4]];
     assert_eq!(processed_data, expected_data);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_2x2_kernel_beyond_bounds_gets_fresh_defaults() {
+    let mut data = vec![vec![0u32; 3]; 2];
+    let mut last_seen = None;
+    apply_2x2_kernel_processing(&mut data, |kernel| {
+        last_seen = Some((*kernel.tl, *kernel.tr, *kernel.bl, *kernel.br));
+        *kernel.tl += 1;
+        *kernel.tr += 1;
+        *kernel.bl += 1;
+        *kernel.br += 1;
+    });
+
+    assert_eq!(data, vec![vec![1, 2, 2], vec![2, 4, 4]]);
+    // Bottom-right kernel has only its top-left within bounds, the rest was written
+    // beyond bounds by preceding kernels and still reads as default
+    assert_eq!(last_seen, Some((3, 0, 0, 0)));
+}
+
+#[test]
+fn test_kernel_processing_visit_order() {
+    let mut data = vec![vec![0u32; 3]; 3];
+    let mut counter = 0;
+    apply_kernel_processing(&mut data, ScanOrder::LeftToRight, |mut kernel| {
+        counter += 1;
+        *kernel.anchor() = counter;
+    });
+    assert_eq!(data, vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+
+    let mut counter = 0;
+    apply_kernel_processing(&mut data, ScanOrder::Serpentine, |mut kernel| {
+        counter += 1;
+        *kernel.anchor() = counter;
+    });
+    assert_eq!(data, vec![vec![1, 2, 3], vec![6, 5, 4], vec![7, 8, 9]]);
+}
+
+#[test]
+fn test_kernel_processing_wide_footprint_edges() {
+    // Every element pushes 1 to the two next elements and the 3x1 row below
+    let footprint = [(1, 0), (2, 0), (-1, 1), (0, 1), (1, 1)];
+
+    for scan_order in [ScanOrder::LeftToRight, ScanOrder::Serpentine] {
+        let mut data = vec![vec![0u32; 4]; 2];
+        apply_kernel_processing(&mut data, scan_order, |mut kernel| {
+            assert!(kernel.get(0, -3).is_none());
+            for (dx, dy) in footprint {
+                if let Some(neighbour) = kernel.get_mut(dx, dy) {
+                    *neighbour += 1;
+                }
+            }
+        });
+
+        assert_eq!(data[0], vec![0, 1, 2, 2], "{scan_order:?}");
+        let expected_second_row = match scan_order {
+            ScanOrder::LeftToRight => vec![2, 4, 5, 4],
+            ScanOrder::Serpentine => vec![4, 5, 4, 2],
+        };
+        assert_eq!(data[1], expected_second_row, "{scan_order:?}");
+    }
+}
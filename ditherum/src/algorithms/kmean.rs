@@ -1,18 +1,22 @@
 use std::fmt::Debug;
-use rand::seq::IndexedRandom;
+use crate::progress::{
+    NoProgress,
+    Progress,
+    ProgressRange
+};
+use rand::{
+    seq::IndexedRandom, 
+    Rng, 
+    SeedableRng
+};
 
 const MULTITHREADE_ITEMS_COUNT_THRESHOLD: usize = 50;
 const CONVERGE_THRESHOLD: f32 = 0.05;
-const CONVERGE_ENOUGH_THRESHOLD: f32 = 0.8;
 const ITERATION_MAX_COUNT: usize = 120;
 
 /// Errors that can occur while finding centroids using the K-means algorithm.
 #[derive(Debug, thiserror::Error)]
 pub enum CentroidsFindError {
-    /// The algorithm exceeded the maximum allowed number of iterations without converging.
-    #[error("TooManyIterations")]
-    TooManyIterations,
-
     /// The input contains fewer elements than the requested number of centroids.
     #[error("TooManyCentroids expected={expected}, actual={actual}")]
     TooManyCentroids {
@@ -23,12 +27,17 @@ pub enum CentroidsFindError {
     /// The input data is empty.
     #[error("InputEmpty")]
     InputEmpty,
+
+    /// No centroid was requested or found.
+    #[error("NoCentroids")]
+    NoCentroids,
 }
 
 /// Validates the input data for the K-means clustering algorithm.
 ///
-/// The function checks two conditions:
+/// The function checks three conditions:
 /// - The input slice must not be empty.
+/// - At least one centroid must be requested.
 /// - The number of input elements must be at least as many as `centroids_count`.
 ///
 /// # Parameters
@@ -45,6 +54,8 @@ where
 {
     if input.is_empty() {
         Err(CentroidsFindError::InputEmpty)
+    } else if centroids_count == 0 {
+        Err(CentroidsFindError::NoCentroids)
     } else if input.len() < centroids_count {
         Err(CentroidsFindError::TooManyCentroids { expected: centroids_count, actual: input.len() })
     } else {
@@ -287,6 +298,7 @@ where
 /// # Parameters
 ///
 /// * `clusters` - A slice of clusters, each represented as a vector of data points.
+/// * `last_centroids` - Centroids the clusters were assigned to, kept for empty clusters.
 /// * `calculate_mean` - A function or closure that computes the mean of a slice of points.
 ///
 /// # Returns
@@ -294,6 +306,7 @@ where
 /// A vector of new centroids, each computed as the mean of the corresponding cluster.
 fn create_centroids_from_clusters<T, M>(
     clusters: &[Vec<T>],
+    last_centroids: &[T],
     calculate_mean: &M
 ) -> Vec<T>
 where 
@@ -301,10 +314,214 @@ where
     M: Fn(&[T]) -> T
 {
     clusters.iter()
-        .map(|cluster| calculate_mean(cluster))
+        .zip(last_centroids.iter())
+        .map(|(cluster, last_centroid)| {
+            // Mean of nothing is not defined, e.g. when duplicated points were chosen as centroids
+            if cluster.is_empty() {
+                *last_centroid
+            } else {
+                calculate_mean(cluster)
+            }
+        })
         .collect()
 }
 
+/// Chooses initial centroids using k-means++ seeding.
+///
+/// The first centroid is a random item, every next one is chosen with probability proportional
+/// to the squared distance from the closest centroid chosen so far. Centroids are spread over
+/// the data, so small but distinct groups of items get their own centroid.
+///
+/// # Parameters
+///
+/// * `input` - A slice of input data points, must not be empty.
+/// * `centroids_count` - The number of centroids to choose.
+/// * `distance_measure` - A closure that computes the distance between two points.
+/// * `rng` - Source of randomness.
+pub fn kmeans_plus_plus_centroids<T, D, R>(
+    input: &[T],
+    centroids_count: usize,
+    distance_measure: &D,
+    rng: &mut R
+) -> Vec<T>
+where 
+    T: Debug + Copy + Clone,
+    D: Fn(&T, &T) -> f32,
+    R: Rng + ?Sized
+{
+    let Some(first_centroid) = input.choose(rng).copied() else {
+        return vec![];
+    };
+
+    let mut centroids = Vec::with_capacity(centroids_count);
+    centroids.push(first_centroid);
+
+    let mut closest_distances = input.iter()
+        .map(|item| distance_measure(item, &first_centroid).powi(2))
+        .collect::<Vec<_>>();
+
+    while centroids.len() < centroids_count {
+        let distances_sum = closest_distances.iter().sum::<f32>();
+
+        let chosen_idx = if distances_sum > 0.0 {
+            let mut remaining = rng.random::<f32>() * distances_sum;
+            closest_distances.iter()
+                .position(|distance| {
+                    remaining -= distance;
+                    remaining <= 0.0
+                })
+                .unwrap_or(input.len() - 1)
+        } else {
+            // Every item is already a centroid, only duplicates can be chosen
+            rng.random_range(0..input.len())
+        };
+
+        let centroid = input[chosen_idx];
+        centroids.push(centroid);
+
+        closest_distances.iter_mut()
+            .zip(input.iter())
+            .for_each(|(closest_distance, item)| {
+                *closest_distance = closest_distance.min(distance_measure(item, &centroid).powi(2));
+            });
+    }
+
+    centroids
+}
+
+/// Parameters of K-means clustering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KMeansOptions {
+    /// Seed of the random generator, the same seed and input always give the same centroids.
+    pub seed: u64,
+
+    /// Count of clusterings started from different k-means++ seedings,
+    /// the one with the lowest inertia is returned.
+    pub restarts: usize,
+
+    /// Iterations of a single clustering at most.
+    pub iterations_max: usize,
+}
+
+impl KMeansOptions {
+    pub const SEED_DEFAULT: u64 = 0x5EED;
+    /// Sensible count of restarts, each one costs a full clustering.
+    pub const RESTARTS_RANGE: std::ops::RangeInclusive<usize> = 1..=16;
+}
+
+impl Default for KMeansOptions {
+    fn default() -> Self {
+        Self {
+            seed: Self::SEED_DEFAULT,
+            restarts: 1,
+            iterations_max: ITERATION_MAX_COUNT,
+        }
+    }
+}
+
+/// Centroids found by K-means clustering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KMeansResult<T> {
+    pub centroids: Vec<T>,
+
+    /// Sum of squared distances of items to their closest centroids, lower is better.
+    pub inertia: f32,
+
+    /// Whether centroids converged before the iterations cap was hit.
+    pub converged: bool,
+}
+
+/// Sum of squared distances of items to their closest centroids.
+fn calculate_inertia<T, D>(
+    input: &[T],
+    centroids: &[T],
+    distance_measure: &D
+) -> f32
+where 
+    T: Debug + Copy + Clone,
+    D: Fn(&T, &T) -> f32,
+{
+    input.iter()
+        .map(|item| centroids.iter()
+            .map(|centroid| distance_measure(item, centroid))
+            .fold(f32::INFINITY, f32::min)
+            .powi(2)
+        )
+        .sum()
+}
+
+/// Runs K-means iterations from initial centroids until they converge or `iterations_max`
+/// is hit. In the latter case the centroids with the lowest inertia seen so far are returned.
+/// `progress` is reported after every iteration as part of `iterations_max`,
+/// cancelled clustering returns the best centroids so far.
+fn run_kmeans<T, D, M>(
+    input: &[T],
+    initial_centroids: Vec<T>,
+    distance_measure: &D,
+    calculate_mean: &M,
+    iterations_max: usize,
+    progress: &dyn Progress
+) -> KMeansResult<T>
+where 
+    T: Debug + Copy + Clone + Send + Sync,
+    D: Fn(&T, &T) -> f32 + Send + Sync,
+    M: Fn(&[T]) -> T
+{
+    let mut last_centroids;
+    let mut centroids = initial_centroids;
+    let mut best_so_far: Option<KMeansResult<T>> = None;
+    let mut iterations_count = 0;
+
+    loop {
+        iterations_count += 1;
+        log::debug!("Iteration {iterations_count}.");
+
+        // Assign each input point to the nearest centroid.
+        let clusters = create_clusters_assignment(input, &centroids, distance_measure);
+        log::trace!("Clusters: {clusters:?}");
+
+        // Score centroids the clusters were assigned to
+        let inertia = clusters.iter()
+            .zip(centroids.iter())
+            .map(|(cluster, centroid)| cluster.iter()
+                .map(|item| distance_measure(item, centroid).powi(2))
+                .sum::<f32>()
+            )
+            .sum::<f32>();
+        if best_so_far.as_ref().is_none_or(|best| inertia < best.inertia) {
+            best_so_far = Some(KMeansResult { centroids: centroids.clone(), inertia, converged: false });
+        }
+
+        // Compute new centroids as the mean of the clusters.
+        last_centroids = centroids;
+        centroids = create_centroids_from_clusters(&clusters, &last_centroids, calculate_mean);
+
+        // Check if the centroids have converged.
+        if check_converges(
+            &last_centroids, 
+            &centroids, 
+            CONVERGE_THRESHOLD,
+            distance_measure
+        ) {
+            log::debug!("Found solution after {iterations_count} iterations!");
+            let inertia = calculate_inertia(input, &centroids, distance_measure);
+            progress.report(1.0);
+            return KMeansResult { centroids, inertia, converged: true };
+        }
+        progress.report(iterations_count as f32 / iterations_max.max(1) as f32);
+        
+        if progress.is_cancelled() {
+            log::debug!("Cancelled after {iterations_count} iterations, returning best solution so far.");
+            return best_so_far.expect("At least 1 iteration was done");
+        }
+
+        if iterations_count >= iterations_max {
+            log::debug!("Iterations exhausted after {iterations_count} iterations, returning best solution so far.");
+            return best_so_far.expect("At least 1 iteration was done");
+        }
+    }
+}
+
 /// Performs K-means clustering to find a set of centroids for the input data.
 ///
 /// This function implements a K-means clustering algorithm that repeatedly assigns data
@@ -312,6 +529,9 @@ where
 /// the assigned points. Iteration continues until the centroids converge (i.e., change less
 /// than a specified threshold) or the maximum number of iterations is reached.
 ///
+/// Initial centroids are chosen by k-means++ seeding with [`KMeansOptions::SEED_DEFAULT`],
+/// so results are reproducible. See [`find_centroids_with_options`] for seed and restarts.
+///
 /// # Parameters
 ///
 /// * `input` - A slice of input data points.
@@ -321,8 +541,9 @@ where
 ///
 /// # Returns
 ///
-/// Returns `Ok(Vec<T>)` containing the computed centroids if the algorithm converges,
-/// or a [`CentroidsFindError`] if an error occurs (e.g., too many iterations, input is empty).
+/// Returns `Ok(Vec<T>)` containing the computed centroids, when the algorithm does not converge
+/// the best centroids found so far. A [`CentroidsFindError`] if the input is invalid
+/// (e.g., input is empty).
 ///
 /// # Examples
 ///
@@ -358,6 +579,56 @@ pub fn find_centroids<T, D, M>(
     calculate_mean: M
 
 ) -> Result<Vec<T>, CentroidsFindError>
+where 
+    T: Debug + Copy + Clone + Send + Sync,
+    D: Fn(&T, &T) -> f32 + Send + Sync,
+    M: Fn(&[T]) -> T
+{
+    find_centroids_with_options(
+        input, 
+        centroids_count, 
+        &KMeansOptions::default(), 
+        distance_measure, 
+        calculate_mean
+    ).map(|result| result.centroids)
+}
+
+/// Performs K-means clustering `options.restarts` times, each one from different k-means++
+/// seeding, and returns the result with the lowest inertia. See [`find_centroids`].
+///
+/// # Parameters
+///
+/// * `input` - A slice of input data points.
+/// * `centroids_count` - The number of centroids (clusters) to compute.
+/// * `options` - Seed, restarts and iterations cap.
+/// * `distance_measure` - A closure that computes the distance between two points.
+/// * `calculate_mean` - A closure that computes the mean of a slice of data points.
+pub fn find_centroids_with_options<T, D, M>(
+    input: &[T], 
+    centroids_count: usize,
+    options: &KMeansOptions,
+    distance_measure: D,
+    calculate_mean: M
+) -> Result<KMeansResult<T>, CentroidsFindError>
+where 
+    T: Debug + Copy + Clone + Send + Sync,
+    D: Fn(&T, &T) -> f32 + Send + Sync,
+    M: Fn(&[T]) -> T
+{
+    find_centroids_with_progress(input, centroids_count, options, distance_measure, calculate_mean, &NoProgress)
+}
+
+/// Performs K-means clustering like [`find_centroids_with_options`], `progress` is reported
+/// after every iteration, every restart takes an equal part of it. Cancelled clustering
+/// skips the remaining iterations and restarts.
+pub fn find_centroids_with_progress<T, D, M>(
+    input: &[T], 
+    centroids_count: usize,
+    options: &KMeansOptions,
+    distance_measure: D,
+    calculate_mean: M,
+    progress: &dyn Progress
+) -> Result<KMeansResult<T>, CentroidsFindError>
 where 
     T: Debug + Copy + Clone + Send + Sync,
     D: Fn(&T, &T) -> f32 + Send + Sync,
@@ -368,63 +639,34 @@ where
     // If the number of input points equals the requested centroids count,
     // return the input data as the centroids.
     if input.len() == centroids_count {
-        return Ok(input.to_vec());
+        progress.report(1.0);
+        return Ok(KMeansResult { centroids: input.to_vec(), inertia: 0.0, converged: true });
     }
 
-    let mut rng = rand::rng();
-
-    let mut last_centroids;
-    let mut centroids = input
-        .choose_multiple(&mut rng, centroids_count)
-        .copied()
-        .collect::<Vec<_>>();
-    let mut clusters;
-    let mut iterations_count = 0;
-    // println!("Initial centroids={centroids:?}");
+    let mut rng = rand::rngs::StdRng::seed_from_u64(options.seed);
+    let mut best: Option<KMeansResult<T>> = None;
+    let restarts = options.restarts.max(1);
 
-    loop {
-        iterations_count += 1;
-        log::debug!("Iteration {iterations_count}.");
-
-        // Assign each input point to the nearest centroid.
-        clusters = create_clusters_assignment(input, &centroids, &distance_measure);
-        log::trace!("Clusters: {clusters:?}");
+    for restart in 0..restarts {
+        let initial_centroids = kmeans_plus_plus_centroids(input, centroids_count, &distance_measure, &mut rng);
+        let restart_progress = ProgressRange::step(progress, restart, restarts);
+        let result = run_kmeans(input, initial_centroids, &distance_measure, &calculate_mean, options.iterations_max, &restart_progress);
+        log::debug!("Restart {restart}, inertia={}, converged={}", result.inertia, result.converged);
 
-        // Compute new centroids as the mean of the clusters.
-        last_centroids = centroids;
-        centroids = create_centroids_from_clusters(&clusters, &calculate_mean);
+        if best.as_ref().is_none_or(|best| result.inertia < best.inertia) {
+            best = Some(result);
+        }
 
-        // Check if the centroids have converged.
-        if check_converges(
-            &last_centroids, 
-            &centroids, 
-            CONVERGE_THRESHOLD,
-            &distance_measure
-        ) {
-            log::debug!("Found solution after {iterations_count} iterations!");
+        if progress.is_cancelled() {
             break;
         }
-        
-        if iterations_count > ITERATION_MAX_COUNT {
-            // Iterations exhausted, but solution can be good enough
-            if check_converges(
-                &last_centroids, 
-                &centroids, 
-                CONVERGE_ENOUGH_THRESHOLD,
-                &distance_measure
-            ) {
-                log::debug!("Found good enough solution after {iterations_count} iterations!");
-                break;
-            } else {
-                return Err(CentroidsFindError::TooManyIterations);
-            }
-        }
     }
 
-    Ok(centroids)
-}         
+    Ok(best.expect("At least 1 clustering was done"))
+}
 
 #[cfg(test)]
+#[allow(clippy::redundant_pattern_matching)] // Tests keep their original assertions style
 mod tests {
     use super::*;
 
@@ -467,5 +709,86 @@ mod tests {
         let centroids = centroids.unwrap();
         assert_eq!(centroids.len(), centroids_count);
     }
-}
 
+    #[test]
+    fn test_centroids_are_deterministic_for_seed() {
+        let input_data: Vec<f32> = (0..300).map(|v| ((v * 37) % 101) as f32).collect();
+        let distance_measure = |a: &f32, b: &f32| { (a - b).abs() };
+        let calculate_mean = |arr: &[f32]| { arr.iter().sum::<f32>() / arr.len() as f32 };
+
+        let options = KMeansOptions { seed: 42, restarts: 3, ..Default::default() };
+        let first = find_centroids_with_options(&input_data, 6, &options, distance_measure, calculate_mean).unwrap();
+        let second = find_centroids_with_options(&input_data, 6, &options, distance_measure, calculate_mean).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.centroids.len(), 6);
+    }
+
+    #[test]
+    fn test_restarts_do_not_worsen_inertia() {
+        let input_data: Vec<f32> = (0..200).map(|v| ((v * 13) % 97) as f32 + if v % 5 == 0 { 300.0 } else { 0.0 }).collect();
+        let distance_measure = |a: &f32, b: &f32| { (a - b).abs() };
+        let calculate_mean = |arr: &[f32]| { arr.iter().sum::<f32>() / arr.len() as f32 };
+
+        let single = KMeansOptions { seed: 7, restarts: 1, ..Default::default() };
+        let multiple = KMeansOptions { seed: 7, restarts: 5, ..Default::default() };
+        let single = find_centroids_with_options(&input_data, 4, &single, distance_measure, calculate_mean).unwrap();
+        let multiple = find_centroids_with_options(&input_data, 4, &multiple, distance_measure, calculate_mean).unwrap();
+        // First restart of both uses the same seeding
+        assert!(multiple.inertia <= single.inertia);
+    }
+
+    #[test]
+    fn test_iterations_cap_returns_best_so_far() {
+        let input_data: Vec<f32> = (0..100).map(|v| (v * v % 89) as f32).collect();
+        let distance_measure = |a: &f32, b: &f32| { (a - b).abs() };
+        let calculate_mean = |arr: &[f32]| { arr.iter().sum::<f32>() / arr.len() as f32 };
+
+        let options = KMeansOptions { iterations_max: 1, ..Default::default() };
+        let result = find_centroids_with_options(&input_data, 5, &options, distance_measure, calculate_mean).unwrap();
+        assert_eq!(result.centroids.len(), 5);
+        assert!(result.inertia.is_finite());
+    }
+
+    #[test]
+    fn test_progress_grows_up_to_finish_over_restarts() {
+        let input_data: Vec<f32> = (0..300).map(|v| ((v * 37) % 101) as f32).collect();
+        let distance_measure = |a: &f32, b: &f32| { (a - b).abs() };
+        let calculate_mean = |arr: &[f32]| { arr.iter().sum::<f32>() / arr.len() as f32 };
+
+        let reported = std::sync::Mutex::new(Vec::new());
+        let progress = |fraction: f32| reported.lock().unwrap().push(fraction);
+        let options = KMeansOptions { restarts: 3, ..Default::default() };
+        find_centroids_with_progress(&input_data, 6, &options, distance_measure, calculate_mean, &progress).unwrap();
+
+        let reported = reported.into_inner().unwrap();
+        assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]), "{reported:?}");
+        assert!(reported.iter().any(|fraction| (fraction - 1.0 / 3.0).abs() < 1e-6), "First restart finished");
+        assert_eq!(reported.last(), Some(&1.0));
+    }
+
+    struct Cancelled(std::sync::Mutex<usize>);
+
+    impl Progress for Cancelled {
+        fn report(&self, _fraction: f32) {
+            *self.0.lock().unwrap() += 1;
+        }
+
+        fn is_cancelled(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_cancelled_clustering_stops_after_first_iteration() {
+        let input_data: Vec<f32> = (0..300).map(|v| ((v * 37) % 101) as f32).collect();
+        let distance_measure = |a: &f32, b: &f32| { (a - b).abs() };
+        let calculate_mean = |arr: &[f32]| { arr.iter().sum::<f32>() / arr.len() as f32 };
+
+        let progress = Cancelled(Default::default());
+        let options = KMeansOptions { restarts: 3, ..Default::default() };
+        let result = find_centroids_with_progress(&input_data, 6, &options, distance_measure, calculate_mean, &progress).unwrap();
+
+        assert_eq!(result.centroids.len(), 6, "Best centroids so far are returned");
+        assert_eq!(*progress.0.lock().unwrap(), 1);
+    }
+}
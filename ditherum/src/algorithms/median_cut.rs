@@ -0,0 +1,101 @@
+/// Point in 3 dimensional color space, e.g. sRGB channels or CIELAB coordinates.
+pub type ColorPoint = [f32; 3];
+
+/// Axis with the widest range of values and the range itself.
+fn widest_axis(points: &[ColorPoint]) -> (usize, f32) {
+    (0..3)
+        .map(|axis| {
+            let (min, max) = points.iter()
+                .map(|p| p[axis])
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+            (axis, max - min)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .expect("there are 3 axes")
+}
+
+/// Mean of color points, `points` must not be empty.
+pub fn color_points_mean(points: &[ColorPoint]) -> ColorPoint {
+    let sum = points.iter()
+        .fold([0.0; 3], |acc, p| [acc[0] + p[0], acc[1] + p[1], acc[2] + p[2]]);
+    let count = points.len() as f32;
+    [sum[0] / count, sum[1] / count, sum[2] / count]
+}
+
+/// Reduces points to at most `boxes_count` representatives using median cut.
+///
+/// Starting from a single box containing all points, the box with the widest range
+/// along any axis is repeatedly split at the median of that axis. Representatives are
+/// means of the final boxes.
+///
+/// # Returns
+/// Means of boxes ordered by the count of points in a box, descending. Less than
+/// `boxes_count` of them when points can not be split further.
+pub fn median_cut(points: &[ColorPoint], boxes_count: usize) -> Vec<ColorPoint> {
+    if points.is_empty() || boxes_count == 0 {
+        return vec![];
+    }
+
+    let mut boxes = vec![points.to_vec()];
+
+    while boxes.len() < boxes_count {
+        let widest_box = boxes.iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(idx, b)| (idx, widest_axis(b)))
+            .filter(|(_, (_, range))| *range > 0.0)
+            .max_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b));
+
+        let Some((box_idx, (axis, _))) = widest_box else {
+            // Every box contains identical points only
+            break;
+        };
+
+        let mut box_points = boxes.swap_remove(box_idx);
+        box_points.sort_by(|a, b| a[axis].total_cmp(&b[axis]));
+        let upper_half = box_points.split_off(box_points.len() / 2);
+        boxes.push(box_points);
+        boxes.push(upper_half);
+    }
+
+    boxes.sort_by_key(|b| std::cmp::Reverse(b.len()));
+    boxes.iter()
+        .map(|b| color_points_mean(b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_cut_separates_groups() {
+        let mut points = vec![[0.0, 0.0, 0.0]; 20];
+        points.extend(vec![[1.0, 1.0, 1.0]; 20]);
+
+        let representatives = median_cut(&points, 2);
+        assert_eq!(representatives.len(), 2);
+        assert!(representatives.contains(&[0.0, 0.0, 0.0]));
+        assert!(representatives.contains(&[1.0, 1.0, 1.0]));
+
+        // Boxes of identical points are not split
+        assert_eq!(median_cut(&points, 4).len(), 2);
+    }
+
+    #[test]
+    fn test_median_cut_ordered_by_box_size() {
+        let mut points = vec![[0.0, 0.0, 0.0]; 10];
+        points.extend(vec![[0.0, 1.0, 0.0]; 10]);
+        points.extend(vec![[0.0, 0.0, 1.0]; 20]);
+
+        let representatives = median_cut(&points, 3);
+        assert_eq!(representatives[0], [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_median_cut_identical_points() {
+        let points = vec![[0.5, 0.5, 0.5]; 10];
+        assert_eq!(median_cut(&points, 3), vec![[0.5, 0.5, 0.5]]);
+        assert!(median_cut(&[], 3).is_empty());
+    }
+}
@@ -1,3 +1,6 @@
 pub mod kmean;
 pub mod kernel;
-pub mod dithering;
\ No newline at end of file
+pub mod dithering;
+pub mod median_cut;
+pub mod threshold_map;
+pub mod confetti;
\ No newline at end of file
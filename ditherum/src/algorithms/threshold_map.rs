@@ -0,0 +1,273 @@
+use std::sync::{
+    Arc,
+    OnceLock
+};
+
+use rand::{
+    seq::SliceRandom,
+    SeedableRng
+};
+
+/// Size of the tiled blue noise threshold map.
+pub const BLUE_NOISE_SIZE: usize = 64;
+
+/// Seed of blue noise generation, fixed so the same image always gets the same drills layout.
+const BLUE_NOISE_SEED: u64 = 0x00D1_A30D;
+
+/// Standard deviation of the gaussian energy filter of void-and-cluster method.
+const VOID_AND_CLUSTER_SIGMA: f32 = 1.5;
+
+/// Square matrix of thresholds in range `0.0..1.0`, tiled over the image in ordered dithering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdMap {
+    size: usize,
+    thresholds: Vec<f32>,
+}
+
+impl ThresholdMap {
+    /// Creates map from ranks, every value of `0..size*size` must occur exactly once.
+    fn from_ranks(size: usize, ranks: &[usize]) -> Self {
+        debug_assert_eq!(ranks.len(), size * size);
+
+        let count = ranks.len() as f32;
+        let thresholds = ranks.iter()
+            .map(|rank| (*rank as f32 + 0.5) / count)
+            .collect();
+
+        Self { size, thresholds }
+    }
+
+    /// Bayer matrix of size `2^order`, e.g. order 2 gives 4x4 matrix.
+    pub fn bayer(order: u32) -> Self {
+        let mut size = 1;
+        let mut ranks = vec![0usize];
+
+        for _ in 0..order {
+            let size_next = size * 2;
+            let mut ranks_next = vec![0; size_next * size_next];
+
+            for y in 0..size {
+                for x in 0..size {
+                    let rank = 4 * ranks[y * size + x];
+                    ranks_next[y * size_next + x] = rank;
+                    ranks_next[y * size_next + x + size] = rank + 2;
+                    ranks_next[(y + size) * size_next + x] = rank + 3;
+                    ranks_next[(y + size) * size_next + x + size] = rank + 1;
+                }
+            }
+
+            size = size_next;
+            ranks = ranks_next;
+        }
+
+        Self::from_ranks(size, &ranks)
+    }
+
+    /// Blue noise map of [`BLUE_NOISE_SIZE`], generated once and shared.
+    pub fn blue_noise() -> Arc<ThresholdMap> {
+        static BLUE_NOISE: OnceLock<Arc<ThresholdMap>> = OnceLock::new();
+        BLUE_NOISE
+            .get_or_init(|| Arc::new(Self::generate_blue_noise(BLUE_NOISE_SIZE, BLUE_NOISE_SEED)))
+            .clone()
+    }
+
+    /// Generates blue noise map using void-and-cluster method by Ulichney.
+    /// Noise tiles seamlessly, energy is computed on a torus.
+    pub fn generate_blue_noise(size: usize, seed: u64) -> Self {
+        assert!(size >= 4);
+        VoidAndCluster::new(size, seed).generate()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Threshold of the image pixel, map is tiled over the image.
+    pub fn threshold(&self, x: u32, y: u32) -> f32 {
+        let (x, y) = (x as usize % self.size, y as usize % self.size);
+        self.thresholds[y * self.size + x]
+    }
+}
+
+/// State of void-and-cluster blue noise generation.
+struct VoidAndCluster {
+    size: usize,
+    seed: u64,
+    /// Gaussian weights by toroidal offset, indexed like pixels.
+    filter: Vec<f32>,
+}
+
+impl VoidAndCluster {
+    fn new(size: usize, seed: u64) -> Self {
+        let mut filter = vec![0.0; size * size];
+        for dy in 0..size {
+            for dx in 0..size {
+                let wrap = |d: usize| d.min(size - d) as f32;
+                let distance_squared = wrap(dx).powi(2) + wrap(dy).powi(2);
+                filter[dy * size + dx] = (-distance_squared / (2.0 * VOID_AND_CLUSTER_SIGMA.powi(2))).exp();
+            }
+        }
+
+        Self { size, seed, filter }
+    }
+
+    fn count(&self) -> usize {
+        self.size * self.size
+    }
+
+    /// Adds (or subtracts) influence of a pixel to the energy of all pixels.
+    fn spread_energy(&self, energy: &mut [f32], idx: usize, sign: f32) {
+        let (px, py) = (idx % self.size, idx / self.size);
+        for y in 0..self.size {
+            let dy = (y + self.size - py) % self.size;
+            for x in 0..self.size {
+                let dx = (x + self.size - px) % self.size;
+                energy[y * self.size + x] += sign * self.filter[dy * self.size + dx];
+            }
+        }
+    }
+
+    fn energy_of(&self, pattern: &[bool], minority: bool) -> Vec<f32> {
+        let mut energy = vec![0.0; self.count()];
+        pattern.iter()
+            .enumerate()
+            .filter(|(_, value)| **value == minority)
+            .for_each(|(idx, _)| self.spread_energy(&mut energy, idx, 1.0));
+        energy
+    }
+
+    /// Pixel of `value` with the highest energy.
+    fn tightest_cluster(pattern: &[bool], energy: &[f32], value: bool) -> usize {
+        (0..pattern.len())
+            .filter(|idx| pattern[*idx] == value)
+            .max_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+            .expect("pattern should contain searched value")
+    }
+
+    /// Pixel of `!value` with the lowest energy.
+    fn largest_void(pattern: &[bool], energy: &[f32], value: bool) -> usize {
+        (0..pattern.len())
+            .filter(|idx| pattern[*idx] != value)
+            .min_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+            .expect("pattern should contain void")
+    }
+
+    /// Random pattern with ~10% of minority pixels, relaxed into evenly spread one.
+    fn initial_pattern(&self) -> Vec<bool> {
+        let count = self.count();
+        let ones_count = (count / 10).max(1);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        let mut pattern = vec![false; count];
+        pattern[..ones_count].fill(true);
+        pattern.shuffle(&mut rng);
+
+        let mut energy = self.energy_of(&pattern, true);
+        loop {
+            let cluster = Self::tightest_cluster(&pattern, &energy, true);
+            pattern[cluster] = false;
+            self.spread_energy(&mut energy, cluster, -1.0);
+
+            let void = Self::largest_void(&pattern, &energy, true);
+            pattern[void] = true;
+            self.spread_energy(&mut energy, void, 1.0);
+
+            if void == cluster {
+                return pattern;
+            }
+        }
+    }
+
+    fn generate(&self) -> ThresholdMap {
+        let count = self.count();
+        let prototype = self.initial_pattern();
+        let ones_count = prototype.iter().filter(|v| **v).count();
+        let mut ranks = vec![0usize; count];
+
+        // Phase 1, rank minority pixels of the prototype by removing tightest clusters
+        let mut pattern = prototype.clone();
+        let mut energy = self.energy_of(&pattern, true);
+        for rank in (0..ones_count).rev() {
+            let cluster = Self::tightest_cluster(&pattern, &energy, true);
+            pattern[cluster] = false;
+            self.spread_energy(&mut energy, cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+
+        // Phase 2, fill largest voids up to half of the pixels
+        let mut pattern = prototype;
+        let mut energy = self.energy_of(&pattern, true);
+        for rank in ones_count..(count / 2) {
+            let void = Self::largest_void(&pattern, &energy, true);
+            pattern[void] = true;
+            self.spread_energy(&mut energy, void, 1.0);
+            ranks[void] = rank;
+        }
+
+        // Phase 3, zeros are minority now, fill their tightest clusters
+        let mut energy = self.energy_of(&pattern, false);
+        for rank in (count / 2)..count {
+            let cluster = Self::tightest_cluster(&pattern, &energy, false);
+            pattern[cluster] = true;
+            self.spread_energy(&mut energy, cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+
+        ThresholdMap::from_ranks(self.size, &ranks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_thresholds_unique(map: &ThresholdMap) {
+        let count = map.size() * map.size();
+        let mut ranks = map.thresholds.iter()
+            .map(|t| (t * count as f32 - 0.5).round() as usize)
+            .collect::<Vec<_>>();
+        ranks.sort();
+        assert_eq!(ranks, (0..count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bayer_matrices() {
+        let bayer_2x2 = ThresholdMap::bayer(1);
+        assert_eq!(bayer_2x2.size(), 2);
+        assert_eq!(bayer_2x2.thresholds, vec![0.125, 0.625, 0.875, 0.375]);
+
+        for order in 1..=3 {
+            let bayer = ThresholdMap::bayer(order);
+            assert_eq!(bayer.size(), 1 << order);
+            assert_thresholds_unique(&bayer);
+        }
+    }
+
+    #[test]
+    fn test_threshold_map_is_tiled() {
+        let bayer_4x4 = ThresholdMap::bayer(2);
+        assert_eq!(bayer_4x4.threshold(1, 2), bayer_4x4.threshold(5, 6));
+        assert_eq!(bayer_4x4.threshold(3, 0), bayer_4x4.threshold(7, 12));
+    }
+
+    #[test]
+    fn test_blue_noise_generation() {
+        let blue_noise = ThresholdMap::generate_blue_noise(16, 7);
+        assert_eq!(blue_noise.size(), 16);
+        assert_thresholds_unique(&blue_noise);
+
+        // Lowest thresholds should be spread evenly, not clustered as neighbours
+        let darkest = (0..16u32 * 16)
+            .filter(|idx| blue_noise.threshold(idx % 16, idx / 16) < 0.1)
+            .collect::<Vec<_>>();
+        for a in darkest.iter() {
+            for b in darkest.iter().filter(|b| *b != a) {
+                let (dx, dy) = ((a % 16).abs_diff(b % 16), (a / 16).abs_diff(b / 16));
+                let (dx, dy) = (dx.min(16 - dx), dy.min(16 - dy));
+                assert!(dx > 1 || dy > 1, "{a} and {b} are neighbours");
+            }
+        }
+
+        assert_eq!(blue_noise, ThresholdMap::generate_blue_noise(16, 7));
+    }
+}
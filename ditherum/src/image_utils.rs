@@ -4,6 +4,7 @@ use crate::palette_utils::{
         self, 
         mix_rgb_colors
     }, 
+    ColorDistance, 
     PaletteSrgb
 };
 
@@ -39,6 +40,12 @@ pub fn generate_gradient_image(
     img
 }
 
+/// Whether the pixel is left out of processing, pixels of `mask` darker than half are.
+/// Without mask every pixel is processed.
+pub fn is_masked_out(mask: Option<&image::GrayImage>, x: u32, y: u32) -> bool {
+    mask.is_some_and(|mask| mask.get_pixel(x, y)[0] < 128)
+}
+
 /// Converts an `image::RgbImage` to a 2D vector of `palette::Srgb<f32>`.
 pub fn image_rgb_to_matrix_srgb_f32(source_image: &image::RgbImage) -> Vec<Vec<palette::Srgb<f32>>> {
     let (width, height) = (source_image.width() as usize, source_image.height() as usize);
@@ -52,16 +59,21 @@ pub fn image_rgb_to_matrix_srgb_f32(source_image: &image::RgbImage) -> Vec<Vec<p
     srgb_float_image
 }
 
-pub fn matrix_srgb_float_palette_quantization(matrix: &[Vec<palette::Srgb<f32>>], palette_srgb_u8: &PaletteSrgb<u8>) -> image::RgbImage {
+pub fn matrix_srgb_float_palette_quantization(
+    matrix: &[Vec<palette::Srgb<f32>>], 
+    palette_srgb_u8: &PaletteSrgb<u8>,
+    color_distance: ColorDistance
+) -> image::RgbImage {
     let height = matrix.len();
     assert!(height > 0);
 
     let width = matrix[0].len();
     assert!(width > 0);
 
+    let matcher = palette_srgb_u8.matcher(color_distance);
     image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
         let srgb_float_color = matrix[y as usize][x as usize];
-        let srgb_u8_color = palette_srgb_u8.find_closest(srgb_float_color);
+        let srgb_u8_color = palette_srgb_u8.as_ref()[matcher.find_closest_idx(srgb_float_color)];
         palette_utils::color_manip::srgb_u8_to_rgb_u8(&srgb_u8_color)
     })
 }
\ No newline at end of file
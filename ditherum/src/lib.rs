@@ -1,3 +1,4 @@
 pub mod algorithms;
 pub mod image_utils;
 pub mod palette_utils;
+pub mod progress;
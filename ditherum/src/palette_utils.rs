@@ -1,7 +1,12 @@
 use std::collections::HashMap;
 
 use palette::{
-    color_difference::EuclideanDistance, 
+    color_difference::Ciede2000, 
+    white_point::D65, 
+    IntoColor, 
+    Lab, 
+    LinSrgb, 
+    Oklab, 
     Srgb
 };
 
@@ -10,6 +15,27 @@ use serde::{
     Serialize
 };
 
+use crate::progress::{
+    NoProgress, 
+    Progress
+};
+
+use crate::algorithms::{
+    kmean::{
+        find_centroids_with_progress, 
+        CentroidsFindError, 
+        KMeansOptions
+    }, 
+    median_cut::{
+        color_points_mean, 
+        median_cut, 
+        ColorPoint
+    }
+};
+
+/// Pixels of an image sampled for clustering at most.
+pub const CLUSTERING_SAMPLES_MAX: usize = 16_384;
+
 // pub trait AllowedChannelType {}
 
 // impl AllowedChannelType for u8 {}
@@ -24,21 +50,288 @@ pub struct PaletteSrgb<T> {
     colors: Vec<Srgb<T>>
 }
 
+/// Strategy used to measure how different two colors are.
+///
+/// Every strategy maps colors into its own space once, see [`ColorDistance::to_space`],
+/// so palettes can be prepared upfront with [`PaletteMatcher`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorDistance {
+    /// Euclidean distance of gamma encoded sRGB. Fast, but far from human perception.
+    Srgb,
+    /// Euclidean distance of linear light RGB.
+    LinearRgb,
+    /// CIE76, Euclidean distance in CIELAB.
+    Cie76,
+    /// CIE94 with graphic arts weights, corrects CIE76 for saturated colors.
+    Cie94,
+    /// CIEDE2000, the most accurate and the slowest one.
+    Ciede2000,
+    /// Euclidean distance in OKLab. Close to CIEDE2000 at cost of CIE76.
+    #[default]
+    Oklab,
+}
+
+/// Color converted into the space of a [`ColorDistance`].
+pub type SpaceColor = [f32; 3];
+
+impl ColorDistance {
+    /// CIE94 weights for graphic arts.
+    const CIE94_K1: f32 = 0.045;
+    const CIE94_K2: f32 = 0.015;
+
+    /// Converts color into the space the distance is measured in. Channels
+    /// may exceed `0.0..=1.0`, e.g. with accumulated dithering error.
+    pub fn to_space(&self, color: Srgb<f32>) -> SpaceColor {
+        match self {
+            ColorDistance::Srgb => [color.red, color.green, color.blue],
+            ColorDistance::LinearRgb => {
+                let linear: LinSrgb<f32> = color.into_linear();
+                [linear.red, linear.green, linear.blue]
+            },
+            ColorDistance::Cie76 | ColorDistance::Cie94 | ColorDistance::Ciede2000 => {
+                let lab: Lab<D65, f32> = color.into_color();
+                [lab.l, lab.a, lab.b]
+            },
+            ColorDistance::Oklab => {
+                let oklab: Oklab<f32> = color.into_color();
+                [oklab.l, oklab.a, oklab.b]
+            },
+        }
+    }
+
+    /// Distance between colors already converted with [`ColorDistance::to_space`].
+    ///
+    /// Only ordering of the results is meaningful across strategies, Euclidean ones
+    /// return squared distance.
+    pub fn distance_in_space(&self, reference: &SpaceColor, sample: &SpaceColor) -> f32 {
+        match self {
+            ColorDistance::Srgb 
+                | ColorDistance::LinearRgb 
+                | ColorDistance::Cie76 
+                | ColorDistance::Oklab => {
+                reference.iter()
+                    .zip(sample.iter())
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum()
+            },
+            ColorDistance::Cie94 => {
+                let [l1, a1, b1] = *reference;
+                let [l2, a2, b2] = *sample;
+
+                let chroma_1 = (a1 * a1 + b1 * b1).sqrt();
+                let chroma_2 = (a2 * a2 + b2 * b2).sqrt();
+                let delta_l = l1 - l2;
+                let delta_c = chroma_1 - chroma_2;
+                let delta_h_squared = ((a1 - a2).powi(2) + (b1 - b2).powi(2) - delta_c * delta_c).max(0.0);
+
+                let s_c = 1.0 + Self::CIE94_K1 * chroma_1;
+                let s_h = 1.0 + Self::CIE94_K2 * chroma_1;
+
+                delta_l * delta_l + (delta_c / s_c).powi(2) + delta_h_squared / (s_h * s_h)
+            },
+            ColorDistance::Ciede2000 => {
+                let reference = Lab::<D65, f32>::new(reference[0], reference[1], reference[2]);
+                let sample = Lab::<D65, f32>::new(sample[0], sample[1], sample[2]);
+                reference.difference(sample)
+            },
+        }
+    }
+
+    /// Distance between two colors, see [`ColorDistance::distance_in_space`].
+    pub fn distance(&self, reference: Srgb<f32>, sample: Srgb<f32>) -> f32 {
+        self.distance_in_space(&self.to_space(reference), &self.to_space(sample))
+    }
+}
+
+/// Palette colors converted upfront into the space of a [`ColorDistance`],
+/// for repeated closest color lookups.
+#[derive(Debug, Clone)]
+pub struct PaletteMatcher {
+    color_distance: ColorDistance,
+    colors_in_space: Vec<SpaceColor>,
+}
+
+impl PaletteMatcher {
+    pub fn new<I>(colors: I, color_distance: ColorDistance) -> Self 
+    where 
+        I: IntoIterator<Item = Srgb<f32>>
+    {
+        let colors_in_space = colors.into_iter()
+            .map(|c| color_distance.to_space(c))
+            .collect();
+
+        Self { color_distance, colors_in_space }
+    }
+
+    pub fn color_distance(&self) -> ColorDistance {
+        self.color_distance
+    }
+
+    /// Finds index of the palette color closest to `random_color`.
+    ///
+    /// # Panics
+    /// When matcher was created from an empty palette.
+    pub fn find_closest_idx(&self, random_color: Srgb<f32>) -> usize {
+        assert!(!self.colors_in_space.is_empty());
+
+        let random_color = self.color_distance.to_space(random_color);
+        self.colors_in_space.iter()
+            .map(|c| self.color_distance.distance_in_space(c, &random_color))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(idx, _)| idx)
+            .expect("At least 1 element was in palette")
+    }
+}
+
+impl<T> PaletteSrgb<T> 
+where 
+    T: palette::stimulus::IntoStimulus<f32> + Copy,
+{
+    /// Prepares palette for repeated closest color lookups using `color_distance`.
+    pub fn matcher(&self, color_distance: ColorDistance) -> PaletteMatcher {
+        PaletteMatcher::new(self.colors.iter().map(|c| c.into_format()), color_distance)
+    }
+
+    /// Index of the closest color in sRGB, cheap for a single lookup unlike [`Self::matcher`].
+    fn find_closest_idx(&self, random_color: Srgb<f32>) -> usize {
+        self.colors.iter()
+            .map(|c| ColorDistance::Srgb.distance(c.into_format(), random_color))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(idx, _)| idx)
+            .expect("At least 1 element was in palette")
+    }
+}
+
+/// Method of reducing colors of an image into a palette by clustering them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteClustering {
+    /// K-means with k-means++ seeding, keeps small but distinct groups of colors.
+    #[default]
+    KMeans,
+    /// Median cut, fast and deterministic.
+    MedianCut,
+}
+
+fn srgb_to_lab_point(color: Srgb<f32>) -> ColorPoint {
+    let lab: Lab<D65, f32> = color.into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+fn lab_point_to_srgb(point: &ColorPoint) -> Srgb<f32> {
+    let srgb: Srgb<f32> = Lab::<D65, f32>::new(point[0], point[1], point[2]).into_color();
+    Srgb::new(
+        srgb.red.clamp(0.0, 1.0),
+        srgb.green.clamp(0.0, 1.0),
+        srgb.blue.clamp(0.0, 1.0)
+    )
+}
+
+fn lab_points_distance(a: &ColorPoint, b: &ColorPoint) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Pixels of an image in CIELAB, evenly sampled down to [`CLUSTERING_SAMPLES_MAX`].
+fn image_lab_samples(img: &image::RgbImage) -> Vec<ColorPoint> {
+    let pixels_count = img.pixels().len();
+    let step = pixels_count.div_ceil(CLUSTERING_SAMPLES_MAX).max(1);
+
+    img.pixels()
+        .step_by(step)
+        .map(|p| srgb_to_lab_point(color_manip::rgb_u8_to_srgb_float(p)))
+        .collect()
+}
+
 // TODO make it generic somehow
 impl PaletteSrgb<f32> {
-    pub fn find_closest(&self, random_color: Srgb<f32>) -> Srgb<f32> {
-        assert!(!self.colors.is_empty());
+    /// Extracts representative colors of an image by clustering its pixels in CIELAB.
+    ///
+    /// # Parameters
+    /// - `img`: An `RgbImage` from the `image` crate.
+    /// - `colors_count`: Count of clusters, palette can be smaller when image has less colors.
+    /// - `clustering`: Clustering method.
+    /// - `kmeans`: Seed and restarts of [`PaletteClustering::KMeans`], same seed gives same palette.
+    ///
+    /// # Returns
+    /// A palette of clusters' centers ordered by count of pixels in a cluster, descending.
+    /// [`CentroidsFindError::NoCentroids`] when `colors_count` is zero.
+    pub fn from_image_clustered(
+        img: &image::RgbImage, 
+        colors_count: usize, 
+        clustering: PaletteClustering,
+        kmeans: &KMeansOptions
+    ) -> Result<Self, CentroidsFindError> {
+        Self::from_image_clustered_with_progress(img, colors_count, clustering, kmeans, &NoProgress)
+    }
+
+    /// Extracts representative colors of an image like [`Self::from_image_clustered`],
+    /// `progress` is reported by every iteration of k-means, median cut reports only its end.
+    pub fn from_image_clustered_with_progress(
+        img: &image::RgbImage, 
+        colors_count: usize, 
+        clustering: PaletteClustering,
+        kmeans: &KMeansOptions,
+        progress: &dyn Progress
+    ) -> Result<Self, CentroidsFindError> {
+        let samples = image_lab_samples(img);
+        if samples.is_empty() {
+            return Err(CentroidsFindError::InputEmpty);
+        }
+        let colors_count = colors_count.min(samples.len());
+
+        let centroids = match clustering {
+            PaletteClustering::KMeans => {
+                find_centroids_with_progress(
+                    &samples, 
+                    colors_count, 
+                    kmeans, 
+                    lab_points_distance, 
+                    color_points_mean,
+                    progress
+                )?.centroids
+            },
+            PaletteClustering::MedianCut => {
+                let centroids = median_cut(&samples, colors_count);
+                progress.report(1.0);
+                centroids
+            },
+        };
+        if centroids.is_empty() {
+            return Err(CentroidsFindError::NoCentroids);
+        }
 
-        let result = self.colors.iter()
-            .min_by_key(|c| c.distance_squared(random_color) as i32)
-            .unwrap();
-        *result
+        // Order by size of clusters
+        let mut clusters_sizes = vec![0usize; centroids.len()];
+        samples.iter().for_each(|sample| {
+            let closest_idx = centroids.iter()
+                .map(|c| lab_points_distance(c, sample))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(idx, _)| idx)
+                .expect("At least 1 centroid was found");
+            clusters_sizes[closest_idx] += 1;
+        });
+
+        let mut centroids_sized = centroids.iter()
+            .zip(clusters_sizes)
+            .collect::<Vec<_>>();
+        centroids_sized.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+        Ok(centroids_sized.into_iter()
+            .map(|(centroid, _)| lab_point_to_srgb(centroid))
+            .collect())
     }
-}
 
-impl<T> PaletteSrgb<T> {
+    pub fn find_closest(&self, random_color: Srgb<f32>) -> Srgb<f32> {
+        self.colors[self.find_closest_idx(random_color)]
+    }
+}
 
-    pub fn from_iter<I: IntoIterator<Item = palette::Srgb<T>>>(iter: I) -> Self {
+impl<T> FromIterator<Srgb<T>> for PaletteSrgb<T> {
+    fn from_iter<I: IntoIterator<Item = Srgb<T>>>(iter: I) -> Self {
         Self { colors: iter.into_iter().collect() }
     }
 }
@@ -105,12 +398,7 @@ impl PaletteSrgb<u8> {
     }
 
     pub fn find_closest(&self, random_color: Srgb<f32>) -> Srgb<u8> {
-        assert!(!self.colors.is_empty());
-
-        let result = self.colors.iter()
-            .min_by_key(|c| c.into_format().distance_squared(random_color) as i32)
-            .unwrap();
-        *result
+        self.colors[self.find_closest_idx(random_color)]
     }
     // Count colors ?
 }
@@ -206,6 +494,7 @@ impl From<&PaletteSrgb<u8>> for PaletteSrgb<f32> {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)] // Tests keep their original assertions style
 mod tests {
     use super::*;
     use crate::image_utils::generate_gradient_image; // adjust module path to match your layout
@@ -264,4 +553,104 @@ mod tests {
         assert_eq!(closest_black, Srgb::new(0, 0, 0));
         assert_eq!(closest_white, Srgb::new(255, 255, 255));
     }
-}
\ No newline at end of file
+
+    const ALL_COLOR_DISTANCES: [ColorDistance; 6] = [
+        ColorDistance::Srgb,
+        ColorDistance::LinearRgb,
+        ColorDistance::Cie76,
+        ColorDistance::Cie94,
+        ColorDistance::Ciede2000,
+        ColorDistance::Oklab,
+    ];
+
+    #[test]
+    fn test_color_distance_of_same_color_is_zero() {
+        let color = Srgb::new(0.8, 0.6, 0.5);
+        for color_distance in ALL_COLOR_DISTANCES {
+            assert!(color_distance.distance(color, color).abs() < 1e-4, "{color_distance:?}");
+            assert!(color_distance.distance(color, Srgb::new(0.1, 0.2, 0.3)) > 0.0, "{color_distance:?}");
+        }
+    }
+
+    #[test]
+    fn test_float_palette_closest_color_is_not_first_one() {
+        let palette = PaletteSrgb::<f32>::from_iter([
+            Srgb::new(0.0, 0.0, 0.0),
+            Srgb::new(0.5, 0.5, 0.5),
+            Srgb::new(1.0, 1.0, 1.0),
+        ]);
+
+        assert_eq!(palette.find_closest(Srgb::new(0.45, 0.5, 0.55)), Srgb::new(0.5, 0.5, 0.5));
+        for color_distance in ALL_COLOR_DISTANCES {
+            let matcher = palette.matcher(color_distance);
+            assert_eq!(matcher.find_closest_idx(Srgb::new(0.45, 0.5, 0.55)), 1, "{color_distance:?}");
+            assert_eq!(matcher.find_closest_idx(Srgb::new(0.95, 0.9, 1.0)), 2, "{color_distance:?}");
+        }
+    }
+
+    #[test]
+    fn test_ciede2000_reference_pair() {
+        // First pair of test data from Sharma, Wu, Dalal "The CIEDE2000 Color-Difference Formula"
+        let distance = ColorDistance::Ciede2000.distance_in_space(
+            &[50.0, 2.6772, -79.7751],
+            &[50.0, 0.0, -82.7485]
+        );
+        assert!((distance - 2.0425).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_cie94_lightness_only_difference() {
+        let distance = ColorDistance::Cie94.distance_in_space(
+            &[50.0, 10.0, 10.0],
+            &[40.0, 10.0, 10.0]
+        );
+        assert!((distance - 100.0).abs() < 1e-3);
+    }
+
+    fn image_with_accent() -> image::RgbImage {
+        // Half black, half white, small red accent
+        image::RgbImage::from_fn(100, 100, |x, y| {
+            if (45..55).contains(&x) && (48..50).contains(&y) {
+                image::Rgb([220, 20, 30])
+            } else if x < 50 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn test_palette_from_image_kmeans_keeps_accent() {
+        let palette = PaletteSrgb::from_image_clustered(&image_with_accent(), 3, PaletteClustering::KMeans, &Default::default()).unwrap();
+        assert_eq!(palette.as_ref().len(), 3);
+
+        let red_accent = Srgb::new(220u8, 20, 30).into_format::<f32>();
+        let has_accent = palette.as_ref().iter()
+            .any(|c| ColorDistance::Cie76.distance(*c, red_accent) < 1.0);
+        assert!(has_accent, "{palette:?}");
+    }
+
+    #[test]
+    fn test_palette_from_image_median_cut() {
+        let palette = PaletteSrgb::from_image_clustered(&image_with_accent(), 4, PaletteClustering::MedianCut, &Default::default()).unwrap();
+        assert!(!palette.as_ref().is_empty());
+        assert!(palette.as_ref().len() <= 4);
+    }
+
+    #[test]
+    fn test_palette_from_image_kmeans_is_reproducible() {
+        let kmeans = KMeansOptions { seed: 3, restarts: 2, ..Default::default() };
+        let first = PaletteSrgb::from_image_clustered(&image_with_accent(), 3, PaletteClustering::KMeans, &kmeans).unwrap();
+        let second = PaletteSrgb::from_image_clustered(&image_with_accent(), 3, PaletteClustering::KMeans, &kmeans).unwrap();
+        assert_eq!(first.as_ref(), second.as_ref());
+    }
+
+    #[test]
+    fn test_palette_from_image_no_colors_should_result_err() {
+        for clustering in [PaletteClustering::KMeans, PaletteClustering::MedianCut] {
+            let result = PaletteSrgb::from_image_clustered(&image_with_accent(), 0, clustering, &Default::default());
+            assert!(matches!(result, Err(CentroidsFindError::NoCentroids)), "{clustering:?}: {result:?}");
+        }
+    }
+}
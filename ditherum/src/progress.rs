@@ -0,0 +1,98 @@
+/// Observer of long running algorithms, e.g. dithering or clustering.
+pub trait Progress: Sync {
+    /// Called with fraction of finished work in range `0.0..=1.0`.
+    /// It may be called often, implementations should be cheap.
+    fn report(&self, fraction: f32);
+
+    /// Checked by algorithms along with reporting. Once cancelled, they return early
+    /// with an unfinished result, which should be thrown away. Never cancelled by default.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Progress which ignores every report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&self, _fraction: f32) {}
+}
+
+impl<F> Progress for F
+where
+    F: Fn(f32) + Sync
+{
+    fn report(&self, fraction: f32) {
+        self(fraction)
+    }
+}
+
+/// Maps reports of a single step into part `from..=to` of the outer progress,
+/// so the step can report in range `0.0..=1.0` not knowing about other steps.
+pub struct ProgressRange<'a> {
+    outer: &'a dyn Progress,
+    from: f32,
+    to: f32,
+}
+
+impl<'a> ProgressRange<'a> {
+    pub fn new(outer: &'a dyn Progress, from: f32, to: f32) -> Self {
+        Self { outer, from, to }
+    }
+
+    /// Range of the `index`-th out of `count` equal steps.
+    pub fn step(outer: &'a dyn Progress, index: usize, count: usize) -> Self {
+        let count = count.max(1) as f32;
+        Self::new(outer, index as f32 / count, (index + 1) as f32 / count)
+    }
+}
+
+impl Progress for ProgressRange<'_> {
+    fn report(&self, fraction: f32) {
+        let fraction = fraction.clamp(0.0, 1.0);
+        self.outer.report(self.from + (self.to - self.from) * fraction)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.outer.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_range_maps_into_outer() {
+        let reported = std::sync::Mutex::new(Vec::new());
+        let outer = |fraction: f32| reported.lock().unwrap().push(fraction);
+
+        let second_half = ProgressRange::new(&outer, 0.5, 1.0);
+        second_half.report(0.0);
+        second_half.report(0.5);
+        second_half.report(2.0);
+
+        let inner = ProgressRange::step(&second_half, 1, 2);
+        inner.report(0.0);
+
+        assert_eq!(*reported.lock().unwrap(), [0.5, 0.75, 1.0, 0.75]);
+    }
+
+    struct Cancelled;
+
+    impl Progress for Cancelled {
+        fn report(&self, _fraction: f32) {}
+
+        fn is_cancelled(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_progress_range_forwards_cancellation() {
+        assert!(!NoProgress.is_cancelled());
+        assert!(ProgressRange::new(&Cancelled, 0.0, 0.5).is_cancelled());
+        assert!(!ProgressRange::new(&NoProgress, 0.0, 0.5).is_cancelled());
+    }
+}